@@ -35,16 +35,24 @@ fn main() {
         format!("{}:{}", args.hostname, args.port),
         args.username,
         args.private_key_path,
+        None,
     )
     .unwrap_or_else(|e| {
         error!("failed to connect to gerrit: {}", e);
         std::process::exit(1);
     });
 
-    let gerrit_stream = gerrit::event_stream(connection);
+    let gerrit_stream = gerrit::event_stream(connection, Default::default());
 
-    tokio::run(gerrit_stream.for_each(|event| {
-        println!("{:#?}", event);
-        Ok(())
-    }));
+    tokio::run(
+        gerrit_stream
+            .then(|result| {
+                match result {
+                    Ok(event) => println!("{:#?}", event),
+                    Err(e) => error!("event stream error: {}", e),
+                }
+                Ok(())
+            })
+            .for_each(|()| Ok(())),
+    );
 }
@@ -37,6 +37,7 @@ fn main() {
             format!("{}:{}", args.hostname, args.port),
             args.username.clone(),
             args.private_key_path.clone(),
+            None,
         )
         .unwrap_or_else(|e| {
             error!("failed to connect to gerrit: {}", e);
@@ -44,15 +45,24 @@ fn main() {
         })
     };
 
-    let gerrit_stream = gerrit::extended_event_stream(connect(), connect(), |_| {
-        Cow::Borrowed(&[
-            gerrit::ExtendedInfo::SubmitRecords,
-            gerrit::ExtendedInfo::InlineComments,
-        ])
-    });
+    let command_runner = gerrit::CommandRunner::new(connect());
+    let gerrit_stream =
+        gerrit::extended_event_stream(connect(), Default::default(), command_runner, |_| {
+            Cow::Borrowed(&[
+                gerrit::ExtendedInfo::SubmitRecords,
+                gerrit::ExtendedInfo::InlineComments,
+            ])
+        });
 
-    tokio::run(gerrit_stream.for_each(|event| {
-        println!("{:#?}", event);
-        Ok(())
-    }));
+    tokio::run(
+        gerrit_stream
+            .then(|result| {
+                match result {
+                    Ok(event) => println!("{:#?}", event),
+                    Err(e) => error!("event stream error: {}", e),
+                }
+                Ok(())
+            })
+            .for_each(|()| Ok(())),
+    );
 }
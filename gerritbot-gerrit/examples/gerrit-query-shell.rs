@@ -36,6 +36,7 @@ fn main() {
         format!("{}:{}", args.hostname, args.port),
         args.username,
         args.private_key_path,
+        None,
     )
     .unwrap_or_else(|e| {
         error!("connection to gerrit failed: {}", e);
@@ -47,7 +48,7 @@ fn main() {
 
     tokio::run(
         stdin_lines
-            .map_err(|e| format!("failed to read line: {}", e))
+            .from_err::<gerrit::Error>()
             .and_then(move |line| command_runner.run_command(format!("gerrit query {}", line)))
             .map_err(|e| error!("error: {}", e))
             .for_each(|output| {
@@ -36,6 +36,7 @@ fn main() {
         format!("{}:{}", args.hostname, args.port),
         args.username,
         args.private_key_path,
+        None,
     )
     .unwrap_or_else(|e| {
         error!("connection to gerrit failed: {}", e);
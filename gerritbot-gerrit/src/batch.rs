@@ -0,0 +1,115 @@
+//! Coalescing of stream items arriving in bursts.
+
+use std::mem;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll, Stream};
+use tokio_timer::Delay;
+
+/// Stream adapter collecting items of the underlying stream into batches.
+///
+/// A batch is started by the first item and is emitted either when `window`
+/// has elapsed since then or when it contains `max_size` items. Errors of the
+/// underlying stream are passed through immediately.
+pub(crate) struct Batched<S: Stream> {
+    stream: S,
+    window: Duration,
+    max_size: usize,
+    items: Vec<S::Item>,
+    deadline: Option<Delay>,
+    done: bool,
+}
+
+impl<S: Stream> Batched<S> {
+    pub(crate) fn new(stream: S, window: Duration, max_size: usize) -> Self {
+        assert!(max_size > 0, "batch size must be positive");
+        Self {
+            stream,
+            window,
+            max_size,
+            items: Vec::new(),
+            deadline: None,
+            done: false,
+        }
+    }
+
+    fn take_batch(&mut self) -> Vec<S::Item> {
+        self.deadline = None;
+        mem::take(&mut self.items)
+    }
+}
+
+impl<S: Stream> Stream for Batched<S> {
+    type Item = Vec<S::Item>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while !self.done && self.items.len() < self.max_size {
+            match self.stream.poll()? {
+                Async::Ready(Some(item)) => {
+                    if self.items.is_empty() {
+                        self.deadline = Some(Delay::new(Instant::now() + self.window));
+                    }
+                    self.items.push(item);
+                }
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => break,
+            }
+        }
+
+        if self.items.is_empty() {
+            return Ok(if self.done {
+                Async::Ready(None)
+            } else {
+                Async::NotReady
+            });
+        }
+
+        if self.done || self.items.len() >= self.max_size {
+            return Ok(Async::Ready(Some(self.take_batch())));
+        }
+
+        let deadline_reached = match self.deadline.as_mut().map(Delay::poll) {
+            Some(Ok(Async::NotReady)) => false,
+            // a failing timer must not hold back the items
+            _ => true,
+        };
+
+        if deadline_reached {
+            Ok(Async::Ready(Some(self.take_batch())))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::stream;
+
+    #[test]
+    fn test_batched_max_size() {
+        let batches = Batched::new(stream::iter_ok::<_, ()>(1..=5), Duration::from_secs(60), 2)
+            .collect()
+            .wait();
+        assert_eq!(batches, Ok(vec![vec![1, 2], vec![3, 4], vec![5]]));
+    }
+
+    #[test]
+    fn test_batched_window() {
+        let (tx, rx) = futures::sync::mpsc::unbounded::<u32>();
+        tx.unbounded_send(1).unwrap();
+        tx.unbounded_send(2).unwrap();
+
+        // the sender is still alive, so the batch is emitted by the window
+        let batched = Batched::new(rx, Duration::from_millis(10), 10);
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let batch = runtime
+            .block_on(batched.into_future().map(|(batch, _)| batch))
+            .map_err(|_| ())
+            .unwrap();
+        assert_eq!(batch, Some(vec![1, 2]));
+    }
+}
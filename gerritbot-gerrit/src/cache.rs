@@ -0,0 +1,147 @@
+//! Cache of changes fetched as extended event info.
+//!
+//! Gerrit often emits several events for the same change in a short time,
+//! e.g. when reviewers are added right after a patchset was uploaded. The
+//! cache allows to reuse the change fetched for the first event.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use crate::{Change, Event, ExtendedInfo};
+
+/// Cached changes are identified by the fetched extended info, the change
+/// number and the patchset number.
+type CacheKey = (Vec<ExtendedInfo>, u32, u32);
+
+struct CacheEntry {
+    inserted: Instant,
+    /// Time the change was fetched.
+    fetched_on: DateTime<Utc>,
+    change: Change,
+}
+
+pub(crate) struct ChangeCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<CacheKey, CacheEntry>,
+}
+
+impl ChangeCache {
+    pub(crate) fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Get the cached change for the event. Changes fetched before the event
+    /// was created are outdated and not returned.
+    pub(crate) fn get(&self, extended_info: &[ExtendedInfo], event: &Event) -> Option<&Change> {
+        let key = (
+            extended_info.to_vec(),
+            event.change()?.number,
+            event.patchset()?.number,
+        );
+        let created_on = event.created_on()?;
+        self.entries
+            .get(&key)
+            .filter(|entry| entry.inserted.elapsed() < self.ttl && entry.fetched_on > created_on)
+            .map(|entry| &entry.change)
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        extended_info: &[ExtendedInfo],
+        patchset_number: u32,
+        change: Change,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = (extended_info.to_vec(), change.number, patchset_number);
+        self.entries.insert(
+            key,
+            CacheEntry {
+                inserted: Instant::now(),
+                fetched_on: Utc::now(),
+                change,
+            },
+        );
+
+        if self.entries.len() > self.capacity {
+            let ttl = self.ttl;
+            self.entries
+                .retain(|_, entry| entry.inserted.elapsed() < ttl);
+        }
+
+        // still too many entries, evict the oldest ones
+        while self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.inserted)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => self.entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EVENT_JSON: &str = r#"{"reviewer":{"name":"jdoe","email":"john.doe@localhost","username":"jdoe"},"patchSet":{"number":1,"revision":"c4f7d43450e366f9c8e4dcb94fbd91573cd40766","parents":["20332c6ee056bdf3f814c8cff9905154d443d2f0"],"ref":"refs/changes/01/1/1","uploader":{"name":"Administrator","email":"admin@example.com","username":"admin"},"createdOn":1553631812,"author":{"name":"Frank Benkstein","email":"frank@benkstein.net","username":""},"isDraft":false,"kind":"REWORK","sizeInsertions":0,"sizeDeletions":-18},"change":{"project":"gerritbot-rs","branch":"master","id":"I5e53df227fd2739ddd65c3034b2f9f789200bd89","number":1,"subject":"get rid of non-macro extern crate","owner":{"name":"Administrator","email":"admin@example.com","username":"admin"},"url":"http://localhost:8080/1","commitMessage":"get rid of non-macro extern crate\n\nChange-Id: I5e53df227fd2739ddd65c3034b2f9f789200bd89\n","createdOn":1553631812,"status":"NEW"},"project":"gerritbot-rs","refName":"refs/heads/master","changeKey":{"id":"I5e53df227fd2739ddd65c3034b2f9f789200bd89"},"type":"reviewer-added","eventCreatedOn":1553632329}"#;
+
+    fn get_event() -> Event {
+        serde_json::from_str(EVENT_JSON).expect("failed to decode event")
+    }
+
+    const INFO: &[ExtendedInfo] = &[ExtendedInfo::SubmitRecords];
+
+    #[test]
+    fn test_get_cached_change() {
+        let event = get_event();
+        let mut cache = ChangeCache::new(Duration::from_secs(60), 10);
+        assert!(cache.get(INFO, &event).is_none());
+
+        cache.insert(INFO, 1, event.change().unwrap().clone());
+        assert!(cache.get(INFO, &event).is_some());
+        assert!(cache.get(&[ExtendedInfo::AllApprovals], &event).is_none());
+    }
+
+    #[test]
+    fn test_outdated_change_is_not_returned() {
+        let mut event = get_event();
+        let mut cache = ChangeCache::new(Duration::from_secs(60), 10);
+        cache.insert(INFO, 1, event.change().unwrap().clone());
+
+        if let Event::ReviewerAdded(ref mut event) = event {
+            event.created_on = Utc::now() + chrono::Duration::seconds(60);
+        }
+        assert!(cache.get(INFO, &event).is_none());
+    }
+
+    #[test]
+    fn test_expired_change_is_not_returned() {
+        let event = get_event();
+        let mut cache = ChangeCache::new(Duration::from_secs(0), 10);
+        cache.insert(INFO, 1, event.change().unwrap().clone());
+        assert!(cache.get(INFO, &event).is_none());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let event = get_event();
+        let mut cache = ChangeCache::new(Duration::from_secs(60), 1);
+        cache.insert(INFO, 1, event.change().unwrap().clone());
+        cache.insert(INFO, 2, event.change().unwrap().clone());
+        assert_eq!(cache.entries.len(), 1);
+    }
+}
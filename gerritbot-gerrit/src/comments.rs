@@ -0,0 +1,95 @@
+//! Inline comments already reported with a comment-added event.
+//!
+//! Gerrit returns all inline comments of a patchset, and the comments have no
+//! timestamp. When an author comments several times on the same patchset, the
+//! comments reported with the earlier events are remembered, so only the new
+//! ones are attached to the later events.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::{Event, InlineComment};
+
+/// Reported comments are identified by the change number, the patchset number
+/// and the author.
+type Key = (u32, u32, String);
+
+/// A comment is identified by its file, line and message.
+type CommentKey = (String, u32, String);
+
+struct Entry {
+    updated: Instant,
+    comments: HashSet<CommentKey>,
+}
+
+pub(crate) struct ReportedComments {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<Key, Entry>,
+}
+
+fn comment_key(comment: &InlineComment) -> CommentKey {
+    (comment.file.clone(), comment.line, comment.message.clone())
+}
+
+impl ReportedComments {
+    pub(crate) fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Remove the inline comments of a comment-added event which were already
+    /// reported, and remember the remaining ones.
+    pub(crate) fn retain_new(&mut self, event: &mut Event) {
+        let event = match event {
+            Event::CommentAdded(event) => event,
+            _ => return,
+        };
+        let author = match event
+            .author
+            .username
+            .as_ref()
+            .or(event.author.email.as_ref())
+        {
+            Some(author) => author.clone(),
+            None => return,
+        };
+        let comments = match event.patchset.comments.as_mut() {
+            Some(comments) => comments,
+            None => return,
+        };
+
+        let ttl = self.ttl;
+        let key = (event.change.number, event.patchset.number, author);
+        let entry = self.entries.entry(key).or_insert_with(|| Entry {
+            updated: Instant::now(),
+            comments: HashSet::new(),
+        });
+        if entry.updated.elapsed() >= ttl {
+            entry.comments.clear();
+        }
+        entry.updated = Instant::now();
+        comments.retain(|comment| entry.comments.insert(comment_key(comment)));
+
+        if self.entries.len() > self.capacity {
+            self.entries
+                .retain(|_, entry| entry.updated.elapsed() < ttl);
+        }
+
+        // still too many entries, evict the oldest ones
+        while self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.updated)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => self.entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+}
@@ -1,19 +1,69 @@
 use std::borrow::Cow;
-use std::io::{BufRead, BufReader, Read as _};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Read as _};
 use std::net::TcpStream;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use backoff::Operation as _; // for retry_notify
+use chrono::{DateTime, Utc};
+use futures::future::Loop;
 use futures::sync::mpsc::{channel, Receiver, Sender};
 use futures::sync::oneshot;
-use futures::{future, Future, Sink, Stream};
-use log::{debug, error, info};
+use futures::{future, stream, Future, Sink, Stream};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+mod batch;
+mod cache;
+mod comments;
+mod metrics;
+mod project_filter;
+mod proxy;
+mod query;
+mod recovery;
+mod review;
+pub mod timestamp;
+mod version;
+
+pub use metrics::{Counters, Metrics, NoMetrics};
+pub use project_filter::ProjectFilter;
+pub use proxy::ProxyJump;
+pub use query::Query;
+pub use review::Review;
+pub use version::GerritVersion;
 
 /// Gerrit username
 pub type Username = String;
 
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Ssh(#[from] ssh2::Error),
+    #[error("failed to decode: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("command exited with status {status}: {stderr}")]
+    CommandFailed { status: i32, stderr: String },
+    #[error("connection thread is gone")]
+    Disconnected,
+}
+
+impl Error {
+    /// Whether the error is caused by the connection and might go away after
+    /// reconnecting.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(_) | Error::Ssh(_) => true,
+            Error::Decode(_) | Error::CommandFailed { .. } | Error::Disconnected => false,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
     pub name: Option<String>,
@@ -21,6 +71,17 @@ pub struct User {
     pub email: Option<String>,
 }
 
+impl User {
+    /// Whether both users are the same Gerrit account, judging by the username
+    /// or, if missing, the email address.
+    pub fn is_same_user(&self, other: &User) -> bool {
+        match (&self.username, &other.username) {
+            (Some(username), Some(other_username)) => username == other_username,
+            _ => self.email.is_some() && self.email == other.email,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Approval {
@@ -30,6 +91,8 @@ pub struct Approval {
     pub value: String,
     pub old_value: Option<String>,
     pub by: Option<User>,
+    #[serde(with = "timestamp::option", default)]
+    pub granted_on: Option<DateTime<Utc>>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -41,7 +104,8 @@ pub struct Patchset {
     #[serde(rename = "ref")]
     pub reference: String,
     pub uploader: User,
-    pub created_on: u32,
+    #[serde(with = "timestamp")]
+    pub created_on: DateTime<Utc>,
     pub author: User,
     #[serde(default)]
     pub is_draft: bool,
@@ -80,7 +144,28 @@ pub enum SubmitStatus {
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SubmitRecord {
-    status: SubmitStatus,
+    pub status: SubmitStatus,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum SubmitRequirementStatus {
+    SATISFIED,
+    UNSATISFIED,
+    OVERRIDDEN,
+    NOT_APPLICABLE,
+    ERROR,
+    FORCED,
+}
+
+/// Submit requirement as reported by Gerrit 3.5+ instead of submit records.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SubmitRequirement {
+    pub name: String,
+    pub status: SubmitRequirementStatus,
+    #[serde(default)]
+    pub is_legacy: bool,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -95,16 +180,42 @@ pub struct Change {
     pub owner: User,
     pub url: String,
     pub commit_message: String,
+    #[serde(with = "timestamp::option", default)]
+    pub created_on: Option<DateTime<Utc>>,
     pub status: ChangeStatus,
     pub current_patch_set: Option<Patchset>,
     pub patch_sets: Option<Vec<Patchset>>,
     pub comments: Option<Vec<Comment>>,
     pub submit_records: Option<Vec<SubmitRecord>>,
+    pub submit_requirements: Option<Vec<SubmitRequirement>>,
+    pub all_reviewers: Option<Vec<User>>,
+}
+
+impl Change {
+    /// Whether the change can be submitted according to its submit
+    /// requirements or, for older Gerrit versions, its submit records. `None`
+    /// if neither was fetched (see `ExtendedInfo::SubmitRecords` and
+    /// `ExtendedInfo::SubmitRequirements`).
+    pub fn is_submittable(&self) -> Option<bool> {
+        if let Some(requirements) = &self.submit_requirements {
+            Some(requirements.iter().all(|requirement| {
+                requirement.status != SubmitRequirementStatus::UNSATISFIED
+                    && requirement.status != SubmitRequirementStatus::ERROR
+            }))
+        } else {
+            self.submit_records.as_ref().map(|records| {
+                records
+                    .iter()
+                    .any(|record| matches!(record.status, SubmitStatus::OK))
+            })
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Comment {
-    pub timestamp: u64,
+    #[serde(with = "timestamp")]
+    pub timestamp: DateTime<Utc>,
     pub reviewer: User,
     pub message: String,
 }
@@ -123,8 +234,8 @@ pub struct CommentAddedEvent {
     pub author: User,
     pub approvals: Option<Vec<Approval>>,
     pub comment: String,
-    #[serde(rename = "eventCreatedOn")]
-    pub created_on: u32,
+    #[serde(rename = "eventCreatedOn", with = "timestamp")]
+    pub created_on: DateTime<Utc>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -133,8 +244,8 @@ pub struct ReviewerAddedEvent {
     #[serde(rename = "patchSet")]
     pub patchset: Patchset,
     pub reviewer: User,
-    #[serde(rename = "eventCreatedOn")]
-    pub created_on: u32,
+    #[serde(rename = "eventCreatedOn", with = "timestamp")]
+    pub created_on: DateTime<Utc>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -145,8 +256,8 @@ pub struct ChangeMergedEvent {
     pub submitter: User,
     #[serde(rename = "newRev")]
     pub new_revision: String,
-    #[serde(rename = "eventCreatedOn")]
-    pub created_on: u32,
+    #[serde(rename = "eventCreatedOn", with = "timestamp")]
+    pub created_on: DateTime<Utc>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -156,32 +267,149 @@ pub struct ChangeAbandonedEvent {
     pub patchset: Patchset,
     pub abandoner: User,
     pub reason: Option<String>,
-    #[serde(rename = "eventCreatedOn")]
-    pub created_on: u32,
+    #[serde(rename = "eventCreatedOn", with = "timestamp")]
+    pub created_on: DateTime<Utc>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(tag = "type")]
+pub struct ChangeDeletedEvent {
+    pub change: Change,
+    pub deleter: User,
+    #[serde(rename = "eventCreatedOn", with = "timestamp")]
+    pub created_on: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
 pub enum Event {
-    #[serde(rename = "comment-added")]
     CommentAdded(CommentAddedEvent),
-    #[serde(rename = "reviewer-added")]
     ReviewerAdded(ReviewerAddedEvent),
-    #[serde(rename = "change-merged")]
     ChangeMerged(ChangeMergedEvent),
-    #[serde(rename = "change-abandoned")]
     ChangeAbandoned(ChangeAbandonedEvent),
+    ChangeDeleted(ChangeDeletedEvent),
+    /// Event of a type not known to this library. Only delivered by the event
+    /// stream if `EventStreamOptions::all_events` is set, otherwise the stream
+    /// subscribes to the known event types only.
+    Unknown {
+        event_type: String,
+        raw: serde_json::Value,
+    },
 }
 
 impl Event {
-    fn change_and_patchset_mut(&mut self) -> Option<(&mut Change, &mut Patchset)> {
-        Some(match self {
-            Event::CommentAdded(event) => (&mut event.change, &mut event.patchset),
-            Event::ReviewerAdded(event) => (&mut event.change, &mut event.patchset),
-            Event::ChangeMerged(event) => (&mut event.change, &mut event.patchset),
-            Event::ChangeAbandoned(event) => (&mut event.change, &mut event.patchset),
+    /// The event type as used by Gerrit, e.g. "comment-added".
+    pub fn type_name(&self) -> &str {
+        match self {
+            Event::CommentAdded(_) => "comment-added",
+            Event::ReviewerAdded(_) => "reviewer-added",
+            Event::ChangeMerged(_) => "change-merged",
+            Event::ChangeAbandoned(_) => "change-abandoned",
+            Event::ChangeDeleted(_) => "change-deleted",
+            Event::Unknown { event_type, .. } => event_type,
+        }
+    }
+
+    pub fn change(&self) -> Option<&Change> {
+        match self {
+            Event::CommentAdded(event) => Some(&event.change),
+            Event::ReviewerAdded(event) => Some(&event.change),
+            Event::ChangeMerged(event) => Some(&event.change),
+            Event::ChangeAbandoned(event) => Some(&event.change),
+            Event::ChangeDeleted(event) => Some(&event.change),
+            Event::Unknown { .. } => None,
+        }
+    }
+
+    pub fn patchset(&self) -> Option<&Patchset> {
+        match self {
+            Event::CommentAdded(event) => Some(&event.patchset),
+            Event::ReviewerAdded(event) => Some(&event.patchset),
+            Event::ChangeMerged(event) => Some(&event.patchset),
+            Event::ChangeAbandoned(event) => Some(&event.patchset),
+            Event::ChangeDeleted(_) | Event::Unknown { .. } => None,
+        }
+    }
+
+    pub fn created_on(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Event::CommentAdded(event) => Some(event.created_on),
+            Event::ReviewerAdded(event) => Some(event.created_on),
+            Event::ChangeMerged(event) => Some(event.created_on),
+            Event::ChangeAbandoned(event) => Some(event.created_on),
+            Event::ChangeDeleted(event) => Some(event.created_on),
+            Event::Unknown { raw, .. } => raw
+                .get("eventCreatedOn")
+                .and_then(serde_json::Value::as_i64)
+                .and_then(timestamp::from_epoch_seconds),
+        }
+    }
+
+    fn key(&self) -> Option<EventKey> {
+        Some(EventKey {
+            event_type: self.type_name().to_string(),
+            change_id: self.change()?.id.clone(),
+            created_on: self.created_on()?,
         })
     }
+
+    fn change_and_patchset_mut(&mut self) -> Option<(&mut Change, &mut Patchset)> {
+        match self {
+            Event::CommentAdded(event) => Some((&mut event.change, &mut event.patchset)),
+            Event::ReviewerAdded(event) => Some((&mut event.change, &mut event.patchset)),
+            Event::ChangeMerged(event) => Some((&mut event.change, &mut event.patchset)),
+            Event::ChangeAbandoned(event) => Some((&mut event.change, &mut event.patchset)),
+            Event::ChangeDeleted(_) | Event::Unknown { .. } => None,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Event {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error as _;
+
+        let raw = serde_json::Value::deserialize(deserializer)?;
+        let event_type = raw
+            .get("type")
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| D::Error::missing_field("type"))?
+            .to_string();
+
+        let event = match event_type.as_str() {
+            "comment-added" => serde_json::from_value(raw).map(Event::CommentAdded),
+            "reviewer-added" => serde_json::from_value(raw).map(Event::ReviewerAdded),
+            "change-merged" => serde_json::from_value(raw).map(Event::ChangeMerged),
+            "change-abandoned" => serde_json::from_value(raw).map(Event::ChangeAbandoned),
+            "change-deleted" => serde_json::from_value(raw).map(Event::ChangeDeleted),
+            _ => Ok(Event::Unknown { event_type, raw }),
+        };
+        event.map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for Event {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::Error as _;
+
+        let mut value = match self {
+            Event::CommentAdded(event) => serde_json::to_value(event),
+            Event::ReviewerAdded(event) => serde_json::to_value(event),
+            Event::ChangeMerged(event) => serde_json::to_value(event),
+            Event::ChangeAbandoned(event) => serde_json::to_value(event),
+            Event::ChangeDeleted(event) => serde_json::to_value(event),
+            Event::Unknown { raw, .. } => return raw.serialize(serializer),
+        }
+        .map_err(S::Error::custom)?;
+
+        if let serde_json::Value::Object(fields) = &mut value {
+            fields.insert("type".to_string(), self.type_name().into());
+        }
+        value.serialize(serializer)
+    }
 }
 
 fn get_pub_key_path(priv_key_path: &Path) -> PathBuf {
@@ -192,10 +420,13 @@ fn get_pub_key_path(priv_key_path: &Path) -> PathBuf {
 
 pub struct Connection {
     pub session: ssh2::Session,
+    version: Option<GerritVersion>,
     // Data needed for reconnection in case this connection was terminated.
     host: String,
     username: String,
     priv_key_path: PathBuf,
+    passphrase: Option<String>,
+    proxy_jump: Option<ProxyJump>,
 }
 
 impl Connection {
@@ -204,57 +435,100 @@ impl Connection {
         username: &str,
         pub_key_path: &Path,
         priv_key_path: &Path,
-    ) -> Result<ssh2::Session, String> {
-        let mut session = ssh2::Session::new().unwrap();
+        passphrase: Option<&str>,
+        proxy_jump: Option<&ProxyJump>,
+    ) -> Result<ssh2::Session, Error> {
+        let mut session = ssh2::Session::new()?;
 
         debug!("Connecting to tcp: {}", &host);
 
-        let tcp = TcpStream::connect(&host)
-            .map_err(|err| format!("Could not connect to gerrit at {}: {:?}", host, err))?;
+        let tcp = match proxy_jump {
+            Some(proxy_jump) => proxy::connect_via_jump_host(
+                proxy_jump,
+                host,
+                username,
+                pub_key_path,
+                priv_key_path,
+                passphrase,
+            )?,
+            None => TcpStream::connect(host)?,
+        };
 
         session.set_tcp_stream(tcp);
-        session
-            .handshake()
-            .map_err(|err| format!("Could not connect to gerrit: {:?}", err))?;
+        session.handshake()?;
 
         // Try to authenticate
-        session
-            .userauth_pubkey_file(username, Some(pub_key_path), priv_key_path, None)
-            .map_err(|err| format!("Could not authenticate: {:?}", err))?;
+        session.userauth_pubkey_file(username, Some(pub_key_path), priv_key_path, passphrase)?;
 
         Ok(session)
     }
 
-    pub fn connect(host: String, username: String, priv_key_path: PathBuf) -> Result<Self, String> {
+    /// Connect to Gerrit. The passphrase is only needed if the private key is
+    /// encrypted.
+    pub fn connect(
+        host: String,
+        username: String,
+        priv_key_path: PathBuf,
+        passphrase: Option<String>,
+    ) -> Result<Self, Error> {
+        Self::connect_via(host, username, priv_key_path, passphrase, None)
+    }
+
+    /// Connect to Gerrit, optionally through a jump host.
+    pub fn connect_via(
+        host: String,
+        username: String,
+        priv_key_path: PathBuf,
+        passphrase: Option<String>,
+        proxy_jump: Option<ProxyJump>,
+    ) -> Result<Self, Error> {
         let pub_key_path = get_pub_key_path(&priv_key_path);
         debug!("Will use public key: {}", pub_key_path.to_str().unwrap());
 
-        let session =
-            Self::connect_session(&host, &username, &pub_key_path, &priv_key_path)?;
+        let session = Self::connect_session(
+            &host,
+            &username,
+            &pub_key_path,
+            &priv_key_path,
+            passphrase.as_deref(),
+            proxy_jump.as_ref(),
+        )?;
+        let version = version::detect_version(&session);
 
         Ok(Self {
             session,
+            version,
             host,
             username,
             priv_key_path,
+            passphrase,
+            proxy_jump,
         })
     }
 
     /// Reconnect once.
-    pub fn reconnect(&mut self) -> Result<(), String> {
+    pub fn reconnect(&mut self) -> Result<(), Error> {
         let pub_key_path = get_pub_key_path(&self.priv_key_path);
         self.session = Self::connect_session(
             &self.host,
             &self.username,
             &pub_key_path,
             &self.priv_key_path,
+            self.passphrase.as_deref(),
+            self.proxy_jump.as_ref(),
         )?;
+        self.version = version::detect_version(&self.session);
         Ok(())
     }
 
+    /// Version of the Gerrit server, if it could be detected.
+    pub fn version(&self) -> Option<GerritVersion> {
+        self.version
+    }
+
     /// Reconnect repeatedly with exponential backoff. This will try to
     /// reconnect indefinitely.
-    pub fn reconnect_repeatedly(&mut self) -> Result<(), String> {
+    pub fn reconnect_repeatedly(&mut self) -> Result<(), Error> {
         let mut backoff = backoff::ExponentialBackoff::default();
         let mut reconnect = || self.reconnect().map_err(backoff::Error::Transient);
 
@@ -266,35 +540,128 @@ impl Connection {
             .map_err(|e| match e {
                 // neither of these should happen unless we reconfigure backoff
                 // not to retry indefinitely
-                backoff::Error::Transient(e) | backoff::Error::Permanent(e) => {
-                    format!("reconnect failed: {}", e)
-                }
+                backoff::Error::Transient(e) | backoff::Error::Permanent(e) => e,
             })
     }
 }
 
 struct CommandRequest {
     command: String,
-    sender: oneshot::Sender<Result<String, String>>,
+    sender: oneshot::Sender<Result<String, Error>>,
 }
 
+/// Options controlling how commands are run.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandOptions {
+    /// Abandon a command (and its connection) if it doesn't finish within this
+    /// time.
+    pub timeout: Option<Duration>,
+    /// How often a command is retried after a connection failure or timeout.
+    /// The connection is reestablished before each retry.
+    pub max_retries: u32,
+}
+
+impl Default for CommandOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            max_retries: 3,
+        }
+    }
+}
+
+fn duration_to_millis(duration: Duration) -> u32 {
+    duration.as_millis().min(u128::from(u32::MAX)) as u32
+}
+
+/// Timeout of the session in milliseconds. A timeout of zero means no timeout
+/// for libssh2, both if none or a timeout of zero is given.
+fn session_timeout_millis(timeout: Option<Duration>) -> u32 {
+    timeout.map(duration_to_millis).unwrap_or(0)
+}
+
+/// A connection commands are run on.
+trait CommandConnection {
+    /// Run a command once. Errors that might go away after reconnecting are
+    /// reported as transient.
+    fn run_command_once(
+        &mut self,
+        command: &str,
+        timeout: Option<Duration>,
+    ) -> Result<String, backoff::Error<Error>>;
+
+    fn reconnect(&mut self) -> Result<(), Error>;
+}
+
+impl CommandConnection for Connection {
+    fn run_command_once(
+        &mut self,
+        command: &str,
+        timeout: Option<Duration>,
+    ) -> Result<String, backoff::Error<Error>> {
+        CommandRunner::run_command_once(self, command, timeout)
+    }
+
+    fn reconnect(&mut self) -> Result<(), Error> {
+        Connection::reconnect(self)
+    }
+}
+
+/// Runs commands over SSH. Commands are dispatched round-robin to a pool of
+/// worker threads, each with its own connection.
+#[derive(Clone)]
 pub struct CommandRunner {
-    sender: Sender<CommandRequest>,
+    senders: Vec<Sender<CommandRequest>>,
+    next_sender: usize,
 }
 
 impl CommandRunner {
     pub fn new(connection: Connection) -> Self {
-        let (sender, receiver) = channel(1);
+        Self::with_connections(vec![connection], CommandOptions::default())
+    }
 
-        thread::Builder::new()
-            .name("SSH command runner".to_string())
-            .spawn(move || Self::run_commands(connection, receiver))
-            .expect("failed to spawn thread");
+    /// Create a command runner with one worker per connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `connections` is empty.
+    pub fn with_connections(connections: Vec<Connection>, options: CommandOptions) -> Self {
+        assert!(
+            !connections.is_empty(),
+            "command runner needs at least one connection"
+        );
+
+        let senders = connections
+            .into_iter()
+            .enumerate()
+            .map(|(index, connection)| {
+                let (sender, receiver) = channel(1);
+
+                thread::Builder::new()
+                    .name(format!("SSH command runner {}", index))
+                    .spawn(move || Self::run_commands(connection, receiver, options))
+                    .expect("failed to spawn thread");
+
+                sender
+            })
+            .collect();
+
+        Self {
+            senders,
+            next_sender: 0,
+        }
+    }
 
-        Self { sender }
+    /// Number of connections commands are distributed over.
+    pub fn pool_size(&self) -> usize {
+        self.senders.len()
     }
 
-    fn run_commands(connection: Connection, receiver: Receiver<CommandRequest>) {
+    fn run_commands(
+        connection: Connection,
+        receiver: Receiver<CommandRequest>,
+        options: CommandOptions,
+    ) {
         let mut connection = connection;
         let mut connection_healthy = true;
 
@@ -308,228 +675,634 @@ impl CommandRunner {
                 }
             };
 
-            let command_result = loop {
-                if !connection_healthy {
-                    info!("reconnecting");
+            if sender.is_canceled() {
+                // e.g. the caller timed out while the command was queued
+                debug!("skipping cancelled command");
+                continue;
+            }
 
-                    if let Err(e) = connection.reconnect_repeatedly() {
-                        error!("reconnect failed permanently: {}", e);
-                        return;
-                    }
+            let command_result = Self::run_command_with_retries(
+                &mut connection,
+                &mut connection_healthy,
+                &command,
+                options,
+                &mut backoff::ExponentialBackoff::default(),
+            );
 
-                    connection_healthy = true;
-                }
+            if sender.send(command_result).is_err() {
+                // the caller is not interested in the result anymore
+                debug!("failed to send command result");
+            }
+        }
+    }
 
-                let mut ssh_channel = match connection.session.channel_session() {
-                    Ok(channel) => channel,
-                    Err(e) => {
-                        error!("failed to create ssh session channel: {}", e);
-                        connection_healthy = false;
-                        continue;
+    /// Run a command, retrying it after transient failures up to
+    /// `options.max_retries` times with the backoff. A connection which failed
+    /// is reconnected before the next attempt.
+    fn run_command_with_retries<C, B>(
+        connection: &mut C,
+        connection_healthy: &mut bool,
+        command: &str,
+        options: CommandOptions,
+        backoff: &mut B,
+    ) -> Result<String, Error>
+    where
+        C: CommandConnection,
+        B: backoff::backoff::Backoff,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let attempt_result = if *connection_healthy {
+                connection.run_command_once(command, options.timeout)
+            } else {
+                info!("reconnecting");
+                connection
+                    .reconnect()
+                    .map_err(backoff::Error::Transient)
+                    .and_then(|()| {
+                        *connection_healthy = true;
+                        connection.run_command_once(command, options.timeout)
+                    })
+            };
+
+            match attempt_result {
+                Ok(data) => return Ok(data),
+                Err(backoff::Error::Permanent(e)) => return Err(e),
+                Err(backoff::Error::Transient(e)) => {
+                    *connection_healthy = false;
+
+                    if attempt >= options.max_retries {
+                        return Err(e);
                     }
-                };
 
-                if let Err(e) = ssh_channel.exec(&command) {
-                    error!("failed to request exec channel: {}", e);
-                    break Err(format!("failed to request exec channel: {}", e));
+                    attempt += 1;
+                    let delay = backoff.next_backoff().unwrap_or_default();
+                    warn!(
+                        "command failed, retrying in {:?} ({}/{}): {}",
+                        delay, attempt, options.max_retries, e
+                    );
+                    thread::sleep(delay);
                 }
+            }
+        }
+    }
 
-                let mut data = String::new();
+    /// Run a command once. Errors that might go away after reconnecting are
+    /// reported as transient.
+    fn run_command_once(
+        connection: &mut Connection,
+        command: &str,
+        timeout: Option<Duration>,
+    ) -> Result<String, backoff::Error<Error>> {
+        let into_backoff_error = |e: Error| {
+            if e.is_transient() {
+                backoff::Error::Transient(e)
+            } else {
+                backoff::Error::Permanent(e)
+            }
+        };
 
-                if let Err(e) = ssh_channel.read_to_string(&mut data) {
-                    break Err(format!("failed to read from channel: {}", e));
-                }
+        connection
+            .session
+            .set_timeout(session_timeout_millis(timeout));
 
-                match ssh_channel
-                    .close()
-                    .and_then(|()| ssh_channel.wait_close())
-                    .and_then(|()| ssh_channel.exit_status())
-                {
-                    Ok(0) => break Ok(data),
-                    Ok(i) => break Err(format!("command exited with status {}", i)),
-                    Err(e) => break Err(format!("failed to close command channel: {}", e)),
-                }
-            };
+        let mut ssh_channel = connection
+            .session
+            .channel_session()
+            .map_err(|e| into_backoff_error(e.into()))?;
 
-            if sender.send(command_result).is_err() {
-                // receiver was closed, this is either an error or a signal to exit
-                debug!("failed to send command result");
-                return;
-            }
+        ssh_channel
+            .exec(command)
+            .map_err(|e| into_backoff_error(e.into()))?;
+
+        let mut data = String::new();
+        let mut stderr = String::new();
+
+        ssh_channel
+            .read_to_string(&mut data)
+            .and_then(|_| ssh_channel.stderr().read_to_string(&mut stderr))
+            .map_err(|e| into_backoff_error(e.into()))?;
+
+        let status = ssh_channel
+            .close()
+            .and_then(|()| ssh_channel.wait_close())
+            .and_then(|()| ssh_channel.exit_status())
+            .map_err(|e| into_backoff_error(e.into()))?;
+
+        if status == 0 {
+            Ok(data)
+        } else {
+            Err(into_backoff_error(Error::CommandFailed { status, stderr }))
         }
     }
 
-    pub fn run_command(&mut self, command: String) -> impl Future<Item = String, Error = String> {
+    /// Run the query and decode the resulting changes.
+    pub fn query(&mut self, query: &Query) -> impl Future<Item = Vec<Change>, Error = Error> {
+        self.run_command(query.to_command())
+            .and_then(|output| Query::parse_result(&output))
+    }
+
+    pub fn run_command(&mut self, command: String) -> impl Future<Item = String, Error = Error> {
+        // drop workers whose thread is gone
+        let pool_size = self.senders.len();
+        self.senders.retain(|sender| !sender.is_closed());
+        if self.senders.len() < pool_size {
+            warn!(
+                "removed {} dead command runner(s), {} left",
+                pool_size - self.senders.len(),
+                self.senders.len()
+            );
+        }
+
+        // try the workers round-robin, starting with the next one
+        let workers: Vec<_> = self
+            .senders
+            .iter()
+            .cycle()
+            .skip(self.next_sender)
+            .take(self.senders.len())
+            .cloned()
+            .collect();
+        self.next_sender = (self.next_sender + 1) % self.senders.len().max(1);
+
         // create a channel that the command thread can use to send the result of the command back
         let (sender, receiver) = oneshot::channel();
-        self.sender
-            .clone()
-            .send(CommandRequest { command, sender })
-            .map_err(|_| "command thread died before sending".to_string())
-            .and_then(|_| receiver.map_err(|_| "command thread died after sending".to_string()))
-            .and_then(|result| result)
+        let request = CommandRequest { command, sender };
+
+        future::loop_fn((workers.into_iter(), request), |(mut workers, request)| {
+            future::result(workers.next().ok_or(Error::Disconnected)).and_then(move |worker| {
+                worker.send(request).then(move |result| match result {
+                    Ok(_) => Ok(Loop::Break(())),
+                    Err(e) => {
+                        warn!("command runner is gone, trying the next one");
+                        Ok(Loop::Continue((workers, e.into_inner())))
+                    }
+                })
+            })
+        })
+        .and_then(|()| receiver.map_err(|_| Error::Disconnected))
+        .and_then(|result| result)
     }
 }
 
-fn receiver_into_event_stream(rx: Receiver<String>) -> impl Stream<Item = Event, Error = ()> {
-    rx.filter_map(|event_data| {
-        serde_json::from_str(&event_data)
-            .map_err(|e| error!("failed to decode gerrit event: {}", e))
-            .ok()
-    })
-    .inspect(|event| debug!("Incoming Gerrit event: {:#?}", event))
+fn receiver_into_event_stream(
+    rx: Receiver<Result<String, Error>>,
+    metrics: Arc<dyn Metrics>,
+) -> impl Stream<Item = Event, Error = Error> {
+    let decode_metrics = metrics.clone();
+    rx.map_err(|()| Error::Disconnected)
+        .and_then(|event_data| event_data)
+        .and_then(move |event_data| {
+            serde_json::from_str(&event_data).map_err(|e| {
+                decode_metrics.decode_failed();
+                Error::from(e)
+            })
+        })
+        .inspect(move |event: &Event| {
+            debug!("Incoming Gerrit event: {:#?}", event);
+            metrics.event_received(event.type_name());
+        })
+}
+
+/// Event types the stream subscribes to, unless all events are requested.
+const STREAM_EVENT_TYPES: &[&str] = &[
+    "comment-added",
+    "reviewer-added",
+    "change-abandoned",
+    "change-merged",
+    "change-deleted",
+];
+
+/// The command streaming the events, optionally filtered by the known event
+/// types the server supports. If the version is unknown, all known event types
+/// are assumed to be supported.
+fn stream_events_command(version: Option<GerritVersion>, all_events: bool) -> String {
+    let mut command = "gerrit stream-events".to_string();
+    if !all_events {
+        let supported_event_types = STREAM_EVENT_TYPES
+            .iter()
+            .filter(|event_type| match version {
+                Some(version) => version.supports_event(event_type),
+                None => true,
+            });
+        for event_type in supported_event_types {
+            command.push_str(" -s ");
+            command.push_str(event_type);
+        }
+    }
+    command
+}
+
+/// Identifies an event independently of its content.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct EventKey {
+    event_type: String,
+    change_id: String,
+    created_on: DateTime<Utc>,
 }
 
-const GERRIT_STREAM_EVENTS_COMMAND: &str = "gerrit stream-events \
-                                            -s comment-added \
-                                            -s reviewer-added \
-                                            -s change-abandoned \
-                                            -s change-merged";
+/// The fields of a raw stream event needed to track the stream position and
+/// to filter events.
+#[derive(Deserialize)]
+struct EventHeader {
+    #[serde(rename = "type")]
+    event_type: String,
+    #[serde(rename = "changeKey")]
+    change_key: ChangeKey,
+    #[serde(rename = "eventCreatedOn", with = "timestamp")]
+    created_on: DateTime<Utc>,
+    change: ChangeHeader,
+}
 
-pub fn event_stream(connection: Connection) -> impl Stream<Item = Event, Error = ()> {
+#[derive(Deserialize)]
+struct ChangeHeader {
+    project: String,
+}
+
+/// Options for `event_stream` and `extended_event_stream`.
+#[derive(Debug, Clone)]
+pub struct EventStreamOptions {
+    /// Recover events created after this time when first connecting.
+    pub since: Option<DateTime<Utc>>,
+    /// Only events of matching projects are delivered.
+    pub projects: ProjectFilter,
+    /// Receives measurements of the stream.
+    pub metrics: Arc<dyn Metrics>,
+    /// Subscribe to events of all types instead of the known ones only.
+    /// Events of unknown types are delivered as `Event::Unknown`.
+    pub all_events: bool,
+}
+
+impl Default for EventStreamOptions {
+    fn default() -> Self {
+        Self {
+            since: None,
+            projects: ProjectFilter::default(),
+            metrics: Arc::new(NoMetrics),
+            all_events: false,
+        }
+    }
+}
+
+impl EventHeader {
+    fn into_key(self) -> EventKey {
+        EventKey {
+            event_type: self.event_type,
+            change_id: self.change_key.id,
+            created_on: self.created_on,
+        }
+    }
+}
+
+/// Stream of Gerrit events. The connection is reestablished if it breaks.
+/// Afterwards, events missed in the meantime are recovered (as far as possible)
+/// by querying the changes updated since the last seen event. If
+/// `options.since` is given, events created after that time are recovered when
+/// first connecting. Events of projects not matching `options.projects` are
+/// dropped before they are decoded.
+pub fn event_stream(
+    connection: Connection,
+    options: EventStreamOptions,
+) -> impl Stream<Item = Event, Error = Error> {
     let (main_tx, rx) = channel(1);
 
-    fn process_events(connection: &mut Connection, tx: &Sender<String>) -> Result<(), ()> {
-        let mut ssh_channel = connection
-            .session
-            .channel_session()
-            .map_err(|err| error!("Could not open SSH channel: {:?}", err))?;
-        ssh_channel
-            .exec(GERRIT_STREAM_EVENTS_COMMAND)
-            .map_err(|err| {
-                error!(
-                    "Could not execute gerrit stream-event command over ssh: {:?}",
-                    err
-                )
-            })?;
+    /// Send event data or an error to the stream. Returns `false` if the
+    /// stream was dropped.
+    fn send_event_data(tx: &Sender<Result<String, Error>>, data: Result<String, Error>) -> bool {
+        tx.clone().send(data).wait().is_ok()
+    }
+
+    /// Forward events until the connection breaks (`Err`) or the stream was
+    /// dropped (`Ok`).
+    fn process_events(
+        connection: &mut Connection,
+        tx: &Sender<Result<String, Error>>,
+        projects: &ProjectFilter,
+        all_events: bool,
+        last_created_on: &mut Option<DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        let mut ssh_channel = connection.session.channel_session()?;
+        ssh_channel.exec(&stream_events_command(connection.version(), all_events))?;
         info!("Connected to Gerrit.");
 
+        // The stream is already open at this point, so recovered events might
+        // be delivered by the stream again. Remember them to skip duplicates.
+        let mut recovered_events = HashSet::new();
+
+        if let Some(since) = *last_created_on {
+            match recovery::query_missed_events(connection, since) {
+                Ok(events) => {
+                    if !events.is_empty() {
+                        info!("Recovered {} missed event(s).", events.len());
+                    }
+
+                    for event in events {
+                        *last_created_on = (*last_created_on).max(event.created_on());
+                        if let Some(change) = event.change() {
+                            if !projects.is_match(&change.project) {
+                                continue;
+                            }
+                        }
+                        recovered_events.extend(event.key());
+                        let data = serde_json::to_string(&event).map_err(Error::from);
+
+                        if !send_event_data(tx, data) {
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(e) => error!("failed to recover missed events: {}", e),
+            }
+        }
+
         let buf_channel = BufReader::new(ssh_channel);
         for line in buf_channel.lines() {
-            let line =
-                line.map_err(|_| error!("Could not read line from buffer. Will drop connection."))?;
-            tx.clone()
-                .send(line)
-                .wait()
-                .map_err(|err| error!("Cannot send message through channel {:?}", err))?;
+            let line = line?;
+
+            if let Ok(header) = serde_json::from_str::<EventHeader>(&line) {
+                *last_created_on = (*last_created_on).max(Some(header.created_on));
+
+                if !projects.is_match(&header.change.project) {
+                    continue;
+                }
+
+                if !recovered_events.is_empty() && recovered_events.remove(&header.into_key()) {
+                    debug!("Skipping already recovered event.");
+                    continue;
+                }
+            }
+
+            if !send_event_data(tx, Ok(line)) {
+                return Ok(());
+            }
         }
         Ok(())
     }
 
+    let EventStreamOptions {
+        since,
+        projects,
+        metrics,
+        all_events,
+    } = options;
+    let stream_metrics = metrics.clone();
+
     thread::spawn(move || {
         let mut connection = connection;
+        let mut last_created_on = since;
         while !main_tx.is_closed() {
-            if process_events(&mut connection, &main_tx).is_err() {
+            if let Err(e) = process_events(
+                &mut connection,
+                &main_tx,
+                &projects,
+                all_events,
+                &mut last_created_on,
+            ) {
+                error!("event stream broke: {}", e);
+
+                // report the (transient) error; stop if nobody is listening
+                if !send_event_data(&main_tx, Err(e)) {
+                    return;
+                }
+
                 info!("reconnecting");
 
                 if let Err(e) = connection.reconnect_repeatedly() {
                     error!("reconnect failed permanently: {}", e);
+                    send_event_data(&main_tx, Err(e));
                     return;
                 }
+                stream_metrics.reconnected();
             }
         }
     });
 
-    receiver_into_event_stream(rx)
+    receiver_into_event_stream(rx, metrics)
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ExtendedInfo {
     SubmitRecords,
     InlineComments,
     AllApprovals,
+    AllReviewers,
+    /// Submit requirements of Gerrit 3.5+, only fetched from servers known to
+    /// support them.
+    SubmitRequirements,
 }
 
-/// Fetch extended event info. On error the original event and an error message
-/// is returned.
-fn fetch_extended_info(
-    command_runner: &mut CommandRunner,
-    event: Event,
-    extended_info: &[ExtendedInfo],
-) -> impl Future<Item = Event, Error = (Event, String)> {
-    if extended_info.is_empty() {
-        return future::Either::A(future::ok(event));
-    }
-
-    let mut event = event;
-    let mut query = "gerrit query --format=JSON".to_string();
-
-    if extended_info.contains(&ExtendedInfo::SubmitRecords) {
-        query += " --submit-records";
+/// Time window in which events are collected to fetch their extended info
+/// with a single query.
+const EXTENDED_INFO_BATCH_WINDOW: Duration = Duration::from_millis(100);
+
+/// Maximum number of events whose extended info is fetched with one query.
+const EXTENDED_INFO_BATCH_SIZE: usize = 50;
+
+/// Time for which fetched changes are reused for further events of the same
+/// patchset.
+const CHANGE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Maximum number of cached changes.
+const CHANGE_CACHE_CAPACITY: usize = 1000;
+
+/// Time for which the inline comments reported for an author and patchset are
+/// remembered.
+const REPORTED_COMMENTS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Maximum number of authors and patchsets whose reported inline comments are
+/// remembered.
+const REPORTED_COMMENTS_CAPACITY: usize = 1000;
+
+/// Drop extended info the Gerrit server cannot provide. If the version is
+/// unknown, everything is assumed to be supported.
+fn supported_extended_info(
+    version: Option<GerritVersion>,
+    extended_info: Cow<'static, [ExtendedInfo]>,
+) -> Cow<'static, [ExtendedInfo]> {
+    match version {
+        Some(version) if !extended_info.iter().all(|info| version.supports(*info)) => Cow::Owned(
+            extended_info
+                .iter()
+                .cloned()
+                .filter(|info| version.supports(*info))
+                .collect(),
+        ),
+        _ => extended_info,
     }
+}
 
-    if extended_info.contains(&ExtendedInfo::InlineComments) {
-        query += " --patch-sets --comments";
-    }
+/// Copy extended info from the queried change into the event.
+fn apply_extended_info(event: &mut Event, new_change: &Change) {
+    let (change, patchset) = match event.change_and_patchset_mut() {
+        Some(change_and_patchset) => change_and_patchset,
+        None => return,
+    };
 
-    if extended_info.contains(&ExtendedInfo::AllApprovals) {
-        query += " --all-approvals";
+    // copy patchset from change for the comments
+    if let Some(new_patchset) = new_change
+        .patch_sets
+        .iter()
+        .flatten()
+        .find(|new_patchset| new_patchset.number == patchset.number)
+    {
+        *patchset = new_patchset.clone();
     }
 
-    let change_id = if let Some((change, _)) = event.change_and_patchset_mut() {
-        &change.id
-    } else {
-        return future::Either::A(future::ok(event));
-    };
-
-    query += &format!(" change:{}", change_id);
+    // copy over submit records and requirements
+    change.submit_records = new_change.submit_records.clone();
+    change.submit_requirements = new_change.submit_requirements.clone();
 
-    future::Either::B(command_runner.run_command(query).then(
-        move |result| -> Result<Event, (Event, String)> {
-            let result = match result {
-                Ok(result) => result,
-                Err(e) => return Err((event, e)),
-            };
-            let line = result.lines().next().unwrap_or("");
+    // copy over reviewers
+    change.all_reviewers = new_change.all_reviewers.clone();
 
-            // Need to borrow here again to prevent overlapping borrows.
-            // change_and_patchset_mut cannot return None here if it didn't
-            // above.
-            let (change, patchset) = event.change_and_patchset_mut().unwrap();
+    // the patchset contains the inline comments of all reviewers, only the
+    // ones of the author belong to the event
+    if let Event::CommentAdded(event) = event {
+        let author = &event.author;
+        if let Some(comments) = event.patchset.comments.as_mut() {
+            comments.retain(|comment| comment.reviewer.is_same_user(author));
+        }
+    }
+}
 
-            let mut new_change: Change = match serde_json::from_str(line) {
-                Ok(change) => change,
-                Err(e) => return Err((event, format!("failed to decode result: {}", e))),
+/// Fetch extended info for a batch of events. Events requiring the same kind
+/// of extended info are fetched with a single query, changes found in `cache`
+/// are not fetched again. Errors are logged and the affected events are
+/// returned unchanged.
+fn fetch_extended_info(
+    command_runner: &mut CommandRunner,
+    cache: Arc<Mutex<cache::ChangeCache>>,
+    metrics: Arc<dyn Metrics>,
+    events: Vec<(Event, Cow<'static, [ExtendedInfo]>)>,
+) -> impl Future<Item = Vec<Event>, Error = Error> {
+    let mut events = events;
+    // events whose extended info still needs to be fetched
+    let mut pending = vec![false; events.len()];
+    let mut change_numbers: BTreeMap<Vec<ExtendedInfo>, Vec<u32>> = BTreeMap::new();
+
+    {
+        let cache = cache.lock().unwrap();
+        for ((event, extended_info), pending) in events.iter_mut().zip(&mut pending) {
+            if extended_info.is_empty() {
+                continue;
+            }
+            if let Some(cached_change) = cache.get(extended_info, event) {
+                debug!(
+                    "using cached extended info for change {}",
+                    cached_change.number
+                );
+                let cached_change = cached_change.clone();
+                apply_extended_info(event, &cached_change);
+                continue;
+            }
+            let number = match event.change() {
+                Some(change) => change.number,
+                None => continue,
             };
-
-            // copy patchset from change for the comments
-            if let Some(patchsets) = new_change.patch_sets.take() {
-                if let Some(new_patchset) = patchsets
-                    .into_iter()
-                    .find(|new_patchset| new_patchset.number == patchset.number)
-                {
-                    *patchset = new_patchset;
-                }
+            *pending = true;
+            let numbers = change_numbers.entry(extended_info.to_vec()).or_default();
+            if !numbers.contains(&number) {
+                numbers.push(number);
             }
+        }
+    }
 
-            // copy over submit records
-            change.submit_records = new_change.submit_records.take();
-
-            Ok(event)
-        },
-    ))
+    let queries = change_numbers
+        .into_iter()
+        .map(|(extended_info, numbers)| {
+            let query = Query::changes(&numbers).with_extended_info(&extended_info);
+            let started = Instant::now();
+            let metrics = metrics.clone();
+            command_runner.query(&query).then(move |result| {
+                metrics.query_finished(started.elapsed());
+                let changes = result.unwrap_or_else(|e| {
+                    error!("failed to fetch extended event info: {}", e);
+                    Vec::new()
+                });
+                Ok::<_, Error>((extended_info, changes))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    future::join_all(queries).map(move |results| {
+        let changes: HashMap<(&[ExtendedInfo], u32), &Change> = results
+            .iter()
+            .flat_map(|(extended_info, changes)| {
+                changes
+                    .iter()
+                    .map(move |change| ((&extended_info[..], change.number), change))
+            })
+            .collect();
+
+        let mut cache = cache.lock().unwrap();
+        events
+            .into_iter()
+            .zip(pending)
+            .map(|((mut event, extended_info), pending)| {
+                let numbers = event
+                    .change()
+                    .map(|change| change.number)
+                    .zip(event.patchset().map(|patchset| patchset.number));
+                if let (true, Some((change_number, patchset_number))) = (pending, numbers) {
+                    match changes.get(&(extended_info.as_ref(), change_number)) {
+                        Some(new_change) => {
+                            apply_extended_info(&mut event, new_change);
+                            cache.insert(&extended_info, patchset_number, (*new_change).clone());
+                        }
+                        None => debug!("no extended info for change {}", change_number),
+                    }
+                }
+                event
+            })
+            .collect()
+    })
 }
 
+/// Stream of events (see `event_stream`) with extended info fetched by
+/// `command_runner`. Events arriving in bursts are collected into batches
+/// whose extended info is fetched with a single query. Changes fetched for
+/// previous events of the same patchset are reused for a short time. Up to
+/// `command_runner.pool_size()` batches are extended concurrently; the order of
+/// events is preserved. Inline comments already reported with an earlier event
+/// of the same author and patchset are removed.
 pub fn extended_event_stream<F>(
     stream_connection: Connection,
-    command_connection: Connection,
+    options: EventStreamOptions,
+    command_runner: CommandRunner,
     select_extended_info: F,
-) -> impl Stream<Item = Event, Error = ()>
+) -> impl Stream<Item = Event, Error = Error>
 where
     F: FnMut(&Event) -> Cow<'static, [ExtendedInfo]>,
 {
-    let mut command_runner = CommandRunner::new(command_connection);
+    let mut command_runner = command_runner;
     let mut select_extended_info = select_extended_info;
-
-    event_stream(stream_connection).and_then(move |event| {
+    let concurrency = command_runner.pool_size();
+    let cache = Arc::new(Mutex::new(cache::ChangeCache::new(
+        CHANGE_CACHE_TTL,
+        CHANGE_CACHE_CAPACITY,
+    )));
+    let mut reported_comments =
+        comments::ReportedComments::new(REPORTED_COMMENTS_TTL, REPORTED_COMMENTS_CAPACITY);
+
+    let metrics = options.metrics.clone();
+    let version = stream_connection.version();
+    let events = event_stream(stream_connection, options).map(move |event| {
         let extended_info = select_extended_info(&event);
-        fetch_extended_info(&mut command_runner, event, extended_info.as_ref()).or_else(
-            |(event, err)| {
-                error!("failed to fetch extended event info: {}", err);
-                Ok(event)
-            },
-        )
-    })
+        (event, supported_extended_info(version, extended_info))
+    });
+
+    batch::Batched::new(events, EXTENDED_INFO_BATCH_WINDOW, EXTENDED_INFO_BATCH_SIZE)
+        .map(move |events| {
+            fetch_extended_info(&mut command_runner, cache.clone(), metrics.clone(), events)
+        })
+        .buffered(concurrency)
+        .map(stream::iter_ok)
+        .flatten()
+        .map(move |mut event| {
+            reported_comments.retain_new(&mut event);
+            event
+        })
 }
 
 #[cfg(test)]
@@ -538,10 +1311,196 @@ mod test {
 
     use spectral::prelude::*;
 
+    #[test]
+    fn test_dead_command_runner_is_skipped() {
+        let (dead_sender, dead_receiver) = channel(1);
+        let (live_sender, live_receiver) = channel::<CommandRequest>(1);
+        drop(dead_receiver);
+
+        thread::spawn(move || {
+            for request in live_receiver.wait().flatten() {
+                let _ = request.sender.send(Ok(format!("ran {}", request.command)));
+            }
+        });
+
+        let mut runner = CommandRunner {
+            senders: vec![dead_sender, live_sender],
+            next_sender: 0,
+        };
+        let output = runner.run_command("true".to_string()).wait();
+        assert_that!(output.ok()).is_equal_to(Some("ran true".to_string()));
+        assert_that!(runner.pool_size()).is_equal_to(1);
+
+        let output = runner.run_command("false".to_string()).wait();
+        assert_that!(output.ok()).is_equal_to(Some("ran false".to_string()));
+    }
+
+    /// Connection failing the given number of times before running commands.
+    struct FlakyConnection {
+        failures: u32,
+        runs: u32,
+        reconnects: u32,
+    }
+
+    impl CommandConnection for FlakyConnection {
+        fn run_command_once(
+            &mut self,
+            command: &str,
+            _timeout: Option<Duration>,
+        ) -> Result<String, backoff::Error<Error>> {
+            self.runs += 1;
+            if self.runs <= self.failures {
+                let e = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset");
+                return Err(backoff::Error::Transient(e.into()));
+            }
+            match command {
+                "false" => Err(backoff::Error::Permanent(Error::CommandFailed {
+                    status: 1,
+                    stderr: String::new(),
+                })),
+                _ => Ok(format!("ran {}", command)),
+            }
+        }
+
+        fn reconnect(&mut self) -> Result<(), Error> {
+            self.reconnects += 1;
+            Ok(())
+        }
+    }
+
+    fn run_with_retries(
+        connection: &mut FlakyConnection,
+        command: &str,
+        max_retries: u32,
+    ) -> Result<String, Error> {
+        let mut backoff = backoff::ExponentialBackoff::default();
+        backoff.initial_interval = Duration::from_millis(1);
+        backoff.current_interval = backoff.initial_interval;
+        let options = CommandOptions {
+            timeout: None,
+            max_retries,
+        };
+        CommandRunner::run_command_with_retries(
+            connection,
+            &mut true,
+            command,
+            options,
+            &mut backoff,
+        )
+    }
+
+    #[test]
+    fn test_command_is_retried_after_transient_failures() {
+        let mut connection = FlakyConnection {
+            failures: 2,
+            runs: 0,
+            reconnects: 0,
+        };
+        let output = run_with_retries(&mut connection, "true", 3);
+        assert_that!(output.ok()).is_equal_to(Some("ran true".to_string()));
+        assert_that!(connection.runs).is_equal_to(3);
+        // reconnected before each retry
+        assert_that!(connection.reconnects).is_equal_to(2);
+    }
+
+    #[test]
+    fn test_command_fails_after_max_retries() {
+        let mut connection = FlakyConnection {
+            failures: 10,
+            runs: 0,
+            reconnects: 0,
+        };
+        let output = run_with_retries(&mut connection, "true", 3);
+        assert!(matches!(output, Err(Error::Io(_))));
+        // the first attempt and three retries
+        assert_that!(connection.runs).is_equal_to(4);
+
+        let mut connection = FlakyConnection {
+            failures: 1,
+            runs: 0,
+            reconnects: 0,
+        };
+        let output = run_with_retries(&mut connection, "true", 0);
+        assert!(matches!(output, Err(Error::Io(_))));
+        assert_that!(connection.runs).is_equal_to(1);
+    }
+
+    #[test]
+    fn test_permanent_failure_is_not_retried() {
+        let mut connection = FlakyConnection {
+            failures: 0,
+            runs: 0,
+            reconnects: 0,
+        };
+        let output = run_with_retries(&mut connection, "false", 3);
+        assert!(matches!(
+            output,
+            Err(Error::CommandFailed { status: 1, .. })
+        ));
+        assert_that!(connection.runs).is_equal_to(1);
+        assert_that!(connection.reconnects).is_equal_to(0);
+    }
+
+    #[test]
+    fn test_session_timeout() {
+        // zero means no timeout for libssh2
+        assert_that!(session_timeout_millis(None)).is_equal_to(0);
+        assert_that!(session_timeout_millis(Some(Duration::from_secs(0)))).is_equal_to(0);
+        assert_that!(session_timeout_millis(Some(Duration::from_millis(1500)))).is_equal_to(1500);
+        assert_that!(session_timeout_millis(Some(Duration::from_secs(u64::MAX))))
+            .is_equal_to(u32::MAX);
+    }
+
+    #[test]
+    fn test_stream_events_command() {
+        assert_eq!(
+            stream_events_command(None, false),
+            "gerrit stream-events -s comment-added -s reviewer-added -s change-abandoned \
+             -s change-merged -s change-deleted"
+        );
+        assert_eq!(stream_events_command(None, true), "gerrit stream-events");
+    }
+
+    #[test]
+    fn test_supported_extended_info_of_old_version() {
+        let extended_info = Cow::Borrowed(
+            &[
+                ExtendedInfo::SubmitRecords,
+                ExtendedInfo::AllReviewers,
+                ExtendedInfo::SubmitRequirements,
+            ][..],
+        );
+        assert_eq!(
+            &supported_extended_info(Some(GerritVersion::new(2, 8, 0)), extended_info.clone())[..],
+            &[ExtendedInfo::SubmitRecords]
+        );
+        assert_eq!(
+            &supported_extended_info(Some(GerritVersion::new(3, 4, 1)), extended_info.clone())[..],
+            &[ExtendedInfo::SubmitRecords, ExtendedInfo::AllReviewers]
+        );
+        assert_eq!(
+            supported_extended_info(Some(GerritVersion::new(3, 5, 0)), extended_info.clone()),
+            extended_info
+        );
+    }
+
+    #[test]
+    fn test_stream_events_command_of_old_version() {
+        assert_eq!(
+            stream_events_command(Some(GerritVersion::new(2, 14, 20)), false),
+            "gerrit stream-events -s comment-added -s reviewer-added -s change-abandoned \
+             -s change-merged"
+        );
+        assert_eq!(
+            stream_events_command(Some(GerritVersion::new(3, 4, 1)), false),
+            stream_events_command(None, false)
+        );
+    }
+
     #[test]
     fn test_get_pub_key_path() {
         let result = get_pub_key_path(&PathBuf::from("some_priv_key"));
-        assert!(result == PathBuf::from("some_priv_key.pub"));
+        assert_eq!(result, PathBuf::from("some_priv_key.pub"));
     }
 
     const COMMENT_ADDED_JSON: &str = r#"
@@ -552,6 +1511,18 @@ mod test {
 {"reviewer":{"name":"jdoe","email":"john.doe@localhost","username":"jdoe"},"patchSet":{"number":1,"revision":"c4f7d43450e366f9c8e4dcb94fbd91573cd40766","parents":["20332c6ee056bdf3f814c8cff9905154d443d2f0"],"ref":"refs/changes/01/1/1","uploader":{"name":"Administrator","email":"admin@example.com","username":"admin"},"createdOn":1553631812,"author":{"name":"Frank Benkstein","email":"frank@benkstein.net","username":""},"isDraft":false,"kind":"REWORK","sizeInsertions":0,"sizeDeletions":-18},"change":{"project":"gerritbot-rs","branch":"master","id":"I5e53df227fd2739ddd65c3034b2f9f789200bd89","number":1,"subject":"get rid of non-macro extern crate","owner":{"name":"Administrator","email":"admin@example.com","username":"admin"},"assignee":{"name":"jdoe","email":"john.doe@localhost","username":"jdoe"},"url":"http://localhost:8080/1","commitMessage":"get rid of non-macro extern crate\n\nChange-Id: I5e53df227fd2739ddd65c3034b2f9f789200bd89\n","createdOn":1553631812,"status":"NEW"},"project":"gerritbot-rs","refName":"refs/heads/master","changeKey":{"id":"I5e53df227fd2739ddd65c3034b2f9f789200bd89"},"type":"reviewer-added","eventCreatedOn":1553632329}
 "#;
 
+    #[test]
+    fn test_deserialize_submit_requirements() {
+        let change: Change = serde_json::from_str(
+            r#"{"project":"gerritbot-rs","branch":"master","id":"I5e53df227fd2739ddd65c3034b2f9f789200bd89","number":1,"subject":"get rid of non-macro extern crate","owner":{"name":"Administrator","email":"admin@example.com","username":"admin"},"url":"http://localhost:8080/1","commitMessage":"get rid of non-macro extern crate\n","status":"NEW","submitRequirements":[{"name":"Code-Review","status":"SATISFIED","isLegacy":false},{"name":"Verified","status":"NOT_APPLICABLE","isLegacy":true}]}"#,
+        )
+        .expect("failed to deserialize change");
+        let requirements = change.submit_requirements.as_ref().unwrap();
+        assert_eq!(requirements.len(), 2);
+        assert!(requirements[1].is_legacy);
+        assert_eq!(change.is_submittable(), Some(true));
+    }
+
     #[test]
     fn test_deserialize_comment_added() {
         let event: Event =
@@ -567,6 +1538,74 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_apply_extended_info_keeps_inline_comments_of_author() {
+        let mut event: Event =
+            serde_json::from_str(COMMENT_ADDED_JSON).expect("failed to deserialize event");
+        let inline_comment = |username: &str| InlineComment {
+            file: "src/lib.rs".to_string(),
+            line: 1,
+            reviewer: User {
+                name: None,
+                username: Some(username.to_string()),
+                email: None,
+            },
+            message: "Typo".to_string(),
+        };
+
+        let mut change = event.change().unwrap().clone();
+        let mut patchset = event.patchset().unwrap().clone();
+        patchset.comments = Some(vec![inline_comment("admin"), inline_comment("jdoe")]);
+        change.patch_sets = Some(vec![patchset]);
+
+        apply_extended_info(&mut event, &change);
+        let comments = event.patchset().unwrap().comments.as_ref().unwrap();
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].reviewer.username.as_deref(), Some("admin"));
+    }
+
+    #[test]
+    fn test_reported_inline_comments_are_not_repeated() {
+        let event: Event =
+            serde_json::from_str(COMMENT_ADDED_JSON).expect("failed to deserialize event");
+        let inline_comment = |line: u32, message: &str| InlineComment {
+            file: "src/lib.rs".to_string(),
+            line,
+            reviewer: User {
+                name: None,
+                username: Some("admin".to_string()),
+                email: None,
+            },
+            message: message.to_string(),
+        };
+        let mut reported = comments::ReportedComments::new(Duration::from_secs(60), 10);
+        let mut comment = |comments: Vec<InlineComment>| {
+            let mut event = event.clone();
+            let mut change = event.change().unwrap().clone();
+            let mut patchset = event.patchset().unwrap().clone();
+            patchset.comments = Some(comments);
+            change.patch_sets = Some(vec![patchset]);
+            apply_extended_info(&mut event, &change);
+            reported.retain_new(&mut event);
+            let comments = event.patchset().unwrap().comments.clone().unwrap();
+            comments
+                .into_iter()
+                .map(|comment| comment.message)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(comment(vec![inline_comment(1, "Typo")]), vec!["Typo"]);
+        // the second reply of the author on the patchset only reports the new
+        // comments
+        assert_eq!(
+            comment(vec![
+                inline_comment(1, "Typo"),
+                inline_comment(2, "Missing test")
+            ]),
+            vec!["Missing test"]
+        );
+    }
+
     #[test]
     fn test_reviewer_added() {
         let event: Event =
@@ -580,4 +1619,26 @@ mod test {
             _ => panic!("unexpected_event: {:?}", event),
         }
     }
+
+    #[test]
+    fn test_deserialize_unknown_event() {
+        let json = r#"{"type":"ref-updated","submitter":{"name":"Administrator","email":"admin@example.com","username":"admin"},"refUpdate":{"oldRev":"0000000000000000000000000000000000000000","newRev":"7f9b3d1ec2a0e1f6d2d1f1b8c4c1a0e2d8b9e6f3","refName":"refs/heads/master","project":"gerritbot-rs"},"eventCreatedOn":1553632500}"#;
+        let event: Event = serde_json::from_str(json).expect("failed to deserialize event");
+        assert_eq!(event.type_name(), "ref-updated");
+        assert_eq!(
+            event.created_on(),
+            timestamp::from_epoch_seconds(1553632500)
+        );
+        assert!(event.change().is_none());
+        match &event {
+            Event::Unknown { raw, .. } => {
+                assert_eq!(raw["refUpdate"]["refName"], "refs/heads/master");
+            }
+            _ => panic!("unexpected_event: {:?}", event),
+        }
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
+    }
 }
@@ -0,0 +1,156 @@
+//! Instrumentation of the event stream.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Receives measurements of `event_stream` and `extended_event_stream`. All
+/// methods do nothing by default.
+pub trait Metrics: Send + Sync {
+    /// An event of the given type was received and decoded.
+    fn event_received(&self, _event_type: &str) {}
+
+    /// An event could not be decoded.
+    fn decode_failed(&self) {}
+
+    /// The event stream reconnected to Gerrit.
+    fn reconnected(&self) {}
+
+    /// A query for extended event info finished (successfully or not).
+    fn query_finished(&self, _latency: Duration) {}
+}
+
+impl fmt::Debug for dyn Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+/// Metrics that are not recorded anywhere.
+#[derive(Debug, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Metrics recorded in memory.
+#[derive(Debug, Default)]
+pub struct Counters {
+    events_received: Mutex<BTreeMap<String, u64>>,
+    decode_failures: AtomicU64,
+    reconnects: AtomicU64,
+    queries: AtomicU64,
+    query_latency_millis: AtomicU64,
+}
+
+impl Counters {
+    /// Number of received events per event type.
+    pub fn events_received(&self) -> BTreeMap<String, u64> {
+        self.events_received.lock().unwrap().clone()
+    }
+
+    pub fn decode_failures(&self) -> u64 {
+        self.decode_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    /// Average latency of all queries so far.
+    pub fn average_query_latency(&self) -> Option<Duration> {
+        let queries = self.queries();
+        if queries == 0 {
+            return None;
+        }
+        let total_millis = self.query_latency_millis.load(Ordering::Relaxed);
+        Some(Duration::from_millis(total_millis / queries))
+    }
+}
+
+impl Metrics for Counters {
+    fn event_received(&self, event_type: &str) {
+        *self
+            .events_received
+            .lock()
+            .unwrap()
+            .entry(event_type.to_string())
+            .or_default() += 1;
+    }
+
+    fn decode_failed(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn query_finished(&self, latency: Duration) {
+        let millis = latency.as_millis().min(u128::from(u64::MAX)) as u64;
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.query_latency_millis
+            .fetch_add(millis, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "events received:")?;
+        let events_received = self.events_received();
+        if events_received.is_empty() {
+            write!(f, " none")?;
+        }
+        for (event_type, count) in &events_received {
+            write!(f, " {}={}", event_type, count)?;
+        }
+        write!(
+            f,
+            ", decode failures: {}, reconnects: {}, queries: {}",
+            self.decode_failures(),
+            self.reconnects(),
+            self.queries()
+        )?;
+        if let Some(latency) = self.average_query_latency() {
+            write!(f, " (avg. {:?})", latency)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::default();
+        assert_eq!(counters.average_query_latency(), None);
+
+        counters.event_received("comment-added");
+        counters.event_received("comment-added");
+        counters.event_received("change-merged");
+        counters.decode_failed();
+        counters.reconnected();
+        counters.query_finished(Duration::from_millis(10));
+        counters.query_finished(Duration::from_millis(30));
+
+        assert_eq!(counters.events_received()["comment-added"], 2);
+        assert_eq!(counters.decode_failures(), 1);
+        assert_eq!(counters.reconnects(), 1);
+        assert_eq!(
+            counters.average_query_latency(),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            counters.to_string(),
+            "events received: change-merged=1 comment-added=2, decode failures: 1, \
+             reconnects: 1, queries: 2 (avg. 20ms)"
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use regex::Regex;
+
+/// Matches project names against a list of regular expressions. Each
+/// expression has to match the whole project name. An empty filter matches
+/// all projects.
+#[derive(Debug, Clone, Default)]
+pub struct ProjectFilter {
+    patterns: Vec<Regex>,
+}
+
+impl ProjectFilter {
+    pub fn new<I, S>(patterns: I) -> Result<Self, regex::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let patterns = patterns
+            .into_iter()
+            .map(|pattern| Regex::new(&format!("^(?:{})$", pattern.as_ref())))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub fn is_match(&self, project: &str) -> bool {
+        self.patterns.is_empty() || self.patterns.iter().any(|re| re.is_match(project))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_empty_filter_matches_everything() {
+        let filter = ProjectFilter::default();
+        assert!(filter.is_match("some/project"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = ProjectFilter::new(["gerritbot-rs", "platform/.*"]).unwrap();
+        assert!(filter.is_match("gerritbot-rs"));
+        assert!(filter.is_match("platform/build"));
+        assert!(!filter.is_match("gerritbot-rs-fork"));
+        assert!(!filter.is_match("other/platform/build"));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(ProjectFilter::new(["("]).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Connecting to Gerrit through an SSH jump host.
+//!
+//! libssh2 needs a socket to talk to the server, so a direct-tcpip channel
+//! opened on the jump host is bridged to a local loopback socket by a
+//! forwarding thread. The thread sleeps in `poll` until either socket is
+//! ready.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+
+use log::{debug, error};
+
+use crate::{Connection, Error};
+
+/// SSH port used if the jump host doesn't specify one.
+const DEFAULT_SSH_PORT: u16 = 22;
+
+/// An intermediate SSH host given as `[user@]host[:port]`. Without a user, the
+/// Gerrit username is used. The jump host has to accept the same key as
+/// Gerrit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyJump {
+    pub username: Option<String>,
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for ProxyJump {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (username, host) = match s.rfind('@') {
+            Some(pos) => (Some(s[..pos].to_string()), &s[pos + 1..]),
+            None => (None, s),
+        };
+        let (host, port) = split_host_port(host, DEFAULT_SSH_PORT)
+            .ok_or_else(|| format!("invalid jump host: {}", s))?;
+        if host.is_empty() || username.as_deref() == Some("") {
+            return Err(format!("invalid jump host: {}", s));
+        }
+        Ok(Self {
+            username,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for ProxyJump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(username) = &self.username {
+            write!(f, "{}@", username)?;
+        }
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Split `host[:port]` into host and port.
+fn split_host_port(s: &str, default_port: u16) -> Option<(&str, u16)> {
+    match s.rfind(':') {
+        Some(pos) => s[pos + 1..].parse().ok().map(|port| (&s[..pos], port)),
+        None => Some((s, default_port)),
+    }
+}
+
+/// Open a TCP stream to `host` (given as `host:port`) tunneled through the jump
+/// host.
+pub(crate) fn connect_via_jump_host(
+    proxy_jump: &ProxyJump,
+    host: &str,
+    username: &str,
+    pub_key_path: &Path,
+    priv_key_path: &Path,
+    passphrase: Option<&str>,
+) -> Result<TcpStream, Error> {
+    let (target_host, target_port) = split_host_port(host, DEFAULT_SSH_PORT).ok_or_else(|| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid host: {}", host),
+        ))
+    })?;
+
+    debug!("Connecting to jump host: {}", proxy_jump);
+    let jump_session = Connection::connect_session(
+        &format!("{}:{}", proxy_jump.host, proxy_jump.port),
+        proxy_jump.username.as_deref().unwrap_or(username),
+        pub_key_path,
+        priv_key_path,
+        passphrase,
+        None,
+    )?;
+    let channel = jump_session.channel_direct_tcpip(target_host, target_port, None)?;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let local_stream = TcpStream::connect(listener.local_addr()?)?;
+    let (tunnel_stream, _) = listener.accept()?;
+
+    thread::Builder::new()
+        .name(format!("SSH tunnel to {}", host))
+        .spawn(move || {
+            if let Err(e) = forward(&jump_session, channel, tunnel_stream) {
+                error!("SSH tunnel broke: {}", e);
+            }
+        })?;
+
+    Ok(local_stream)
+}
+
+/// Copy data between the channel and the local stream until either side is
+/// closed.
+fn forward(
+    jump_session: &ssh2::Session,
+    mut channel: ssh2::Channel,
+    mut stream: TcpStream,
+) -> io::Result<()> {
+    jump_session.set_blocking(false);
+    stream.set_nonblocking(true)?;
+
+    let mut buf = [0; 16 * 1024];
+    loop {
+        let mut idle = true;
+
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                write_all(&mut channel, &buf[..n], || {
+                    poll(&mut [session_pollfd(jump_session)])
+                })?;
+                idle = false;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+        }
+
+        match channel.read(&mut buf) {
+            Ok(0) if channel.eof() => break,
+            Ok(0) => (),
+            Ok(n) => {
+                let stream_fd = stream.as_raw_fd();
+                write_all(&mut stream, &buf[..n], || {
+                    poll(&mut [pollfd(stream_fd, libc::POLLOUT)])
+                })?;
+                idle = false;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+        }
+
+        // Both sides would block, and libssh2 has no buffered data of the
+        // channel, so wait until either socket is ready.
+        if idle {
+            poll(&mut [
+                pollfd(stream.as_raw_fd(), libc::POLLIN),
+                session_pollfd(jump_session),
+            ])?;
+        }
+    }
+
+    debug!("SSH tunnel closed");
+    Ok(())
+}
+
+/// Like `Write::write_all`, but waits with `wait` if the writer would block.
+fn write_all<W, F>(writer: &mut W, mut data: &[u8], mut wait: F) -> io::Result<()>
+where
+    W: Write,
+    F: FnMut() -> io::Result<()>,
+{
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => wait()?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn pollfd(fd: RawFd, events: libc::c_short) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    }
+}
+
+/// Wait for the socket of the session in the directions libssh2 is blocked
+/// on. Incoming data is always waited for.
+fn session_pollfd(session: &ssh2::Session) -> libc::pollfd {
+    let events = match session.block_directions() {
+        ssh2::BlockDirections::Outbound | ssh2::BlockDirections::Both => {
+            libc::POLLIN | libc::POLLOUT
+        }
+        ssh2::BlockDirections::Inbound | ssh2::BlockDirections::None => libc::POLLIN,
+    };
+    pollfd(session.as_raw_fd(), events)
+}
+
+/// Wait until one of the file descriptors is ready.
+fn poll(fds: &mut [libc::pollfd]) -> io::Result<()> {
+    // no timeout
+    let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+    if result < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_proxy_jump() {
+        assert_eq!(
+            "bastion.example.com".parse(),
+            Ok(ProxyJump {
+                username: None,
+                host: "bastion.example.com".to_string(),
+                port: 22,
+            })
+        );
+        assert_eq!(
+            "jdoe@bastion.example.com:2222".parse(),
+            Ok(ProxyJump {
+                username: Some("jdoe".to_string()),
+                host: "bastion.example.com".to_string(),
+                port: 2222,
+            })
+        );
+        assert!("bastion.example.com:ssh".parse::<ProxyJump>().is_err());
+        assert!("@bastion.example.com".parse::<ProxyJump>().is_err());
+    }
+
+    #[test]
+    fn test_poll_waits_for_data() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        let mut buf = [0; 5];
+        assert_eq!(
+            server.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        let writer = thread::spawn(move || client.write_all(b"hello"));
+        poll(&mut [pollfd(server.as_raw_fd(), libc::POLLIN)]).unwrap();
+        assert_eq!(server.read(&mut buf).unwrap(), 5);
+        writer.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+//! Builder for `gerrit query` commands.
+
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::{Change, Error, ExtendedInfo};
+
+/// A `gerrit query` command for changes matching a search expression.
+///
+/// ```
+/// use gerritbot_gerrit::Query;
+///
+/// let query = Query::change(42).with_submit_records().current_patch_set();
+/// assert_eq!(
+///     query.to_command(),
+///     "gerrit query --format=JSON --current-patch-set --submit-records 'change:42'"
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    search: String,
+    current_patch_set: bool,
+    patch_sets: bool,
+    comments: bool,
+    submit_records: bool,
+    submit_requirements: bool,
+    all_approvals: bool,
+    all_reviewers: bool,
+    start: usize,
+}
+
+/// Statistics Gerrit appends as the last line of the query output.
+#[derive(Deserialize)]
+struct QueryStats {
+    #[serde(rename = "moreChanges", default)]
+    more_changes: bool,
+}
+
+impl Query {
+    /// Query changes matching the given search expression, e.g.
+    /// `status:open project:foo`.
+    pub fn new<S: Into<String>>(search: S) -> Self {
+        Self {
+            search: search.into(),
+            current_patch_set: false,
+            patch_sets: false,
+            comments: false,
+            submit_records: false,
+            submit_requirements: false,
+            all_approvals: false,
+            all_reviewers: false,
+            start: 0,
+        }
+    }
+
+    /// Query a single change by its number or Change-Id.
+    pub fn change<I: fmt::Display>(id: I) -> Self {
+        Self::new(format!("change:{}", id))
+    }
+
+    /// Query several changes by their numbers or Change-Ids at once.
+    pub fn changes<I>(ids: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: fmt::Display,
+    {
+        let changes: Vec<String> = ids.into_iter().map(|id| format!("change:{}", id)).collect();
+        Self::new(changes.join(" OR "))
+    }
+
+    pub fn current_patch_set(self) -> Self {
+        Self {
+            current_patch_set: true,
+            ..self
+        }
+    }
+
+    pub fn with_patch_sets(self) -> Self {
+        Self {
+            patch_sets: true,
+            ..self
+        }
+    }
+
+    /// Include change messages and, together with `with_patch_sets`, inline
+    /// comments.
+    pub fn with_comments(self) -> Self {
+        Self {
+            comments: true,
+            ..self
+        }
+    }
+
+    pub fn with_submit_records(self) -> Self {
+        Self {
+            submit_records: true,
+            ..self
+        }
+    }
+
+    /// Include the submit requirements, supported by Gerrit 3.5+.
+    pub fn with_submit_requirements(self) -> Self {
+        Self {
+            submit_requirements: true,
+            ..self
+        }
+    }
+
+    pub fn with_all_approvals(self) -> Self {
+        Self {
+            all_approvals: true,
+            ..self
+        }
+    }
+
+    pub fn with_all_reviewers(self) -> Self {
+        Self {
+            all_reviewers: true,
+            ..self
+        }
+    }
+
+    /// Skip the given number of changes, to fetch the next page of a query
+    /// whose result was truncated.
+    pub fn with_start(self, start: usize) -> Self {
+        Self { start, ..self }
+    }
+
+    /// Request the given extended info.
+    pub fn with_extended_info(self, extended_info: &[ExtendedInfo]) -> Self {
+        extended_info
+            .iter()
+            .fold(self, |query, extended_info| match extended_info {
+                ExtendedInfo::SubmitRecords => query.with_submit_records(),
+                ExtendedInfo::SubmitRequirements => query.with_submit_requirements(),
+                ExtendedInfo::InlineComments => query.with_patch_sets().with_comments(),
+                ExtendedInfo::AllApprovals => query.with_all_approvals(),
+                ExtendedInfo::AllReviewers => query.with_all_reviewers(),
+            })
+    }
+
+    /// The command to run on the Gerrit server.
+    pub fn to_command(&self) -> String {
+        let mut command = "gerrit query --format=JSON".to_string();
+
+        let options = [
+            (self.current_patch_set, "--current-patch-set"),
+            (self.patch_sets, "--patch-sets"),
+            (self.comments, "--comments"),
+            (self.submit_records, "--submit-records"),
+            (self.submit_requirements, "--submit-requirements"),
+            (self.all_approvals, "--all-approvals"),
+            (self.all_reviewers, "--all-reviewers"),
+        ];
+        for (_, option) in options.iter().filter(|(enabled, _)| *enabled) {
+            command += " ";
+            command += option;
+        }
+
+        if self.start > 0 {
+            command += &format!(" --start {}", self.start);
+        }
+
+        command += " ";
+        command += &quote(&self.search);
+
+        command
+    }
+
+    /// Decode the output of the query command. The last line contains query
+    /// statistics and is skipped.
+    pub fn parse_result(output: &str) -> Result<Vec<Change>, Error> {
+        output
+            .lines()
+            .filter(|line| !line.contains(r#""type":"stats""#))
+            .map(|line| serde_json::from_str(line).map_err(Error::from))
+            .collect()
+    }
+
+    /// Whether the query output was truncated by the query limit and more
+    /// changes can be fetched with `with_start`.
+    pub fn has_more_changes(output: &str) -> Result<bool, Error> {
+        match output
+            .lines()
+            .find(|line| line.contains(r#""type":"stats""#))
+        {
+            Some(line) => Ok(serde_json::from_str::<QueryStats>(line)?.more_changes),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Quote an argument of a command; the command is run by a shell on the
+/// server.
+pub(crate) fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_changes_query() {
+        let query = Query::changes([1, 42])
+            .with_extended_info(&[ExtendedInfo::InlineComments, ExtendedInfo::AllReviewers]);
+        assert_eq!(
+            query.to_command(),
+            "gerrit query --format=JSON --patch-sets --comments --all-reviewers \
+             'change:1 OR change:42'"
+        );
+    }
+
+    #[test]
+    fn test_submit_requirements_query() {
+        let query = Query::change(42).with_extended_info(&[
+            ExtendedInfo::SubmitRecords,
+            ExtendedInfo::SubmitRequirements,
+        ]);
+        assert_eq!(
+            query.to_command(),
+            "gerrit query --format=JSON --submit-records --submit-requirements 'change:42'"
+        );
+    }
+
+    #[test]
+    fn test_query_with_start() {
+        let query = Query::new("status:open").with_start(500);
+        assert_eq!(
+            query.to_command(),
+            "gerrit query --format=JSON --start 500 'status:open'"
+        );
+    }
+
+    #[test]
+    fn test_search_is_quoted() {
+        let query = Query::new("message:it's");
+        assert_eq!(
+            query.to_command(),
+            r"gerrit query --format=JSON 'message:it'\''s'"
+        );
+    }
+
+    #[test]
+    fn test_parse_result() {
+        let output = concat!(
+            r#"{"project":"gerritbot-rs","branch":"master","id":"I5e53df227fd2739ddd65c3034b2f9f789200bd89","number":1,"subject":"get rid of non-macro extern crate","owner":{"name":"Administrator","email":"admin@example.com","username":"admin"},"url":"http://localhost:8080/1","commitMessage":"get rid of non-macro extern crate\n","createdOn":1553631812,"lastUpdated":1553632500,"open":true,"status":"NEW"}"#,
+            "\n",
+            r#"{"type":"stats","rowCount":1,"runTimeMilliseconds":3,"moreChanges":false}"#,
+            "\n"
+        );
+        let changes = Query::parse_result(output).expect("failed to parse result");
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].number, 1);
+        assert!(!Query::has_more_changes(output).unwrap());
+
+        let truncated =
+            r#"{"type":"stats","rowCount":500,"runTimeMilliseconds":42,"moreChanges":true}"#;
+        assert!(Query::has_more_changes(truncated).unwrap());
+    }
+}
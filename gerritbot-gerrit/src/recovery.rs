@@ -0,0 +1,238 @@
+//! Recovery of events missed while the event stream was disconnected.
+//!
+//! Gerrit doesn't replay events, so missed events are reconstructed from the
+//! change messages of all changes updated since the last seen event. Only
+//! comment-added, change-merged and change-abandoned events can be recovered
+//! this way.
+
+use std::io::Read as _;
+
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+
+use crate::{
+    Approval, Change, ChangeAbandonedEvent, ChangeMergedEvent, ChangeStatus, Comment,
+    CommentAddedEvent, Connection, Error, Event, Patchset, Query,
+};
+
+/// Maximum number of changes whose events are recovered. Changes beyond it
+/// are skipped after a long outage.
+const MAX_RECOVERED_CHANGES: usize = 5000;
+
+fn missed_events_query(since: DateTime<Utc>) -> Query {
+    Query::new(format!(
+        "after:\"{}\"",
+        since.format("%Y-%m-%d %H:%M:%S +0000")
+    ))
+    .current_patch_set()
+    .with_all_approvals()
+    .with_comments()
+}
+
+/// Query Gerrit for changes updated after `since` and reconstruct the events
+/// that happened after that point in time. The events are ordered by time.
+///
+/// The query result is fetched page by page, as Gerrit truncates it at the
+/// query limit of the user.
+pub(crate) fn query_missed_events(
+    connection: &mut Connection,
+    since: DateTime<Utc>,
+) -> Result<Vec<Event>, Error> {
+    let mut changes = Vec::new();
+
+    loop {
+        let query = missed_events_query(since)
+            .with_start(changes.len())
+            .to_command();
+        debug!("recovering missed events: {}", query);
+
+        let mut ssh_channel = connection.session.channel_session()?;
+        ssh_channel.exec(&query)?;
+
+        let mut data = String::new();
+        ssh_channel.read_to_string(&mut data)?;
+
+        let page = Query::parse_result(&data)?;
+        let more_changes = !page.is_empty() && Query::has_more_changes(&data)?;
+        changes.extend(page);
+        if !more_changes {
+            break;
+        }
+        if changes.len() >= MAX_RECOVERED_CHANGES {
+            warn!(
+                "more than {} changes were updated since {}; events of older changes are not recovered",
+                MAX_RECOVERED_CHANGES, since
+            );
+            break;
+        }
+        debug!(
+            "query limit hit after {} changes, fetching next page",
+            changes.len()
+        );
+    }
+
+    let mut events: Vec<Event> = changes
+        .into_iter()
+        .flat_map(|change| synthesize_events(change, since))
+        .collect();
+    events.sort_by_key(Event::created_on);
+
+    Ok(events)
+}
+
+/// Get the patchset number from a change message like "Patch Set 3: ...".
+fn patchset_number(message: &str) -> Option<u32> {
+    message
+        .strip_prefix("Patch Set ")
+        .and_then(|rest| rest.split(|c: char| !c.is_ascii_digit()).next())
+        .and_then(|number| number.parse().ok())
+}
+
+/// Approvals granted together with the given comment.
+fn approvals_for_comment(patchset: &Patchset, comment: &Comment) -> Option<Vec<Approval>> {
+    let approvals: Vec<Approval> = patchset
+        .approvals
+        .iter()
+        .flatten()
+        .filter(|approval| {
+            approval.granted_on == Some(comment.timestamp)
+                && approval.by.as_ref().and_then(|by| by.email.as_ref())
+                    == comment.reviewer.email.as_ref()
+        })
+        .cloned()
+        .collect();
+
+    if approvals.is_empty() {
+        None
+    } else {
+        Some(approvals)
+    }
+}
+
+fn synthesize_events(change: Change, since: DateTime<Utc>) -> Vec<Event> {
+    let mut change = change;
+    let comments = change.comments.take().unwrap_or_default();
+    let patchsets = change.patch_sets.take().unwrap_or_default();
+    let current_patchset = match change.current_patch_set.take() {
+        Some(patchset) => patchset,
+        None => {
+            warn!(
+                "cannot recover events of change {} without patchset",
+                change.id
+            );
+            return Vec::new();
+        }
+    };
+
+    comments
+        .into_iter()
+        .filter(|comment| comment.timestamp > since)
+        .filter_map(|comment| {
+            let patchset = patchset_number(&comment.message)
+                .and_then(|number| patchsets.iter().find(|p| p.number == number))
+                .unwrap_or(&current_patchset);
+            let created_on = comment.timestamp;
+
+            if comment.message.starts_with("Abandoned") {
+                if let ChangeStatus::ABANDONED = change.status {
+                    let reason = comment.message["Abandoned".len()..].trim();
+                    return Some(Event::ChangeAbandoned(ChangeAbandonedEvent {
+                        change: change.clone(),
+                        patchset: patchset.clone(),
+                        abandoner: comment.reviewer,
+                        reason: if reason.is_empty() {
+                            None
+                        } else {
+                            Some(reason.to_string())
+                        },
+                        created_on,
+                    }));
+                }
+                None
+            } else if comment.message.starts_with("Change has been successfully") {
+                if let ChangeStatus::MERGED = change.status {
+                    return Some(Event::ChangeMerged(ChangeMergedEvent {
+                        change: change.clone(),
+                        patchset: patchset.clone(),
+                        submitter: comment.reviewer,
+                        new_revision: current_patchset.revision.clone(),
+                        created_on,
+                    }));
+                }
+                None
+            } else if comment.message.starts_with("Uploaded patch set")
+                || comment.message.starts_with("Restored")
+            {
+                // these messages don't correspond to any event we listen to
+                None
+            } else {
+                Some(Event::CommentAdded(CommentAddedEvent {
+                    change: change.clone(),
+                    patchset: patchset.clone(),
+                    approvals: approvals_for_comment(patchset, &comment),
+                    author: comment.reviewer,
+                    comment: comment.message,
+                    created_on,
+                }))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timestamp;
+
+    const CHANGE_JSON: &str = r#"{"project":"gerritbot-rs","branch":"master","id":"I5e53df227fd2739ddd65c3034b2f9f789200bd89","number":1,"subject":"get rid of non-macro extern crate","owner":{"name":"Administrator","email":"admin@example.com","username":"admin"},"url":"http://localhost:8080/1","commitMessage":"get rid of non-macro extern crate\n\nChange-Id: I5e53df227fd2739ddd65c3034b2f9f789200bd89\n","createdOn":1553631812,"lastUpdated":1553632500,"open":false,"status":"MERGED","comments":[{"timestamp":1553631812,"reviewer":{"name":"Administrator","email":"admin@example.com","username":"admin"},"message":"Uploaded patch set 1."},{"timestamp":1553632440,"reviewer":{"name":"jdoe","email":"john.doe@localhost","username":"jdoe"},"message":"Patch Set 1: Code-Review+2\n\nLooks good."},{"timestamp":1553632500,"reviewer":{"name":"Administrator","email":"admin@example.com","username":"admin"},"message":"Change has been successfully merged by Administrator"}],"currentPatchSet":{"number":1,"revision":"c4f7d43450e366f9c8e4dcb94fbd91573cd40766","parents":["20332c6ee056bdf3f814c8cff9905154d443d2f0"],"ref":"refs/changes/01/1/1","uploader":{"name":"Administrator","email":"admin@example.com","username":"admin"},"createdOn":1553631812,"author":{"name":"Frank Benkstein","email":"frank@benkstein.net","username":""},"isDraft":false,"kind":"REWORK","approvals":[{"type":"Code-Review","description":"Code-Review","value":"2","grantedOn":1553632440,"by":{"name":"jdoe","email":"john.doe@localhost","username":"jdoe"}}],"sizeInsertions":0,"sizeDeletions":-18}}"#;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        timestamp::from_epoch_seconds(seconds).unwrap()
+    }
+
+    fn get_change() -> Change {
+        serde_json::from_str(CHANGE_JSON).expect("failed to decode change")
+    }
+
+    #[test]
+    fn test_patchset_number() {
+        assert_eq!(patchset_number("Patch Set 12: Code-Review+1"), Some(12));
+        assert_eq!(patchset_number("Patch Set 3:\n\n(1 comment)"), Some(3));
+        assert_eq!(patchset_number("Uploaded patch set 1."), None);
+    }
+
+    #[test]
+    fn test_synthesize_events() {
+        let events = synthesize_events(get_change(), time(1553631812));
+        assert_eq!(events.len(), 2);
+
+        match &events[0] {
+            Event::CommentAdded(event) => {
+                assert_eq!(event.author.username.as_deref(), Some("jdoe"));
+                assert_eq!(event.created_on, time(1553632440));
+                let approvals = event.approvals.as_ref().expect("no approvals");
+                assert_eq!(approvals.len(), 1);
+                assert_eq!(approvals[0].value, "2");
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        match &events[1] {
+            Event::ChangeMerged(event) => {
+                assert_eq!(event.submitter.username.as_deref(), Some("admin"));
+                assert_eq!(
+                    event.new_revision,
+                    "c4f7d43450e366f9c8e4dcb94fbd91573cd40766"
+                );
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_synthesize_events_skips_old_comments() {
+        let events = synthesize_events(get_change(), time(1553632440));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].type_name(), "change-merged");
+    }
+}
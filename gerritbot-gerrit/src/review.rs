@@ -0,0 +1,107 @@
+//! Builder for `gerrit review` commands.
+
+use crate::query::quote;
+
+/// A `gerrit review` command voting on, commenting, abandoning or restoring a
+/// patch set.
+///
+/// ```
+/// use gerritbot_gerrit::Review;
+///
+/// let review = Review::new().label("Code-Review", 1).message("Looks good");
+/// assert_eq!(
+///     review.to_command(42, 3),
+///     "gerrit review --label 'Code-Review=+1' --message 'Looks good' 42,3"
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Review {
+    labels: Vec<(String, i32)>,
+    message: Option<String>,
+    abandon: bool,
+    restore: bool,
+}
+
+impl Review {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Vote with the value on the label, e.g. `Code-Review`.
+    pub fn label<S: Into<String>>(mut self, label: S, value: i32) -> Self {
+        self.labels.push((label.into(), value));
+        self
+    }
+
+    pub fn message<S: Into<String>>(self, message: S) -> Self {
+        Self {
+            message: Some(message.into()),
+            ..self
+        }
+    }
+
+    /// Abandon the change.
+    pub fn abandon(self) -> Self {
+        Self {
+            abandon: true,
+            ..self
+        }
+    }
+
+    /// Restore the abandoned change.
+    pub fn restore(self) -> Self {
+        Self {
+            restore: true,
+            ..self
+        }
+    }
+
+    /// The command to run on the Gerrit server for the patch set of the
+    /// change.
+    pub fn to_command(&self, change_number: u32, patchset_number: u32) -> String {
+        let mut command = "gerrit review".to_string();
+        if self.abandon {
+            command += " --abandon";
+        }
+        if self.restore {
+            command += " --restore";
+        }
+        for (label, value) in &self.labels {
+            command += " --label ";
+            command += &quote(&format!("{}={:+}", label, value));
+        }
+        if let Some(message) = &self.message {
+            command += " --message ";
+            command += &quote(message);
+        }
+        command += &format!(" {},{}", change_number, patchset_number);
+        command
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_message_is_quoted() {
+        let review = Review::new().label("Verified", -1).message("it's broken");
+        assert_eq!(
+            review.to_command(1, 2),
+            r"gerrit review --label 'Verified=-1' --message 'it'\''s broken' 1,2"
+        );
+    }
+
+    #[test]
+    fn test_abandon() {
+        let review = Review::new().abandon().message("obsolete");
+        assert_eq!(
+            review.to_command(1, 2),
+            "gerrit review --abandon --message 'obsolete' 1,2"
+        );
+        assert_eq!(
+            Review::new().restore().to_command(1, 2),
+            "gerrit review --restore 1,2"
+        );
+    }
+}
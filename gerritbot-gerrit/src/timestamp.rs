@@ -0,0 +1,84 @@
+//! (De)serialization of Gerrit timestamps, which are given as seconds since
+//! the Unix epoch. Use with `#[serde(with = "timestamp")]` or, for optional
+//! fields, `#[serde(with = "timestamp::option")]`.
+
+use chrono::{DateTime, TimeZone as _, Utc};
+use serde::{de, Deserialize, Deserializer, Serializer};
+
+/// Convert seconds since the Unix epoch to a timestamp.
+pub fn from_epoch_seconds(seconds: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(seconds, 0).single()
+}
+
+pub fn serialize<S>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_i64(timestamp.timestamp())
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let seconds = i64::deserialize(deserializer)?;
+    from_epoch_seconds(seconds)
+        .ok_or_else(|| de::Error::custom(format!("timestamp out of range: {}", seconds)))
+}
+
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S>(timestamp: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match timestamp {
+            Some(timestamp) => serializer.serialize_some(&timestamp.timestamp()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<i64>::deserialize(deserializer)? {
+            Some(seconds) => from_epoch_seconds(seconds)
+                .map(Some)
+                .ok_or_else(|| de::Error::custom(format!("timestamp out of range: {}", seconds))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Serialize;
+
+    use super::*;
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    struct Timestamps {
+        #[serde(with = "super")]
+        created_on: DateTime<Utc>,
+        #[serde(with = "super::option", default)]
+        granted_on: Option<DateTime<Utc>>,
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let timestamps: Timestamps = serde_json::from_str(r#"{"created_on":1553631812}"#).unwrap();
+        assert_eq!(
+            timestamps,
+            Timestamps {
+                created_on: Utc.ymd(2019, 3, 26).and_hms(20, 23, 32),
+                granted_on: None,
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&timestamps).unwrap(),
+            r#"{"created_on":1553631812,"granted_on":null}"#
+        );
+    }
+}
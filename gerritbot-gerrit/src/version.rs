@@ -0,0 +1,146 @@
+//! Detection of the Gerrit server version.
+
+use std::fmt;
+use std::io::Read as _;
+use std::str::FromStr;
+
+use log::{info, warn};
+
+use crate::{Error, ExtendedInfo};
+
+/// Version of a Gerrit server as reported by `gerrit version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GerritVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl GerritVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Whether the server can provide the extended info.
+    pub fn supports(&self, extended_info: ExtendedInfo) -> bool {
+        match extended_info {
+            ExtendedInfo::SubmitRecords
+            | ExtendedInfo::InlineComments
+            | ExtendedInfo::AllApprovals => true,
+            // `gerrit query --all-reviewers` was added in Gerrit 2.9
+            ExtendedInfo::AllReviewers => *self >= GerritVersion::new(2, 9, 0),
+            // `gerrit query --submit-requirements` was added in Gerrit 3.5
+            ExtendedInfo::SubmitRequirements => *self >= GerritVersion::new(3, 5, 0),
+        }
+    }
+
+    /// Whether the server emits events of the type, so the event stream can
+    /// subscribe to it.
+    pub fn supports_event(&self, event_type: &str) -> bool {
+        match event_type {
+            // change-deleted events were added in Gerrit 2.15
+            "change-deleted" => *self >= GerritVersion::new(2, 15, 0),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for GerritVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for GerritVersion {
+    type Err = ();
+
+    /// Parse the output of `gerrit version`, e.g. "gerrit version 3.4.1".
+    /// Suffixes like "-rc1" are ignored, a missing patch version is 0.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let version = s.trim();
+        let version = version.strip_prefix("gerrit version ").unwrap_or(version);
+        let mut numbers = version
+            .split(|c: char| !c.is_ascii_digit())
+            .take_while(|number| !number.is_empty())
+            .map(|number| number.parse::<u32>().map_err(|_| ()));
+
+        let major = numbers.next().ok_or(())??;
+        let minor = numbers.next().ok_or(())??;
+        let patch = numbers.next().unwrap_or(Ok(0))?;
+
+        Ok(Self::new(major, minor, patch))
+    }
+}
+
+/// Run `gerrit version` on the session. Failures are logged and result in an
+/// unknown version.
+pub(crate) fn detect_version(session: &ssh2::Session) -> Option<GerritVersion> {
+    let run = || -> Result<String, Error> {
+        let mut ssh_channel = session.channel_session()?;
+        ssh_channel.exec("gerrit version")?;
+        let mut data = String::new();
+        ssh_channel.read_to_string(&mut data)?;
+        ssh_channel.wait_close()?;
+        Ok(data)
+    };
+
+    match run() {
+        Ok(output) => match output.parse() {
+            Ok(version) => {
+                info!("Connected to Gerrit {}", version);
+                Some(version)
+            }
+            Err(()) => {
+                warn!("Could not parse Gerrit version: {:?}", output);
+                None
+            }
+        },
+        Err(e) => {
+            warn!("Could not detect Gerrit version: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            "gerrit version 3.4.1\n".parse(),
+            Ok(GerritVersion::new(3, 4, 1))
+        );
+        assert_eq!(
+            "gerrit version 2.16.8-rc1".parse(),
+            Ok(GerritVersion::new(2, 16, 8))
+        );
+        assert_eq!(
+            "gerrit version 3.5".parse(),
+            Ok(GerritVersion::new(3, 5, 0))
+        );
+        assert_eq!("gerrit version unknown".parse::<GerritVersion>(), Err(()));
+    }
+
+    #[test]
+    fn test_supports() {
+        assert!(!GerritVersion::new(2, 8, 6).supports(ExtendedInfo::AllReviewers));
+        assert!(GerritVersion::new(2, 9, 0).supports(ExtendedInfo::AllReviewers));
+        assert!(GerritVersion::new(3, 4, 1).supports(ExtendedInfo::AllReviewers));
+
+        assert!(!GerritVersion::new(3, 4, 1).supports(ExtendedInfo::SubmitRequirements));
+        assert!(GerritVersion::new(3, 5, 0).supports(ExtendedInfo::SubmitRequirements));
+    }
+
+    #[test]
+    fn test_supports_event() {
+        assert!(!GerritVersion::new(2, 14, 20).supports_event("change-deleted"));
+        assert!(GerritVersion::new(2, 15, 0).supports_event("change-deleted"));
+        assert!(GerritVersion::new(2, 14, 20).supports_event("comment-added"));
+    }
+}
@@ -58,12 +58,17 @@ fn main() {
                 let next_client = client.clone();
 
                 client
-                    .register_webhook(&webhook_url)
+                    .register_webhook(&webhook_url, None)
                     .map_err(|e| error!("failed to register webhook: {}", e))
                     .map(move |()| next_client)
             })
             .and_then(move |client| {
                 spark::sqs_event_stream(spark_config.sqs_url.clone(), sqs_region, client.clone())
+                    // ignore card actions
+                    .filter_map(|event| match event {
+                        spark::Event::Message(message) => Some(message),
+                        _ => None,
+                    })
                     .for_each(move |message| {
                         debug!("got a message: {:?}", message);
 
@@ -78,8 +83,12 @@ fn main() {
                                 markdown: message.markdown.as_deref(),
                                 html: message.html.as_deref(),
                                 text: Some(&message.text),
+                                attachments: None,
+                                files: None,
+                                parent_id: None,
                             }))
                         }
+                        .map(|_message| ())
                         .map_err(|e| error!("failed to send message: {}", e))
                     })
             })
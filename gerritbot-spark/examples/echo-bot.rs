@@ -55,15 +55,19 @@ fn main() {
                 let next_client = client.clone();
 
                 client
-                    .register_webhook(&webhook_url)
+                    .register_webhook(&webhook_url, None)
                     .map_err(|e| error!("failed to register webhook: {}", e))
                     .map(move |()| next_client)
             })
             .and_then(move |client| {
-                let spark::WebhookServer { messages, server } =
-                    spark::start_webhook_server(&endpoint_address, client.clone());
+                let spark::WebhookServer { events, server, .. } =
+                    spark::start_webhook_server(&endpoint_address, client.clone(), None);
 
-                // consume messages
+                // consume messages, ignore card actions
+                let messages = events.filter_map(|event| match event {
+                    spark::Event::Message(message) => Some(message),
+                    _ => None,
+                });
                 let messages_future = messages.for_each(move |message| {
                     debug!("got a message: {:?}", message);
 
@@ -78,8 +82,12 @@ fn main() {
                             markdown: message.markdown.as_deref(),
                             html: message.html.as_deref(),
                             text: Some(&message.text),
+                            attachments: None,
+                            files: None,
+                            parent_id: None,
                         }))
                     }
+                    .map(|_message| ())
                     .map_err(|e| error!("failed to send message: {}", e))
                 });
 
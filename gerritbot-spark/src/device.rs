@@ -0,0 +1,324 @@
+//! Receiving messages over the websocket of a Webex device registered for the
+//! bot. Unlike webhooks, this doesn't need a public url.
+
+use std::time::{Duration, Instant};
+
+use futures::{future, stream, Future, Stream};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::websocket;
+use crate::{
+    check_status, decode_json_body, AttachmentAction, AttachmentActionId, Client, Email, Error,
+    Message, MessageId, PersonId, RoomId, WebhookData,
+};
+
+/// Webex device management service.
+const DEVICES_URL: &str = "https://wdm-a.wbx2.com/wdm/api/v1/devices";
+
+/// Name of the device registered by the bot. An existing device with this
+/// name is reused.
+const DEVICE_NAME: &str = "gerritbot";
+
+/// Time to wait before reconnecting after the websocket connection was lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Device {
+    url: String,
+    name: String,
+    web_socket_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Devices {
+    #[serde(default)]
+    devices: Vec<Device>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DeviceRegistration<'a> {
+    device_name: &'a str,
+    device_type: &'a str,
+    localized_model: &'a str,
+    model: &'a str,
+    name: &'a str,
+    system_name: &'a str,
+    system_version: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct Authorization<'a> {
+    id: String,
+    #[serde(rename = "type")]
+    message_type: &'a str,
+    data: AuthorizationData,
+}
+
+#[derive(Serialize, Debug)]
+struct AuthorizationData {
+    token: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeviceEvent {
+    data: DeviceEventData,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DeviceEventData {
+    event_type: String,
+    activity: Option<Activity>,
+}
+
+/// Something that happened in a conversation, e.g. a message was posted.
+#[derive(Deserialize, Debug)]
+struct Activity {
+    id: String,
+    verb: String,
+    actor: Actor,
+    target: Option<ActivityObject>,
+    parent: Option<ActivityObject>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Actor {
+    id: String,
+    email_address: Option<Email>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ActivityObject {
+    id: String,
+}
+
+/// Id of the resource as used by the Webex REST API, which is derived from
+/// the uuid used internally.
+fn hydra_id(resource_type: &str, uuid: &str) -> String {
+    base64::encode_config(
+        format!("ciscospark://us/{}/{}", resource_type, uuid),
+        base64::STANDARD_NO_PAD,
+    )
+}
+
+/// Translate a conversation activity into the data of the corresponding
+/// webhook post. Other activities are ignored.
+fn activity_to_webhook_data(activity: Activity) -> Option<WebhookData> {
+    let person_id = PersonId::new(hydra_id("PEOPLE", &activity.actor.id));
+    let person_email = activity.actor.email_address.unwrap_or_default();
+    let room_id = activity
+        .target
+        .map(|target| RoomId::new(hydra_id("ROOM", &target.id)))
+        .unwrap_or_default();
+
+    match &activity.verb[..] {
+        "post" | "share" => Some(WebhookData::Messages(Message {
+            id: MessageId::new(hydra_id("MESSAGE", &activity.id)),
+            person_id,
+            person_email,
+            room_id,
+            ..Default::default()
+        })),
+        "cardAction" => Some(WebhookData::AttachmentActions(AttachmentAction {
+            id: AttachmentActionId::new(hydra_id("ATTACHMENT_ACTION", &activity.id)),
+            action_type: "submit".to_string(),
+            message_id: activity
+                .parent
+                .map(|parent| MessageId::new(hydra_id("MESSAGE", &parent.id)))
+                .unwrap_or_default(),
+            person_id,
+            room_id,
+            person_email,
+            ..Default::default()
+        })),
+        verb => {
+            debug!("ignoring activity: {}", verb);
+            None
+        }
+    }
+}
+
+/// Decode a websocket message into webhook data. Other events are ignored.
+fn decode_event(text: &str) -> Option<WebhookData> {
+    let event: DeviceEvent = serde_json::from_str(text)
+        .map_err(|e| debug!("ignoring device event: {}", e))
+        .ok()?;
+    if event.data.event_type != "conversation.activity" {
+        debug!("ignoring device event: {}", event.data.event_type);
+        return None;
+    }
+    event.data.activity.and_then(activity_to_webhook_data)
+}
+
+fn list_devices(client: &Client) -> impl Future<Item = Devices, Error = Error> {
+    client
+        .client
+        .get(DEVICES_URL)
+        .bearer_auth(&client.bot_token)
+        .header(http::header::ACCEPT, "application/json")
+        .send()
+        .from_err()
+        .and_then(check_status)
+        .and_then(|response| decode_json_body(response.into_body()))
+}
+
+fn register_device(client: &Client) -> impl Future<Item = Device, Error = Error> {
+    let registration = DeviceRegistration {
+        device_name: DEVICE_NAME,
+        device_type: "DESKTOP",
+        localized_model: "rust",
+        model: "rust",
+        name: DEVICE_NAME,
+        system_name: DEVICE_NAME,
+        system_version: env!("CARGO_PKG_VERSION"),
+    };
+    client
+        .client
+        .post(DEVICES_URL)
+        .bearer_auth(&client.bot_token)
+        .header(http::header::ACCEPT, "application/json")
+        .json(&registration)
+        .send()
+        .from_err()
+        .and_then(check_status)
+        .and_then(|response| decode_json_body(response.into_body()))
+}
+
+/// Reuse the device registered by the bot or register a new one.
+fn get_device(client: Client) -> impl Future<Item = Device, Error = Error> {
+    list_devices(&client).and_then(move |devices| {
+        match devices
+            .devices
+            .into_iter()
+            .find(|device| device.name == DEVICE_NAME)
+        {
+            Some(device) => {
+                debug!("reusing device: {}", device.url);
+                future::Either::A(future::ok(device))
+            }
+            None => {
+                info!("registering Webex device");
+                future::Either::B(register_device(&client))
+            }
+        }
+    })
+}
+
+/// Webhook data of the activities received over a single websocket
+/// connection.
+fn connection_activities(client: Client) -> impl Stream<Item = WebhookData, Error = Error> {
+    let token = client.bot_token.clone();
+    get_device(client)
+        .and_then(|device| {
+            info!("connecting to Webex device websocket");
+            websocket::connect(&device.web_socket_url)
+        })
+        .and_then(move |connection| {
+            let authorization = Authorization {
+                id: hex::encode(rand::random::<[u8; 16]>()),
+                message_type: "authorization",
+                data: AuthorizationData {
+                    token: format!("Bearer {}", token),
+                },
+            };
+            connection.send_text(serde_json::to_string(&authorization)?)?;
+            Ok(connection)
+        })
+        .map(|connection| connection.filter_map(|text| decode_event(&text)))
+        .flatten_stream()
+}
+
+/// Webhook data of the activities received over the websocket of the bot's
+/// device. Reconnects if the connection is lost.
+pub(crate) fn activity_stream(client: Client) -> impl Stream<Item = WebhookData, Error = ()> {
+    stream::repeat::<_, ()>(())
+        .map(move |()| {
+            connection_activities(client.clone())
+                // log the error and end the stream of this connection
+                .then(|result| {
+                    Ok::<_, ()>(result.map_err(|e| error!("Webex device connection failed: {}", e)))
+                })
+                .take_while(|result| Ok(result.is_ok()))
+                .filter_map(Result::ok)
+                .chain(reconnect_delay())
+        })
+        .flatten()
+}
+
+/// Empty stream that ends after the reconnect delay.
+fn reconnect_delay() -> impl Stream<Item = WebhookData, Error = ()> {
+    future::lazy(|| {
+        info!("reconnecting to Webex in {:?}", RECONNECT_DELAY);
+        tokio::timer::Delay::new(Instant::now() + RECONNECT_DELAY)
+    })
+    .map_err(|e| error!("reconnect timer failed: {}", e))
+    .map(|()| stream::empty())
+    .flatten_stream()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hydra_id() {
+        assert_eq!(
+            hydra_id("PEOPLE", "f5b36187-c8dd-4727-8b2f-f9c447f29046"),
+            "Y2lzY29zcGFyazovL3VzL1BFT1BMRS9mNWIzNjE4Ny1jOGRkLTQ3MjctOGIyZi1mOWM0NDdmMjkwNDY"
+        );
+    }
+
+    #[test]
+    fn test_decode_event() {
+        let event = serde_json::json!({
+            "id": "event-id",
+            "data": {
+                "eventType": "conversation.activity",
+                "activity": {
+                    "id": "activity-id",
+                    "objectType": "activity",
+                    "verb": "post",
+                    "actor": {
+                        "id": "person-id",
+                        "objectType": "person",
+                        "emailAddress": "jdoe@example.com",
+                    },
+                    "target": {
+                        "id": "conversation-id",
+                        "objectType": "conversation",
+                    },
+                },
+            },
+        });
+        match decode_event(&event.to_string()) {
+            Some(WebhookData::Messages(message)) => {
+                assert_eq!(
+                    message.id,
+                    MessageId::new(hydra_id("MESSAGE", "activity-id"))
+                );
+                assert_eq!(
+                    message.person_id,
+                    PersonId::new(hydra_id("PEOPLE", "person-id"))
+                );
+                assert_eq!(
+                    message.person_email,
+                    Email::new("jdoe@example.com".to_string())
+                );
+                assert_eq!(
+                    message.room_id,
+                    RoomId::new(hydra_id("ROOM", "conversation-id"))
+                );
+            }
+            data => panic!("unexpected webhook data: {:?}", data),
+        }
+
+        let event = serde_json::json!({
+            "data": { "eventType": "status.start_typing" },
+        });
+        assert!(decode_event(&event.to_string()).is_none());
+    }
+}
@@ -0,0 +1,109 @@
+//! Kafka consumer reading the messages of a topic on a thread.
+//!
+//! The consumer joins a consumer group, so the partitions of the topic are
+//! balanced between the bots of the group. The offset of a message is stored
+//! once the message was passed on, and stored offsets are committed
+//! periodically.
+
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::mpsc::{channel, Sender};
+use futures::{Future as _, Sink as _, Stream};
+use log::{debug, error, info};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer as _};
+use rdkafka::Message as _;
+
+use crate::Error;
+
+/// Time a poll waits for new messages.
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time to wait before reconnecting after an error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+struct Consumer {
+    brokers: Vec<String>,
+    topic: String,
+    group: String,
+}
+
+impl Consumer {
+    fn create(&self) -> Result<BaseConsumer, Error> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers.join(","))
+            .set("group.id", &self.group)
+            .set("client.id", "gerritbot")
+            // without committed offsets, start with new messages
+            .set("auto.offset.reset", "latest")
+            .set("enable.auto.commit", "true")
+            .set("enable.auto.offset.store", "false")
+            .set("enable.partition.eof", "false")
+            .create()?;
+        consumer.subscribe(&[&self.topic])?;
+        Ok(consumer)
+    }
+
+    /// Consume the topic until the receiver is dropped.
+    fn consume(&self, sender: &Sender<Vec<u8>>) -> Result<(), Error> {
+        let consumer = self.create()?;
+        info!(
+            "consuming Kafka topic {} as group {}",
+            self.topic, self.group
+        );
+
+        let mut sender = sender.clone();
+        while !sender.is_closed() {
+            let message = match consumer.poll(POLL_TIMEOUT) {
+                Some(message) => message?,
+                None => continue,
+            };
+            debug!(
+                "received Kafka message at {}/{}",
+                message.partition(),
+                message.offset()
+            );
+            if let Some(payload) = message.payload() {
+                sender = match sender.send(payload.to_vec()).wait() {
+                    Ok(sender) => sender,
+                    // receiver dropped
+                    Err(_) => return Ok(()),
+                };
+            }
+            consumer.store_offset(&message)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn kafka_receiver(
+    brokers: Vec<String>,
+    topic: String,
+    group: String,
+) -> impl Stream<Item = Vec<u8>, Error = ()> {
+    let (sender, receiver) = channel(1);
+    let consumer = Consumer {
+        brokers,
+        topic,
+        group,
+    };
+
+    thread::Builder::new()
+        .name("Kafka consumer".to_string())
+        .spawn(move || {
+            while !sender.is_closed() {
+                match consumer.consume(&sender) {
+                    Ok(()) => return,
+                    Err(e) => {
+                        error!("Kafka consumer failed: {}", e);
+                        info!("reconnecting to Kafka in {:?}", RECONNECT_DELAY);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        })
+        .expect("failed to spawn thread");
+
+    receiver
+}
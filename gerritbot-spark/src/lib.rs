@@ -2,17 +2,39 @@
 #![allow(dead_code)]
 
 use std::convert::identity;
+use std::fmt;
+use std::fs;
 use std::io;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::future::{self, Future};
-use futures::sync::mpsc::channel;
-use futures::{IntoFuture as _, Sink, Stream};
+use futures::sync::mpsc::{channel, Sender};
+use futures::sync::oneshot;
+use futures::{stream, IntoFuture as _, Sink, Stream};
 use log::{debug, error, info, warn};
+use lru_time_cache::LruCache;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+mod device;
+mod kafka;
+mod metrics;
+mod pubsub;
+mod retry;
+mod sns;
 mod sqs;
+mod tls;
+mod websocket;
+
+pub use metrics::{CallOutcome, CallStats, Counters, Metrics, NoMetrics};
+pub use pubsub::PubSubCredentials;
+pub use retry::RetryQueue;
+pub use sns::SnsServerOptions;
+pub use tls::TlsAcceptor;
 
 //
 // Spark data model
@@ -110,6 +132,8 @@ newtype_string!(Email, EmailRef);
 newtype_string!(WebhookId, WebhookIdRef);
 newtype_string!(MessageId, MessageIdRef);
 newtype_string!(RoomId, RoomIdRef);
+newtype_string!(AttachmentActionId, AttachmentActionIdRef);
+newtype_string!(MembershipId, MembershipIdRef);
 
 #[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "lowercase")]
@@ -124,6 +148,8 @@ pub enum ResourceType {
     Memberships,
     Messages,
     Rooms,
+    #[serde(rename = "attachmentActions")]
+    AttachmentActions,
 }
 
 #[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -171,16 +197,106 @@ pub struct WebhookMessage {
     app_id: String,
     created: Timestamp,
     created_by: PersonId,
-    pub data: Message,
+    #[serde(flatten)]
+    pub data: WebhookData,
     event: EventType,
     name: String,
     org_id: String,
     owned_by: String,
-    resource: ResourceId,
     status: String,
     target_url: String,
 }
 
+/// Resource a webhook post is about. Only its id and a few other fields are
+/// included; the rest has to be fetched.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "resource", content = "data", rename_all = "camelCase")]
+pub enum WebhookData {
+    Messages(Message),
+    AttachmentActions(AttachmentAction),
+    Memberships(Membership),
+}
+
+impl WebhookData {
+    /// Id of the resource, e.g. of the message.
+    fn id(&self) -> &str {
+        match self {
+            WebhookData::Messages(message) => &message.id.0,
+            WebhookData::AttachmentActions(action) => &action.id.0,
+            WebhookData::Memberships(membership) => &membership.id.0,
+        }
+    }
+
+    fn person_id(&self) -> &PersonIdRef {
+        match self {
+            WebhookData::Messages(message) => &message.person_id,
+            WebhookData::AttachmentActions(action) => &action.person_id,
+            WebhookData::Memberships(membership) => &membership.person_id,
+        }
+    }
+}
+
+/// Membership of a person in a room. A post contains all of its fields.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Membership {
+    pub id: MembershipId,
+    pub room_id: RoomId,
+    pub room_type: Option<RoomType>,
+    pub person_id: PersonId,
+    pub person_email: Email,
+    pub person_display_name: Option<String>,
+    #[serde(default)]
+    pub is_moderator: bool,
+    pub created: Option<Timestamp>,
+}
+
+/// A space in Webex.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Room {
+    pub id: RoomId,
+    pub title: String,
+    #[serde(rename = "type")]
+    pub room_type: RoomType,
+    #[serde(default)]
+    pub is_locked: bool,
+    pub team_id: Option<String>,
+    pub creator_id: Option<PersonId>,
+    pub last_activity: Option<Timestamp>,
+    pub created: Option<Timestamp>,
+}
+
+/// Submission of an Adaptive Card, e.g. by clicking an `Action.Submit`
+/// button.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachmentAction {
+    pub id: AttachmentActionId,
+    #[serde(rename = "type")]
+    pub action_type: String,
+    pub message_id: MessageId,
+    pub person_id: PersonId,
+    pub room_id: RoomId,
+    pub created: Option<Timestamp>,
+    /// Values of the card's inputs and the `data` of the submit action; not
+    /// contained in a post.
+    #[serde(default)]
+    pub inputs: serde_json::Value,
+    /// Not part of the Webex API; filled in when the action is fetched.
+    #[serde(default)]
+    pub person_email: Email,
+}
+
+/// Something a user did that the bot is notified about.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Message(Message),
+    AttachmentAction(AttachmentAction),
+    /// The bot was added to a room.
+    Membership(Membership),
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Message {
@@ -197,6 +313,9 @@ pub struct Message {
     pub markdown: Option<String>,
     pub html: Option<String>,
     pub files: Option<Vec<String>>,
+    pub mentioned_people: Option<Vec<PersonId>>,
+    /// Mentioned groups of people, e.g. `all`.
+    pub mentioned_groups: Option<Vec<String>>,
 }
 
 impl Default for Message {
@@ -212,8 +331,83 @@ impl Default for Message {
             markdown: Default::default(),
             html: Default::default(),
             files: Default::default(),
+            mentioned_people: Default::default(),
+            mentioned_groups: Default::default(),
+        }
+    }
+}
+
+impl Message {
+    /// Remove a leading mention of the bot from the text. In group rooms,
+    /// messages start with the bot's display name or the first word of it.
+    pub fn strip_mention(&mut self, bot_name: &str) {
+        let first_name = bot_name.split_whitespace().next().unwrap_or(bot_name);
+        for name in &[bot_name, first_name] {
+            if name.is_empty() || !self.text.starts_with(name) {
+                continue;
+            }
+            let rest = &self.text[name.len()..];
+            if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+                self.text = rest.trim_start().to_string();
+                return;
+            }
+        }
+    }
+
+    /// Whether the person is mentioned in the message.
+    pub fn mentions(&self, person_id: &PersonIdRef) -> bool {
+        self.mentioned_people
+            .iter()
+            .flatten()
+            .any(|mentioned| mentioned == person_id)
+    }
+
+    /// Text with the mentions of people and groups removed. The mentions are
+    /// looked up in the html of the message; without html, only surrounding
+    /// whitespace is removed.
+    pub fn text_without_mentions(&self) -> String {
+        let mut text = self.text.clone();
+        for name in self.html.as_deref().map(mention_names).unwrap_or_default() {
+            if let Some(start) = text.find(&name[..]).filter(|_| !name.is_empty()) {
+                let mut end = start + name.len();
+                if text[end..].starts_with(' ') {
+                    end += 1;
+                }
+                text.replace_range(start..end, "");
+            }
         }
+        text.trim().to_string()
+    }
+}
+
+/// Names of the people and groups mentioned in the html of a message, e.g.
+/// `<spark-mention data-object-type="person" ...>Name</spark-mention>`.
+fn mention_names(html: &str) -> Vec<String> {
+    const START_TAG: &str = "<spark-mention";
+    const END_TAG: &str = "</spark-mention>";
+
+    let mut names = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find(START_TAG) {
+        rest = &rest[start + START_TAG.len()..];
+        let name_start = match rest.find('>') {
+            Some(pos) => pos + 1,
+            None => break,
+        };
+        let name_end = match rest.find(END_TAG) {
+            Some(pos) if pos >= name_start => pos,
+            _ => break,
+        };
+        let name = rest[name_start..name_end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&");
+        names.push(name);
+        rest = &rest[name_end + END_TAG.len()..];
     }
+    names
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -262,6 +456,58 @@ impl<'a> From<&'a EmailRef> for CreateMessageTarget<'a> {
     }
 }
 
+/// Content attached to a message, e.g. an Adaptive Card.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    pub content_type: String,
+    pub content: serde_json::Value,
+}
+
+impl Attachment {
+    /// Content type of Adaptive Card attachments.
+    pub const ADAPTIVE_CARD_CONTENT_TYPE: &'static str = "application/vnd.microsoft.card.adaptive";
+
+    pub fn adaptive_card(card: serde_json::Value) -> Self {
+        Self {
+            content_type: Self::ADAPTIVE_CARD_CONTENT_TYPE.to_string(),
+            content: card,
+        }
+    }
+}
+
+/// File attached to a message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MessageFile {
+    /// Url Webex downloads the file from.
+    Url(String),
+    /// Local file uploaded along with the message.
+    Path(PathBuf),
+}
+
+fn has_no_file_urls(files: &Option<&[MessageFile]>) -> bool {
+    !files
+        .unwrap_or_default()
+        .iter()
+        .any(|file| matches!(file, MessageFile::Url(_)))
+}
+
+/// Serialize the urls of the files, local files are uploaded separately.
+fn serialize_file_urls<S>(files: &Option<&[MessageFile]>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let urls: Vec<&str> = files
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|file| match file {
+            MessageFile::Url(url) => Some(&url[..]),
+            MessageFile::Path(_) => None,
+        })
+        .collect();
+    urls.serialize(serializer)
+}
+
 #[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateMessageParameters<'a> {
@@ -271,21 +517,54 @@ pub struct CreateMessageParameters<'a> {
     pub markdown: Option<&'a str>,
     /// Note: This parameter is not in the documented API.
     pub html: Option<&'a str>,
+    /// Clients that can't render the attachments show `text` or `markdown`
+    /// instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachments: Option<&'a [Attachment]>,
+    /// Files attached to the message. Webex accepts only one file per message
+    /// and none together with `attachments`. A local file is uploaded as
+    /// multipart form, without the urls.
+    #[serde(
+        skip_serializing_if = "has_no_file_urls",
+        serialize_with = "serialize_file_urls"
+    )]
+    pub files: Option<&'a [MessageFile]>,
+    /// Post the message as reply in the thread of this message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<&'a MessageIdRef>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-struct PersonDetails {
-    id: PersonId,
-    emails: Vec<Email>,
-    display_name: String,
-    nick_name: Option<String>,
-    org_id: String,
-    created: Timestamp,
-    last_activity: Option<String>,
-    status: Option<String>,
+struct UpdateMessageParameters {
+    room_id: RoomId,
+    markdown: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PersonDetails {
+    pub id: PersonId,
+    pub emails: Vec<Email>,
+    pub display_name: String,
+    pub nick_name: Option<String>,
+    pub org_id: String,
+    pub created: Timestamp,
+    pub last_activity: Option<String>,
+    pub status: Option<String>,
     #[serde(rename = "type")]
-    person_type: String,
+    pub person_type: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct People {
+    items: Vec<PersonDetails>,
+}
+
+/// Page of a list of resources.
+#[derive(Deserialize, Debug)]
+struct Items<T> {
+    items: Vec<T>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -295,6 +574,12 @@ struct WebhookRegistration {
     target_url: String,
     resource: ResourceType,
     event: EventType,
+    /// Used by Webex to sign webhook posts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    /// Only resources matching the filter are posted, e.g. `personId=<id>`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -329,6 +614,9 @@ pub struct Client {
     url: String,
     bot_token: String,
     bot_id: PersonId,
+    bot_name: String,
+    retry_queue: Option<RetryQueue>,
+    metrics: Arc<dyn Metrics>,
 }
 
 #[derive(Debug, Error)]
@@ -346,6 +634,130 @@ pub enum Error {
     DeleteWebhook(String),
     #[error(transparent)]
     IoError(#[from] io::Error),
+    #[error("websocket error: {0}")]
+    WebSocket(String),
+    #[error(transparent)]
+    OpensslError(#[from] openssl::error::ErrorStack),
+    #[error("failed to create token: {0}")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("invalid url: {0}")]
+    InvalidUrl(String),
+    #[error("kafka error: {0}")]
+    Kafka(#[from] rdkafka::error::KafkaError),
+    #[error("Webex API error: {0}")]
+    Api(ApiError),
+}
+
+impl Error {
+    /// Whether repeating the failed request is pointless, cf.
+    /// `ApiError::is_permanent`.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Error::Api(e) => e.is_permanent(),
+            _ => false,
+        }
+    }
+}
+
+/// Error response of the Webex API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    pub status: http::StatusCode,
+    /// Description of the error, or the response body if it is not the usual
+    /// error json.
+    pub message: String,
+    /// Id of the request, which the Webex support asks for.
+    pub tracking_id: Option<String>,
+}
+
+impl ApiError {
+    fn from_response(status: http::StatusCode, tracking_id: Option<String>, body: &[u8]) -> Self {
+        #[derive(Deserialize)]
+        struct ErrorDescription {
+            description: String,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct ErrorBody {
+            message: Option<String>,
+            #[serde(default)]
+            errors: Vec<ErrorDescription>,
+            tracking_id: Option<String>,
+        }
+
+        let (message, body_tracking_id) = match serde_json::from_slice::<ErrorBody>(body) {
+            Ok(ErrorBody {
+                message,
+                errors,
+                tracking_id,
+            }) => (
+                message.or_else(|| errors.into_iter().next().map(|e| e.description)),
+                tracking_id,
+            ),
+            Err(_) => (
+                Some(String::from_utf8_lossy(body).trim().to_string()).filter(|s| !s.is_empty()),
+                None,
+            ),
+        };
+        Self {
+            status,
+            message: message
+                .or_else(|| status.canonical_reason().map(str::to_string))
+                .unwrap_or_default(),
+            tracking_id: body_tracking_id.or(tracking_id),
+        }
+    }
+
+    /// Whether the request was rejected as is, e.g. because of invalid
+    /// markdown or an unknown person. Rate limited requests and server errors
+    /// are worth repeating.
+    pub fn is_permanent(&self) -> bool {
+        self.status.is_client_error()
+            && self.status != http::StatusCode::TOO_MANY_REQUESTS
+            && self.status != http::StatusCode::REQUEST_TIMEOUT
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)?;
+        if let Some(tracking_id) = &self.tracking_id {
+            write!(f, " (tracking id: {})", tracking_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fail with `Error::Api` if the response has an error status.
+pub(crate) fn check_status(
+    response: reqwest::r#async::Response,
+) -> impl Future<Item = reqwest::r#async::Response, Error = Error> {
+    let status = response.status();
+    if status.is_success() {
+        return future::Either::A(future::ok(response));
+    }
+    let tracking_id = response
+        .headers()
+        .get("trackingid")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    future::Either::B(
+        response
+            .into_body()
+            .fold(Vec::new(), |mut v, chunk| {
+                v.extend_from_slice(&chunk);
+                future::ok::<_, reqwest::Error>(v)
+            })
+            .from_err()
+            .and_then(move |body| {
+                Err(Error::Api(ApiError::from_response(
+                    status,
+                    tracking_id,
+                    &body,
+                )))
+            }),
+    )
 }
 
 impl Client {
@@ -358,14 +770,38 @@ impl Client {
             url: spark_api_url,
             bot_token,
             bot_id: PersonId(String::new()),
+            bot_name: String::new(),
+            retry_queue: None,
+            metrics: Arc::new(NoMetrics),
         };
 
-        bootstrap_client.get_bot_id().map(|bot_id| Client {
-            bot_id,
+        bootstrap_client.get_bot_details().map(|details| Client {
+            bot_id: details.id,
+            bot_name: details.display_name,
             ..bootstrap_client
         })
     }
 
+    /// Record the latency and outcome of the call with the metrics. The time
+    /// is measured from the first poll.
+    fn instrument<F>(
+        &self,
+        operation: &'static str,
+        call: F,
+    ) -> impl Future<Item = F::Item, Error = Error>
+    where
+        F: Future<Error = Error>,
+    {
+        let metrics = self.metrics.clone();
+        future::lazy(move || {
+            let started = Instant::now();
+            call.then(move |result| {
+                metrics.call_finished(operation, started.elapsed(), CallOutcome::of(&result));
+                result
+            })
+        })
+    }
+
     /// Try to get json from the given url with basic token authorization.
     fn api_get_json<T>(&self, resource: &str) -> impl Future<Item = T, Error = Error>
     where
@@ -377,9 +813,64 @@ impl Client {
             .header(http::header::ACCEPT, "application/json")
             .send()
             .from_err()
+            .and_then(check_status)
             .and_then(|response| decode_json_body(response.into_body()))
     }
 
+    /// Get a page of a paginated resource as json. Also returns the url of
+    /// the next page, if any.
+    fn api_get_json_page<T>(
+        &self,
+        url: &str,
+    ) -> impl Future<Item = (T, Option<String>), Error = Error>
+    where
+        for<'a> T: Deserialize<'a>,
+    {
+        self.client
+            .get(url)
+            .bearer_auth(&self.bot_token)
+            .header(http::header::ACCEPT, "application/json")
+            .send()
+            .from_err()
+            .and_then(check_status)
+            .and_then(|response| {
+                let next_page_url = response
+                    .headers()
+                    .get_all(http::header::LINK)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .find_map(next_page_link)
+                    .map(str::to_string);
+                decode_json_body(response.into_body()).map(|page| (page, next_page_url))
+            })
+    }
+
+    /// Get the items of all pages of a paginated resource.
+    fn api_get_all_pages<T>(&self, url: String) -> impl Future<Item = Vec<T>, Error = Error>
+    where
+        for<'a> T: Deserialize<'a>,
+    {
+        let client = self.clone();
+        future::loop_fn((Vec::new(), url), move |(mut items, url)| {
+            client
+                .api_get_json_page(&url)
+                .map(move |(page, next_page_url): (Items<T>, _)| {
+                    items.extend(page.items);
+                    match next_page_url {
+                        Some(url) => future::Loop::Continue((items, url)),
+                        None => future::Loop::Break(items),
+                    }
+                })
+        })
+    }
+
+    /// Url of the resource with the query parameters.
+    fn api_url(&self, resource: &str, params: &[(&str, &str)]) -> Result<String, Error> {
+        reqwest::Url::parse_with_params(&format!("{}/{}", self.url, resource), params)
+            .map(|url| url.into_string())
+            .map_err(|e| Error::InvalidUrl(e.to_string()))
+    }
+
     /// Try to post json to the given url with basic token authorization.
     fn api_post_json<T>(&self, resource: &str, data: &T) -> impl Future<Item = (), Error = Error>
     where
@@ -392,9 +883,81 @@ impl Client {
             .json(data)
             .send()
             .from_err()
+            .and_then(check_status)
             .map(|_| ())
     }
 
+    /// Like `api_post_json`, but decode the json response.
+    fn api_post_json_with_response<T, R>(
+        &self,
+        resource: &str,
+        data: &T,
+    ) -> impl Future<Item = R, Error = Error>
+    where
+        T: Serialize,
+        for<'a> R: Deserialize<'a>,
+    {
+        self.client
+            .post(&format!("{}/{}", self.url, resource))
+            .bearer_auth(&self.bot_token)
+            .header(http::header::ACCEPT, "application/json")
+            .json(data)
+            .send()
+            .from_err()
+            .and_then(check_status)
+            .and_then(|response| decode_json_body(response.into_body()))
+    }
+
+    /// Post the string fields of the json object and the file as multipart
+    /// form and decode the json response.
+    fn api_post_multipart_with_response<R>(
+        &self,
+        resource: &str,
+        fields: &serde_json::Value,
+        file: &Path,
+    ) -> impl Future<Item = R, Error = Error>
+    where
+        for<'a> R: Deserialize<'a>,
+    {
+        let form = match multipart_form(fields, file) {
+            Ok(form) => form,
+            Err(e) => return future::Either::A(future::err(e)),
+        };
+        future::Either::B(
+            self.client
+                .post(&format!("{}/{}", self.url, resource))
+                .bearer_auth(&self.bot_token)
+                .header(http::header::ACCEPT, "application/json")
+                .multipart(form)
+                .send()
+                .from_err()
+                .and_then(check_status)
+                .and_then(|response| decode_json_body(response.into_body())),
+        )
+    }
+
+    /// Put json to the given url with basic token authorization and decode the
+    /// response.
+    fn api_put_json_with_response<T, R>(
+        &self,
+        resource: &str,
+        data: &T,
+    ) -> impl Future<Item = R, Error = Error>
+    where
+        T: Serialize,
+        for<'a> R: Deserialize<'a>,
+    {
+        self.client
+            .put(&format!("{}/{}", self.url, resource))
+            .bearer_auth(&self.bot_token)
+            .header(http::header::ACCEPT, "application/json")
+            .json(data)
+            .send()
+            .from_err()
+            .and_then(check_status)
+            .and_then(|response| decode_json_body(response.into_body()))
+    }
+
     /// Try to post json to the given url with basic token authorization.
     fn api_delete(&self, resource: &str) -> impl Future<Item = (), Error = Error> {
         self.client
@@ -403,134 +966,484 @@ impl Client {
             .header(http::header::ACCEPT, "application/json")
             .send()
             .from_err()
+            .and_then(check_status)
             .map(|_| ())
     }
 
-    fn get_bot_id(&self) -> impl Future<Item = PersonId, Error = Error> {
+    fn get_bot_details(&self) -> impl Future<Item = PersonDetails, Error = Error> {
         self.api_get_json("people/me")
-            .map(|details: PersonDetails| details.id)
     }
 
-    fn add_webhook(&self, url: &str) -> impl Future<Item = (), Error = Error> {
+    fn add_webhook(
+        &self,
+        url: &str,
+        resource: ResourceType,
+        secret: Option<&str>,
+    ) -> impl Future<Item = (), Error = Error> {
         let webhook = WebhookRegistration {
             name: "gerritbot".to_string(),
             target_url: url.to_string(),
-            resource: ResourceType::Messages,
+            resource,
             event: EventType::Created,
+            secret: secret.map(str::to_string),
+            // only memberships of the bot itself are of interest
+            filter: match resource {
+                ResourceType::Memberships => Some(format!("personId={}", self.bot_id)),
+                _ => None,
+            },
         };
 
-        debug!("adding webhook: {:?}", webhook);
+        // don't log the secret
+        debug!(
+            "adding webhook for {:?}: {}",
+            webhook.resource, webhook.target_url
+        );
 
-        self.api_post_json("webhooks", &webhook)
+        self.instrument("add_webhook", self.api_post_json("webhooks", &webhook))
             .map(|()| debug!("added webhook"))
     }
 
+    /// List webhooks of all pages.
     fn list_webhooks(&self) -> impl Future<Item = Webhooks, Error = Error> {
-        self.api_get_json("webhooks")
+        let webhooks = self.api_get_all_pages(format!("{}/webhooks", self.url));
+        self.instrument("list_webhooks", webhooks)
+            .map(|items| Webhooks { items })
     }
 
     fn delete_webhook(&self, id: &WebhookId) -> impl Future<Item = (), Error = Error> {
-        self.api_delete(&format!("webhooks/{}", id))
-            .or_else(|e| match e {
-                Error::ReqwestError(ref e)
-                    if e.status() == Some(http::StatusCode::NO_CONTENT)
-                        || e.status() == Some(http::StatusCode::NOT_FOUND) =>
-                {
-                    Ok(())
-                }
-                _ => Err(Error::DeleteWebhook(format!(
-                    "Could not delete webhook: {}",
-                    e
-                ))),
-            })
-            .map(|()| debug!("deleted webhook"))
+        self.instrument(
+            "delete_webhook",
+            self.api_delete(&format!("webhooks/{}", id)),
+        )
+        .or_else(|e| match e {
+            Error::Api(ref e) if e.status == http::StatusCode::NOT_FOUND => Ok(()),
+            _ => Err(Error::DeleteWebhook(format!(
+                "Could not delete webhook: {}",
+                e
+            ))),
+        })
+        .map(|()| debug!("deleted webhook"))
     }
 
-    pub fn register_webhook(self, url: &str) -> impl Future<Item = (), Error = Error> {
+    /// Replace all message, card action and membership webhooks by ones
+    /// posting to the url. If a secret is given, Webex signs the posts with
+    /// it.
+    pub fn register_webhook(
+        self,
+        url: &str,
+        secret: Option<&str>,
+    ) -> impl Future<Item = (), Error = Error> {
+        const RESOURCES: [ResourceType; 3] = [
+            ResourceType::Messages,
+            ResourceType::AttachmentActions,
+            ResourceType::Memberships,
+        ];
+
         let url = url.to_string();
+        let secret = secret.map(str::to_string);
         let delete_client = self.clone();
         let add_client = self.clone();
         self.list_webhooks()
             .map(|webhooks| futures::stream::iter_ok(webhooks.items))
             .flatten_stream()
             .filter(|webhook| {
-                webhook.resource == ResourceType::Messages && webhook.event == EventType::Created
+                RESOURCES.contains(&webhook.resource) && webhook.event == EventType::Created
             })
             .inspect(|webhook| debug!("Removing webhook from Spark: {}", webhook.target_url))
             .for_each(move |webhook| delete_client.delete_webhook(&webhook.id))
-            .and_then(move |()| add_client.add_webhook(&url))
+            .and_then(move |()| {
+                stream::iter_ok(RESOURCES.iter().cloned()).for_each(move |resource| {
+                    add_client.add_webhook(&url, resource, secret.as_deref())
+                })
+            })
     }
 
     pub fn id(&self) -> &PersonId {
         &self.bot_id
     }
 
+    /// Display name of the bot.
+    pub fn name(&self) -> &str {
+        &self.bot_name
+    }
+
     pub fn send_message<'a, T: ?Sized>(
         &self,
         target: &'a T,
         markdown: &'a str,
-    ) -> impl Future<Item = (), Error = Error>
+    ) -> impl Future<Item = Message, Error = Error>
+    where
+        &'a T: Into<CreateMessageTarget<'a>>,
+    {
+        self.create_message(CreateMessageParameters {
+            target: target.into(),
+            markdown: Some(markdown),
+            text: None,
+            html: None,
+            attachments: None,
+            files: None,
+            parent_id: None,
+        })
+    }
+
+    /// Send a message with an Adaptive Card. The markdown is shown by clients
+    /// that can't render the card.
+    pub fn send_card<'a, T: ?Sized>(
+        &self,
+        target: &'a T,
+        markdown: &'a str,
+        card: &serde_json::Value,
+    ) -> impl Future<Item = Message, Error = Error>
     where
         &'a T: Into<CreateMessageTarget<'a>>,
     {
+        let attachments = [Attachment::adaptive_card(card.clone())];
         self.create_message(CreateMessageParameters {
             target: target.into(),
             markdown: Some(markdown),
             text: None,
             html: None,
+            attachments: Some(&attachments),
+            files: None,
+            parent_id: None,
         })
     }
 
+    /// Record the latency and outcome of the API calls with the metrics.
+    pub fn with_metrics(self, metrics: Arc<dyn Metrics>) -> Self {
+        Self { metrics, ..self }
+    }
+
+    /// Queue messages before sending them, so they are retried if sending
+    /// fails. The queue has to be run with this client.
+    pub fn with_retry_queue(self, retry_queue: RetryQueue) -> Self {
+        Self {
+            retry_queue: Some(retry_queue),
+            ..self
+        }
+    }
+
+    /// Create a message and return it as stored by Webex, e.g. to learn its
+    /// id. Markdown longer than `MAX_MESSAGE_SIZE` is sent as several
+    /// messages, in order. Attachments and files are sent with the first one,
+    /// which is returned. With a retry queue, the message is queued and
+    /// retried later if sending fails.
     pub fn create_message(
         &self,
         parameters: CreateMessageParameters,
-    ) -> impl Future<Item = (), Error = Error> {
+    ) -> impl Future<Item = Message, Error = Error> {
+        let retry = self
+            .retry_queue
+            .clone()
+            .map(|retry_queue| (retry_queue, retry::QueuedMessage::new(&parameters)));
+        self.send_message_parts(parameters).then(move |result| {
+            if let Some((retry_queue, message)) = retry {
+                match result {
+                    // retrying won't help if the error is permanent
+                    Err(ref e) if !e.is_permanent() => {
+                        retry_queue.push(message);
+                    }
+                    _ => (),
+                }
+            }
+            result
+        })
+    }
+
+    fn send_message_parts(
+        &self,
+        parameters: CreateMessageParameters,
+    ) -> impl Future<Item = Message, Error = Error> {
         debug!("send message to {:?}", parameters.target);
-        let json = match serde_json::to_value(&parameters) {
-            Ok(json) => json,
+        let markdown_parts = match parameters.markdown {
+            Some(markdown) => split_message(markdown, MAX_MESSAGE_SIZE)
+                .into_iter()
+                .map(Some)
+                .collect(),
+            None => vec![None],
+        };
+        let requests = markdown_parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, markdown)| {
+                serde_json::to_value(&CreateMessageParameters {
+                    markdown,
+                    attachments: parameters.attachments.filter(|_| i == 0),
+                    files: parameters.files.filter(|_| i == 0),
+                    ..parameters.clone()
+                })
+            })
+            .collect::<Result<Vec<_>, _>>();
+        let requests = match requests {
+            Ok(requests) => requests,
             Err(e) => return future::Either::A(future::err(e).from_err()),
         };
 
-        future::Either::B(self.api_post_json("messages", &json))
+        let local_file = parameters
+            .files
+            .unwrap_or_default()
+            .iter()
+            .find_map(|file| match file {
+                MessageFile::Path(path) => Some(path.clone()),
+                MessageFile::Url(_) => None,
+            });
+
+        let client = self.clone();
+        future::Either::B(
+            stream::iter_ok(requests.into_iter().enumerate())
+                .and_then(move |(i, json)| {
+                    let request = match local_file.as_ref().filter(|_| i == 0) {
+                        Some(path) => future::Either::A(
+                            client.api_post_multipart_with_response("messages", &json, path),
+                        ),
+                        None => {
+                            future::Either::B(client.api_post_json_with_response("messages", &json))
+                        }
+                    };
+                    client.instrument("send_message", request)
+                })
+                .collect()
+                // there is at least one part
+                .map(|mut messages: Vec<Message>| messages.remove(0)),
+        )
     }
 
     pub fn get_message(
         &self,
-        message_id: &MessageId,
+        message_id: &MessageIdRef,
     ) -> impl Future<Item = Message, Error = Error> {
-        self.api_get_json(&format!("messages/{}", message_id))
+        self.instrument(
+            "get_message",
+            self.api_get_json(&format!("messages/{}", message_id)),
+        )
     }
-}
 
-fn reject_webhook_request(
-    request: &hyper::Request<hyper::Body>,
-) -> Option<hyper::Response<hyper::Body>> {
-    use hyper::{Body, Response};
-
-    if request.uri() != "/" {
-        // only accept requests at "/"
-        Some(
-            Response::builder()
-                .status(http::StatusCode::NOT_FOUND)
-                .body(Body::empty())
-                .unwrap(),
+    /// Replace the markdown of a message sent by the bot. Webex shows the
+    /// message as edited.
+    pub fn update_message(
+        &self,
+        message_id: &MessageIdRef,
+        room_id: &RoomIdRef,
+        markdown: &str,
+    ) -> impl Future<Item = Message, Error = Error> {
+        debug!("update message {}", message_id);
+        self.instrument(
+            "update_message",
+            self.api_put_json_with_response(
+                &format!("messages/{}", message_id),
+                &UpdateMessageParameters {
+                    room_id: room_id.to_owned(),
+                    markdown: markdown.to_string(),
+                },
+            ),
         )
-    } else if request.method() != http::Method::POST {
-        // only accept POST
-        Some(
-            Response::builder()
-                .status(http::StatusCode::METHOD_NOT_ALLOWED)
-                .body(Body::empty())
-                .unwrap(),
+    }
+
+    /// Look up the person with the email. Resolves to `None` if there is no
+    /// such person.
+    pub fn find_person(
+        &self,
+        email: &EmailRef,
+    ) -> impl Future<Item = Option<PersonDetails>, Error = Error> {
+        let url = match self.api_url("people", &[("email", &email.0)]) {
+            Ok(url) => url,
+            Err(e) => return future::Either::A(future::err(e)),
+        };
+        future::Either::B(
+            self.instrument("find_person", self.api_get_json_page(&url))
+                .map(|(people, _): (People, _)| people.items.into_iter().next()),
         )
-    } else if !request
-        .headers()
-        .get(http::header::CONTENT_TYPE)
-        .map(|v| v.as_bytes().starts_with(&b"application/json"[..]))
-        .unwrap_or(false)
-    {
-        // require "content-type: application/json"
-        Some(
+    }
+
+    /// List the rooms the bot is in, optionally only the ones of the type.
+    pub fn list_rooms(
+        &self,
+        room_type: Option<RoomType>,
+    ) -> impl Future<Item = Vec<Room>, Error = Error> {
+        let room_type = room_type.map(|room_type| match room_type {
+            RoomType::Direct => "direct",
+            RoomType::Group => "group",
+        });
+        let params: Vec<_> = room_type
+            .map(|room_type| ("type", room_type))
+            .into_iter()
+            .collect();
+        let rooms = future::result(self.api_url("rooms", &params)).and_then({
+            let client = self.clone();
+            move |url| client.api_get_all_pages(url)
+        });
+        self.instrument("list_rooms", rooms)
+    }
+
+    pub fn get_room(&self, room_id: &RoomIdRef) -> impl Future<Item = Room, Error = Error> {
+        self.instrument("get_room", self.api_get_json(&format!("rooms/{}", room_id)))
+    }
+
+    /// List the memberships of the room. Without room, the memberships of
+    /// the bot are listed, i.e. one per room it is in.
+    pub fn list_memberships(
+        &self,
+        room_id: Option<&RoomIdRef>,
+    ) -> impl Future<Item = Vec<Membership>, Error = Error> {
+        let params: Vec<_> = room_id
+            .map(|room_id| ("roomId", &room_id.0))
+            .into_iter()
+            .collect();
+        let memberships = future::result(self.api_url("memberships", &params)).and_then({
+            let client = self.clone();
+            move |url| client.api_get_all_pages(url)
+        });
+        self.instrument("list_memberships", memberships)
+    }
+
+    /// Fetch a card action including its inputs and the email of the person
+    /// who submitted it.
+    pub fn get_attachment_action(
+        &self,
+        action_id: &AttachmentActionIdRef,
+    ) -> impl Future<Item = AttachmentAction, Error = Error> {
+        let client = self.clone();
+        let action = self
+            .api_get_json(&format!("attachment/actions/{}", action_id))
+            .and_then(move |action: AttachmentAction| {
+                client
+                    .api_get_json(&format!("people/{}", action.person_id))
+                    .map(move |person: PersonDetails| AttachmentAction {
+                        person_email: person.emails.into_iter().next().unwrap_or_default(),
+                        ..action
+                    })
+            });
+        self.instrument("get_attachment_action", action)
+    }
+}
+
+/// Extract the url of the next page from a `Link` header, e.g.
+/// `<https://webexapis.com/v1/webhooks?cursor=abc>; rel="next"`.
+fn next_page_link(link_header: &str) -> Option<&str> {
+    link_header.split(',').find_map(|link| {
+        let mut parts = link.split(';').map(str::trim);
+        let url = parts.next()?.strip_prefix('<')?.strip_suffix('>')?;
+        parts
+            .any(|param| param == "rel=\"next\"" || param == "rel=next")
+            .then_some(url)
+    })
+}
+
+/// Maximum size of the markdown of a message accepted by Webex.
+pub const MAX_MESSAGE_SIZE: usize = 7439;
+
+/// Split markdown into parts of at most `max_size` bytes. Parts end at
+/// paragraph boundaries if possible, otherwise at line breaks or, as a last
+/// resort, anywhere. Always returns at least one part.
+fn split_message(markdown: &str, max_size: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = markdown;
+    while rest.len() > max_size {
+        let mut end = max_size;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let head = &rest[..end];
+        let end = head
+            .rfind("\n\n")
+            .or_else(|| head.rfind('\n'))
+            .filter(|&pos| pos > 0)
+            .unwrap_or(end);
+        parts.push(&rest[..end]);
+        rest = rest[end..].trim_start_matches('\n');
+    }
+    if !rest.is_empty() || parts.is_empty() {
+        parts.push(rest);
+    }
+    parts
+}
+
+/// Escape the characters of the text that have a meaning in markdown, e.g.
+/// `*` or `[`, so that user-generated text is shown as is.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if let '\\' | '`' | '*' | '_' | '[' | ']' | '~' | '<' | '>' = c {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Header of webhook posts containing the hex encoded HMAC-SHA1 of the body,
+/// keyed with the webhook's secret.
+const SIGNATURE_HEADER: &str = "x-spark-signature";
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(data)?;
+    signer.sign_to_vec()
+}
+
+/// Check the signature Webex computed for the body of a webhook post.
+fn is_valid_signature(secret: &str, signature: &[u8], body: &[u8]) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    match hmac_sha1(secret.as_bytes(), body) {
+        Ok(expected) => {
+            signature.len() == expected.len() && openssl::memcmp::eq(&signature, &expected)
+        }
+        Err(e) => {
+            error!("failed to compute webhook signature: {}", e);
+            false
+        }
+    }
+}
+
+/// Only accept POST requests at the path.
+fn reject_request<B>(
+    request: &hyper::Request<B>,
+    path: &str,
+) -> Option<hyper::Response<hyper::Body>> {
+    use hyper::{Body, Response};
+
+    if request.uri().path() != path {
+        Some(
+            Response::builder()
+                .status(http::StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    } else if request.method() != http::Method::POST {
+        Some(
+            Response::builder()
+                .status(http::StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    } else {
+        None
+    }
+}
+
+/// Check the headers of a webhook post before its body is read.
+fn reject_webhook_request<B>(
+    request: &hyper::Request<B>,
+    path: &str,
+) -> Option<hyper::Response<hyper::Body>> {
+    use hyper::{Body, Response};
+
+    if let Some(response) = reject_request(request, path) {
+        Some(response)
+    } else if !request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .map(|v| v.as_bytes().starts_with(&b"application/json"[..]))
+        .unwrap_or(false)
+    {
+        // require "content-type: application/json"
+        Some(
             Response::builder()
                 .status(http::StatusCode::UNSUPPORTED_MEDIA_TYPE)
                 .body(Body::empty())
@@ -541,6 +1454,104 @@ fn reject_webhook_request(
     }
 }
 
+/// Require a valid signature if the webhook has a secret.
+fn reject_unsigned_request(
+    request: &hyper::Request<hyper::Chunk>,
+    secret: Option<&str>,
+) -> Option<hyper::Response<hyper::Body>> {
+    use hyper::{Body, Response};
+
+    if !secret
+        .map(|secret| {
+            request
+                .headers()
+                .get(SIGNATURE_HEADER)
+                .map(|signature| is_valid_signature(secret, signature.as_bytes(), request.body()))
+                .unwrap_or(false)
+        })
+        .unwrap_or(true)
+    {
+        Some(
+            Response::builder()
+                .status(http::StatusCode::UNAUTHORIZED)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    } else {
+        None
+    }
+}
+
+/// Maximum size of the body of an accepted request.
+const MAX_REQUEST_BODY_SIZE: usize = 1024 * 1024;
+
+/// Read the body of the request. Bodies larger than `MAX_REQUEST_BODY_SIZE`
+/// are rejected without reading them completely.
+pub(crate) fn read_body(
+    request: hyper::Request<hyper::Body>,
+) -> impl Future<Item = hyper::Request<hyper::Chunk>, Error = hyper::Response<hyper::Body>> {
+    use hyper::{Body, Response};
+
+    let error_response = |status| {
+        Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let content_length = request
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > MAX_REQUEST_BODY_SIZE) {
+        return future::Either::A(future::err(error_response(
+            http::StatusCode::PAYLOAD_TOO_LARGE,
+        )));
+    }
+
+    let (parts, body) = request.into_parts();
+    future::Either::B(
+        body.map_err(|e| {
+            warn!("failed to read request body: {}", e);
+            http::StatusCode::BAD_REQUEST
+        })
+        .fold(Vec::new(), |mut data, chunk| {
+            if data.len() + chunk.len() > MAX_REQUEST_BODY_SIZE {
+                return Err(http::StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            data.extend_from_slice(&chunk);
+            Ok(data)
+        })
+        .map(move |data| hyper::Request::from_parts(parts, hyper::Chunk::from(data)))
+        .map_err(error_response),
+    )
+}
+
+/// Form with the string fields of the json object and the file as `files`.
+fn multipart_form(
+    fields: &serde_json::Value,
+    file: &Path,
+) -> Result<reqwest::r#async::multipart::Form, Error> {
+    use reqwest::r#async::multipart::{Form, Part};
+
+    let file_name = file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mime = mime_guess::from_path(file).first_or_octet_stream();
+    let part = Part::bytes(fs::read(file)?)
+        .file_name(file_name)
+        .mime_str(mime.as_ref())?;
+    let form = fields
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+        .fold(Form::new(), |form, (name, value)| form.text(name, value));
+    Ok(form.part("files", part))
+}
+
 /// Decode json body of HTTP request or response.
 fn decode_json_body<T, B, C, E>(body: B) -> impl Future<Item = T, Error = Error>
 where
@@ -568,104 +1579,465 @@ where
     /// Future of webhook server. Must be run in order for messages to produce
     /// anything.
     pub server: S,
+    /// Stops accepting webhook posts when sent. The server finishes after
+    /// the posts in flight are handed over, and then the stream of posts
+    /// ends.
+    pub shutdown: oneshot::Sender<()>,
+}
+
+/// Address the webhook server listens on. Parsed from a TCP socket address
+/// like `127.0.0.1:8888` or a unix socket path prefixed with `unix:`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for ListenAddress {
+    fn from(address: SocketAddr) -> Self {
+        ListenAddress::Tcp(address)
+    }
+}
+
+impl FromStr for ListenAddress {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(ListenAddress::Unix(path.into())),
+            None => s.parse().map(ListenAddress::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => address.fmt(f),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Options of the webhook server.
+#[derive(Clone)]
+pub struct WebhookServerOptions {
+    /// Path webhook posts are accepted at.
+    pub path: String,
+    /// If set, only posts signed with the secret are accepted.
+    pub secret: Option<String>,
+    /// If set, HTTPS is served instead of plain HTTP.
+    pub tls: Option<TlsAcceptor>,
 }
 
+impl Default for WebhookServerOptions {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            secret: None,
+            tls: None,
+        }
+    }
+}
+
+/// Start a server receiving webhook posts. If a secret is given, only posts
+/// signed with it are accepted.
 pub fn start_raw_webhook_server(
     listen_address: &SocketAddr,
+    secret: Option<&str>,
 ) -> RawWebhookServer<
     impl Stream<Item = WebhookMessage, Error = ()>,
     impl Future<Item = (), Error = hyper::Error>,
 > {
-    use hyper::{Body, Response};
+    let options = WebhookServerOptions {
+        secret: secret.map(str::to_string),
+        ..Default::default()
+    };
+    start_raw_webhook_server_with_options(&[ListenAddress::Tcp(*listen_address)], options)
+        .unwrap_or_else(|e| panic!("failed to start webhook server: {}", e))
+}
+
+/// Start a server receiving webhook posts on all of the addresses.
+pub fn start_raw_webhook_server_with_options(
+    listen_addresses: &[ListenAddress],
+    options: WebhookServerOptions,
+) -> Result<
+    RawWebhookServer<
+        impl Stream<Item = WebhookMessage, Error = ()>,
+        impl Future<Item = (), Error = hyper::Error>,
+    >,
+    Error,
+> {
+    let WebhookServerOptions { path, secret, tls } = options;
+    let handler: RequestHandler = Arc::new(move |request| {
+        if let Some(error_response) = reject_webhook_request(&request, &path) {
+            return Box::new(future::err(error_response));
+        }
+        let secret = secret.clone();
+        Box::new(read_body(request).and_then(move |request| {
+            if let Some(error_response) = reject_unsigned_request(&request, secret.as_deref()) {
+                return future::err(error_response);
+            }
+            // now try to decode the body
+            future::ok(
+                serde_json::from_slice::<WebhookMessage>(request.body())
+                    .map_err(|e| error!("failed to decode post body: {}", e))
+                    .ok(),
+            )
+        }))
+    });
+    start_server(listen_addresses, tls, handler)
+}
+
+/// Decodes a request into a webhook post, if it contains one, or into the
+/// response rejecting it. The headers are checked before the body is read.
+pub(crate) type RequestHandler = Arc<
+    dyn Fn(
+            hyper::Request<hyper::Body>,
+        ) -> Box<
+            dyn Future<Item = Option<WebhookMessage>, Error = hyper::Response<hyper::Body>> + Send,
+        > + Send
+        + Sync,
+>;
+
+/// Start a server on all of the addresses producing the webhook posts of the
+/// requests.
+pub(crate) fn start_server(
+    listen_addresses: &[ListenAddress],
+    tls: Option<TlsAcceptor>,
+    handler: RequestHandler,
+) -> Result<
+    RawWebhookServer<
+        impl Stream<Item = WebhookMessage, Error = ()>,
+        impl Future<Item = (), Error = hyper::Error>,
+    >,
+    Error,
+> {
     let (message_sink, messages) = channel(1);
+    let (shutdown, shutdown_signal) = oneshot::channel();
+    // a dropped sender doesn't stop the server
+    let shutdown_signal = shutdown_signal
+        .or_else(|_| future::empty::<(), ()>())
+        .shared();
+
+    let servers = listen_addresses
+        .iter()
+        .map(|listen_address| {
+            let server = serve_at(
+                listen_address,
+                tls.clone(),
+                handler.clone(),
+                message_sink.clone(),
+                shutdown_signal.clone().map(|_| ()).map_err(|_| ()),
+            )?;
+            info!("listening to Spark on {}", listen_address);
+            Ok(server)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(RawWebhookServer {
+        messages,
+        server: future::join_all(servers).map(|_| ()),
+        shutdown,
+    })
+}
 
-    info!("listening to Spark on {}", listen_address);
+type ServerFuture = Box<dyn Future<Item = (), Error = hyper::Error> + Send>;
+
+fn serve_at(
+    listen_address: &ListenAddress,
+    tls: Option<TlsAcceptor>,
+    handler: RequestHandler,
+    message_sink: Sender<WebhookMessage>,
+    shutdown_signal: impl Future<Item = (), Error = ()> + Send + 'static,
+) -> Result<ServerFuture, Error> {
+    Ok(match (listen_address, tls) {
+        (ListenAddress::Tcp(address), None) => Box::new(serve(
+            hyper::Server::builder(hyper::server::conn::AddrIncoming::bind(address)?),
+            handler,
+            message_sink,
+            shutdown_signal,
+        )),
+        (ListenAddress::Tcp(address), Some(acceptor)) => {
+            let listener = tokio::net::TcpListener::bind(address)?;
+            Box::new(serve(
+                hyper::Server::builder(acceptor.incoming(listener.incoming())),
+                handler,
+                message_sink,
+                shutdown_signal,
+            ))
+        }
+        (ListenAddress::Unix(socket_path), tls) => {
+            remove_stale_socket(socket_path)?;
+            let listener = tokio::net::UnixListener::bind(socket_path)?;
+            match tls {
+                None => Box::new(serve(
+                    hyper::Server::builder(listener.incoming()),
+                    handler,
+                    message_sink,
+                    shutdown_signal,
+                )),
+                Some(acceptor) => Box::new(serve(
+                    hyper::Server::builder(acceptor.incoming(listener.incoming())),
+                    handler,
+                    message_sink,
+                    shutdown_signal,
+                )),
+            }
+        }
+    })
+}
+
+/// Remove a socket left over by a previous run, which would prevent binding.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt as _;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+fn serve<I>(
+    builder: hyper::server::Builder<I>,
+    handler: RequestHandler,
+    message_sink: Sender<WebhookMessage>,
+    shutdown_signal: impl Future<Item = (), Error = ()> + Send + 'static,
+) -> impl Future<Item = (), Error = hyper::Error> + Send
+where
+    I: Stream + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    I::Item: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+{
+    use hyper::{Body, Response};
 
     // very simple webhook listener
-    let server = hyper::Server::bind(listen_address).serve(move || {
-        let message_sink = message_sink.clone();
+    builder
+        .serve(move || {
+            let message_sink = message_sink.clone();
+            let handler = handler.clone();
 
-        hyper::service::service_fn_ok(move |request: hyper::Request<Body>| {
-            debug!("webhook request: {:?}", request);
+            hyper::service::service_fn(move |request: hyper::Request<Body>| {
+                debug!("webhook request: {:?}", request);
 
-            if let Some(error_response) = reject_webhook_request(&request) {
-                // reject requests we don't understand
-                warn!("rejecting webhook request: {:?}", error_response);
-                error_response
-            } else {
                 let message_sink = message_sink.clone();
-                // now try to decode the body
-                let f = decode_json_body(request.into_body())
-                    .map_err(|e| error!("failed to decode post body: {}", e))
-                    .and_then(|post: WebhookMessage| {
-                        message_sink
-                            .send(post)
-                            .map_err(|e| error!("failed to send post body: {}", e))
-                            .map(|_| ())
-                    });
-
-                // spawn a future so all of the above actually happens
-                // XXX: maybe send future over the stream instead?
-                tokio::spawn(f);
-
-                Response::new(Body::empty())
-            }
+                handler(request).then(|result| match result {
+                    // respond when the post is handed over, so that a
+                    // graceful shutdown waits for it
+                    Ok(Some(post)) => future::Either::A(message_sink.send(post).then(|result| {
+                        if let Err(e) = result {
+                            error!("failed to send post body: {}", e);
+                        }
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    })),
+                    Ok(None) => future::Either::B(future::ok(Response::new(Body::empty()))),
+                    Err(error_response) => {
+                        // reject requests we don't understand
+                        warn!("rejecting webhook request: {:?}", error_response);
+                        future::Either::B(future::ok(error_response))
+                    }
+                })
+            })
         })
-    });
+        .with_graceful_shutdown(shutdown_signal)
+}
+
+/// Number of recently received resource ids remembered to drop duplicates.
+const RECENT_IDS_CAPACITY: usize = 1000;
+
+/// Time for which received resource ids are remembered.
+const RECENT_IDS_EXPIRATION: Duration = Duration::from_secs(60 * 60);
 
-    RawWebhookServer { messages, server }
+/// Drop webhook data of resources received recently. Webex retries webhook
+/// posts and SQS delivers at least once, so the same message can arrive
+/// twice.
+fn skip_duplicates<M>(raw_messages: M) -> impl Stream<Item = WebhookData, Error = ()>
+where
+    M: Stream<Item = WebhookData, Error = ()>,
+{
+    let mut recent_ids =
+        LruCache::with_expiry_duration_and_capacity(RECENT_IDS_EXPIRATION, RECENT_IDS_CAPACITY);
+    raw_messages.filter(move |data| {
+        let is_new = recent_ids.insert(data.id().to_string(), ()).is_none();
+        if !is_new {
+            debug!("dropping duplicate webhook data: {}", data.id());
+        }
+        is_new
+    })
 }
 
-/// Fetch messages from webhook message stream using client. Skip messages from
-/// own id, log and then ignore errors.
-fn fetch_messages<M>(client: Client, raw_messages: M) -> impl Stream<Item = Message, Error = ()>
+/// Fetch messages and card actions of webhook data using client. Skip
+/// resources created by own id, log and then ignore errors. Mentions of the
+/// bot are stripped from messages in group rooms. Of the memberships, only
+/// the ones of the bot are kept; they don't need to be fetched. Duplicates
+/// are dropped.
+fn fetch_events<M>(client: Client, raw_messages: M) -> impl Stream<Item = Event, Error = ()>
 where
-    M: Stream<Item = WebhookMessage, Error = ()>,
+    M: Stream<Item = WebhookData, Error = ()>,
 {
     let own_id = client.id().clone();
-    raw_messages
-        // ignore own messages
-        .filter(move |post| post.data.person_id != own_id)
-        .and_then(move |post| {
-            client.get_message(&post.data.id).then(|message_result| {
-                future::ok(
-                    message_result
-                        .map_err(|e| error!("failed to fetch message: {}", e))
-                        .map(Some)
-                        .unwrap_or(None),
-                )
-            })
+    let own_name = client.name().to_string();
+    skip_duplicates(raw_messages)
+        // ignore own messages and memberships of others
+        .filter(move |data| match data {
+            WebhookData::Memberships(_) => data.person_id() == own_id,
+            _ => data.person_id() != own_id,
+        })
+        .and_then(move |data| {
+            let event = match data {
+                WebhookData::Messages(message) => future::Either::A(future::Either::A(
+                    client
+                        .get_message(&message.id)
+                        .map(Event::Message)
+                        .map_err(|e| error!("failed to fetch message: {}", e)),
+                )),
+                WebhookData::AttachmentActions(action) => future::Either::A(future::Either::B(
+                    client
+                        .get_attachment_action(&action.id)
+                        .map(Event::AttachmentAction)
+                        .map_err(|e| error!("failed to fetch card action: {}", e)),
+                )),
+                WebhookData::Memberships(membership) => {
+                    future::Either::B(future::ok(Event::Membership(membership)))
+                }
+            };
+            event.then(|event_result| future::ok(event_result.ok()))
         })
         .filter_map(std::convert::identity)
+        .map(move |event| match event {
+            Event::Message(mut message) => {
+                if message.room_type == RoomType::Group {
+                    message.strip_mention(&own_name);
+                }
+                Event::Message(message)
+            }
+            event => event,
+        })
 }
 
-pub struct WebhookServer<M, S>
+pub struct WebhookServer<E, S>
 where
-    M: Stream<Item = Message, Error = ()>,
+    E: Stream<Item = Event, Error = ()>,
     S: Future<Item = (), Error = hyper::Error>,
 {
-    /// Stream of webhook posts.
-    pub messages: M,
+    /// Stream of messages and card actions.
+    pub events: E,
     /// Future of webhook server. Must be run in order for messages to produce
     /// anything.
     pub server: S,
+    /// Stops accepting webhook posts when sent. The server finishes after
+    /// the posts in flight are handed over, and then the stream of posts
+    /// ends.
+    pub shutdown: oneshot::Sender<()>,
 }
 
 pub fn start_webhook_server(
     listen_address: &SocketAddr,
     client: Client,
+    secret: Option<&str>,
 ) -> WebhookServer<
-    impl Stream<Item = Message, Error = ()>,
+    impl Stream<Item = Event, Error = ()>,
     impl Future<Item = (), Error = hyper::Error>,
 > {
     let RawWebhookServer {
         messages: raw_messages,
         server,
-    } = start_raw_webhook_server(listen_address);
+        shutdown,
+    } = start_raw_webhook_server(listen_address, secret);
+
+    let events = fetch_events(client, raw_messages.map(|post| post.data));
+
+    WebhookServer {
+        events,
+        server,
+        shutdown,
+    }
+}
+
+/// Start a server receiving webhook posts on all of the addresses and fetch
+/// the messages and card actions of the posts.
+pub fn start_webhook_server_with_options(
+    listen_addresses: &[ListenAddress],
+    client: Client,
+    options: WebhookServerOptions,
+) -> Result<
+    WebhookServer<
+        impl Stream<Item = Event, Error = ()>,
+        impl Future<Item = (), Error = hyper::Error>,
+    >,
+    Error,
+> {
+    let RawWebhookServer {
+        messages: raw_messages,
+        server,
+        shutdown,
+    } = start_raw_webhook_server_with_options(listen_addresses, options)?;
+
+    let events = fetch_events(client, raw_messages.map(|post| post.data));
 
-    let messages = fetch_messages(client, raw_messages);
+    Ok(WebhookServer {
+        events,
+        server,
+        shutdown,
+    })
+}
 
-    WebhookServer { messages, server }
+/// Start a server receiving webhook posts pushed by Amazon SNS on all of the
+/// addresses.
+pub fn start_raw_sns_server(
+    listen_addresses: &[ListenAddress],
+    options: SnsServerOptions,
+) -> Result<
+    RawWebhookServer<
+        impl Stream<Item = WebhookMessage, Error = ()>,
+        impl Future<Item = (), Error = hyper::Error>,
+    >,
+    Error,
+> {
+    let SnsServerOptions {
+        path,
+        topic_arn,
+        tls,
+    } = options;
+    start_server(listen_addresses, tls, sns::request_handler(path, topic_arn))
+}
+
+/// Start a server receiving webhook posts pushed by Amazon SNS on all of the
+/// addresses and fetch the messages and card actions of the posts.
+pub fn start_sns_server(
+    listen_addresses: &[ListenAddress],
+    client: Client,
+    options: SnsServerOptions,
+) -> Result<
+    WebhookServer<
+        impl Stream<Item = Event, Error = ()>,
+        impl Future<Item = (), Error = hyper::Error>,
+    >,
+    Error,
+> {
+    let RawWebhookServer {
+        messages: raw_messages,
+        server,
+        shutdown,
+    } = start_raw_sns_server(listen_addresses, options)?;
+
+    let events = fetch_events(client, raw_messages.map(|post| post.data));
+
+    Ok(WebhookServer {
+        events,
+        server,
+        shutdown,
+    })
 }
 
 pub fn raw_sqs_event_stream(
@@ -691,15 +2063,561 @@ pub fn sqs_event_stream(
     sqs_url: String,
     sqs_region: rusoto_core::Region,
     client: Client,
-) -> impl Stream<Item = Message, Error = ()> {
+) -> impl Stream<Item = Event, Error = ()> {
     let raw_messages = raw_sqs_event_stream(sqs_url, sqs_region);
-    fetch_messages(client, raw_messages)
+    fetch_events(client, raw_messages.map(|post| post.data))
+}
+
+/// Stream of the webhook posts pulled from a Google Cloud Pub/Sub
+/// subscription, given as `projects/<project>/subscriptions/<name>`.
+pub fn raw_pubsub_event_stream(
+    subscription: String,
+    credentials: PubSubCredentials,
+) -> impl Stream<Item = WebhookMessage, Error = ()> {
+    pubsub::pubsub_receiver(subscription, credentials).filter_map(|data| {
+        serde_json::from_slice(&data)
+            // log and ignore errors
+            .map_err(|e| error!("failed to parse Pub/Sub message data: {}", e))
+            .ok()
+    })
+}
+
+pub fn pubsub_event_stream(
+    subscription: String,
+    credentials: PubSubCredentials,
+    client: Client,
+) -> impl Stream<Item = Event, Error = ()> {
+    let raw_messages = raw_pubsub_event_stream(subscription, credentials);
+    fetch_events(client, raw_messages.map(|post| post.data))
+}
+
+/// Stream of the webhook posts consumed from a Kafka topic. The offsets are
+/// committed for the consumer group.
+pub fn raw_kafka_event_stream(
+    brokers: Vec<String>,
+    topic: String,
+    group: String,
+) -> impl Stream<Item = WebhookMessage, Error = ()> {
+    kafka::kafka_receiver(brokers, topic, group).filter_map(|data| {
+        serde_json::from_slice(&data)
+            // log and ignore errors
+            .map_err(|e| error!("failed to parse Kafka message: {}", e))
+            .ok()
+    })
+}
+
+pub fn kafka_event_stream(
+    brokers: Vec<String>,
+    topic: String,
+    group: String,
+    client: Client,
+) -> impl Stream<Item = Event, Error = ()> {
+    let raw_messages = raw_kafka_event_stream(brokers, topic, group);
+    fetch_events(client, raw_messages.map(|post| post.data))
+}
+
+/// Receive messages and card actions over the websocket of a Webex device
+/// registered for the bot, so no webhook is needed. Reconnects if the
+/// connection is lost.
+pub fn device_event_stream(client: Client) -> impl Stream<Item = Event, Error = ()> {
+    let activities = device::activity_stream(client.clone());
+    fetch_events(client, activities)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn webhook_request(signature: Option<&str>, body: &str) -> hyper::Request<hyper::Chunk> {
+        let mut request = hyper::Request::builder();
+        request
+            .method(http::Method::POST)
+            .uri("/")
+            .header(http::header::CONTENT_TYPE, "application/json");
+        if let Some(signature) = signature {
+            request.header(SIGNATURE_HEADER, signature);
+        }
+        request.body(hyper::Chunk::from(body.to_string())).unwrap()
+    }
+
+    #[test]
+    fn split_message() {
+        assert_eq!(super::split_message("", 10), [""]);
+        assert_eq!(super::split_message("short", 10), ["short"]);
+        assert_eq!(
+            super::split_message("first\n\nsecond\nthird", 16),
+            ["first", "second\nthird"]
+        );
+        assert_eq!(
+            super::split_message("first line\nsecond line", 16),
+            ["first line", "second line"]
+        );
+        assert_eq!(
+            super::split_message("0123456789abcdef", 10),
+            ["0123456789", "abcdef"]
+        );
+        // never split inside a character
+        assert_eq!(super::split_message("äöü", 3), ["ä", "ö", "ü"]);
+    }
+
+    #[test]
+    fn escape_markdown() {
+        assert_eq!(super::escape_markdown("plain text."), "plain text.");
+        assert_eq!(
+            super::escape_markdown("Fix *bold* [link](url) in `my_file`"),
+            r"Fix \*bold\* \[link\](url) in \`my\_file\`"
+        );
+        assert_eq!(super::escape_markdown(r"a\b"), r"a\\b");
+    }
+
+    #[test]
+    fn next_page_link() {
+        assert_eq!(
+            super::next_page_link(r#"<https://webexapis.com/v1/webhooks?cursor=abc>; rel="next""#),
+            Some("https://webexapis.com/v1/webhooks?cursor=abc")
+        );
+        assert_eq!(
+            super::next_page_link(
+                r#"<https://example.com/first>; rel="first", <https://example.com/next>; rel="next""#
+            ),
+            Some("https://example.com/next")
+        );
+        assert_eq!(
+            super::next_page_link(r#"<https://example.com/first>; rel="first""#),
+            None
+        );
+    }
+
+    #[test]
+    fn webhook_signature() {
+        let body = "The quick brown fox jumps over the lazy dog";
+        let signature = "de7c9b85b8b78aa6bc8a7a36f70a90701c9db4d9";
+
+        let request = webhook_request(Some(signature), body);
+        assert!(reject_webhook_request(&request, "/").is_none());
+        assert!(reject_unsigned_request(&request, Some("key")).is_none());
+        assert!(reject_unsigned_request(&request, None).is_none());
+
+        let reject_status = |request: hyper::Request<hyper::Chunk>, secret| {
+            reject_unsigned_request(&request, secret).map(|response| response.status())
+        };
+        assert_eq!(
+            reject_status(request, Some("other key")),
+            Some(http::StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            reject_status(webhook_request(None, body), Some("key")),
+            Some(http::StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            reject_status(webhook_request(Some("not hex"), body), Some("key")),
+            Some(http::StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn webhook_path() {
+        let mut request = webhook_request(None, "{}");
+        assert_eq!(
+            reject_webhook_request(&request, "/gerritbot").map(|r| r.status()),
+            Some(http::StatusCode::NOT_FOUND)
+        );
+        *request.uri_mut() = "/gerritbot?key=value".parse().unwrap();
+        assert!(reject_webhook_request(&request, "/gerritbot").is_none());
+    }
+
+    #[test]
+    fn request_body_size_is_limited() {
+        let request = |body: Vec<u8>, content_length: Option<usize>| {
+            let mut request = hyper::Request::builder();
+            request.method(http::Method::POST).uri("/");
+            if let Some(content_length) = content_length {
+                request.header(http::header::CONTENT_LENGTH, content_length);
+            }
+            request.body(hyper::Body::from(body)).unwrap()
+        };
+        let read_status = |request| read_body(request).wait().map_err(|r| r.status());
+
+        let body = read_body(request(b"{}".to_vec(), None)).wait().unwrap();
+        assert_eq!(&body.body()[..], b"{}");
+        assert_eq!(
+            read_status(request(b"{}".to_vec(), Some(MAX_REQUEST_BODY_SIZE + 1))).err(),
+            Some(http::StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert_eq!(
+            read_status(request(vec![b' '; MAX_REQUEST_BODY_SIZE + 1], None)).err(),
+            Some(http::StatusCode::PAYLOAD_TOO_LARGE)
+        );
+    }
+
+    #[test]
+    fn parse_listen_address() {
+        assert_eq!(
+            "127.0.0.1:8888".parse::<ListenAddress>().unwrap(),
+            ListenAddress::Tcp("127.0.0.1:8888".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:/run/gerritbot.sock".parse::<ListenAddress>().unwrap(),
+            ListenAddress::Unix("/run/gerritbot.sock".into())
+        );
+        assert!("localhost".parse::<ListenAddress>().is_err());
+        assert_eq!(
+            ListenAddress::Unix("/run/gerritbot.sock".into()).to_string(),
+            "unix:/run/gerritbot.sock"
+        );
+    }
+
+    #[test]
+    fn strip_mention() {
+        let message = |text: &str| Message {
+            text: text.to_string(),
+            ..Default::default()
+        };
+
+        let mut msg = message("Gerrit Bot status");
+        msg.strip_mention("Gerrit Bot");
+        assert_eq!(msg.text, "status");
+
+        let mut msg = message("Gerrit  filter enable");
+        msg.strip_mention("Gerrit Bot");
+        assert_eq!(msg.text, "filter enable");
+
+        let mut msg = message("Gerrit Bot");
+        msg.strip_mention("Gerrit Bot");
+        assert_eq!(msg.text, "");
+
+        let mut msg = message("Gerritbot status");
+        msg.strip_mention("Gerrit Bot");
+        assert_eq!(msg.text, "Gerritbot status");
+    }
+
+    #[test]
+    fn api_error_from_response() {
+        let e = ApiError::from_response(
+            http::StatusCode::BAD_REQUEST,
+            Some("header-tracking-id".to_string()),
+            br#"{
+                "message": "Unable to post message: invalid markdown",
+                "errors": [{"description": "Unable to post message: invalid markdown"}],
+                "trackingId": "ROUTER_5E7C"
+            }"#,
+        );
+        assert_eq!(e.message, "Unable to post message: invalid markdown");
+        assert_eq!(e.tracking_id, Some("ROUTER_5E7C".to_string()));
+        assert!(e.is_permanent());
+        assert_eq!(
+            e.to_string(),
+            "400 Bad Request: Unable to post message: invalid markdown (tracking id: ROUTER_5E7C)"
+        );
+
+        let e = ApiError::from_response(
+            http::StatusCode::BAD_GATEWAY,
+            Some("header-tracking-id".to_string()),
+            b"<html>Bad Gateway</html>\n",
+        );
+        assert_eq!(e.message, "<html>Bad Gateway</html>");
+        assert_eq!(e.tracking_id, Some("header-tracking-id".to_string()));
+        assert!(!e.is_permanent());
+
+        let e = ApiError::from_response(http::StatusCode::TOO_MANY_REQUESTS, None, b"");
+        assert_eq!(e.message, "Too Many Requests");
+        assert!(!Error::Api(e).is_permanent());
+    }
+
+    #[test]
+    fn duplicates_skipped() {
+        let message = |id: &str| {
+            WebhookData::Messages(Message {
+                id: MessageId::new(id.to_string()),
+                ..Default::default()
+            })
+        };
+        let ids: Vec<_> = skip_duplicates(stream::iter_ok(vec![
+            message("message-1"),
+            message("message-2"),
+            message("message-1"),
+            message("message-3"),
+            message("message-2"),
+        ]))
+        .map(|data| data.id().to_string())
+        .collect()
+        .wait()
+        .unwrap();
+        assert_eq!(ids, ["message-1", "message-2", "message-3"]);
+    }
+
+    #[test]
+    fn mentions() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "id": "message-id",
+            "roomId": "room-id",
+            "roomType": "group",
+            "text": "Gerrit Bot subscribe room demo Jane Doe",
+            "html": "<p><spark-mention data-object-type=\"person\" data-object-id=\"bot-id\">\
+                     Gerrit Bot</spark-mention> subscribe room demo \
+                     <spark-mention data-object-type=\"person\" data-object-id=\"jane-id\">\
+                     Jane Doe</spark-mention></p>",
+            "personId": "person-id",
+            "personEmail": "jdoe@example.com",
+            "mentionedPeople": ["bot-id", "jane-id"],
+            "mentionedGroups": ["all"],
+            "created": "2019-03-26T20:23:32.000Z",
+        }))
+        .unwrap();
+        assert!(message.mentions(PersonIdRef::new("jane-id")));
+        assert!(!message.mentions(PersonIdRef::new("other-id")));
+        assert_eq!(message.mentioned_groups, Some(vec!["all".to_string()]));
+        assert_eq!(message.text_without_mentions(), "subscribe room demo");
+
+        let message = Message {
+            text: " status ".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(message.text_without_mentions(), "status");
+    }
+
+    #[test]
+    fn create_message_with_card() {
+        let email = Email::new("jdoe@example.com".to_string());
+        let attachments = [Attachment::adaptive_card(
+            serde_json::json!({"type": "AdaptiveCard", "version": "1.2"}),
+        )];
+        let parameters = CreateMessageParameters {
+            target: (&email).into(),
+            text: None,
+            markdown: Some("fallback"),
+            html: None,
+            attachments: Some(&attachments),
+            files: None,
+            parent_id: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&parameters).unwrap(),
+            serde_json::json!({
+                "toPersonEmail": "jdoe@example.com",
+                "text": null,
+                "markdown": "fallback",
+                "html": null,
+                "attachments": [{
+                    "contentType": "application/vnd.microsoft.card.adaptive",
+                    "content": {"type": "AdaptiveCard", "version": "1.2"},
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn create_message_with_files() {
+        let email = Email::new("jdoe@example.com".to_string());
+        let files = [
+            MessageFile::Url("https://example.com/report.pdf".to_string()),
+            MessageFile::Path("/tmp/diff.html".into()),
+        ];
+        let parameters = CreateMessageParameters {
+            target: (&email).into(),
+            text: None,
+            markdown: Some("weekly report"),
+            html: None,
+            attachments: None,
+            files: Some(&files),
+            parent_id: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&parameters).unwrap(),
+            serde_json::json!({
+                "toPersonEmail": "jdoe@example.com",
+                "text": null,
+                "markdown": "weekly report",
+                "html": null,
+                "files": ["https://example.com/report.pdf"],
+            })
+        );
+
+        let local_files = [MessageFile::Path("/tmp/diff.html".into())];
+        let parameters = CreateMessageParameters {
+            files: Some(&local_files),
+            ..parameters
+        };
+        assert!(serde_json::to_value(&parameters)
+            .unwrap()
+            .get("files")
+            .is_none());
+        assert!(matches!(
+            multipart_form(
+                &serde_json::to_value(&parameters).unwrap(),
+                Path::new("/nonexistent/diff.html")
+            ),
+            Err(Error::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn create_threaded_message() {
+        let email = Email::new("jdoe@example.com".to_string());
+        let parent_id = MessageId::new("Y2lzY29zcGFyazovL3VzL01FU1NBR0UvMQ".to_string());
+        let parameters = CreateMessageParameters {
+            target: (&email).into(),
+            text: None,
+            markdown: Some("reply"),
+            html: None,
+            attachments: None,
+            files: None,
+            parent_id: Some(&parent_id),
+        };
+        assert_eq!(
+            serde_json::to_value(&parameters).unwrap(),
+            serde_json::json!({
+                "toPersonEmail": "jdoe@example.com",
+                "text": null,
+                "markdown": "reply",
+                "html": null,
+                "parentId": "Y2lzY29zcGFyazovL3VzL01FU1NBR0UvMQ",
+            })
+        );
+    }
+
+    fn webhook_post(resource: &str, data: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "id": "webhook-id",
+            "name": "gerritbot",
+            "targetUrl": "https://example.com/",
+            "resource": resource,
+            "event": "created",
+            "orgId": "org-id",
+            "createdBy": "creator-id",
+            "appId": "app-id",
+            "ownedBy": "creator",
+            "status": "active",
+            "created": "2019-03-26T20:23:32.000Z",
+            "actorId": "person-id",
+            "data": data,
+        })
+    }
+
+    #[test]
+    fn decode_webhook_message() {
+        let post = webhook_post(
+            "messages",
+            serde_json::json!({
+                "id": "message-id",
+                "roomId": "room-id",
+                "roomType": "direct",
+                "personId": "person-id",
+                "personEmail": "jdoe@example.com",
+                "created": "2019-03-26T20:23:32.000Z",
+            }),
+        );
+        let post: WebhookMessage = serde_json::from_value(post).unwrap();
+        match post.data {
+            WebhookData::Messages(message) => {
+                assert_eq!(message.id, MessageId::new("message-id".to_string()))
+            }
+            data => panic!("unexpected webhook data: {:?}", data),
+        }
+    }
+
+    #[test]
+    fn decode_webhook_attachment_action() {
+        let post = webhook_post(
+            "attachmentActions",
+            serde_json::json!({
+                "id": "action-id",
+                "type": "submit",
+                "messageId": "message-id",
+                "personId": "person-id",
+                "roomId": "room-id",
+                "created": "2019-03-26T20:23:32.000Z",
+            }),
+        );
+        let post: WebhookMessage = serde_json::from_value(post).unwrap();
+        assert_eq!(post.data.person_id(), PersonIdRef::new("person-id"));
+        match post.data {
+            WebhookData::AttachmentActions(action) => {
+                assert_eq!(action.id, AttachmentActionId::new("action-id".to_string()));
+                assert_eq!(action.inputs, serde_json::Value::Null);
+            }
+            data => panic!("unexpected webhook data: {:?}", data),
+        }
+    }
+
+    #[test]
+    fn decode_webhook_membership() {
+        let post = webhook_post(
+            "memberships",
+            serde_json::json!({
+                "id": "membership-id",
+                "roomId": "room-id",
+                "roomType": "group",
+                "personId": "bot-id",
+                "personEmail": "gerritbot@webex.bot",
+                "personDisplayName": "gerritbot",
+                "personOrgId": "org-id",
+                "isModerator": false,
+                "isMonitor": false,
+                "created": "2019-03-26T20:23:32.000Z",
+            }),
+        );
+        let post: WebhookMessage = serde_json::from_value(post).unwrap();
+        assert_eq!(post.data.person_id(), PersonIdRef::new("bot-id"));
+        match post.data {
+            WebhookData::Memberships(membership) => {
+                assert_eq!(membership.room_id, RoomId::new("room-id".to_string()));
+                assert_eq!(membership.room_type, Some(RoomType::Group));
+            }
+            data => panic!("unexpected webhook data: {:?}", data),
+        }
+    }
+
+    #[test]
+    fn decode_rooms() {
+        let rooms: Items<Room> = serde_json::from_value(serde_json::json!({
+            "items": [{
+                "id": "room-id",
+                "title": "Reviews",
+                "type": "group",
+                "isLocked": false,
+                "lastActivity": "2019-03-26T20:23:32.000Z",
+                "creatorId": "person-id",
+                "created": "2019-03-26T20:23:32.000Z",
+                "ownerId": "org-id",
+            }],
+        }))
+        .unwrap();
+        assert_eq!(rooms.items.len(), 1);
+        assert_eq!(rooms.items[0].id, RoomId::new("room-id".to_string()));
+        assert_eq!(rooms.items[0].room_type, RoomType::Group);
+        assert!(rooms.items[0].team_id.is_none());
+    }
+
+    #[test]
+    fn webhook_server_shutdown() {
+        let socket_path =
+            std::env::temp_dir().join(format!("gerritbot-webhook-{}.sock", std::process::id()));
+        let listen_addresses = [
+            ListenAddress::Tcp("127.0.0.1:0".parse().unwrap()),
+            ListenAddress::Unix(socket_path.clone()),
+        ];
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let RawWebhookServer {
+            messages,
+            server,
+            shutdown,
+        } = runtime
+            .block_on(future::lazy(move || {
+                start_raw_webhook_server_with_options(&listen_addresses, Default::default())
+            }))
+            .unwrap();
+        assert!(socket_path.exists());
+
+        shutdown.send(()).unwrap();
+        runtime.block_on(server).unwrap();
+        // the stream of posts ends with the server
+        assert_eq!(runtime.block_on(messages.collect()).unwrap().len(), 0);
+
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+
     #[test]
     fn person_id_ref() {
         let p = PersonId("person-id".to_string());
@@ -0,0 +1,288 @@
+//! Instrumentation of the calls of the Webex API.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::Error;
+
+/// Upper bounds of the latency histogram buckets in milliseconds. Slower
+/// calls are counted in an additional last bucket.
+const LATENCY_BUCKETS_MILLIS: [u64; 9] = [10, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// How an API call finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CallOutcome {
+    Success,
+    /// Webex responded with 429 Too Many Requests.
+    RateLimited,
+    /// Webex responded with another 4xx status.
+    ClientError,
+    /// Webex responded with a 5xx status.
+    ServerError,
+    /// The request could not be sent or the response not be received.
+    Connection,
+    /// The response could not be decoded.
+    Decode,
+    Other,
+}
+
+impl CallOutcome {
+    pub fn of<T>(result: &Result<T, Error>) -> Self {
+        let e = match result {
+            Ok(_) => return CallOutcome::Success,
+            Err(e) => e,
+        };
+        match e {
+            Error::ReqwestError(e) => match e.status() {
+                Some(http::StatusCode::TOO_MANY_REQUESTS) => CallOutcome::RateLimited,
+                Some(status) if status.is_client_error() => CallOutcome::ClientError,
+                Some(status) if status.is_server_error() => CallOutcome::ServerError,
+                Some(_) => CallOutcome::Other,
+                None if e.is_serialization() => CallOutcome::Decode,
+                None => CallOutcome::Connection,
+            },
+            Error::Api(e) => match e.status {
+                http::StatusCode::TOO_MANY_REQUESTS => CallOutcome::RateLimited,
+                status if status.is_client_error() => CallOutcome::ClientError,
+                status if status.is_server_error() => CallOutcome::ServerError,
+                _ => CallOutcome::Other,
+            },
+            Error::HyperError(_) | Error::IoError(_) => CallOutcome::Connection,
+            Error::JsonError(_) => CallOutcome::Decode,
+            _ => CallOutcome::Other,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CallOutcome::Success => "success",
+            CallOutcome::RateLimited => "rate_limited",
+            CallOutcome::ClientError => "client_error",
+            CallOutcome::ServerError => "server_error",
+            CallOutcome::Connection => "connection",
+            CallOutcome::Decode => "decode",
+            CallOutcome::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for CallOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Receives measurements of the calls of `Client`, e.g. `send_message` or
+/// `get_message`. All methods do nothing by default.
+pub trait Metrics: Send + Sync {
+    /// An API call finished after the given time.
+    fn call_finished(&self, _operation: &str, _latency: Duration, _outcome: CallOutcome) {}
+}
+
+impl fmt::Debug for dyn Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+/// Metrics that are not recorded anywhere.
+#[derive(Debug, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Measurements of the calls of one operation.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CallStats {
+    /// Number of calls by outcome.
+    pub outcomes: BTreeMap<CallOutcome, u64>,
+    /// Number of calls per latency bucket, cf. `latency_buckets`.
+    pub latency_histogram: [u64; LATENCY_BUCKETS_MILLIS.len() + 1],
+    pub total_latency: Duration,
+}
+
+impl CallStats {
+    /// Upper bounds of the buckets of the latency histogram. The last bucket
+    /// has no bound.
+    pub fn latency_buckets() -> impl Iterator<Item = Option<Duration>> {
+        LATENCY_BUCKETS_MILLIS
+            .iter()
+            .map(|millis| Some(Duration::from_millis(*millis)))
+            .chain(std::iter::once(None))
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.outcomes.values().sum()
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.calls() - self.outcomes.get(&CallOutcome::Success).unwrap_or(&0)
+    }
+
+    pub fn average_latency(&self) -> Option<Duration> {
+        match self.calls() {
+            0 => None,
+            calls => Some(self.total_latency / calls as u32),
+        }
+    }
+
+    /// Upper bound of the bucket containing the given quantile of the
+    /// latencies, e.g. 0.9 for the 90th percentile. `None` if there are no
+    /// calls or the quantile is in the last bucket.
+    pub fn latency_quantile(&self, quantile: f64) -> Option<Duration> {
+        let calls = self.calls();
+        if calls == 0 {
+            return None;
+        }
+        let rank = (quantile * calls as f64).ceil().max(1.0) as u64;
+        let mut count = 0;
+        for (bucket_count, bound) in self.latency_histogram.iter().zip(Self::latency_buckets()) {
+            count += bucket_count;
+            if count >= rank {
+                return bound;
+            }
+        }
+        None
+    }
+
+    fn record(&mut self, latency: Duration, outcome: CallOutcome) {
+        *self.outcomes.entry(outcome).or_default() += 1;
+        let millis = latency.as_millis();
+        let bucket = LATENCY_BUCKETS_MILLIS
+            .iter()
+            .position(|bound| millis <= u128::from(*bound))
+            .unwrap_or(LATENCY_BUCKETS_MILLIS.len());
+        self.latency_histogram[bucket] += 1;
+        self.total_latency += latency;
+    }
+}
+
+/// Metrics recorded in memory.
+#[derive(Debug, Default)]
+pub struct Counters {
+    calls: Mutex<BTreeMap<String, CallStats>>,
+}
+
+impl Counters {
+    /// Measurements per operation.
+    pub fn calls(&self) -> BTreeMap<String, CallStats> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl Metrics for Counters {
+    fn call_finished(&self, operation: &str, latency: Duration, outcome: CallOutcome) {
+        self.calls
+            .lock()
+            .unwrap()
+            .entry(operation.to_string())
+            .or_default()
+            .record(latency, outcome);
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let calls = self.calls();
+        if calls.is_empty() {
+            return write!(f, "no calls");
+        }
+        for (i, (operation, stats)) in calls.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {} call(s)", operation, stats.calls())?;
+            if stats.failures() > 0 {
+                write!(f, " ({} failed:", stats.failures())?;
+                for (outcome, count) in &stats.outcomes {
+                    if *outcome != CallOutcome::Success {
+                        write!(f, " {}={}", outcome, count)?;
+                    }
+                }
+                write!(f, ")")?;
+            }
+            if let Some(latency) = stats.average_latency() {
+                write!(f, " avg. {:?}", latency)?;
+            }
+            match stats.latency_quantile(0.9) {
+                Some(bound) => write!(f, " p90 <= {:?}", bound)?,
+                None => write!(
+                    f,
+                    " p90 > {:?}",
+                    Duration::from_millis(LATENCY_BUCKETS_MILLIS[LATENCY_BUCKETS_MILLIS.len() - 1])
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_call_outcome() {
+        assert_eq!(CallOutcome::of(&Ok(())), CallOutcome::Success);
+        let json_error = serde_json::from_str::<u32>("").unwrap_err();
+        assert_eq!(
+            CallOutcome::of::<()>(&Err(json_error.into())),
+            CallOutcome::Decode
+        );
+        let io_error = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            CallOutcome::of::<()>(&Err(io_error.into())),
+            CallOutcome::Connection
+        );
+    }
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::default();
+        assert_eq!(counters.to_string(), "no calls");
+
+        for millis in &[5, 20, 30, 40, 80, 90, 120, 200, 300] {
+            counters.call_finished(
+                "send_message",
+                Duration::from_millis(*millis),
+                CallOutcome::Success,
+            );
+        }
+        counters.call_finished(
+            "send_message",
+            Duration::from_millis(20_000),
+            CallOutcome::ServerError,
+        );
+        counters.call_finished(
+            "get_message",
+            Duration::from_millis(10),
+            CallOutcome::RateLimited,
+        );
+
+        let calls = counters.calls();
+        let send_message = &calls["send_message"];
+        assert_eq!(send_message.calls(), 10);
+        assert_eq!(send_message.failures(), 1);
+        assert_eq!(
+            send_message.latency_histogram,
+            [1, 3, 2, 2, 1, 0, 0, 0, 0, 1]
+        );
+        assert_eq!(
+            send_message.average_latency(),
+            Some(Duration::from_micros(2_088_500))
+        );
+        assert_eq!(
+            send_message.latency_quantile(0.5),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(send_message.latency_quantile(1.0), None);
+
+        assert_eq!(
+            counters.to_string(),
+            "get_message: 1 call(s) (1 failed: rate_limited=1) avg. 10ms p90 <= 10ms, \
+             send_message: 10 call(s) (1 failed: server_error=1) avg. 2.0885s p90 <= 500ms"
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Pulling messages from a Google Cloud Pub/Sub subscription over its REST
+//! API.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{future, stream, Future, Stream};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{decode_json_body, Error};
+
+const PUBSUB_URL: &str = "https://pubsub.googleapis.com/v1";
+
+/// Token endpoint of the GCE metadata server, which hands out tokens of the
+/// service account the instance runs as.
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+const PUBSUB_SCOPE: &str = "https://www.googleapis.com/auth/pubsub";
+
+/// Lifetime of the tokens requested for service accounts.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Tokens are refreshed this long before they expire.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Maximum number of messages pulled at once.
+const MAX_MESSAGES: u32 = 10;
+
+/// Time to wait before pulling again after an error.
+const ERROR_DELAY: Duration = Duration::from_secs(10);
+
+/// Key file of a service account as downloaded from the Google Cloud console.
+#[derive(Deserialize, Debug, Clone)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+
+#[derive(Debug, Clone)]
+enum Credentials {
+    ServiceAccount(ServiceAccountKey),
+    Metadata,
+}
+
+/// How the access tokens for Pub/Sub are obtained.
+#[derive(Debug, Clone)]
+pub struct PubSubCredentials(Credentials);
+
+impl PubSubCredentials {
+    /// Load the key file of a service account.
+    pub fn from_key_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let key = serde_json::from_reader(std::fs::File::open(path)?)?;
+        Ok(Self(Credentials::ServiceAccount(key)))
+    }
+
+    /// Use the tokens of the service account of the GCE instance, provided by
+    /// its metadata server.
+    pub fn metadata_server() -> Self {
+        Self(Credentials::Metadata)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+/// JSON Web Token of the service account to request an access token with.
+fn service_account_jwt(key: &ServiceAccountKey, now: u64) -> Result<String, Error> {
+    let claims = Claims {
+        iss: &key.client_email,
+        scope: PUBSUB_SCOPE,
+        aud: &key.token_uri,
+        iat: now,
+        exp: now + TOKEN_LIFETIME.as_secs(),
+    };
+    let private_key = EncodingKey::from_rsa_pem(key.private_key.as_bytes())?;
+    Ok(jsonwebtoken::encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &private_key,
+    )?)
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Debug)]
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+}
+
+/// Provides access tokens, which are cached until shortly before they
+/// expire.
+#[derive(Clone)]
+struct TokenProvider {
+    client: reqwest::r#async::Client,
+    credentials: PubSubCredentials,
+    cached: Arc<Mutex<Option<AccessToken>>>,
+}
+
+impl TokenProvider {
+    fn request_token(&self) -> impl Future<Item = TokenResponse, Error = Error> {
+        let request = match &self.credentials.0 {
+            Credentials::ServiceAccount(key) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                match service_account_jwt(key, now) {
+                    Ok(jwt) => self.client.post(&key.token_uri).form(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("assertion", &jwt),
+                    ]),
+                    Err(e) => return future::Either::A(future::err(e)),
+                }
+            }
+            Credentials::Metadata => self
+                .client
+                .get(METADATA_TOKEN_URL)
+                .header("Metadata-Flavor", "Google"),
+        };
+        future::Either::B(
+            request
+                .send()
+                .and_then(|response| response.error_for_status())
+                .from_err()
+                .and_then(|response| decode_json_body(response.into_body())),
+        )
+    }
+
+    fn token(&self) -> impl Future<Item = String, Error = Error> {
+        if let Some(cached) = &*self.cached.lock().unwrap() {
+            if Instant::now() + TOKEN_REFRESH_MARGIN < cached.expires_at {
+                return future::Either::A(future::ok(cached.token.clone()));
+            }
+        }
+
+        debug!("requesting Pub/Sub access token");
+        let cached = self.cached.clone();
+        future::Either::B(self.request_token().map(move |response| {
+            *cached.lock().unwrap() = Some(AccessToken {
+                token: response.access_token.clone(),
+                expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+            });
+            response.access_token
+        }))
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct PullResponse {
+    #[serde(default)]
+    received_messages: Vec<ReceivedMessage>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ReceivedMessage {
+    ack_id: String,
+    message: PubSubMessage,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PubSubMessage {
+    message_id: String,
+    /// Base64 encoded payload.
+    #[serde(default)]
+    data: String,
+}
+
+#[derive(Clone)]
+struct Subscriber {
+    client: reqwest::r#async::Client,
+    tokens: TokenProvider,
+    /// Full name of the subscription: `projects/<project>/subscriptions/<name>`.
+    subscription: String,
+}
+
+impl Subscriber {
+    fn call<T, R>(&self, method: &str, body: &T) -> impl Future<Item = R, Error = Error>
+    where
+        T: Serialize,
+        for<'a> R: Deserialize<'a>,
+    {
+        let client = self.client.clone();
+        let url = format!("{}/{}:{}", PUBSUB_URL, self.subscription, method);
+        let body = serde_json::to_vec(body);
+        self.tokens
+            .token()
+            .and_then(|token| body.map(|body| (token, body)).map_err(Error::from))
+            .and_then(move |(token, body)| {
+                client
+                    .post(&url)
+                    .bearer_auth(token)
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(body)
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .from_err()
+            })
+            .and_then(|response| decode_json_body(response.into_body()))
+    }
+
+    fn pull(&self) -> impl Future<Item = PullResponse, Error = Error> {
+        self.call(
+            "pull",
+            &serde_json::json!({
+                "maxMessages": MAX_MESSAGES,
+            }),
+        )
+    }
+
+    fn acknowledge(&self, ack_ids: Vec<String>) -> impl Future<Item = (), Error = Error> {
+        self.call::<_, serde_json::Value>(
+            "acknowledge",
+            &serde_json::json!({
+                "ackIds": ack_ids,
+            }),
+        )
+        .map(|_| ())
+    }
+}
+
+/// Decoded payloads of the messages of the subscription. Messages are
+/// acknowledged right after they are pulled.
+pub(crate) fn pubsub_receiver(
+    subscription: String,
+    credentials: PubSubCredentials,
+) -> impl Stream<Item = Vec<u8>, Error = ()> {
+    let client = reqwest::r#async::Client::new();
+    let subscriber = Subscriber {
+        client: client.clone(),
+        tokens: TokenProvider {
+            client,
+            credentials,
+            cached: Default::default(),
+        },
+        subscription,
+    };
+    let ack_subscriber = subscriber.clone();
+
+    // repeatedly pull messages
+    stream::unfold((), move |()| {
+        Some(
+            subscriber
+                .pull()
+                .or_else(|e| {
+                    error!("failed to pull Pub/Sub messages: {}", e);
+                    // don't retry right away
+                    tokio::timer::Delay::new(Instant::now() + ERROR_DELAY)
+                        .then(|_| Ok(PullResponse::default()))
+                })
+                .map(|response| (response, ())),
+        )
+    })
+    // acknowledge messages
+    .and_then(move |response| {
+        let messages = response.received_messages;
+        if messages.is_empty() {
+            return future::Either::A(future::ok(messages));
+        }
+        let ack_ids = messages
+            .iter()
+            .map(|message| message.ack_id.clone())
+            .collect();
+        future::Either::B(ack_subscriber.acknowledge(ack_ids).then(|result| {
+            if let Err(e) = result {
+                error!("failed to acknowledge Pub/Sub messages: {}", e);
+            }
+            // forward messages
+            Ok(messages)
+        }))
+    })
+    // flatten messages to return one by one
+    .map(stream::iter_ok)
+    .flatten()
+    .filter_map(|received| {
+        base64::decode(&received.message.data)
+            .map_err(|e| {
+                warn!(
+                    "failed to decode data of Pub/Sub message {}: {}",
+                    received.message.message_id, e
+                )
+            })
+            .ok()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Verifier;
+
+    #[test]
+    fn test_service_account_jwt() {
+        let private_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let key = ServiceAccountKey {
+            client_email: "gerritbot@project.iam.gserviceaccount.com".to_string(),
+            private_key: String::from_utf8(private_key.private_key_to_pem_pkcs8().unwrap())
+                .unwrap(),
+            token_uri: default_token_uri(),
+        };
+
+        let jwt = service_account_jwt(&key, 1_000_000).unwrap();
+        let parts: Vec<_> = jwt.split('.').collect();
+        assert_eq!(parts.len(), 3);
+
+        let claims: serde_json::Value = serde_json::from_slice(
+            &base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            claims,
+            serde_json::json!({
+                "iss": "gerritbot@project.iam.gserviceaccount.com",
+                "scope": PUBSUB_SCOPE,
+                "aud": "https://oauth2.googleapis.com/token",
+                "iat": 1_000_000,
+                "exp": 1_003_600,
+            })
+        );
+
+        let signature = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &private_key).unwrap();
+        verifier
+            .update(format!("{}.{}", parts[0], parts[1]).as_bytes())
+            .unwrap();
+        assert!(verifier.verify(&signature).unwrap());
+    }
+
+    #[test]
+    fn test_decode_pull_response() {
+        let response: PullResponse = serde_json::from_value(serde_json::json!({
+            "receivedMessages": [{
+                "ackId": "ack-id",
+                "message": {
+                    "data": base64::encode(r#"{"id":"webhook-id"}"#),
+                    "messageId": "message-id",
+                    "publishTime": "2020-01-01T00:00:00.000Z",
+                },
+            }],
+        }))
+        .unwrap();
+        assert_eq!(response.received_messages.len(), 1);
+        assert_eq!(response.received_messages[0].ack_id, "ack-id");
+
+        // no messages
+        let response: PullResponse = serde_json::from_str("{}").unwrap();
+        assert!(response.received_messages.is_empty());
+    }
+}
@@ -0,0 +1,410 @@
+//! Persistent queue of outgoing messages. Messages are queued when sending
+//! them failed, and are retried with exponential backoff, also after a
+//! restart. The file is only written when the queue changes, so messages sent
+//! successfully at the first attempt don't touch it.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{stream, Future, Stream};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::timer::{Interval, Timeout};
+
+use crate::{
+    Attachment, Client, CreateMessageParameters, CreateMessageTarget, Email, MessageFile,
+    MessageId, PersonId, RoomId, Timestamp,
+};
+
+/// Time after which a message is retried for the first time.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The backoff doubles with each attempt up to this limit.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Messages are given up after this many retries.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// How often the queue is checked for messages to retry.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time after which a retry is considered failed.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum MessageTarget {
+    #[serde(rename = "roomId")]
+    RoomId(RoomId),
+    #[serde(rename = "toPersonId")]
+    PersonId(PersonId),
+    #[serde(rename = "toPersonEmail")]
+    PersonEmail(Email),
+}
+
+/// Owned copy of the parameters of a message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QueuedMessage {
+    #[serde(flatten)]
+    target: MessageTarget,
+    text: Option<String>,
+    markdown: Option<String>,
+    html: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    files: Vec<MessageFile>,
+    parent_id: Option<MessageId>,
+}
+
+impl QueuedMessage {
+    pub(crate) fn new(parameters: &CreateMessageParameters) -> Self {
+        Self {
+            target: match parameters.target {
+                CreateMessageTarget::RoomId(room_id) => MessageTarget::RoomId(room_id.to_owned()),
+                CreateMessageTarget::PersonId(person_id) => {
+                    MessageTarget::PersonId(person_id.to_owned())
+                }
+                CreateMessageTarget::PersonEmail(email) => {
+                    MessageTarget::PersonEmail(email.to_owned())
+                }
+            },
+            text: parameters.text.map(str::to_string),
+            markdown: parameters.markdown.map(str::to_string),
+            html: parameters.html.map(str::to_string),
+            attachments: parameters.attachments.unwrap_or_default().to_vec(),
+            files: parameters.files.unwrap_or_default().to_vec(),
+            parent_id: parameters.parent_id.map(ToOwned::to_owned),
+        }
+    }
+
+    pub(crate) fn parameters(&self) -> CreateMessageParameters<'_> {
+        CreateMessageParameters {
+            target: match &self.target {
+                MessageTarget::RoomId(room_id) => room_id.into(),
+                MessageTarget::PersonId(person_id) => person_id.into(),
+                MessageTarget::PersonEmail(email) => email.into(),
+            },
+            text: self.text.as_deref(),
+            markdown: self.markdown.as_deref(),
+            html: self.html.as_deref(),
+            attachments: Some(&self.attachments[..]).filter(|a| !a.is_empty()),
+            files: Some(&self.files[..]).filter(|f| !f.is_empty()),
+            parent_id: self.parent_id.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    id: u64,
+    message: QueuedMessage,
+    /// Number of retries so far.
+    attempts: u32,
+    next_attempt: Timestamp,
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    capacity: usize,
+    next_id: u64,
+    entries: VecDeque<Entry>,
+}
+
+impl Inner {
+    /// Save the queue to a temporary file next to the file, which then
+    /// replaces it, so a crash while writing doesn't lose the queue.
+    fn save(&self) {
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let result = File::create(&tmp_path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| {
+                let mut f = BufWriter::new(f);
+                serde_json::to_writer(&mut f, &self.entries).map_err(|e| e.to_string())?;
+                f.into_inner()
+                    .map_err(|e| e.to_string())?
+                    .sync_all()
+                    .map_err(|e| e.to_string())
+            })
+            .and_then(|()| fs::rename(&tmp_path, &self.path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!(
+                "failed to save retry queue to '{}': {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    INITIAL_BACKOFF
+        .checked_mul(1 << attempts.min(16))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+fn timestamp_after(duration: Duration) -> Timestamp {
+    Timestamp(chrono::Utc::now() + chrono::Duration::from_std(duration).unwrap())
+}
+
+/// Bounded queue of messages to retry, stored in a file. When the queue is
+/// full, the oldest messages are dropped.
+#[derive(Debug, Clone)]
+pub struct RetryQueue(Arc<Mutex<Inner>>);
+
+impl RetryQueue {
+    /// Load the queue from the file, if it exists.
+    pub fn load<P: Into<PathBuf>>(path: P, capacity: usize) -> Self {
+        let path = path.into();
+        let entries: VecDeque<Entry> = match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f)
+                .map_err(|e| {
+                    warn!(
+                        "failed to load retry queue from '{}': {}",
+                        path.display(),
+                        e
+                    )
+                })
+                .unwrap_or_default(),
+            Err(_) => VecDeque::new(),
+        };
+        if !entries.is_empty() {
+            info!("loaded {} message(s) to retry", entries.len());
+        }
+        let next_id = entries.iter().map(|entry| entry.id + 1).max().unwrap_or(0);
+
+        RetryQueue(Arc::new(Mutex::new(Inner {
+            path,
+            capacity,
+            next_id,
+            entries,
+        })))
+    }
+
+    /// Number of queued messages.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queue a message whose sending failed. It is retried after the initial
+    /// backoff.
+    pub(crate) fn push(&self, message: QueuedMessage) -> u64 {
+        let mut inner = self.0.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        while inner.capacity <= inner.entries.len() {
+            match inner.entries.pop_front() {
+                Some(entry) => warn!("retry queue is full, dropping {:?}", entry.message.target),
+                None => break,
+            }
+        }
+        if inner.capacity > 0 {
+            inner.entries.push_back(Entry {
+                id,
+                message,
+                attempts: 0,
+                next_attempt: timestamp_after(INITIAL_BACKOFF),
+            });
+        }
+        inner.save();
+        id
+    }
+
+    /// Remove a message that was sent.
+    pub(crate) fn remove(&self, id: u64) {
+        let mut inner = self.0.lock().unwrap();
+        let len = inner.entries.len();
+        inner.entries.retain(|entry| entry.id != id);
+        if inner.entries.len() != len {
+            inner.save();
+        }
+    }
+
+    /// Messages due for a retry. Their next retry is scheduled right away, so
+    /// they are retried again if this one fails. Messages that were retried
+    /// too often are given up.
+    fn take_due(&self) -> Vec<(u64, QueuedMessage)> {
+        let mut inner = self.0.lock().unwrap();
+        let now = chrono::Utc::now();
+        if inner.entries.iter().all(|entry| entry.next_attempt.0 > now) {
+            return Vec::new();
+        }
+
+        let mut due = Vec::new();
+        inner.entries.retain(|entry| {
+            let given_up = entry.next_attempt.0 <= now && entry.attempts >= MAX_ATTEMPTS;
+            if given_up {
+                error!(
+                    "giving up sending message to {:?} after {} attempts",
+                    entry.message.target, entry.attempts
+                );
+            }
+            !given_up
+        });
+        for entry in inner.entries.iter_mut() {
+            if entry.next_attempt.0 <= now {
+                entry.attempts += 1;
+                entry.next_attempt = timestamp_after(backoff(entry.attempts));
+                due.push((entry.id, entry.message.clone()));
+            }
+        }
+        inner.save();
+        due
+    }
+
+    /// Retry the queued messages using the client. Runs forever.
+    pub fn run(self, client: Client) -> impl Future<Item = (), Error = ()> {
+        Interval::new(Instant::now(), CHECK_INTERVAL)
+            .map_err(|e| error!("retry timer failed: {}", e))
+            .for_each(move |_| {
+                let queue = self.clone();
+                let client = client.clone();
+                stream::iter_ok(self.take_due()).for_each(move |(id, message)| {
+                    let queue = queue.clone();
+                    debug!("retrying message to {:?}", message.target);
+                    Timeout::new(
+                        client.send_message_parts(message.parameters()),
+                        SEND_TIMEOUT,
+                    )
+                    .then(move |result| {
+                        match result {
+                            Ok(_) => {
+                                info!("sent message to {:?} on retry", message.target);
+                                queue.remove(id);
+                            }
+                            Err(e) => match e.into_inner() {
+                                Some(e) if e.is_permanent() => {
+                                    warn!("giving up message to {:?}: {}", message.target, e);
+                                    queue.remove(id);
+                                }
+                                Some(e) => warn!("failed to retry message: {}", e),
+                                None => warn!("failed to retry message: timed out"),
+                            },
+                        }
+                        Ok(())
+                    })
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "gerritbot-retry-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn message(email: &str) -> QueuedMessage {
+        QueuedMessage {
+            target: MessageTarget::PersonEmail(Email::new(email.to_string())),
+            text: None,
+            markdown: Some("hello".to_string()),
+            html: None,
+            attachments: Vec::new(),
+            files: Vec::new(),
+            parent_id: None,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), INITIAL_BACKOFF);
+        assert_eq!(backoff(1), 2 * INITIAL_BACKOFF);
+        assert_eq!(backoff(3), 8 * INITIAL_BACKOFF);
+        assert_eq!(backoff(MAX_ATTEMPTS), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_bounded_and_persistent() {
+        let path = temp_path("bounded");
+        let queue = RetryQueue::load(&path, 2);
+        let first = queue.push(message("a@example.com"));
+        queue.push(message("b@example.com"));
+        queue.push(message("c@example.com"));
+        assert_eq!(queue.len(), 2);
+
+        // the oldest message was dropped
+        queue.remove(first);
+        assert_eq!(queue.len(), 2);
+
+        let loaded = RetryQueue::load(&path, 2);
+        assert_eq!(loaded.len(), 2);
+        let id = loaded.push(message("d@example.com"));
+        assert_eq!(id, 3);
+
+        // the file is replaced, no temporary file is left behind
+        assert!(!path.with_extension("json.tmp").exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_take_due() {
+        let path = temp_path("due");
+        let queue = RetryQueue::load(&path, 10);
+        let id = queue.push(message("a@example.com"));
+        // not due before the initial backoff
+        assert!(queue.take_due().is_empty());
+
+        queue.0.lock().unwrap().entries[0].next_attempt = timestamp_after(Duration::from_secs(0));
+        assert_eq!(queue.take_due(), vec![(id, message("a@example.com"))]);
+        assert!(queue.take_due().is_empty());
+        assert_eq!(queue.0.lock().unwrap().entries[0].attempts, 1);
+
+        // given up after too many attempts
+        {
+            let mut inner = queue.0.lock().unwrap();
+            inner.entries[0].attempts = MAX_ATTEMPTS;
+            inner.entries[0].next_attempt = timestamp_after(Duration::from_secs(0));
+        }
+        assert!(queue.take_due().is_empty());
+        assert!(queue.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_queued_message_parameters() {
+        let email = Email::new("jdoe@example.com".to_string());
+        let attachments = [Attachment::adaptive_card(serde_json::json!({}))];
+        let parameters = CreateMessageParameters {
+            target: (&email).into(),
+            text: None,
+            markdown: Some("hello"),
+            html: None,
+            attachments: Some(&attachments),
+            files: None,
+            parent_id: None,
+        };
+        let message = QueuedMessage::new(&parameters);
+        assert_eq!(
+            serde_json::to_value(message.parameters()).unwrap(),
+            serde_json::to_value(parameters).unwrap()
+        );
+
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(
+            serde_json::from_str::<QueuedMessage>(&json).unwrap(),
+            message
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! Receiving webhook posts pushed by Amazon SNS over HTTP(S). Only messages
+//! with a valid SNS signature are accepted, and subscriptions to the topic are
+//! confirmed automatically.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Future, Stream as _};
+use hyper::{Body, Response};
+use log::{error, info, warn};
+use openssl::hash::MessageDigest;
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+use serde::Deserialize;
+
+use crate::{read_body, reject_request, Error, RequestHandler, TlsAcceptor, WebhookMessage};
+
+/// SNS message as posted to an HTTP(S) subscription.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct SnsMessage {
+    #[serde(rename = "Type")]
+    message_type: String,
+    message_id: String,
+    topic_arn: String,
+    subject: Option<String>,
+    message: String,
+    timestamp: String,
+    token: Option<String>,
+    #[serde(rename = "SubscribeURL")]
+    subscribe_url: Option<String>,
+    signature_version: String,
+    signature: String,
+    #[serde(rename = "SigningCertURL")]
+    signing_cert_url: String,
+}
+
+impl SnsMessage {
+    /// The fields covered by the signature in the format SNS signs them.
+    fn string_to_sign(&self) -> String {
+        let is_notification = self.message_type == "Notification";
+        let fields = [
+            ("Message", Some(&self.message)),
+            ("MessageId", Some(&self.message_id)),
+            ("Subject", self.subject.as_ref().filter(|_| is_notification)),
+            (
+                "SubscribeURL",
+                self.subscribe_url.as_ref().filter(|_| !is_notification),
+            ),
+            ("Timestamp", Some(&self.timestamp)),
+            ("Token", self.token.as_ref().filter(|_| !is_notification)),
+            ("TopicArn", Some(&self.topic_arn)),
+            ("Type", Some(&self.message_type)),
+        ];
+        fields
+            .iter()
+            .filter_map(|(name, value)| value.map(|value| format!("{}\n{}\n", name, value)))
+            .collect()
+    }
+
+    fn is_signed_by(&self, cert: &X509) -> bool {
+        let digest = match &self.signature_version[..] {
+            "1" => MessageDigest::sha1(),
+            "2" => MessageDigest::sha256(),
+            version => {
+                warn!("unsupported SNS signature version: {}", version);
+                return false;
+            }
+        };
+        let signature = match base64::decode(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        cert.public_key()
+            .and_then(|key| {
+                let mut verifier = Verifier::new(digest, &key)?;
+                verifier.update(self.string_to_sign().as_bytes())?;
+                verifier.verify(&signature)
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Only urls of SNS itself are fetched, e.g.
+/// `https://sns.eu-west-1.amazonaws.com/...`.
+fn is_sns_url(url: &str) -> bool {
+    let url = match reqwest::Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    let region = url.host_str().and_then(|host| {
+        host.strip_prefix("sns.").and_then(|host| {
+            host.strip_suffix(".amazonaws.com")
+                .or_else(|| host.strip_suffix(".amazonaws.com.cn"))
+        })
+    });
+    url.scheme() == "https"
+        && region
+            .map(|region| {
+                !region.is_empty()
+                    && region
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            })
+            .unwrap_or(false)
+}
+
+fn error_response(status: http::StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Options of the SNS server.
+#[derive(Clone)]
+pub struct SnsServerOptions {
+    /// Path SNS messages are accepted at.
+    pub path: String,
+    /// If set, only messages of this topic are accepted.
+    pub topic_arn: Option<String>,
+    /// If set, HTTPS is served instead of plain HTTP.
+    pub tls: Option<TlsAcceptor>,
+}
+
+impl Default for SnsServerOptions {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            topic_arn: None,
+            tls: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct SnsReceiver {
+    client: reqwest::r#async::Client,
+    path: String,
+    topic_arn: Option<String>,
+    /// Signing certificates by url.
+    certs: Arc<Mutex<HashMap<String, X509>>>,
+}
+
+impl SnsReceiver {
+    fn signing_cert(&self, url: &str) -> impl Future<Item = X509, Error = Error> {
+        if let Some(cert) = self.certs.lock().unwrap().get(url) {
+            return future::Either::A(future::ok(cert.clone()));
+        }
+        if !is_sns_url(url) {
+            return future::Either::A(future::err(Error::InvalidUrl(url.to_string())));
+        }
+
+        let certs = self.certs.clone();
+        let url = url.to_string();
+        future::Either::B(
+            self.client
+                .get(&url)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .and_then(|response| response.into_body().concat2())
+                .from_err()
+                .and_then(move |pem| {
+                    let cert = X509::from_pem(&pem)?;
+                    certs.lock().unwrap().insert(url, cert.clone());
+                    Ok(cert)
+                }),
+        )
+    }
+
+    fn confirm_subscription(
+        &self,
+        message: &SnsMessage,
+    ) -> impl Future<Item = Option<WebhookMessage>, Error = Response<Body>> {
+        let topic_arn = message.topic_arn.clone();
+        let subscribe_url = match &message.subscribe_url {
+            Some(url) if is_sns_url(url) => url,
+            url => {
+                warn!("invalid SNS subscribe url: {:?}", url);
+                return future::Either::A(future::err(error_response(
+                    http::StatusCode::BAD_REQUEST,
+                )));
+            }
+        };
+        future::Either::B(
+            self.client
+                .get(subscribe_url)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map(move |_| {
+                    info!("confirmed SNS subscription to {}", topic_arn);
+                    None
+                })
+                .map_err(|e| {
+                    error!("failed to confirm SNS subscription: {}", e);
+                    error_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+                }),
+        )
+    }
+
+    fn handle(
+        &self,
+        request: hyper::Request<hyper::Chunk>,
+    ) -> impl Future<Item = Option<WebhookMessage>, Error = Response<Body>> {
+        let message: SnsMessage = match serde_json::from_slice(request.body()) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("failed to decode SNS message: {}", e);
+                return future::Either::A(future::err(error_response(
+                    http::StatusCode::BAD_REQUEST,
+                )));
+            }
+        };
+        if let Some(topic_arn) = &self.topic_arn {
+            if &message.topic_arn != topic_arn {
+                warn!("rejecting SNS message of topic {}", message.topic_arn);
+                return future::Either::A(future::err(error_response(http::StatusCode::FORBIDDEN)));
+            }
+        }
+
+        let receiver = self.clone();
+        future::Either::B(
+            self.signing_cert(&message.signing_cert_url)
+                .then(move |cert| match cert {
+                    Ok(cert) if message.is_signed_by(&cert) => Ok(message),
+                    Ok(_) => {
+                        warn!("invalid SNS signature of message {}", message.message_id);
+                        Err(http::StatusCode::FORBIDDEN)
+                    }
+                    Err(e) => {
+                        warn!("failed to get SNS signing certificate: {}", e);
+                        Err(http::StatusCode::FORBIDDEN)
+                    }
+                })
+                .map_err(error_response)
+                .and_then(move |message| match &message.message_type[..] {
+                    "SubscriptionConfirmation" => {
+                        future::Either::A(receiver.confirm_subscription(&message))
+                    }
+                    "Notification" => future::Either::B(future::ok(
+                        serde_json::from_str::<WebhookMessage>(&message.message)
+                            .map_err(|e| error!("failed to decode SNS notification: {}", e))
+                            .ok(),
+                    )),
+                    message_type => {
+                        info!("ignoring SNS message: {}", message_type);
+                        future::Either::B(future::ok(None))
+                    }
+                }),
+        )
+    }
+}
+
+pub(crate) fn request_handler(path: String, topic_arn: Option<String>) -> RequestHandler {
+    let receiver = SnsReceiver {
+        client: reqwest::r#async::Client::new(),
+        path,
+        topic_arn,
+        certs: Default::default(),
+    };
+    Arc::new(move |request| {
+        if let Some(error_response) = reject_request(&request, &receiver.path) {
+            return Box::new(future::err(error_response));
+        }
+        let receiver = receiver.clone();
+        Box::new(read_body(request).and_then(move |request| receiver.handle(request)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+
+    fn notification() -> SnsMessage {
+        SnsMessage {
+            message_type: "Notification".to_string(),
+            message_id: "message-id".to_string(),
+            topic_arn: "arn:aws:sns:eu-west-1:123456789012:gerritbot".to_string(),
+            subject: None,
+            message: r#"{"id":"webhook-id"}"#.to_string(),
+            timestamp: "2020-01-01T00:00:00.000Z".to_string(),
+            token: None,
+            subscribe_url: None,
+            signature_version: "1".to_string(),
+            signature: String::new(),
+            signing_cert_url: "https://sns.eu-west-1.amazonaws.com/cert.pem".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_string_to_sign() {
+        let mut message = notification();
+        assert_eq!(
+            message.string_to_sign(),
+            "Message\n{\"id\":\"webhook-id\"}\nMessageId\nmessage-id\n\
+             Timestamp\n2020-01-01T00:00:00.000Z\n\
+             TopicArn\narn:aws:sns:eu-west-1:123456789012:gerritbot\nType\nNotification\n"
+        );
+
+        message.message_type = "SubscriptionConfirmation".to_string();
+        message.token = Some("token".to_string());
+        message.subscribe_url = Some("https://sns.eu-west-1.amazonaws.com/?Action=Confirm".into());
+        assert_eq!(
+            message.string_to_sign(),
+            "Message\n{\"id\":\"webhook-id\"}\nMessageId\nmessage-id\n\
+             SubscribeURL\nhttps://sns.eu-west-1.amazonaws.com/?Action=Confirm\n\
+             Timestamp\n2020-01-01T00:00:00.000Z\nToken\ntoken\n\
+             TopicArn\narn:aws:sns:eu-west-1:123456789012:gerritbot\nType\nSubscriptionConfirmation\n"
+        );
+    }
+
+    #[test]
+    fn test_signature() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut cert = X509::builder().unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let mut message = notification();
+        for (version, digest) in &[("1", MessageDigest::sha1()), ("2", MessageDigest::sha256())] {
+            let mut signer = Signer::new(*digest, &key).unwrap();
+            signer.update(message.string_to_sign().as_bytes()).unwrap();
+            message.signature_version = version.to_string();
+            message.signature = base64::encode(signer.sign_to_vec().unwrap());
+            assert!(message.is_signed_by(&cert));
+        }
+
+        message.message = "tampered".to_string();
+        assert!(!message.is_signed_by(&cert));
+    }
+
+    #[test]
+    fn test_is_sns_url() {
+        assert!(is_sns_url(
+            "https://sns.eu-west-1.amazonaws.com/SimpleNotificationService-1234.pem"
+        ));
+        assert!(is_sns_url(
+            "https://sns.cn-north-1.amazonaws.com.cn/cert.pem"
+        ));
+        assert!(!is_sns_url("http://sns.eu-west-1.amazonaws.com/cert.pem"));
+        assert!(!is_sns_url("https://sns.evil.com/cert.pem"));
+        assert!(!is_sns_url(
+            "https://sns.a.b.amazonaws.com.evil.com/cert.pem"
+        ));
+        assert!(!is_sns_url("https://sns..amazonaws.com/cert.pem"));
+    }
+}
@@ -0,0 +1,230 @@
+//! HTTPS for the webhook server, so that it doesn't need a separate TLS
+//! terminator in front of it.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll, Stream};
+use log::warn;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, SslAcceptor, SslFiletype, SslMethod,
+    SslStream,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Timeout;
+
+use crate::Error;
+
+/// Connections that didn't finish the handshake in this time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of handshakes in progress at the same time.
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// Accepts TLS connections with a certificate and private key.
+#[derive(Clone)]
+pub struct TlsAcceptor(SslAcceptor);
+
+impl TlsAcceptor {
+    /// Load the certificate (chain) and the private key from PEM files.
+    pub fn from_pem_files<C, K>(cert_path: C, key_path: K) -> Result<Self, Error>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        builder.set_certificate_chain_file(cert_path)?;
+        builder.set_private_key_file(key_path, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+        Ok(Self(builder.build()))
+    }
+
+    fn accept<S: Read + Write>(&self, stream: S) -> Handshake<S> {
+        Handshake::Start(self.0.clone(), stream)
+    }
+
+    /// Stream of TLS connections on top of the incoming connections.
+    /// Connections whose handshake fails are logged and skipped.
+    pub(crate) fn incoming<I>(
+        self,
+        connections: I,
+    ) -> impl Stream<Item = TlsStream<I::Item>, Error = io::Error>
+    where
+        I: Stream<Error = io::Error>,
+        I::Item: AsyncRead + AsyncWrite,
+    {
+        connections
+            // errors of single connections must not stop the server
+            .then(|result| {
+                Ok::<_, io::Error>(
+                    result
+                        .map_err(|e| warn!("failed to accept connection: {}", e))
+                        .ok(),
+                )
+            })
+            .filter_map(|stream| stream)
+            .map(move |stream| {
+                Timeout::new(self.accept(stream), HANDSHAKE_TIMEOUT).then(|result| {
+                    Ok::<_, io::Error>(
+                        result
+                            .map_err(|e| warn!("TLS handshake failed: {}", e))
+                            .ok(),
+                    )
+                })
+            })
+            .buffer_unordered(MAX_PENDING_HANDSHAKES)
+            .filter_map(|stream| stream)
+    }
+}
+
+enum Handshake<S> {
+    Start(SslAcceptor, S),
+    InProgress(MidHandshakeSslStream<S>),
+    Done,
+}
+
+impl<S: Read + Write> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TlsStream<S>, io::Error> {
+        let result = match mem::replace(self, Handshake::Done) {
+            Handshake::Start(acceptor, stream) => acceptor.accept(stream),
+            Handshake::InProgress(stream) => stream.handshake(),
+            Handshake::Done => panic!("handshake polled after completion"),
+        };
+        match result {
+            Ok(stream) => Ok(Async::Ready(TlsStream(stream))),
+            // the underlying stream is registered to wake up the task
+            Err(HandshakeError::WouldBlock(stream)) => {
+                *self = Handshake::InProgress(stream);
+                Ok(Async::NotReady)
+            }
+            Err(HandshakeError::SetupFailure(e)) => Err(io::Error::other(e)),
+            Err(HandshakeError::Failure(stream)) => Err(io::Error::other(stream.into_error())),
+        }
+    }
+}
+
+/// An established TLS connection.
+pub(crate) struct TlsStream<S>(SslStream<S>);
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(_) => (),
+            Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => (),
+            Err(e) => match e.into_io_error() {
+                Ok(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Ok(e) => return Err(e),
+                Err(e) => return Err(io::Error::other(e)),
+            },
+        }
+        self.0.get_mut().shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use openssl::x509::{X509NameBuilder, X509};
+    use tokio::net::TcpListener;
+
+    /// Write a self-signed certificate and its key to temporary files.
+    fn self_signed_pem_files() -> (std::path::PathBuf, std::path::PathBuf) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, openssl::hash::MessageDigest::sha256())
+            .unwrap();
+
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("gerritbot-tls-{}-cert.pem", std::process::id()));
+        let key_path = dir.join(format!("gerritbot-tls-{}-key.pem", std::process::id()));
+        std::fs::write(&cert_path, cert.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[test]
+    fn test_missing_pem_files() {
+        assert!(
+            TlsAcceptor::from_pem_files("/nonexistent/cert.pem", "/nonexistent/key.pem").is_err()
+        );
+    }
+
+    #[test]
+    fn test_accept_tls_connection() {
+        let (cert_path, key_path) = self_signed_pem_files();
+        let acceptor = TlsAcceptor::from_pem_files(&cert_path, &key_path).unwrap();
+        std::fs::remove_file(&cert_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            // a failed handshake doesn't stop the server
+            let mut plain = std::net::TcpStream::connect(address).unwrap();
+            plain.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            drop(plain);
+
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            let stream = std::net::TcpStream::connect(address).unwrap();
+            let mut stream = connector.build().connect("localhost", stream).unwrap();
+            stream.write_all(b"hello").unwrap();
+            stream.flush().unwrap();
+            // keep the connection open until the server read the message
+            let mut buf = [0; 1];
+            let _ = stream.read(&mut buf);
+        });
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (stream, _) = runtime
+            .block_on(acceptor.incoming(listener.incoming()).into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        let (_, buf) = runtime
+            .block_on(tokio::io::read_exact(stream.unwrap(), [0; 5]))
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(runtime);
+        client.join().unwrap();
+    }
+}
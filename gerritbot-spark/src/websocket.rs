@@ -0,0 +1,108 @@
+//! Websocket client to receive events of a Webex device, on top of
+//! tokio-tungstenite.
+//!
+//! Text messages are exchanged, pings of the server are answered by
+//! tungstenite and the connection is kept alive by pings of its own.
+
+use std::time::Duration;
+
+use futures::stream::SplitStream;
+use futures::sync::mpsc;
+use futures::{future, try_ready, Async, Future, Poll, Sink as _, Stream};
+use log::{debug, error};
+use tokio::net::TcpStream;
+use tokio::timer::Interval;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::Error;
+
+/// How often the connection is pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Open a websocket connection to the url.
+pub(crate) fn connect(url: &str) -> impl Future<Item = Connection, Error = Error> {
+    future::result(
+        url::Url::parse(url).map_err(|e| Error::WebSocket(format!("invalid url: {}", e))),
+    )
+    .and_then(|url| {
+        tokio_tungstenite::connect_async(url)
+            .map_err(|e| Error::WebSocket(format!("failed to connect: {}", e)))
+    })
+    .map(|(stream, _response)| Connection::new(stream))
+}
+
+/// An open websocket connection. As stream, it produces the received text
+/// messages and ends when the server closes the connection.
+pub(crate) struct Connection {
+    messages: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    outgoing: mpsc::UnboundedSender<Message>,
+    ping_interval: Interval,
+}
+
+impl Connection {
+    fn new(stream: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
+        let (sink, messages) = stream.split();
+        let (outgoing, outgoing_messages) = mpsc::unbounded();
+
+        // the connection is closed when the connection and thus the sender
+        // are dropped
+        tokio::spawn(
+            outgoing_messages
+                .forward(sink.sink_map_err(|e| error!("failed to send websocket message: {}", e)))
+                .map(|_| debug!("websocket connection closed")),
+        );
+
+        Self {
+            messages,
+            outgoing,
+            ping_interval: Interval::new_interval(PING_INTERVAL),
+        }
+    }
+
+    fn send(&self, message: Message) -> Result<(), Error> {
+        self.outgoing
+            .unbounded_send(message)
+            .map_err(|_| Error::WebSocket("connection closed".to_string()))
+    }
+
+    pub(crate) fn send_text(&self, text: String) -> Result<(), Error> {
+        self.send(Message::Text(text))
+    }
+}
+
+impl Stream for Connection {
+    type Item = String;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, Error> {
+        while let Async::Ready(Some(_)) = self
+            .ping_interval
+            .poll()
+            .map_err(|e| Error::WebSocket(format!("ping timer failed: {}", e)))?
+        {
+            self.send(Message::Ping(Vec::new()))?;
+        }
+
+        loop {
+            let message = try_ready!(self
+                .messages
+                .poll()
+                .map_err(|e| Error::WebSocket(e.to_string())));
+            match message {
+                Some(Message::Text(text)) => return Ok(Async::Ready(Some(text))),
+                Some(Message::Binary(data)) => {
+                    return String::from_utf8(data)
+                        .map(|text| Async::Ready(Some(text)))
+                        .map_err(|e| Error::WebSocket(format!("invalid message: {}", e)));
+                }
+                Some(Message::Ping(_)) | Some(Message::Pong(_)) => (),
+                Some(Message::Close(_)) => {
+                    debug!("websocket connection closed by server");
+                    return Ok(Async::Ready(None));
+                }
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
@@ -2,6 +2,7 @@
 #![recursion_limit = "128"]
 #![deny(bare_trait_objects)]
 
+use std::fmt;
 use std::io::{BufRead as _, BufReader, Write as _};
 use std::path::PathBuf;
 
@@ -87,30 +88,23 @@ impl From<SimpleInputMessage> for spark::Message {
 #[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 struct SimpleOutputMessage {
-    email: spark::Email,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<spark::Email>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_id: Option<spark::RoomId>,
     text: String,
 }
 
-#[derive(Clone)]
-enum ConsoleSparkClient {
-    Plain,
-    Json,
-}
-
-impl bot::SparkClient for ConsoleSparkClient {
-    type ReplyFuture = future::FutureResult<(), spark::Error>;
-    fn send_message(&self, email: &spark::EmailRef, msg: &str) -> Self::ReplyFuture {
+impl ConsoleSparkClient {
+    fn write_message(&self, recipient: impl fmt::Display, message: SimpleOutputMessage) {
         // Write synchronously and crash if writing fails. There's no point in
         // error handling here.
         match self {
             ConsoleSparkClient::Plain => {
-                writeln!(std::io::stdout(), "{}: {}", email, msg).expect("writing to stdout failed")
+                writeln!(std::io::stdout(), "{}: {}", recipient, message.text)
+                    .expect("writing to stdout failed")
             }
             ConsoleSparkClient::Json => {
-                let message = SimpleOutputMessage {
-                    email: email.to_owned(),
-                    text: msg.to_string(),
-                };
                 serde_json::to_writer(std::io::stdout(), &message)
                     .expect("writing JSON to stdout failed");
                 std::io::stdout()
@@ -118,7 +112,35 @@ impl bot::SparkClient for ConsoleSparkClient {
                     .expect("writing to stdout failed");
             }
         }
-        future::ok(())
+    }
+}
+
+#[derive(Clone)]
+enum ConsoleSparkClient {
+    Plain,
+    Json,
+}
+
+impl bot::SparkClient for ConsoleSparkClient {
+    type ReplyFuture = future::FutureResult<Option<spark::MessageId>, spark::Error>;
+    fn send_message(&self, email: &spark::EmailRef, msg: &str) -> Self::ReplyFuture {
+        let message = SimpleOutputMessage {
+            email: Some(email.to_owned()),
+            room_id: None,
+            text: msg.to_string(),
+        };
+        self.write_message(email, message);
+        future::ok(None)
+    }
+
+    fn send_room_message(&self, room_id: &spark::RoomIdRef, msg: &str) -> Self::ReplyFuture {
+        let message = SimpleOutputMessage {
+            email: None,
+            room_id: Some(room_id.to_owned()),
+            text: msg.to_string(),
+        };
+        self.write_message(room_id, message);
+        future::ok(None)
     }
 }
 
@@ -143,18 +165,20 @@ fn main() {
             format!("{}:{}", args.hostname, args.port),
             args.username.clone(),
             args.identity_file.clone(),
+            None,
         )
         .unwrap_or_else(|e| {
             error!("failed to connect to gerrit: {}", e);
             std::process::exit(1);
         })
     };
+    let gerrit_command_runner = gerrit::CommandRunner::new(connect_to_gerrit());
     let gerrit_event_stream = gerrit::extended_event_stream(
         connect_to_gerrit(),
-        connect_to_gerrit(),
+        Default::default(),
+        gerrit_command_runner.clone(),
         bot::request_extended_gerrit_info,
     );
-    let gerrit_command_runner = gerrit::CommandRunner::new(connect_to_gerrit());
     let bot_builder = bot::Builder::new(bot::State::new());
     let bot_builder = {
         if let Some(format_script) = args.format_script {
@@ -173,7 +197,7 @@ fn main() {
         stream::iter_ok::<_, ()>(
             BufReader::new(std::io::stdin())
                 .lines()
-                .filter_map(Result::ok),
+                .map_while(Result::ok),
         )
         .forward(stdin_lines_sender.sink_map_err(|e| error!("sink error: {}", e)))
         .wait()
@@ -224,9 +248,10 @@ fn main() {
         }
     };
 
-    let spark_messages = stdin_lines
+    let spark_events = stdin_lines
         .filter(|line| !line.is_empty())
-        .filter_map(message_from_line);
+        .filter_map(message_from_line)
+        .map(spark::Event::Message);
     let spark_client = if use_json {
         ConsoleSparkClient::Json
     } else {
@@ -234,5 +259,5 @@ fn main() {
     };
 
     let bot = bot_builder.build(gerrit_command_runner, spark_client);
-    tokio::run(bot.run(gerrit_event_stream, spark_messages));
+    tokio::run(bot.run(gerrit_event_stream, spark_events));
 }
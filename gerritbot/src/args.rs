@@ -1,4 +1,6 @@
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
 use std::path::PathBuf;
 
 use log::debug;
@@ -6,6 +8,8 @@ use rusoto_core::Region;
 use serde::Deserialize;
 use structopt::StructOpt;
 
+use gerritbot_spark as spark;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub gerrit: GerritConfig,
@@ -13,32 +17,295 @@ pub struct Config {
     pub bot: BotConfig,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Deserialize, Clone)]
 pub struct GerritConfig {
     pub host: String,
     pub username: String,
     pub priv_key_path: PathBuf,
+    /// Passphrase of the private key, if it is encrypted.
+    pub passphrase: Option<String>,
+    /// File to read the private key passphrase from. Takes precedence over
+    /// `passphrase`.
+    pub passphrase_file: Option<PathBuf>,
+    /// Number of SSH connections used to run Gerrit commands in parallel.
+    #[serde(default = "default_command_connections")]
+    pub command_connections: usize,
+    /// Timeout for Gerrit commands in seconds. No timeout if not set.
+    pub command_timeout: Option<u64>,
+    /// How often a Gerrit command is retried after a connection failure or
+    /// timeout.
+    pub command_retries: Option<u32>,
+    /// Regular expressions matching the projects whose events are processed.
+    /// Events of all projects are processed if empty.
+    #[serde(default)]
+    pub projects: Vec<String>,
+    /// Stream events of all types instead of the handled ones only. Events of
+    /// unknown types are only counted and logged.
+    #[serde(default)]
+    pub all_events: bool,
+    /// SSH jump host (`[user@]host[:port]`) to connect to Gerrit through.
+    pub proxy_jump: Option<String>,
+    /// Web URL of Gerrit that links are built from. Derived from the change
+    /// URLs if not set.
+    pub base_url: Option<String>,
+}
+
+// The passphrase must not end up in the debug log of the config.
+impl fmt::Debug for GerritConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GerritConfig")
+            .field("host", &self.host)
+            .field("username", &self.username)
+            .field("priv_key_path", &self.priv_key_path)
+            .field(
+                "passphrase",
+                &self.passphrase.as_ref().map(|_| "<redacted>"),
+            )
+            .field("passphrase_file", &self.passphrase_file)
+            .field("command_connections", &self.command_connections)
+            .field("command_timeout", &self.command_timeout)
+            .field("command_retries", &self.command_retries)
+            .field("projects", &self.projects)
+            .field("all_events", &self.all_events)
+            .field("proxy_jump", &self.proxy_jump)
+            .field("base_url", &self.base_url)
+            .finish()
+    }
+}
+
+fn default_command_connections() -> usize {
+    1
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct SparkConfig {
     pub bot_token: String,
     pub api_uri: String,
+    /// Not needed in websocket mode.
+    #[serde(default)]
     pub webhook_url: String,
+    /// Secret Webex signs webhook posts with. Posts without a valid signature
+    /// are rejected.
+    pub webhook_secret: Option<String>,
+    /// Retry messages whose sending failed.
+    pub retry_queue: Option<RetryQueueConfig>,
     pub mode: ModeConfig,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetryQueueConfig {
+    /// File the queued messages are stored in.
+    pub path: PathBuf,
+    /// Maximum number of queued messages. The oldest ones are dropped when
+    /// the queue is full.
+    #[serde(default = "default_retry_queue_capacity")]
+    pub capacity: usize,
+}
+
+fn default_retry_queue_capacity() -> usize {
+    1000
+}
+
+fn default_webhook_path() -> String {
+    "/".to_string()
+}
+
+fn deserialize_one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsConfig {
+    /// PEM file with the certificate, optionally followed by the rest of its
+    /// chain.
+    pub cert_path: PathBuf,
+    /// PEM file with the private key of the certificate.
+    pub key_path: PathBuf,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub enum ModeConfig {
-    Direct { endpoint: std::net::SocketAddr },
-    Sqs { uri: String, region: Region },
+    Direct {
+        /// One or more addresses to listen on. Unix sockets are given as
+        /// `unix:<path>`.
+        #[serde(deserialize_with = "deserialize_one_or_many")]
+        endpoint: Vec<spark::ListenAddress>,
+        /// Path webhook posts are accepted at.
+        #[serde(default = "default_webhook_path")]
+        path: String,
+        /// Serve HTTPS instead of plain HTTP.
+        #[serde(default)]
+        tls: Option<TlsConfig>,
+    },
+    Sqs {
+        uri: String,
+        region: Region,
+    },
+    /// Pull webhook posts from a Google Cloud Pub/Sub subscription.
+    PubSub {
+        /// Full name of the subscription:
+        /// `projects/<project>/subscriptions/<name>`.
+        subscription: String,
+        /// Key file of a service account. If not set, the service account of
+        /// the GCE instance is used.
+        #[serde(default)]
+        credentials: Option<PathBuf>,
+    },
+    /// Consume webhook posts from a Kafka topic.
+    Kafka {
+        /// Addresses of the bootstrap brokers as `host:port`.
+        #[serde(deserialize_with = "deserialize_one_or_many")]
+        brokers: Vec<String>,
+        topic: String,
+        /// Consumer group the offsets are committed for.
+        group: String,
+    },
+    /// Receive webhook posts pushed by Amazon SNS over HTTP(S).
+    Sns {
+        /// One or more addresses to listen on, like in direct mode.
+        #[serde(deserialize_with = "deserialize_one_or_many")]
+        endpoint: Vec<spark::ListenAddress>,
+        /// Path SNS messages are accepted at.
+        #[serde(default = "default_webhook_path")]
+        path: String,
+        /// If set, only messages of this topic are accepted.
+        #[serde(default)]
+        topic_arn: Option<String>,
+        /// Serve HTTPS instead of plain HTTP.
+        #[serde(default)]
+        tls: Option<TlsConfig>,
+    },
+    /// Receive messages over the websocket of a Webex device, which doesn't
+    /// need a public webhook url.
+    Websocket,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct BotConfig {
     pub msg_expiration: u64,
     pub msg_capacity: usize,
+    /// Size of the memory of the participants of changes and the threads of
+    /// their notifications. Defaults to 10000 changes kept for 14 days.
+    pub change_tracker: Option<ChangeTrackerConfig>,
     pub format_script: Option<String>,
+    /// Tera templates used instead of the functions of the format script
+    /// they are named after, e.g. `format_comment_added`.
+    #[serde(default)]
+    pub format_templates: BTreeMap<String, String>,
+    /// Format scripts used instead of `format_script` for the changes of
+    /// matching projects.
+    #[serde(default)]
+    pub project_format_scripts: Vec<ProjectFormatScriptConfig>,
+    /// Send Adaptive Cards produced by the format script along with the
+    /// notifications.
+    #[serde(default)]
+    pub adaptive_cards: bool,
+    /// Append notifications about merged and abandoned changes to the first
+    /// notification about the change instead of sending a new message.
+    #[serde(default)]
+    pub edit_notifications: bool,
+    /// Gerrit accounts linked by users with `link gerrit <email>` need the
+    /// approval of an admin. Enabled by default.
+    #[serde(default = "default_verify_account_links")]
+    pub verify_account_links: bool,
+    /// Send people involved in Gerrit events who don't use the bot yet a
+    /// one-time invitation.
+    #[serde(default)]
+    pub auto_invite: bool,
+    /// People who never get an invitation.
+    #[serde(default)]
+    pub invite_opt_out: Vec<gerritbot_spark::Email>,
+    /// Users allowed to run admin commands, e.g. to subscribe rooms to
+    /// projects.
+    #[serde(default)]
+    pub admins: Vec<gerritbot_spark::Email>,
+    /// Labels and their values users may vote with from chat, e.g.
+    /// `Code-Review: [-1, 1]`. Voting is disabled without any.
+    #[serde(default)]
+    pub review_labels: BTreeMap<String, Vec<i32>>,
+    /// Aliases of commands, e.g. `mute: disable`. An alias may replace the
+    /// start of a command with arguments, too.
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Number of messages sent at a time. Defaults to 10.
+    pub send_concurrency: Option<usize>,
+    /// Timeout for sending a message in seconds. Defaults to 5.
+    pub send_timeout: Option<u64>,
+    /// Interval in minutes to look for merge conflicts of open changes.
+    /// Disabled by default.
+    pub merge_conflict_check_interval: Option<u64>,
+    /// Days without activity after which owners are reminded of their open
+    /// changes. Disabled by default.
+    pub stale_review_days: Option<u32>,
+    /// Interval in hours to look for changes without activity. Defaults to
+    /// 24.
+    pub stale_review_check_interval: Option<u64>,
+    /// Days after which added reviewers, who didn't vote yet, are reminded of
+    /// the change. Disabled by default.
+    pub reviewer_reminder_days: Option<u32>,
+    /// Number of reminders a reviewer gets at most. Defaults to 3.
+    pub reviewer_reminder_max: Option<u32>,
+    /// Flags enabled by default for changes of matching projects and
+    /// branches. They apply to users who didn't change their flags.
+    #[serde(default)]
+    pub project_flags: Vec<ProjectFlagsConfig>,
+    /// Let format scripts query Gerrit with `gerrit_query`, with this timeout
+    /// in seconds for a query. Disabled by default.
+    pub format_query_timeout: Option<u64>,
+    /// Number of previous saves of the state, which are kept as
+    /// `state.json.1` to `state.json.<n>`. Disabled by default.
+    pub state_backups: Option<usize>,
+    /// Minimum interval in minutes between two rotations of the backups.
+    /// Defaults to 60.
+    pub state_backup_interval: Option<u64>,
+    /// Directory of the state files admins export and import with `admin
+    /// export` and `admin import`. Disabled by default.
+    pub state_export_dir: Option<PathBuf>,
+    /// Interval in minutes to save the state, also without changes asking
+    /// for it. Disabled by default.
+    pub autosave_interval: Option<u64>,
+}
+
+fn default_verify_account_links() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChangeTrackerConfig {
+    /// Number of changes remembered at most.
+    pub capacity: usize,
+    /// Time in seconds after which a change without activity is forgotten.
+    pub expiration: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProjectFlagsConfig {
+    /// Regular expression for the project. Matches all projects if not set.
+    pub project: Option<String>,
+    /// Regular expression for the branch. Matches all branches if not set.
+    pub branch: Option<String>,
+    pub flags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProjectFormatScriptConfig {
+    /// Regular expression for the project.
+    pub project: String,
+    pub format_script: String,
 }
 
 /// Cisco Webex Teams <> Gerrit Bot
@@ -51,6 +318,22 @@ pub struct Args {
     /// Dump default format script and exit
     #[structopt(long)]
     pub dump_format_script: bool,
+    #[structopt(subcommand)]
+    pub command: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub enum Subcommand {
+    /// Print the messages about a recorded Gerrit event and exit
+    Format {
+        /// Format script, the built-in one if not given
+        #[structopt(long, parse(from_os_str))]
+        script: Option<PathBuf>,
+        /// JSON file with the event, as emitted by `gerrit stream-events`
+        #[structopt(long, parse(from_os_str))]
+        event: PathBuf,
+    },
 }
 
 pub fn parse_args() -> Args {
@@ -71,6 +354,15 @@ pub fn parse_config(path: PathBuf) -> Config {
         shellexpand::tilde(&config.gerrit.priv_key_path.to_string_lossy())
             .into_owned()
             .into();
+    // read the private key passphrase from a file if configured
+    if let Some(passphrase_file) = config.gerrit.passphrase_file.as_ref() {
+        let passphrase_file = shellexpand::tilde(&passphrase_file.to_string_lossy()).into_owned();
+        let passphrase = fs::read_to_string(&passphrase_file).unwrap_or_else(|e| {
+            eprintln!("Could not read passphrase file {}: {}", passphrase_file, e);
+            ::std::process::exit(1)
+        });
+        config.gerrit.passphrase = Some(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string());
+    }
     debug!("{:#?}", config);
     config
 }
@@ -2,8 +2,11 @@
 #![recursion_limit = "128"]
 #![deny(bare_trait_objects)]
 
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use futures::sync::oneshot;
 use futures::{future, future::lazy, Future, Stream};
 use log::{debug, error, info, warn};
 
@@ -12,33 +15,193 @@ use gerritbot::args;
 use gerritbot_gerrit as gerrit;
 use gerritbot_spark as spark;
 
-/// Create spark message stream. Returns a future representing a webhook server
-/// and a stream of messages.
-fn create_spark_message_stream(
+/// How often the Gerrit stream and Webex API metrics are logged.
+const METRICS_LOG_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+type SparkEventStream = Box<dyn Stream<Item = spark::Event, Error = ()> + Send>;
+
+/// Create spark event stream. Returns a future representing a webhook server,
+/// a stream of messages and card actions, and in direct mode the sender to
+/// shut down the webhook server.
+fn create_spark_event_stream(
     spark_config: args::SparkConfig,
     spark_client: spark::Client,
 ) -> (
     impl Future<Item = (), Error = ()>,
-    Box<dyn Stream<Item = spark::Message, Error = ()> + Send>,
+    SparkEventStream,
+    Option<oneshot::Sender<()>>,
 ) {
     match spark_config.mode {
         args::ModeConfig::Direct {
-            endpoint: listen_address,
+            endpoint: listen_addresses,
+            path,
+            tls,
+        } => {
+            let options = spark::WebhookServerOptions {
+                path,
+                secret: spark_config.webhook_secret,
+                tls: tls.map(load_tls_acceptor),
+            };
+            let spark::WebhookServer {
+                server,
+                events,
+                shutdown,
+            } = spark::start_webhook_server_with_options(&listen_addresses, spark_client, options)
+                .unwrap_or_else(|e| {
+                    error!("failed to start webhook server: {}", e);
+                    std::process::exit(1);
+                });
+            (
+                future::Either::A(future::Either::A(
+                    server.map_err(|e| error!("webhook server error: {}", e)),
+                )),
+                Box::new(events),
+                Some(shutdown),
+            )
+        }
+        args::ModeConfig::Sns {
+            endpoint: listen_addresses,
+            path,
+            topic_arn,
+            tls,
         } => {
-            let spark::WebhookServer { server, messages } =
-                spark::start_webhook_server(&listen_address, spark_client);
+            let options = spark::SnsServerOptions {
+                path,
+                topic_arn,
+                tls: tls.map(load_tls_acceptor),
+            };
+            let spark::WebhookServer {
+                server,
+                events,
+                shutdown,
+            } = spark::start_sns_server(&listen_addresses, spark_client, options).unwrap_or_else(
+                |e| {
+                    error!("failed to start SNS server: {}", e);
+                    std::process::exit(1);
+                },
+            );
             (
-                future::Either::A(server.map_err(|e| error!("webhook server error: {}", e))),
-                Box::new(messages),
+                future::Either::A(future::Either::B(
+                    server.map_err(|e| error!("SNS server error: {}", e)),
+                )),
+                Box::new(events),
+                Some(shutdown),
             )
         }
         args::ModeConfig::Sqs { uri, region } => (
-            future::Either::B(future::empty()),
+            future::Either::B(future::ok(())),
             Box::new(spark::sqs_event_stream(uri, region, spark_client)),
+            None,
+        ),
+        args::ModeConfig::PubSub {
+            subscription,
+            credentials,
+        } => {
+            let credentials = match credentials {
+                Some(key_path) => spark::PubSubCredentials::from_key_file(&key_path)
+                    .unwrap_or_else(|e| {
+                        error!(
+                            "failed to load Pub/Sub credentials from '{}': {}",
+                            key_path.display(),
+                            e
+                        );
+                        std::process::exit(1);
+                    }),
+                None => spark::PubSubCredentials::metadata_server(),
+            };
+            (
+                future::Either::B(future::ok(())),
+                Box::new(spark::pubsub_event_stream(
+                    subscription,
+                    credentials,
+                    spark_client,
+                )),
+                None,
+            )
+        }
+        args::ModeConfig::Kafka {
+            brokers,
+            topic,
+            group,
+        } => (
+            future::Either::B(future::ok(())),
+            Box::new(spark::kafka_event_stream(
+                brokers,
+                topic,
+                group,
+                spark_client,
+            )),
+            None,
+        ),
+        args::ModeConfig::Websocket => (
+            future::Either::B(future::ok(())),
+            Box::new(spark::device_event_stream(spark_client)),
+            None,
         ),
     }
 }
 
+fn load_tls_acceptor(tls_config: args::TlsConfig) -> spark::TlsAcceptor {
+    spark::TlsAcceptor::from_pem_files(&tls_config.cert_path, &tls_config.key_path).unwrap_or_else(
+        |e| {
+            error!("failed to load TLS certificate: {}", e);
+            std::process::exit(1);
+        },
+    )
+}
+
+/// Resolves when the process receives SIGINT or SIGTERM.
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    Signal::new(SIGINT)
+        .flatten_stream()
+        .select(Signal::new(SIGTERM).flatten_stream())
+        .into_future()
+        .map(|_| ())
+        // keep running if the signals can't be handled
+        .or_else(|(e, _)| {
+            error!("failed to listen for signals: {}", e);
+            future::empty()
+        })
+}
+
+/// End the stream when the signal resolves.
+fn take_until<S, F>(stream: S, signal: F) -> impl Stream<Item = S::Item, Error = S::Error>
+where
+    S: Stream,
+    F: Future,
+{
+    stream
+        .map(Some)
+        .select(signal.then(|_| Ok(None)).into_stream())
+        .take_while(|item| Ok(item.is_some()))
+        .filter_map(|item| item)
+}
+
+/// Print the messages of the format script about the event in the file.
+fn format_event(script: Option<PathBuf>, event: PathBuf) {
+    let read = |path: &PathBuf| {
+        std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Could not read {}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    };
+    let script = script.as_ref().map(read);
+    let messages = bot::render_event(script.as_deref(), &read(&event)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    for (function, message) in messages {
+        println!("--- {} ---", function);
+        match message {
+            Ok(Some(message)) => println!("{}\n", message),
+            Ok(None) => println!("(no message)\n"),
+            Err(e) => println!("(error: {})\n", e),
+        }
+    }
+}
+
 fn main() {
     env_logger::init_from_env(
         env_logger::Env::default()
@@ -54,26 +217,39 @@ fn main() {
         return;
     }
 
+    if let Some(args::Subcommand::Format { script, event }) = args.command {
+        format_event(script, event);
+        return;
+    }
+
     let args::Config {
         gerrit: gerrit_config,
         bot: bot_config,
         spark: spark_config,
     } = args::parse_config(args.config);
 
+    // refuse to run next to another instance using the same state
+    let _state_lock = bot::StateLock::acquire("state.json").unwrap_or_else(|err| {
+        error!("Could not lock 'state.json': {:?}", err);
+        std::process::exit(1);
+    });
+
     // load or create a new bot
     let bot_state = bot::State::load("state.json")
-        .map(|state| {
+        .inspect(|state| {
             info!(
                 "Loaded bot from 'state.json' with {} user(s).",
                 state.num_users()
             );
-            state
         })
         .unwrap_or_else(|err| {
             warn!("Could not load bot from 'state.json': {:?}", err);
             bot::State::new()
         });
 
+    // events after this point in time are recovered when connecting to gerrit
+    let last_event_created_on = bot_state.last_event_created_on();
+
     let bot_builder = bot::Builder::new(bot_state);
     let bot_builder = {
         if bot_config.msg_expiration != 0 && bot_config.msg_capacity != 0 {
@@ -89,6 +265,19 @@ fn main() {
             bot_builder
         }
     };
+    let bot_builder = match bot_config.change_tracker {
+        Some(change_tracker) => {
+            debug!(
+                "Change tracker: capacity - {}, expiration - {} sec",
+                change_tracker.capacity, change_tracker.expiration
+            );
+            bot_builder.with_change_tracker(
+                change_tracker.capacity,
+                Duration::from_secs(change_tracker.expiration),
+            )
+        }
+        None => bot_builder,
+    };
     let bot_builder = {
         if let Some(format_script) = bot_config.format_script {
             bot_builder
@@ -101,59 +290,270 @@ fn main() {
             bot_builder
         }
     };
+    let bot_builder = if bot_config.format_templates.is_empty() {
+        bot_builder
+    } else {
+        bot_builder
+            .with_format_templates(&bot_config.format_templates)
+            .unwrap_or_else(|err| {
+                error!("Failed to set format templates: {:?}", err);
+                std::process::exit(1);
+            })
+    };
+    let bot_builder =
+        bot_config
+            .project_format_scripts
+            .iter()
+            .fold(bot_builder, |bot_builder, config| {
+                bot_builder
+                    .with_project_format_script(&config.project, &config.format_script)
+                    .unwrap_or_else(|err| {
+                        error!("Failed to set format script: {:?}", err);
+                        std::process::exit(1);
+                    })
+            });
+    let bot_builder = if bot_config.adaptive_cards {
+        bot_builder.with_adaptive_cards()
+    } else {
+        bot_builder
+    };
+    let bot_builder = if bot_config.edit_notifications {
+        bot_builder.with_edited_notifications()
+    } else {
+        bot_builder
+    };
+    let bot_builder = if bot_config.verify_account_links {
+        bot_builder
+    } else {
+        bot_builder.without_account_link_verification()
+    };
+    let bot_builder = if bot_config.auto_invite {
+        bot_builder.with_auto_invite(bot_config.invite_opt_out.clone())
+    } else {
+        bot_builder
+    };
+    let bot_builder = bot_builder.with_admins(bot_config.admins.clone());
+    let bot_builder = bot_builder.with_review_labels(bot_config.review_labels.clone());
+    let bot_builder = bot_builder.with_aliases(bot_config.aliases.clone());
+    let bot_builder = match gerrit_config.base_url.clone() {
+        Some(base_url) => bot_builder.with_gerrit_base_url(base_url),
+        None => bot_builder,
+    };
+    let bot_builder = match bot_config.send_concurrency {
+        Some(send_concurrency) => bot_builder.with_send_concurrency(send_concurrency),
+        None => bot_builder,
+    };
+    let bot_builder = match bot_config.send_timeout {
+        Some(send_timeout) => bot_builder.with_send_timeout(Duration::from_secs(send_timeout)),
+        None => bot_builder,
+    };
+    let bot_builder = match bot_config.merge_conflict_check_interval {
+        Some(interval) => bot_builder.with_merge_conflict_check(Duration::from_secs(interval * 60)),
+        None => bot_builder,
+    };
+    let bot_builder = match bot_config.stale_review_days {
+        Some(days) => bot_builder.with_stale_review_reminders(
+            days,
+            bot_config
+                .stale_review_check_interval
+                .map(|interval| Duration::from_secs(interval * 60 * 60)),
+        ),
+        None => bot_builder,
+    };
+    let project_flags = bot_config
+        .project_flags
+        .iter()
+        .map(|config| {
+            bot::ProjectFlags::new(
+                config.project.as_deref(),
+                config.branch.as_deref(),
+                &config.flags,
+            )
+        })
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+    let bot_builder = bot_builder.with_project_flags(project_flags);
+    let bot_builder = match bot_config.reviewer_reminder_days {
+        Some(days) => bot_builder.with_reviewer_reminders(days, bot_config.reviewer_reminder_max),
+        None => bot_builder,
+    };
+    let bot_builder = match bot_config.state_backups {
+        Some(state_backups) => bot_builder.with_state_backups(state_backups),
+        None => bot_builder,
+    };
+    let bot_builder = match bot_config.state_backup_interval {
+        Some(interval) => {
+            bot_builder.with_state_backup_interval(Duration::from_secs(interval * 60))
+        }
+        None => bot_builder,
+    };
+    let bot_builder = match &bot_config.state_export_dir {
+        Some(dir) => bot_builder.with_state_export_dir(dir),
+        None => bot_builder,
+    };
+    let bot_builder = match bot_config.autosave_interval {
+        Some(interval) => bot_builder.with_autosave(Duration::from_secs(interval * 60)),
+        None => bot_builder,
+    };
+    let gerrit_proxy_jump: Option<gerrit::ProxyJump> =
+        gerrit_config.proxy_jump.as_ref().map(|proxy_jump| {
+            proxy_jump.parse().unwrap_or_else(|e| {
+                error!("{}", e);
+                std::process::exit(1);
+            })
+        });
     let connect_to_gerrit = || {
         info!(
             "Connecting to gerrit with username {} at {}",
             gerrit_config.username, gerrit_config.host
         );
-        gerrit::Connection::connect(
+        gerrit::Connection::connect_via(
             gerrit_config.host.clone(),
             gerrit_config.username.clone(),
             gerrit_config.priv_key_path.clone(),
+            gerrit_config.passphrase.clone(),
+            gerrit_proxy_jump.clone(),
         )
         .unwrap_or_else(|e| {
             error!("failed to connect to gerrit: {}", e);
             std::process::exit(1);
         })
     };
+    let gerrit_command_options = {
+        let defaults = gerrit::CommandOptions::default();
+        gerrit::CommandOptions {
+            timeout: gerrit_config.command_timeout.map(Duration::from_secs),
+            max_retries: gerrit_config
+                .command_retries
+                .unwrap_or(defaults.max_retries),
+        }
+    };
+    let gerrit_command_runner = gerrit::CommandRunner::with_connections(
+        (0..gerrit_config.command_connections.max(1))
+            .map(|_| connect_to_gerrit())
+            .collect(),
+        gerrit_command_options,
+    );
+    let bot_builder = match bot_config.format_query_timeout {
+        Some(timeout) => bot_builder
+            .with_format_queries(Duration::from_secs(timeout))
+            .unwrap_or_else(|err| {
+                error!("Failed to set format queries: {:?}", err);
+                std::process::exit(1);
+            }),
+        None => bot_builder,
+    };
+    let gerrit_metrics = Arc::new(gerrit::Counters::default());
+    let spark_metrics = Arc::new(spark::Counters::default());
+    let gerrit_event_stream_options = gerrit::EventStreamOptions {
+        since: last_event_created_on,
+        projects: gerrit::ProjectFilter::new(&gerrit_config.projects).unwrap_or_else(|e| {
+            error!("invalid project filter: {}", e);
+            std::process::exit(1);
+        }),
+        metrics: gerrit_metrics.clone(),
+        all_events: gerrit_config.all_events,
+    };
     let gerrit_event_stream = gerrit::extended_event_stream(
         connect_to_gerrit(),
-        connect_to_gerrit(),
+        gerrit_event_stream_options,
+        gerrit_command_runner.clone(),
         bot::request_extended_gerrit_info,
     );
-    let gerrit_command_runner = gerrit::CommandRunner::new(connect_to_gerrit());
 
     // run rest of the logic while the tokio runtime is running
-    tokio::run(lazy(move || {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap_or_else(|e| {
+        error!("failed to start tokio runtime: {}", e);
+        std::process::exit(1);
+    });
+    let _ = runtime.block_on(lazy(move || {
+        tokio::spawn(
+            tokio::timer::Interval::new(
+                Instant::now() + METRICS_LOG_INTERVAL,
+                METRICS_LOG_INTERVAL,
+            )
+            .for_each({
+                let spark_metrics = spark_metrics.clone();
+                move |_| {
+                    info!("Gerrit stream metrics: {}", gerrit_metrics);
+                    info!("Webex API metrics: {}", spark_metrics);
+                    Ok(())
+                }
+            })
+            .map_err(|e| error!("metrics timer failed: {}", e)),
+        );
+
         let webhook_url = spark_config.webhook_url.clone();
+        let webhook_secret = spark_config.webhook_secret.clone();
+        let retry_queue_config = spark_config.retry_queue.clone();
+        let websocket_mode = matches!(spark_config.mode, args::ModeConfig::Websocket);
 
         spark::Client::new(spark_config.api_uri.clone(), spark_config.bot_token.clone())
             .map_err(|e| error!("failed to create spark client: {}", e))
+            .map(move |client| client.with_metrics(spark_metrics))
+            .map(move |client| match retry_queue_config {
+                Some(config) => {
+                    let retry_queue = spark::RetryQueue::load(config.path, config.capacity);
+                    let client = client.with_retry_queue(retry_queue.clone());
+                    tokio::spawn(retry_queue.run(client.clone()));
+                    client
+                }
+                None => client,
+            })
             .and_then(move |client| {
                 info!("created spark client: {}", client.id());
 
                 let next_client = client.clone();
 
-                client
-                    .register_webhook(&webhook_url)
-                    .map_err(|e| error!("failed to register webhook: {}", e))
-                    .map(move |()| next_client)
+                if websocket_mode {
+                    // messages are received without a webhook
+                    return future::Either::A(future::ok(next_client));
+                }
+                future::Either::B(
+                    client
+                        .register_webhook(&webhook_url, webhook_secret.as_deref())
+                        .map_err(|e| error!("failed to register webhook: {}", e))
+                        .map(move |()| next_client),
+                )
             })
             .and_then(move |spark_client| {
-                let (spark_webhook_server, spark_messages) =
-                    create_spark_message_stream(spark_config.clone(), spark_client.clone());
+                let (spark_webhook_server, spark_events, webhook_shutdown) =
+                    create_spark_event_stream(spark_config.clone(), spark_client.clone());
+
+                let shutdown = shutdown_signal().shared();
+                // the webhook server hands over the posts in flight and then
+                // ends its stream, other streams just end
+                let spark_events: SparkEventStream = if webhook_shutdown.is_some() {
+                    spark_events
+                } else {
+                    Box::new(take_until(spark_events, shutdown.clone()))
+                };
+                let gerrit_event_stream = take_until(gerrit_event_stream, shutdown.clone());
+                tokio::spawn(shutdown.then(move |_| {
+                    info!("Shutting down");
+                    if let Some(webhook_shutdown) = webhook_shutdown {
+                        let _ = webhook_shutdown.send(());
+                    }
+                    Ok(())
+                }));
 
                 let bot = bot_builder.build(gerrit_command_runner, spark_client);
 
                 fn ignore<T>(_: T) {}
 
-                // run webhook server or bot to completion - they should never
-                // exit unless there's an error, in which case they should print
-                // that
+                // run webhook server and bot to completion - they only exit
+                // on shutdown, after the pending messages are sent, or if
+                // there's an error, in which case they should print that
                 spark_webhook_server
-                    .select(bot.run(gerrit_event_stream, spark_messages))
+                    .join(bot.run(gerrit_event_stream, spark_events))
                     .map(ignore)
                     .map_err(ignore)
             })
-    }))
+    }));
+
+    // don't wait for background tasks like the metrics timer
+    runtime.shutdown_now().wait().ok();
 }
@@ -1,9 +1,109 @@
+use std::borrow::Cow;
 use std::str::FromStr;
 
+use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
 use regex::Regex;
 
-use crate::state::UserFlag;
+use gerritbot_spark as spark;
+
+use crate::state::{
+    FilterMode, Permission, QuietHours, Role, TeamSubscription, Timezone, UserFlag, VotePolicy,
+};
+
+/// Hour in the time zone of the user at which a snooze until tomorrow ends.
+const TOMORROW_HOUR: u32 = 8;
+
+/// Longest possible snooze in days.
+const MAX_SNOOZE_DAYS: i64 = 365;
+
+#[derive(Debug, PartialEq)]
+pub enum SnoozeDuration {
+    For(chrono::Duration),
+    /// Until the next day at 8:00 in the time zone of the user.
+    UntilTomorrow,
+}
+
+impl SnoozeDuration {
+    pub fn end(&self, now: DateTime<Utc>, timezone: &Timezone) -> DateTime<Utc> {
+        match self {
+            SnoozeDuration::For(duration) => now + *duration,
+            SnoozeDuration::UntilTomorrow => {
+                let today = now.with_timezone(&timezone.offset_at(now)).date();
+                timezone.local_to_utc(today.succ().naive_local().and_hms(TOMORROW_HOUR, 0, 0))
+            }
+        }
+    }
+}
+
+impl FromStr for SnoozeDuration {
+    type Err = ();
+
+    /// Parse e.g. `30m`, `2h`, `1 day` or `until tomorrow`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        lazy_static! {
+            static ref DURATION_REGEX: Regex =
+                Regex::new(r"(?i)^(\d{1,6})\s*(m|mins?|minutes?|h|hours?|d|days?)$").unwrap();
+        };
+
+        if s.eq_ignore_ascii_case("until tomorrow") {
+            return Ok(SnoozeDuration::UntilTomorrow);
+        }
+        let cap = DURATION_REGEX.captures(s).ok_or(())?;
+        let count: i64 = cap[1].parse().map_err(|_| ())?;
+        let duration = match cap[2].chars().next().map(|c| c.to_ascii_lowercase()) {
+            Some('m') => chrono::Duration::minutes(count),
+            Some('h') => chrono::Duration::hours(count),
+            _ => chrono::Duration::days(count),
+        };
+        if duration > chrono::Duration::days(MAX_SNOOZE_DAYS) {
+            return Err(());
+        }
+        Ok(SnoozeDuration::For(duration))
+    }
+}
+
+/// Label voted on by `review` commands without a label.
+const DEFAULT_REVIEW_LABEL: &str = "Code-Review";
+
+/// Aliases of commands defined by the operator, e.g. `mute` for `disable`.
+/// An alias replaces the start of a message before it is parsed, so aliases
+/// of commands with arguments work, too.
+#[derive(Debug, Clone, Default)]
+pub struct Aliases(Vec<(String, String)>);
+
+impl Aliases {
+    pub fn new(aliases: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut aliases: Vec<_> = aliases
+            .into_iter()
+            .map(|(alias, command)| (alias.trim().to_lowercase(), command.trim().to_string()))
+            .filter(|(alias, _)| !alias.is_empty())
+            .collect();
+        // the longest matching alias wins
+        aliases.sort_by_key(|(alias, _)| std::cmp::Reverse(alias.len()));
+        Self(aliases)
+    }
+
+    /// Replace an alias at the start of the text by its command.
+    pub fn resolve<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let text = text.trim();
+        for (alias, command) in &self.0 {
+            let rest = match text.get(..alias.len()) {
+                Some(prefix) if prefix.to_lowercase() == *alias => &text[alias.len()..],
+                _ => continue,
+            };
+            if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+                return Cow::Owned(format!("{}{}", command, rest));
+            }
+        }
+        Cow::Borrowed(text)
+    }
+
+    /// Parse the text as command after resolving the aliases.
+    pub fn parse(&self, text: &str) -> Result<Command, ()> {
+        self.resolve(text).parse()
+    }
+}
 
 #[derive(Debug)]
 pub enum Command {
@@ -11,11 +111,141 @@ pub enum Command {
     Disable,
     SetFlag(UserFlag, bool),
     Status,
-    Help,
+    /// List all flags and settings of the user.
+    Settings,
+    /// Restore the default settings; only asks for confirmation if not
+    /// confirmed.
+    Reset {
+        confirmed: bool,
+    },
+    /// Undo the last change of the settings.
+    Undo,
+    ExportSettings,
+    /// Replace the settings with the exported ones in JSON.
+    ImportSettings(String),
+    LanguageStatus,
+    /// Reply in the language with the given code from now on.
+    SetLanguage(String),
+    /// Help of all commands or, if given, only of the topic.
+    Help(Option<String>),
     Version,
+    /// Preview of a notification about a review of a change of the sender.
+    Test,
     FilterStatus,
     FilterEnable(bool),
     FilterAdd(String),
+    SetFilterMode(FilterMode),
+    BranchStatus,
+    Snooze(SnoozeDuration),
+    Unsnooze,
+    QuietHoursStatus,
+    /// Set or, if none, remove the quiet hours.
+    SetQuietHours(Option<QuietHours>),
+    TimezoneStatus,
+    SetTimezone(Timezone),
+    DigestStatus,
+    /// Enable the digest at the time in minutes after midnight or, if none,
+    /// disable it.
+    SetDigest(Option<u32>),
+    /// Only notify about changes on branches matching the patterns, or on all
+    /// branches if empty.
+    SetBranches(Vec<String>),
+    VotesStatus,
+    /// Only notify about votes below the value or, if none, about all votes.
+    SetVoteThreshold(Option<i32>),
+    /// Which votes on a label to notify about, overriding the threshold.
+    SetLabelVotes {
+        label: String,
+        policy: VotePolicy,
+    },
+    SubscribeRoom(String),
+    UnsubscribeRoom(String),
+    /// Vote on the current patch set of a change.
+    Review {
+        change_number: u32,
+        label: String,
+        value: i32,
+        message: Option<String>,
+    },
+    /// List the open changes of the user.
+    Reviews,
+    /// List the open changes waiting for the vote of the user.
+    Queue,
+    /// Show the details of a change.
+    Show(u32),
+    /// Comment on the current patch set of a change.
+    Comment {
+        change_number: u32,
+        message: String,
+    },
+    Abandon {
+        change_number: u32,
+        reason: Option<String>,
+    },
+    Restore {
+        change_number: u32,
+    },
+    /// Grant or revoke a permission of a user; only for admins.
+    SetPermission {
+        email: spark::Email,
+        permission: Permission,
+        granted: bool,
+    },
+    /// Make a user an admin, or take the role away; only for admins.
+    SetRole {
+        email: spark::Email,
+        role: Role,
+    },
+    TopicStatus,
+    SubscribeTopic(String),
+    UnsubscribeTopic(String),
+    /// Link a further Gerrit email to the user.
+    LinkGerrit(spark::Email),
+    UnlinkGerrit(spark::Email),
+    /// Link the Gerrit email a user asked for; only for admins.
+    ApproveLink(spark::Email),
+    /// Statistics about the users and the running bot; only for admins.
+    AdminStats,
+    /// Replace the state with one of its backups; only for admins.
+    RestoreState(usize),
+    /// Write the state to a file, or to a file named after the current time;
+    /// only for admins.
+    ExportState(Option<String>),
+    /// Replace the state with the one in a file; only shows what would be
+    /// imported if not confirmed. Only for admins.
+    ImportState {
+        path: String,
+        confirmed: bool,
+    },
+    /// Replace the state with the state file, e.g. after it was edited while
+    /// the bot runs; only for admins.
+    ReloadState,
+    /// Save the state, even if the state file was edited while the bot runs;
+    /// only for admins.
+    OverwriteState,
+    /// Remove the users who weren't active for a number of months; only shows
+    /// them if not confirmed. Only for admins.
+    PruneUsers {
+        months: u32,
+        confirmed: bool,
+    },
+    /// List the teams; only for admins.
+    Teams,
+    /// Show the members and subscriptions of a team; only for admins.
+    TeamStatus(String),
+    /// Add members to a team, or remove them; only for admins.
+    SetTeamMembers {
+        team: String,
+        emails: Vec<spark::Email>,
+        added: bool,
+    },
+    /// Subscribe a team, or unsubscribe it; only for admins.
+    SetTeamSubscription {
+        team: String,
+        subscription: TeamSubscription,
+        subscribed: bool,
+    },
+    DeleteTeam(String),
 }
 
 impl FromStr for Command {
@@ -24,19 +254,320 @@ impl FromStr for Command {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         lazy_static! {
             static ref FILTER_REGEX: Regex = Regex::new(r"(?i)^filter (.*)$").unwrap();
+            static ref HELP_REGEX: Regex = Regex::new(r"(?i)^help\s+(\S+)$").unwrap();
+            static ref SETTINGS_IMPORT_REGEX: Regex =
+                Regex::new(r"(?is)^settings import\s+(.+)$").unwrap();
+            static ref QUIET_HOURS_REGEX: Regex = Regex::new(r"(?i)^quiet hours\s+(.+)$").unwrap();
+            static ref TIMEZONE_REGEX: Regex = Regex::new(r"(?i)^timezone\s+(.+)$").unwrap();
+            static ref LANGUAGE_REGEX: Regex =
+                Regex::new(r"(?i)^language\s+([a-z]{2,3}(?:[-_][a-z]{2,4})?)$").unwrap();
+            static ref DIGEST_REGEX: Regex =
+                Regex::new(r"(?i)^digest(?:\s+at)?\s+(\d{1,2})(?::(\d{2}))?$").unwrap();
+            static ref SNOOZE_REGEX: Regex = Regex::new(r"(?i)^snooze\s+(.+)$").unwrap();
+            static ref VOTES_REGEX: Regex = Regex::new(r"(?i)^votes below\s+([+-]?\d)$").unwrap();
+            static ref LABEL_VOTES_REGEX: Regex = Regex::new(
+                r"(?i)^votes\s+([a-z](?:[a-z0-9-]*[a-z0-9])?)\s+(all|negative|below\s+([+-]?\d))$"
+            )
+            .unwrap();
+            static ref BRANCH_REGEX: Regex = Regex::new(r"(?i)^branch only\s+(.+)$").unwrap();
             static ref FLAG_REGEX: Regex = Regex::new(r"(?i)^(enable|disable) (.*)$").unwrap();
+            static ref SUBSCRIBE_REGEX: Regex =
+                Regex::new(r"(?i)^(subscribe|unsubscribe) room\s+(\S+)$").unwrap();
+            static ref REVIEW_REGEX: Regex = Regex::new(
+                r"(?is)^review\s+(\d+)\s+(?:([a-z](?:[a-z0-9-]*[a-z])?)\s*)?([+-]\d|0)(?:\s+(.*))?$"
+            )
+            .unwrap();
+            static ref SHOW_REGEX: Regex = Regex::new(r"(?i)^show\s+(\d+)$").unwrap();
+            static ref COMMENT_REGEX: Regex = Regex::new(r"(?is)^comment\s+(\d+)\s+(.+)$").unwrap();
+            static ref ABANDON_REGEX: Regex =
+                Regex::new(r"(?is)^abandon\s+(\d+)(?:\s+(.+))?$").unwrap();
+            static ref RESTORE_REGEX: Regex = Regex::new(r"(?i)^restore\s+(\d+)$").unwrap();
+            static ref RESTORE_STATE_REGEX: Regex =
+                Regex::new(r"(?i)^admin restore\s+(\d+)$").unwrap();
+            static ref EXPORT_STATE_REGEX: Regex =
+                Regex::new(r"(?i)^admin export(?:\s+(\S+))?$").unwrap();
+            static ref IMPORT_STATE_REGEX: Regex =
+                Regex::new(r"(?i)^admin import\s+(\S+)(\s+confirm)?$").unwrap();
+            static ref PRUNE_USERS_REGEX: Regex =
+                Regex::new(r"(?i)^admin prune\s+(\d+)(\s+confirm)?$").unwrap();
+            static ref PERMISSION_REGEX: Regex =
+                Regex::new(r"(?i)^(grant|revoke)\s+(\S+)\s+(\S+@\S+)$").unwrap();
+            static ref TOPIC_REGEX: Regex =
+                Regex::new(r"(?i)^(subscribe|unsubscribe) topic\s+(\S+)$").unwrap();
+            static ref LINK_REGEX: Regex =
+                Regex::new(r"(?i)^(?:(link|unlink) gerrit|alias (add|remove))\s+(\S+@\S+)$")
+                    .unwrap();
+            static ref APPROVE_LINK_REGEX: Regex =
+                Regex::new(r"(?i)^approve link\s+(\S+@\S+)$").unwrap();
+            static ref TEAM_REGEX: Regex = Regex::new(r"(?i)^team\s+(\S+)(\s+delete)?$").unwrap();
+            static ref TEAM_MEMBERS_REGEX: Regex =
+                Regex::new(r"(?i)^team\s+(\S+)\s+(add|remove)\s+(.+)$").unwrap();
+            static ref TEAM_SUBSCRIBE_REGEX: Regex = Regex::new(
+                r"(?i)^team\s+(\S+)\s+(subscribe|unsubscribe)\s+(?:project\s+(\S+)(?:\s+branch\s+(\S+))?|topic\s+(\S+))$"
+            )
+            .unwrap();
         };
 
         Ok(match &s.trim().to_lowercase()[..] {
             "enable" => Command::Enable,
             "disable" => Command::Disable,
             "status" => Command::Status,
-            "help" => Command::Help,
+            "settings" => Command::Settings,
+            "settings export" => Command::ExportSettings,
+            "reset" => Command::Reset { confirmed: false },
+            "reset confirm" => Command::Reset { confirmed: true },
+            "undo" => Command::Undo,
+            "language" => Command::LanguageStatus,
+            "help" => Command::Help(None),
             "version" => Command::Version,
+            "test" => Command::Test,
             "filter" => Command::FilterStatus,
             "filter enable" => Command::FilterEnable(true),
             "filter disable" => Command::FilterEnable(false),
+            "filter mode drop" => Command::SetFilterMode(FilterMode::Drop),
+            "filter mode keep" => Command::SetFilterMode(FilterMode::Keep),
+            "branch" => Command::BranchStatus,
+            "unsnooze" | "snooze off" => Command::Unsnooze,
+            "quiet hours" => Command::QuietHoursStatus,
+            "quiet hours off" => Command::SetQuietHours(None),
+            "timezone" => Command::TimezoneStatus,
+            "digest" => Command::DigestStatus,
+            "digest off" => Command::SetDigest(None),
+            "branch all" => Command::SetBranches(Vec::new()),
+            "topics" => Command::TopicStatus,
+            "votes" => Command::VotesStatus,
+            "votes all" => Command::SetVoteThreshold(None),
+            "votes negative" => Command::SetVoteThreshold(Some(0)),
+            "reviews" => Command::Reviews,
+            "queue" => Command::Queue,
+            "admin stats" => Command::AdminStats,
+            "admin reload" => Command::ReloadState,
+            "admin save" => Command::OverwriteState,
+            "teams" => Command::Teams,
             _ => None
+                .or_else(|| {
+                    HELP_REGEX
+                        .captures(s.trim())
+                        .map(|cap| Command::Help(Some(cap[1].to_lowercase())))
+                })
+                .or_else(|| {
+                    SETTINGS_IMPORT_REGEX.captures(s.trim()).map(|cap| {
+                        // the JSON may be pasted as a code block
+                        let json = cap[1].trim_matches('`');
+                        let json = json.strip_prefix("json").unwrap_or(json);
+                        Command::ImportSettings(json.trim().to_string())
+                    })
+                })
+                .or_else(|| {
+                    SUBSCRIBE_REGEX.captures(s.trim()).map(|cap| {
+                        let project = cap[2].to_string();
+                        if cap[1].eq_ignore_ascii_case("subscribe") {
+                            Command::SubscribeRoom(project)
+                        } else {
+                            Command::UnsubscribeRoom(project)
+                        }
+                    })
+                })
+                .or_else(|| {
+                    let cap = REVIEW_REGEX.captures(s.trim())?;
+                    Some(Command::Review {
+                        change_number: cap[1].parse().ok()?,
+                        label: cap
+                            .get(2)
+                            .map_or(DEFAULT_REVIEW_LABEL, |label| label.as_str())
+                            .to_string(),
+                        value: cap[3].parse().ok()?,
+                        message: cap
+                            .get(4)
+                            .map(|message| message.as_str().trim().to_string()),
+                    })
+                })
+                .or_else(|| {
+                    let cap = SHOW_REGEX.captures(s.trim())?;
+                    Some(Command::Show(cap[1].parse().ok()?))
+                })
+                .or_else(|| {
+                    let cap = COMMENT_REGEX.captures(s.trim())?;
+                    Some(Command::Comment {
+                        change_number: cap[1].parse().ok()?,
+                        message: cap[2].to_string(),
+                    })
+                })
+                .or_else(|| {
+                    let cap = ABANDON_REGEX.captures(s.trim())?;
+                    Some(Command::Abandon {
+                        change_number: cap[1].parse().ok()?,
+                        reason: cap.get(2).map(|reason| reason.as_str().to_string()),
+                    })
+                })
+                .or_else(|| {
+                    EXPORT_STATE_REGEX.captures(s.trim()).map(|cap| {
+                        Command::ExportState(cap.get(1).map(|path| path.as_str().to_string()))
+                    })
+                })
+                .or_else(|| {
+                    IMPORT_STATE_REGEX
+                        .captures(s.trim())
+                        .map(|cap| Command::ImportState {
+                            path: cap[1].to_string(),
+                            confirmed: cap.get(2).is_some(),
+                        })
+                })
+                .or_else(|| {
+                    let cap = PRUNE_USERS_REGEX.captures(s.trim())?;
+                    Some(Command::PruneUsers {
+                        months: cap[1].parse().ok()?,
+                        confirmed: cap.get(2).is_some(),
+                    })
+                })
+                .or_else(|| {
+                    let cap = RESTORE_STATE_REGEX.captures(s.trim())?;
+                    Some(Command::RestoreState(cap[1].parse().ok()?))
+                })
+                .or_else(|| {
+                    let cap = RESTORE_REGEX.captures(s.trim())?;
+                    Some(Command::Restore {
+                        change_number: cap[1].parse().ok()?,
+                    })
+                })
+                .or_else(|| {
+                    let cap = PERMISSION_REGEX.captures(s.trim())?;
+                    let email = spark::Email::new(cap[3].to_string());
+                    let granted = cap[1].eq_ignore_ascii_case("grant");
+                    if cap[2].eq_ignore_ascii_case("admin") {
+                        let role = if granted { Role::Admin } else { Role::User };
+                        return Some(Command::SetRole { email, role });
+                    }
+                    Some(Command::SetPermission {
+                        email,
+                        permission: cap[2].to_lowercase().parse().ok()?,
+                        granted,
+                    })
+                })
+                .or_else(|| {
+                    TOPIC_REGEX.captures(s.trim()).map(|cap| {
+                        let topic = cap[2].to_string();
+                        if cap[1].eq_ignore_ascii_case("subscribe") {
+                            Command::SubscribeTopic(topic)
+                        } else {
+                            Command::UnsubscribeTopic(topic)
+                        }
+                    })
+                })
+                .or_else(|| {
+                    LINK_REGEX.captures(s.trim()).map(|cap| {
+                        let email = spark::Email::new(cap[3].to_string());
+                        let action = cap.get(1).or_else(|| cap.get(2)).map_or("", |m| m.as_str());
+                        if action.eq_ignore_ascii_case("link") || action.eq_ignore_ascii_case("add")
+                        {
+                            Command::LinkGerrit(email)
+                        } else {
+                            Command::UnlinkGerrit(email)
+                        }
+                    })
+                })
+                .or_else(|| {
+                    APPROVE_LINK_REGEX
+                        .captures(s.trim())
+                        .map(|cap| Command::ApproveLink(spark::Email::new(cap[1].to_string())))
+                })
+                .or_else(|| {
+                    let cap = TEAM_REGEX.captures(s.trim())?;
+                    let team = cap[1].to_string();
+                    Some(if cap.get(2).is_some() {
+                        Command::DeleteTeam(team)
+                    } else {
+                        Command::TeamStatus(team)
+                    })
+                })
+                .or_else(|| {
+                    let cap = TEAM_MEMBERS_REGEX.captures(s.trim())?;
+                    let emails: Vec<&str> = cap[3]
+                        .split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|email| !email.is_empty())
+                        .collect();
+                    if !emails.iter().all(|email| email.contains('@')) {
+                        return None;
+                    }
+                    Some(Command::SetTeamMembers {
+                        team: cap[1].to_string(),
+                        emails: emails
+                            .into_iter()
+                            .map(|email| spark::Email::new(email.to_string()))
+                            .collect(),
+                        added: cap[2].eq_ignore_ascii_case("add"),
+                    })
+                })
+                .or_else(|| {
+                    let cap = TEAM_SUBSCRIBE_REGEX.captures(s.trim())?;
+                    let subscription = match cap.get(3) {
+                        Some(project) => TeamSubscription::Project {
+                            project: project.as_str().to_string(),
+                            branch: cap.get(4).map(|branch| branch.as_str().to_string()),
+                        },
+                        None => TeamSubscription::Topic(cap[5].to_string()),
+                    };
+                    Some(Command::SetTeamSubscription {
+                        team: cap[1].to_string(),
+                        subscription,
+                        subscribed: cap[2].eq_ignore_ascii_case("subscribe"),
+                    })
+                })
+                .or_else(|| {
+                    QUIET_HOURS_REGEX
+                        .captures(s.trim())
+                        .and_then(|cap| cap[1].parse().ok())
+                        .map(|quiet_hours| Command::SetQuietHours(Some(quiet_hours)))
+                })
+                .or_else(|| {
+                    TIMEZONE_REGEX
+                        .captures(s.trim())
+                        .and_then(|cap| cap[1].parse().ok())
+                        .map(Command::SetTimezone)
+                })
+                .or_else(|| {
+                    VOTES_REGEX
+                        .captures(s.trim())
+                        .and_then(|cap| cap[1].parse().ok())
+                        .map(|threshold| Command::SetVoteThreshold(Some(threshold)))
+                })
+                .or_else(|| {
+                    let cap = LABEL_VOTES_REGEX.captures(s.trim())?;
+                    let policy = match cap.get(3) {
+                        Some(threshold) => VotePolicy::Below(threshold.as_str().parse().ok()?),
+                        None if cap[2].eq_ignore_ascii_case("all") => VotePolicy::All,
+                        None => VotePolicy::Below(0),
+                    };
+                    Some(Command::SetLabelVotes {
+                        label: cap[1].to_string(),
+                        policy,
+                    })
+                })
+                .or_else(|| {
+                    LANGUAGE_REGEX
+                        .captures(s.trim())
+                        .map(|cap| Command::SetLanguage(cap[1].to_lowercase()))
+                })
+                .or_else(|| {
+                    let cap = DIGEST_REGEX.captures(s.trim())?;
+                    let hour: u32 = cap[1].parse().ok()?;
+                    let minute: u32 = cap.get(2).map_or(Ok(0), |m| m.as_str().parse()).ok()?;
+                    if hour > 23 || minute > 59 {
+                        return None;
+                    }
+                    Some(Command::SetDigest(Some(hour * 60 + minute)))
+                })
+                .or_else(|| {
+                    SNOOZE_REGEX
+                        .captures(s.trim())
+                        .and_then(|cap| cap[1].trim().parse().ok())
+                        .map(Command::Snooze)
+                })
+                .or_else(|| {
+                    BRANCH_REGEX.captures(s.trim()).map(|cap| {
+                        Command::SetBranches(cap[1].split_whitespace().map(String::from).collect())
+                    })
+                })
                 .or_else(|| {
                     FILTER_REGEX
                         .captures(s.trim())
@@ -63,7 +594,12 @@ impl FromStr for Command {
 mod test {
     use assert_matches::assert_matches;
 
-    use super::Command;
+    use chrono::FixedOffset;
+
+    use super::{Aliases, Command, SnoozeDuration};
+    use crate::state::{
+        FilterMode, Permission, Role, TeamSubscription, Timezone, UserFlag, VotePolicy,
+    };
 
     macro_rules! test_parse {
         ($name:ident, $s:expr, $( $c:tt )+) => {
@@ -92,8 +628,14 @@ mod test {
     test_parse!(enable_mixed_case, "EnAbLe", Command::Enable);
     test_parse!(disable, Command::Disable);
     test_parse!(status, Command::Status);
-    test_parse!(help, Command::Help);
+    test_parse!(help, Command::Help(None));
+    test_parse!(
+        help_topic,
+        "help Filter",
+        Command::Help(Some(ref topic)) if topic == "filter"
+    );
     test_parse!(version, Command::Version);
+    test_parse!(test, Command::Test);
     test_parse!(filter, Command::FilterStatus);
     test_parse!(filter_enable, "filter enable", Command::FilterEnable(true));
     test_parse!(
@@ -112,5 +654,404 @@ mod test {
         Command::FilterAdd(ref s) if s == " abc def"
     );
 
+    test_parse!(
+        filter_mode_keep,
+        "filter mode keep",
+        Command::SetFilterMode(FilterMode::Keep)
+    );
+    test_parse!(
+        filter_mode_drop,
+        "Filter Mode Drop",
+        Command::SetFilterMode(FilterMode::Drop)
+    );
+
+    test_parse!(
+        snooze_hours,
+        "snooze 2h",
+        Command::Snooze(SnoozeDuration::For(d)) if d == chrono::Duration::hours(2)
+    );
+    test_parse!(
+        snooze_minutes,
+        "Snooze 30 minutes",
+        Command::Snooze(SnoozeDuration::For(d)) if d == chrono::Duration::minutes(30)
+    );
+    test_parse!(
+        snooze_until_tomorrow,
+        "snooze until tomorrow",
+        Command::Snooze(SnoozeDuration::UntilTomorrow)
+    );
+    test_parse!(unsnooze, Command::Unsnooze);
+    test_parse!(snooze_off, "snooze off", Command::Unsnooze);
+
+    test_parse!(quiet_hours, "quiet hours", Command::QuietHoursStatus);
+    test_parse!(
+        quiet_hours_off,
+        "quiet hours off",
+        Command::SetQuietHours(None)
+    );
+    test_parse!(
+        quiet_hours_set,
+        "Quiet hours 19:00-08:00 and weekends",
+        Command::SetQuietHours(Some(ref q)) if q.to_string() == "19:00–08:00 and weekends"
+    );
+    test_parse!(timezone, Command::TimezoneStatus);
+    test_parse!(
+        timezone_utc,
+        "timezone UTC",
+        Command::SetTimezone(Timezone::Offset(tz)) if tz == FixedOffset::east(0)
+    );
+    test_parse!(
+        timezone_offset,
+        "timezone UTC+2",
+        Command::SetTimezone(Timezone::Offset(tz)) if tz == FixedOffset::east(2 * 3600)
+    );
+    test_parse!(
+        timezone_offset_with_minutes,
+        "timezone -03:30",
+        Command::SetTimezone(Timezone::Offset(tz)) if tz == FixedOffset::west(3 * 3600 + 30 * 60)
+    );
+    test_parse!(
+        timezone_named,
+        "timezone Europe/Berlin",
+        Command::SetTimezone(tz) if tz.name() == Some("Europe/Berlin")
+    );
+
+    test_parse!(votes, Command::VotesStatus);
+    test_parse!(votes_all, "votes all", Command::SetVoteThreshold(None));
+    test_parse!(
+        votes_negative,
+        "votes negative",
+        Command::SetVoteThreshold(Some(0))
+    );
+    test_parse!(
+        votes_below,
+        "votes below +2",
+        Command::SetVoteThreshold(Some(2))
+    );
+    test_parse_fail!(votes_below_invalid, "votes below many");
+    test_parse!(
+        label_votes_negative,
+        "votes Verified negative",
+        Command::SetLabelVotes { ref label, policy: VotePolicy::Below(0) } if label == "Verified"
+    );
+    test_parse!(
+        label_votes_all,
+        "votes code-review all",
+        Command::SetLabelVotes { ref label, policy: VotePolicy::All } if label == "code-review"
+    );
+    test_parse!(
+        label_votes_below,
+        "votes Code-Review below -1",
+        Command::SetLabelVotes {
+            policy: VotePolicy::Below(-1),
+            ..
+        }
+    );
+    test_parse!(digest, Command::DigestStatus);
+    test_parse!(digest_off, "digest off", Command::SetDigest(None));
+    test_parse!(
+        digest_at,
+        "digest at 18:30",
+        Command::SetDigest(Some(time)) if time == 18 * 60 + 30
+    );
+    test_parse!(
+        digest_hour,
+        "Digest 9",
+        Command::SetDigest(Some(time)) if time == 9 * 60
+    );
+
+    test_parse!(branch, Command::BranchStatus);
+    test_parse!(
+        branch_all,
+        "branch all",
+        Command::SetBranches(ref branches) if branches.is_empty()
+    );
+    test_parse!(
+        branch_only,
+        "Branch only master  release/*",
+        Command::SetBranches(ref branches) if branches == &["master", "release/*"]
+    );
+
+    test_parse!(
+        subscribe_room,
+        "subscribe room gerritbot-rs",
+        Command::SubscribeRoom(ref s) if s == "gerritbot-rs"
+    );
+    test_parse!(
+        unsubscribe_room,
+        "Unsubscribe room  platform/Build ",
+        Command::UnsubscribeRoom(ref s) if s == "platform/Build"
+    );
+
     test_parse_fail!(unknown_command, "unknown");
+    test_parse_fail!(subscribe_room_without_project, "subscribe room");
+
+    test_parse!(
+        review,
+        "review 12345 +1",
+        Command::Review { change_number: 12345, ref label, value: 1, message: None }
+            if label == "Code-Review"
+    );
+    test_parse!(
+        review_with_label_and_message,
+        "Review 42 Verified-1 Fails on\nWindows",
+        Command::Review { change_number: 42, ref label, value: -1, message: Some(ref message) }
+            if label == "Verified" && message == "Fails on\nWindows"
+    );
+    test_parse!(
+        review_with_spaced_label,
+        "review 42 Code-Review +2",
+        Command::Review { change_number: 42, ref label, value: 2, .. } if label == "Code-Review"
+    );
+    test_parse_fail!(review_without_value, "review 42 looks good");
+
+    test_parse!(
+        comment,
+        "Comment 42 Looks good,\nbut see `main.rs`",
+        Command::Comment { change_number: 42, ref message }
+            if message == "Looks good,\nbut see `main.rs`"
+    );
+    test_parse_fail!(comment_without_text, "comment 42");
+    test_parse_fail!(comment_without_change, "comment looks good");
+
+    test_parse!(
+        abandon,
+        "abandon 42",
+        Command::Abandon {
+            change_number: 42,
+            reason: None
+        }
+    );
+    test_parse!(
+        abandon_with_reason,
+        "Abandon 42 superseded by 43",
+        Command::Abandon { change_number: 42, reason: Some(ref reason) }
+            if reason == "superseded by 43"
+    );
+    test_parse!(
+        restore,
+        "restore 42",
+        Command::Restore { change_number: 42 }
+    );
+    test_parse_fail!(restore_with_reason, "restore 42 still needed");
+    test_parse!(
+        grant_permission,
+        "grant manage_changes jdoe@example.com",
+        Command::SetPermission { ref email, permission: Permission::ManageChanges, granted: true }
+            if email.as_str() == "jdoe@example.com"
+    );
+    test_parse!(
+        revoke_permission,
+        "Revoke Manage_Changes jdoe@example.com",
+        Command::SetPermission {
+            permission: Permission::ManageChanges,
+            granted: false,
+            ..
+        }
+    );
+    test_parse!(
+        grant_admin,
+        "grant admin jdoe@example.com",
+        Command::SetRole { ref email, role: Role::Admin } if email.as_str() == "jdoe@example.com"
+    );
+    test_parse!(
+        revoke_admin,
+        "revoke Admin jdoe@example.com",
+        Command::SetRole {
+            role: Role::User,
+            ..
+        }
+    );
+    test_parse_fail!(
+        grant_unknown_permission,
+        "grant everything jdoe@example.com"
+    );
+
+    test_parse!(reviews, Command::Reviews);
+    test_parse!(queue, Command::Queue);
+    test_parse!(settings, Command::Settings);
+    test_parse!(reset, "reset", Command::Reset { confirmed: false });
+    test_parse!(
+        reset_confirm,
+        "Reset Confirm",
+        Command::Reset { confirmed: true }
+    );
+    test_parse!(undo, Command::Undo);
+    test_parse!(settings_export, "Settings Export", Command::ExportSettings);
+    test_parse!(
+        settings_import,
+        "settings import {\"branches\": [\"master\"]}",
+        Command::ImportSettings(ref json) if json == r#"{"branches": ["master"]}"#
+    );
+    test_parse!(
+        settings_import_code_block,
+        "settings import ```json\n{\"topics\": [\"release\"]}\n```",
+        Command::ImportSettings(ref json) if json == r#"{"topics": ["release"]}"#
+    );
+    test_parse!(admin_stats, "Admin Stats", Command::AdminStats);
+    test_parse!(admin_reload, "admin reload", Command::ReloadState);
+    test_parse!(admin_save, "admin save", Command::OverwriteState);
+    test_parse!(admin_restore, "admin restore 2", Command::RestoreState(2));
+    test_parse!(
+        admin_prune,
+        "admin prune 6",
+        Command::PruneUsers {
+            months: 6,
+            confirmed: false
+        }
+    );
+    test_parse!(
+        admin_prune_confirmed,
+        "Admin Prune 6 confirm",
+        Command::PruneUsers {
+            months: 6,
+            confirmed: true
+        }
+    );
+    test_parse!(admin_export, "admin export", Command::ExportState(None));
+    test_parse!(
+        admin_export_to_path,
+        "admin export /tmp/users.json",
+        Command::ExportState(Some(ref path)) if path == "/tmp/users.json"
+    );
+    test_parse!(
+        admin_import,
+        "admin import users.json",
+        Command::ImportState { ref path, confirmed: false } if path == "users.json"
+    );
+    test_parse!(
+        admin_import_confirm,
+        "Admin Import users.json Confirm",
+        Command::ImportState { ref path, confirmed: true } if path == "users.json"
+    );
+    test_parse!(teams, Command::Teams);
+    test_parse!(
+        team_status,
+        "team Graphics",
+        Command::TeamStatus(ref team) if team == "Graphics"
+    );
+    test_parse!(
+        delete_team,
+        "team graphics delete",
+        Command::DeleteTeam(ref team) if team == "graphics"
+    );
+    test_parse!(
+        add_team_members,
+        "team graphics add alice@example.com, bob@example.com",
+        Command::SetTeamMembers { ref team, ref emails, added: true }
+            if team == "graphics" && emails.len() == 2 && emails[1].as_str() == "bob@example.com"
+    );
+    test_parse_fail!(add_team_members_without_email, "team graphics add alice");
+    test_parse!(
+        subscribe_team_to_branch,
+        "team graphics subscribe project gerritbot-rs branch release",
+        Command::SetTeamSubscription {
+            subscription: TeamSubscription::Project { ref project, branch: Some(ref branch) },
+            subscribed: true,
+            ..
+        } if project == "gerritbot-rs" && branch == "release"
+    );
+    test_parse!(
+        unsubscribe_team_from_topic,
+        "team graphics unsubscribe topic wayland",
+        Command::SetTeamSubscription {
+            subscription: TeamSubscription::Topic(ref topic),
+            subscribed: false,
+            ..
+        } if topic == "wayland"
+    );
+    test_parse!(language, Command::LanguageStatus);
+    test_parse!(
+        set_language,
+        "language DE",
+        Command::SetLanguage(ref language) if language == "de"
+    );
+    test_parse_fail!(set_language_invalid, "language deutsch");
+    test_parse!(show, "show 12345", Command::Show(12345));
+    test_parse_fail!(show_without_change, "show");
+    test_parse!(
+        link_gerrit,
+        "Link Gerrit john.doe@example.com",
+        Command::LinkGerrit(ref email) if email.to_string() == "john.doe@example.com"
+    );
+    test_parse!(
+        unlink_gerrit,
+        "unlink gerrit john.doe@example.com",
+        Command::UnlinkGerrit(_)
+    );
+    test_parse!(
+        approve_link,
+        "approve link jdoe@corp.example.com",
+        Command::ApproveLink(ref email) if email.to_string() == "jdoe@corp.example.com"
+    );
+    test_parse_fail!(link_gerrit_without_email, "link gerrit jdoe");
+    test_parse!(
+        alias_add,
+        "alias add john.doe@example.com",
+        Command::LinkGerrit(ref email) if email.to_string() == "john.doe@example.com"
+    );
+    test_parse!(
+        alias_remove,
+        "Alias Remove john.doe@example.com",
+        Command::UnlinkGerrit(_)
+    );
+    test_parse!(topics, Command::TopicStatus);
+    test_parse!(
+        subscribe_topic,
+        "subscribe topic Wayland-Support",
+        Command::SubscribeTopic(ref s) if s == "Wayland-Support"
+    );
+    test_parse!(
+        unsubscribe_topic,
+        "Unsubscribe topic wayland",
+        Command::UnsubscribeTopic(ref s) if s == "wayland"
+    );
+    test_parse_fail!(subscribe_topic_without_name, "subscribe topic");
+    test_parse_fail!(branch_only_without_branches, "branch only");
+    test_parse_fail!(snooze_without_unit, "snooze 2");
+    test_parse_fail!(snooze_too_long, "snooze 400 days");
+    test_parse_fail!(quiet_hours_invalid, "quiet hours evenings");
+    test_parse_fail!(timezone_invalid, "timezone Europe/Atlantis");
+    test_parse_fail!(timezone_out_of_range, "timezone UTC+15");
+    test_parse_fail!(digest_invalid_time, "digest at 24:00");
+
+    #[test]
+    fn snooze_end() {
+        use chrono::TimeZone as _;
+
+        let now = chrono::Utc.ymd(2020, 2, 29).and_hms(17, 30, 0);
+        assert_eq!(
+            SnoozeDuration::For(chrono::Duration::hours(2)).end(now, &Timezone::default()),
+            chrono::Utc.ymd(2020, 2, 29).and_hms(19, 30, 0)
+        );
+        assert_eq!(
+            SnoozeDuration::UntilTomorrow.end(now, &Timezone::default()),
+            chrono::Utc.ymd(2020, 3, 1).and_hms(8, 0, 0)
+        );
+        // 8:00 in Tokyo is 23:00 UTC the day before
+        let tokyo = Timezone::named("Asia/Tokyo").unwrap();
+        assert_eq!(
+            SnoozeDuration::UntilTomorrow.end(now, &tokyo),
+            chrono::Utc.ymd(2020, 3, 1).and_hms(23, 0, 0)
+        );
+    }
+
+    #[test]
+    fn aliases() {
+        let aliases = Aliases::new(vec![
+            ("mute".to_string(), "disable".to_string()),
+            ("Aktiviere".to_string(), "enable".to_string()),
+            ("mute for".to_string(), "snooze".to_string()),
+        ]);
+        assert_matches!(aliases.parse("Mute"), Ok(Command::Disable));
+        assert_matches!(
+            aliases.parse("aktiviere notify_change_merged"),
+            Ok(Command::SetFlag(UserFlag::NotifyChangeMerged, true))
+        );
+        assert_matches!(aliases.parse("mute for 2h"), Ok(Command::Snooze(_)));
+        // aliases only match whole words
+        assert!(aliases.parse("muted").is_err());
+        // the commands still work
+        assert_matches!(aliases.parse("disable"), Ok(Command::Disable));
+    }
 }
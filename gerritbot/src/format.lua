@@ -1,5 +1,9 @@
--- Get the Gerrit base URL from the given change URL.
+-- Get the Gerrit base URL. The configured `gerrit_base_url` is preferred,
+-- otherwise it is derived from the given change URL.
 local function get_gerrit_base_url(change_url)
+    if gerrit_base_url then
+        return gerrit_base_url
+    end
     return string.sub(change_url, 1, #change_url - string.find(string.reverse(change_url), "/"))
 end
 
@@ -8,6 +12,17 @@ local function get_query_url(base_url, query, ...)
     return string.format("%s/q/%s", base_url, string.format(query, ...))
 end
 
+-- Quote a value of a Gerrit search operator. Quoted values can't contain
+-- `"`, such values are put in braces instead. Returns nil if neither works.
+local function quote_query_value(value)
+    if not string.find(value, '"', 1, true) then
+        return '"' .. value .. '"'
+    elseif not string.find(value, "[{}]") then
+        return "{" .. value .. "}"
+    end
+    return nil
+end
+
 -- Format a link.
 local function format_link(text, target)
     return string.format("[%s](%s)", text, target)
@@ -22,34 +37,34 @@ end
 local function format_user(base_url, user, role)
     return format_query_link(
         base_url,
-        user.name or user.email,
+        escape_markdown(user.name or user.email),
         "%s:%s+status:open",
         role, user.email
     )
 end
 
 -- Format a change's subject.
-local function format_change_subject(change)
-    return format_link(change.subject, change.url)
+local function format_change_subject(base_url, change)
+    return format_link(escape_markdown(change.subject), change_url(change))
 end
 
 -- Format a change's project.
 local function format_change_project(base_url, change)
     local result = format_query_link(
         base_url,
-        change.project,
+        escape_markdown(change.project),
         "project:%s+status:open",
         change.project
     )
 
     if change.branch ~= "master" then
-        result = result .. ", branch:" .. change.branch
+        result = result .. ", branch:" .. escape_markdown(change.branch)
     end
 
     if change.topic then
         result = result .. ", topic:" .. format_query_link(
             base_url,
-            change.topic,
+            escape_markdown(change.topic),
             "topic:%s+status:open",
             change.topic
         )
@@ -58,6 +73,56 @@ local function format_change_project(base_url, change)
     return result
 end
 
+-- Notification texts by language. The global `locale` is the language of the
+-- user the message is for; texts missing in a language fall back to English.
+-- Placeholders work like in the canned replies.
+NOTIFICATIONS = {
+    en = {
+        comment_added = "{change} ({project}){approvals} from {reviewer}",
+        comments = "comments",
+        status_submittable = "🏁 Submittable",
+        status_merged = "📦 Merged",
+        status_abandoned = "☠  Abandoned",
+        reviewer_added = "{change} ({project}) by {owner} 👓 Added as reviewer",
+        change_merged = "{change} ({project}) 📦 Submitted by {submitter}",
+        change_submittable = "{change} ({project}) 🏁 Ready to submit after the review of {reviewer}",
+        merge_conflict = "{change} ({project}) ⚔ Merge conflict, please rebase",
+        stale_reviews = "Just a reminder: these changes of yours had no activity for {days} day(s). Maybe they need a nudge?",
+        change_abandoned = "{change} ({project}) ☠  Abandoned by {abandoner}",
+        change_deleted = "{change} ({project}) 🗑 Deleted by {deleter}",
+        digest = "Your daily digest with {count} notification(s) about {changes} change(s):",
+        digest_more = "… and {count} more.",
+        review_reminder = "⏰ You were added as reviewer of {change} ({project}) {age} ago, and didn't vote yet.",
+    },
+    de = {
+        comment_added = "{change} ({project}){approvals} von {reviewer}",
+        comments = "Kommentare",
+        status_submittable = "🏁 Bereit zum Submit",
+        status_merged = "📦 Gemergt",
+        status_abandoned = "☠  Verworfen",
+        reviewer_added = "{change} ({project}) von {owner} 👓 Als Reviewer hinzugefügt",
+        change_merged = "{change} ({project}) 📦 Submittet von {submitter}",
+        change_submittable = "{change} ({project}) 🏁 Bereit zum Submit nach dem Review von {reviewer}",
+        merge_conflict = "{change} ({project}) ⚔ Merge-Konflikt, bitte rebasen",
+        stale_reviews = "Nur zur Erinnerung: auf diesen Changes von dir gab es seit {days} Tag(en) keine Aktivität. Brauchen sie vielleicht einen Schubs?",
+        change_abandoned = "{change} ({project}) ☠  Verworfen von {abandoner}",
+        change_deleted = "{change} ({project}) 🗑 Gelöscht von {deleter}",
+        digest = "Deine tägliche Zusammenfassung mit {count} Benachrichtigung(en) zu {changes} Change(s):",
+        digest_more = "… und {count} weitere.",
+        review_reminder = "⏰ Du wurdest vor {age} als Reviewer von {change} ({project}) hinzugefügt und hast noch nicht abgestimmt.",
+    },
+}
+
+-- Translate the notification text with the id into the language of the
+-- user, and fill in the placeholders.
+local function translate(id, args)
+    local texts = NOTIFICATIONS[locale] or {}
+    local text = texts[id] or NOTIFICATIONS.en[id]
+    return (text:gsub("{([%w_]+)}", function(name)
+        return (args or {})[name]
+    end))
+end
+
 -- Lua string pattern → table of emoji
 local APPROVAL_ICONS = {
     {"WaitForVerification", {[-1] = "⏳"}},
@@ -121,9 +186,9 @@ local function format_comment(comment, is_human)
 
     for line in lines_iter(comment) do
         if is_human and not line:match "^Patch Set" and not line:match "%(%d+ comments?%)" then
-            table.insert(lines, "> " .. line)
+            table.insert(lines, "> " .. escape_markdown(line))
         elseif string.match(line, "FAILURE") then
-            table.insert(lines, "> " .. line)
+            table.insert(lines, "> " .. escape_markdown(line))
         end
     end
 
@@ -153,12 +218,12 @@ local function format_inline_comment(base_url, change, patchset, comment)
                     comment.line,
                     url,
                     format_user(base_url, comment.reviewer, "reviewer"),
-                    line
+                    escape_markdown(line)
                 )
             )
 
         else
-            table.insert(lines, "> " .. line)
+            table.insert(lines, "> " .. escape_markdown(line))
         end
     end
 
@@ -207,18 +272,36 @@ local function format_approvals(approvals)
     end
 end
 
+-- Check if change can be submitted. Gerrit 3.5+ reports submit requirements,
+-- older versions submit records.
+local function is_submittable(change)
+    if change.submitRequirements then
+        for _i, requirement in ipairs(change.submitRequirements) do
+            if requirement.status == "UNSATISFIED" or requirement.status == "ERROR" then
+                return false
+            end
+        end
+        return true
+    end
+
+    for _i, submit_record in ipairs(change.submitRecords or {}) do
+        if submit_record.status == "OK" then
+            return true
+        end
+    end
+    return false
+end
+
 -- Format change status
 local function format_change_status(change)
     if change.status == "NEW" then
-        for _i, submit_record in ipairs(change.submitRecords or {}) do
-            if submit_record.status == "OK" then
-                return ", 🏁 Submittable"
-            end
+        if is_submittable(change) then
+            return ", " .. translate("status_submittable")
         end
     elseif change.status == "MERGED" then
-        return ", 📦 Merged"
+        return ", " .. translate("status_merged")
     elseif change.status == "ABANDONED" then
-        return ", ☠  Abandoned"
+        return ", " .. translate("status_abandoned")
     end
 end
 
@@ -249,9 +332,12 @@ function format_comment_added(event, flags)
         or formatted_inline_comments
         or formatted_status_message
     then
-        local msg = format_change_subject(change) .. " (" .. format_change_project(base_url, change) .. ")"
-        msg = msg .. (formatted_approvals or " comments")
-        msg = msg .. " from " .. format_user(base_url, event.author, "reviewer")
+        local msg = translate("comment_added", {
+            change = format_change_subject(base_url, change),
+            project = format_change_project(base_url, change),
+            approvals = formatted_approvals or " " .. translate("comments"),
+            reviewer = format_user(base_url, event.author, "reviewer"),
+        })
         msg = msg .. (formatted_status_message or "")
         msg = msg .. (formatted_comment or "")
         msg = msg .. (formatted_inline_comments or "")
@@ -259,42 +345,379 @@ function format_comment_added(event, flags)
     end
 end
 
+-- Adaptive Card for a review, sent alongside the message of
+-- format_comment_added if adaptive cards are enabled.
+function format_comment_added_card(event)
+    local change = event.change
+    local base_url = get_gerrit_base_url(change.url)
+
+    local facts = {}
+    for _i, approval in ipairs(event.approvals or {}) do
+        local approval_value = tonumber(approval.value) or 0
+        local sign = ""
+        if approval_value > 0 then
+            sign = "+"
+        end
+        table.insert(facts, { title = approval.type, value = sign .. approval_value })
+    end
+
+    local reviewers = {}
+    for _i, reviewer in ipairs(change.allReviewers or {}) do
+        table.insert(reviewers, reviewer.name or reviewer.email)
+    end
+    if #reviewers > 0 then
+        table.insert(facts, { title = "Reviewers", value = table.concat(reviewers, ", ") })
+    end
+
+    local body = {
+        { type = "TextBlock", text = change.subject, weight = "Bolder", wrap = true },
+        {
+            type = "TextBlock",
+            text = string.format("%s by %s", change.project, event.author.name or event.author.email),
+            isSubtle = true,
+            wrap = true,
+        },
+    }
+    if #facts > 0 then
+        table.insert(body, { type = "FactSet", facts = facts })
+    end
+
+    return {
+        type = "AdaptiveCard",
+        ["$schema"] = "http://adaptivecards.io/schemas/adaptive-card.json",
+        version = "1.2",
+        body = body,
+        actions = {
+            { type = "Action.OpenUrl", title = "Open in Gerrit", url = change_url(change) },
+        },
+    }
+end
+
 function format_reviewer_added(event, flags)
     local change = event.change
     local base_url = get_gerrit_base_url(change.url)
 
-    return string.format(
-        "%s (%s) by %s 👓 Added as reviewer",
-        format_change_subject(change),
-        format_change_project(base_url, change),
-        format_user(base_url, change.owner, "owner")
-    )
+    return translate("reviewer_added", {
+        change = format_change_subject(base_url, change),
+        project = format_change_project(base_url, change),
+        owner = format_user(base_url, change.owner, "owner"),
+    })
 end
 
 function format_change_merged(event, flags)
     local change = event.change
     local base_url = get_gerrit_base_url(change.url)
 
-    return string.format(
-        "%s (%s) 📦 Submitted by %s",
-        format_change_subject(change),
-        format_change_project(base_url, change),
-        format_user(base_url, event.submitter, "owner")
-    )
+    return translate("change_merged", {
+        change = format_change_subject(base_url, change),
+        project = format_change_project(base_url, change),
+        submitter = format_user(base_url, event.submitter, "owner"),
+    })
+end
+
+function format_change_submittable(event, flags)
+    local change = event.change
+    local base_url = get_gerrit_base_url(change.url)
+
+    return translate("change_submittable", {
+        change = format_change_subject(base_url, change),
+        project = format_change_project(base_url, change),
+        reviewer = format_user(base_url, event.reviewer, "reviewer"),
+    })
+end
+
+function format_merge_conflict(event, flags)
+    local change = event.change
+    local base_url = get_gerrit_base_url(change.url)
+
+    return translate("merge_conflict", {
+        change = format_change_subject(base_url, change),
+        project = format_change_project(base_url, change),
+    })
+end
+
+function format_stale_reviews(reviews)
+    local lines = {}
+    for _, change in ipairs(reviews.changes) do
+        local base_url = get_gerrit_base_url(change.url)
+        table.insert(lines, string.format(
+            "* %s (%s)",
+            format_change_subject(base_url, change),
+            format_change_project(base_url, change)
+        ))
+    end
+    return translate("stale_reviews", { days = reviews.days }) .. "\n\n" .. table.concat(lines, "\n")
 end
 
 function format_change_abandoned(event, flags)
     local change = event.change
     local base_url = get_gerrit_base_url(change.url)
 
+    return translate("change_abandoned", {
+        change = format_change_subject(base_url, change),
+        project = format_change_project(base_url, change),
+        abandoner = format_user(base_url, event.abandoner, "owner"),
+    })
+end
+
+function format_change_deleted(event, flags)
+    local change = event.change
+    local base_url = get_gerrit_base_url(change.url)
+
+    return translate("change_deleted", {
+        change = format_change_subject(base_url, change),
+        project = format_change_project(base_url, change),
+        deleter = format_user(base_url, event.deleter, "owner"),
+    })
+end
+
+function format_digest(digest)
+    -- group the notifications by change, in order of appearance
+    local change_numbers = {}
+    local lines_by_change = {}
+    for _, entry in ipairs(digest.entries) do
+        local lines = lines_by_change[entry.change_number]
+        if lines == nil then
+            lines = {}
+            lines_by_change[entry.change_number] = lines
+            table.insert(change_numbers, entry.change_number)
+        end
+        table.insert(lines, "* " .. entry.message:match("[^\n]*"))
+    end
+
+    local sections = {}
+    for _, change_number in ipairs(change_numbers) do
+        table.insert(sections, table.concat(lines_by_change[change_number], "\n"))
+    end
+
+    local msg = translate("digest", { count = digest.count, changes = #change_numbers })
+        .. "\n\n" .. table.concat(sections, "\n\n")
+    if digest.count > #digest.entries then
+        msg = msg .. "\n\n" .. translate("digest_more", { count = digest.count - #digest.entries })
+    end
+    return msg
+end
+
+function format_reviews(reviews)
+    if #reviews.changes == 0 then
+        return "You don't have any open changes."
+    end
+
+    local lines = {}
+    for _, change in ipairs(reviews.changes) do
+        local base_url = get_gerrit_base_url(change.url)
+        local approvals = change.currentPatchSet and change.currentPatchSet.approvals
+        table.insert(lines, string.format(
+            "* %s (%s)%s%s",
+            format_change_subject(base_url, change),
+            format_change_project(base_url, change),
+            approvals and format_approvals(approvals) or "",
+            format_change_status(change) or ""
+        ))
+    end
+    return "Your open changes:\n\n" .. table.concat(lines, "\n")
+end
+
+-- Format the age of something created at the given time, e.g. `3d`.
+local function format_age(created_on, now)
+    local minutes = (now - created_on) // 60
+    if minutes < 60 then
+        return string.format("%dm", minutes)
+    end
+    local hours = minutes // 60
+    if hours < 24 then
+        return string.format("%dh", hours)
+    end
+    return string.format("%dd", hours // 24)
+end
+
+-- Format a Unix timestamp in the timezone of the user, e.g. `2020-02-28 19:00
+-- Europe/Berlin`. The global `timezone` is set for messages to a user; others
+-- are shown in UTC.
+local function format_time(timestamp)
+    local tz = timezone or { name = "UTC", utc_offset = 0 }
+    local local_time = timestamp + tz.utc_offset
+    local seconds = local_time % 86400
+    -- civil date of the day number, see
+    -- http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    local z = local_time // 86400 + 719468
+    local era = z // 146097
+    local doe = z - era * 146097
+    local yoe = (doe - doe // 1460 + doe // 36524 - doe // 146096) // 365
+    local doy = doe - (365 * yoe + yoe // 4 - yoe // 100)
+    local mp = (5 * doy + 2) // 153
+    local day = doy - (153 * mp + 2) // 5 + 1
+    local month = mp < 10 and mp + 3 or mp - 9
+    local year = yoe + era * 400 + (month <= 2 and 1 or 0)
     return string.format(
-        "%s (%s) ☠  Abandoned by %s",
-        format_change_subject(change),
-        format_change_project(base_url, change),
-        format_user(base_url, event.abandoner, "owner")
+        "%04d-%02d-%02d %02d:%02d %s",
+        year, month, day, seconds // 3600, seconds % 3600 // 60, tz.name
     )
 end
 
+function format_queue(queue)
+    if #queue.changes == 0 then
+        return "No changes are waiting for your review. 🎉"
+    end
+
+    local lines = {}
+    for _, change in ipairs(queue.changes) do
+        local base_url = get_gerrit_base_url(change.url)
+        local age = ""
+        if change.createdOn then
+            age = ", " .. format_age(change.createdOn, queue.now) .. " old"
+        end
+        table.insert(lines, string.format(
+            "* %s (%s) by %s%s",
+            format_change_subject(base_url, change),
+            format_change_project(base_url, change),
+            format_user(base_url, change.owner, "owner"),
+            age
+        ))
+    end
+    return "Changes waiting for your review, oldest first:\n\n" .. table.concat(lines, "\n")
+end
+
+function format_review_reminder(reminder)
+    local change = reminder.change
+    local base_url = get_gerrit_base_url(change.url)
+    return translate("review_reminder", {
+        change = format_change_subject(base_url, change),
+        project = format_change_project(base_url, change),
+        age = format_age(reminder.added, reminder.now),
+    })
+end
+
+function format_change_details(details)
+    local change = details.change
+    local base_url = get_gerrit_base_url(change.url)
+    local lines = {
+        string.format(
+            "%s (%s)\n",
+            format_change_subject(base_url, change),
+            format_change_project(base_url, change)
+        ),
+        "Owner: " .. format_user(base_url, change.owner, "owner"),
+        "Status: " .. change.status .. (format_change_status(change) or ""),
+    }
+    if change.createdOn then
+        table.insert(lines, "Created: " .. format_time(change.createdOn))
+    end
+
+    local patchset = change.currentPatchSet
+    if patchset then
+        table.insert(lines, string.format(
+            "Patch set: %d (+%d, %d)",
+            patchset.number,
+            patchset.sizeInsertions,
+            patchset.sizeDeletions
+        ))
+
+        local votes = {}
+        for _, approval in ipairs(patchset.approvals or {}) do
+            local value = tonumber(approval.value) or 0
+            if value ~= 0 then
+                local icon = get_approval_icon(approval.type, value, 0)
+                local by = ""
+                if approval.by then
+                    by = " by " .. format_user(base_url, approval.by, "reviewer")
+                end
+                table.insert(votes, string.format(
+                    "%s%s%d (%s)%s",
+                    icon and icon .. " " or "",
+                    value > 0 and "+" or "",
+                    value,
+                    approval.type,
+                    by
+                ))
+            end
+        end
+        if #votes > 0 then
+            table.insert(lines, "Votes: " .. table.concat(votes, ", "))
+        end
+    end
+
+    local reviewers = {}
+    for _, reviewer in ipairs(change.allReviewers or {}) do
+        if reviewer.email then
+            table.insert(reviewers, format_user(base_url, reviewer, "reviewer"))
+        end
+    end
+    if #reviewers > 0 then
+        table.insert(lines, "Reviewers: " .. table.concat(reviewers, ", "))
+    end
+
+    -- only if the bot lets format scripts query Gerrit; queries are only
+    -- fetched for listed and shown changes, elsewhere (e.g. in notifications)
+    -- `gerrit_query` only returns cached results
+    local topic = gerrit_query and change.topic and quote_query_value(change.topic)
+    if topic then
+        local related = gerrit_query(string.format("topic:%s status:open", topic))
+        local subjects = {}
+        for _, other in ipairs(related or {}) do
+            if other.number ~= change.number then
+                table.insert(subjects, format_change_subject(base_url, other))
+            end
+        end
+        if #subjects > 0 then
+            table.insert(lines, "Same topic: " .. table.concat(subjects, ", "))
+        end
+    end
+
+    return table.concat(lines, "\n")
+end
+
+function format_admin_stats(stats)
+    local lines = {
+        string.format("**Bot statistics** (up for %s)\n", format_age(stats.started, stats.now)),
+        string.format(
+            "Users: %d, %d with notifications enabled, %d active in the last %d days",
+            stats.user_count,
+            stats.enabled_user_count,
+            stats.active_user_count,
+            stats.active_days
+        ),
+    }
+    local flag_names = {}
+    for flag_name in pairs(stats.flag_counts) do
+        table.insert(flag_names, flag_name)
+    end
+    table.sort(flag_names)
+    for _, flag_name in ipairs(flag_names) do
+        table.insert(lines, string.format("* `%s`: %d", flag_name, stats.flag_counts[flag_name]))
+    end
+
+    table.insert(lines, "")
+    table.insert(lines, string.format(
+        "Messages sent in the last 24h: %d (%d failed since the start)",
+        stats.messages_sent,
+        stats.messages_failed
+    ))
+    table.insert(lines, string.format(
+        "Gerrit events processed: %d (%d stream errors)",
+        stats.gerrit_events,
+        stats.gerrit_errors
+    ))
+
+    local connection
+    if stats.gerrit_connected then
+        connection = "✅ healthy"
+    else
+        connection = "⚠️ broken"
+    end
+    if stats.last_gerrit_event then
+        connection = connection .. string.format(
+            ", last event %s ago", format_age(stats.last_gerrit_event, stats.now))
+    end
+    if stats.last_gerrit_error then
+        connection = connection .. string.format(
+            ", last error %s ago", format_age(stats.last_gerrit_error, stats.now))
+    end
+    table.insert(lines, "Gerrit connection: " .. connection)
+
+    return table.concat(lines, "\n")
+end
+
 function format_version_info(version_info)
     return string.format(
         "%s %s (commit id: %s, built with Rust %s for %s on %s)",
@@ -307,6 +730,313 @@ function format_version_info(version_info)
     )
 end
 
+-- Canned replies to commands by language. Placeholders like `{topic}` are
+-- replaced by the arguments of the reply. Replies missing in a language fall
+-- back to English, so a language may translate only some of them.
+REPLIES = {
+    en = {
+        enabled = "Got it! Happy reviewing!",
+        disabled = "Got it! I will stay silent.",
+        state_enabled = "enabled",
+        state_disabled = "disabled",
+        flag_set = "Flag {flag} **{state}**",
+        language_status = "I answer you in `{language}`. Available languages are {languages}.",
+        language_set = "Got it! I will answer you in `{language}`.",
+        language_unknown = "I don't speak `{language}`. Available languages are {languages}.",
+        reset_confirm = "This restores the default flags, and removes your filter, branches, topics, vote threshold, snooze, quiet hours, timezone, digest and language. If you are sure, reply `reset confirm`.",
+        reset_done = "Got it! Your settings are back to the defaults.",
+        reset_nothing = "You already have the default settings.",
+        undo_nothing = "There is nothing to undo.",
+        settings_export = "Your settings, to be restored with `settings import <json>`:\n```json\n{settings}\n```",
+        settings_imported = "Got it! Your settings are imported.",
+        settings_invalid = "These are no valid settings: {error}",
+        undo_flags = "Got it! Your previous flags are restored.",
+        undo_filter = "Got it! Your previous filter `{filter}` is restored.",
+        undo_no_filter = "Got it! Your filter is removed again.",
+        test_not_notified = "You wouldn't be notified about reviews of your changes. Type in **enable** or enable the notifications with `enable <flag>`.",
+        test_filtered = "You wouldn't be notified about the sample review, your filter drops its notification.",
+        filter_status = "The following filter is configured for you: `{filter}`. It is **{state}**, messages matching it are **{mode}**.",
+        filter_dropped = "dropped",
+        filter_kept = "kept, all others dropped",
+        no_filter = "No filter is configured for you.",
+        filter_added = "Filter successfully added and enabled.",
+        filter_invalid = "Your provided filter is invalid. Please double-check the regex you provided. Specifications of the regex are here: https://doc.rust-lang.org/regex/regex/index.html#syntax",
+        filter_enabled = "Filter successfully enabled. The following filter is configured: {filter}",
+        filter_disabled = "Filter successfully disabled.",
+        filter_enable_missing = "Cannot enable filter since there is none configured. User `filter <regex>` to add a new filter.",
+        filter_disable_missing = "No need to disable the filter since there is none configured.",
+        filter_mode_drop = "Got it! Messages matching your filter are dropped.",
+        filter_mode_keep = "Got it! Only messages matching your filter are delivered.",
+        filter_mode_missing = "Cannot set the filter mode since there is no filter configured. Use `filter <regex>` to add a new filter.",
+        snoozed = "Got it! I will hold back your notifications until {until} and tell you afterwards what you missed.",
+        not_snoozing = "You are not snoozing.",
+        welcome_back = "Welcome back! {summary}",
+        welcome_back_nothing_missed = "Welcome back! You didn't miss anything.",
+        quiet_hours_status = "Your quiet hours are {quiet_hours} ({timezone}).",
+        no_quiet_hours = "You don't have quiet hours.",
+        quiet_hours_set = "Got it! I will hold back your notifications during {quiet_hours} ({timezone}) and send them afterwards. Use `timezone <name or offset>` to set your timezone.",
+        quiet_hours_removed = "Got it! No more quiet hours.",
+        quiet_hours_over = "Your quiet hours are over, this happened in the meantime:\n\n{notifications}",
+        timezone_status = "Your timezone is {timezone}.",
+        timezone_set = "Got it! Your timezone is {timezone}.",
+        digest_status = "You get a daily digest at {time} ({timezone}) with {count} notification(s) so far.",
+        no_digest = "You get notifications right away.",
+        digest_set = "Got it! I will send you your notifications once a day at {time} ({timezone}). Use `timezone <name or offset>` to set your timezone.",
+        digest_removed = "Got it! I will notify you right away again.",
+        branches_status = "You only get notifications about changes on the branches {branches}.",
+        all_branches = "You get notifications about changes on all branches.",
+        branches_set = "Got it! You will only get notifications about changes on the branches {branches}.",
+        all_branches_set = "Got it! You will get notifications about changes on all branches.",
+        votes_status = "You get notifications about reviews with {votes}.",
+        votes_all = "all votes",
+        votes_below = "votes below {threshold}",
+        label_votes_all = "all votes on `{label}`",
+        label_votes_below = "votes on `{label}` below {threshold}",
+        label_votes_set = "Got it! You will get notifications about reviews with {votes}.",
+        all_votes = "You get notifications about all votes.",
+        votes_set = "Got it! You will only get notifications about reviews with votes below {threshold}.",
+        all_votes_set = "Got it! You will get notifications about all votes on all labels.",
+        topics_status = "You are subscribed to the topics {topics}.",
+        no_topics = "You are not subscribed to any topics.",
+        topic_subscribed = "Got it! I will notify you about activity on changes with the topic `{topic}`.",
+        topic_already_subscribed = "You are already subscribed to the topic `{topic}`.",
+        topic_unsubscribed = "Got it! No more notifications about the topic `{topic}`.",
+        topic_not_subscribed = "You are not subscribed to the topic `{topic}`.",
+        voting_disabled = "Voting from chat is not enabled.",
+        change_not_found = "I could not find the change {change}.",
+        gerrit_query_failed = "I could not query Gerrit: {error}",
+        review_failed = "I could not review the change {change}: {error}",
+        vote_not_allowed = "You can't vote {vote} from chat. Allowed are {allowed}.",
+        voted = "Voted {vote} on the change {change}.",
+        commented = "Commented on the change {change}:\n\n{quote}",
+        abandoned = "Abandoned the change {change}.",
+        restored = "Restored the change {change}.",
+        permission_denied = "⛔ Permission denied. {reason}",
+        denied_permissions = "Only bot admins can grant and revoke permissions.",
+        denied_roles = "Only bot admins can make other users admins.",
+        denied_stats = "Only bot admins can see the statistics.",
+        denied_rooms = "Only bot admins can manage room subscriptions.",
+        denied_links = "Only bot admins can approve linked Gerrit accounts.",
+        denied_teams = "Only bot admins can manage teams.",
+        denied_restore = "Only bot admins can restore backups.",
+        restore_unknown = "There is no backup {backup}, I keep {backups} backups.",
+        restore_failed = "I couldn't restore backup {backup}, see the log for details.",
+        restored = "Got it! I restored backup {backup} with {users} users.",
+        denied_export_import = "Only bot admins can export and import the state.",
+        state_exported = "Got it! I'm writing the state with {users} users to `{path}` in the export directory.",
+        state_export_disabled = "Exporting and importing the state is not enabled.",
+        state_export_invalid_name = "`{path}` is not a file name. Give the name of a file in the export directory, without `/`, `\\` or `..`.",
+        state_import_invalid = "I can't import `{path}`: {error}",
+        state_import_confirm = "`{path}` has {users} users and {teams} teams. Type in `admin import {path} confirm` to replace the current state with it.",
+        state_imported = "Got it! I imported the state with {users} users from `{path}`.",
+        denied_state_file = "Only bot admins can reload and save the state.",
+        state_file_modified = "Someone modified `{path}` while I'm running, so I stopped saving the state to it. Type in `admin reload` to use the modified file, or `admin save` to overwrite it.",
+        state_reloaded = "Got it! I reloaded the state with {users} users from `{path}`.",
+        state_overwritten = "Got it! I saved the state with {users} users to `{path}`.",
+        denied_prune = "Only bot admins can remove inactive users.",
+        prune_nothing = "All users were active in the last {months} months.",
+        prune_confirm = "{users} users weren't active in the last {months} months: {emails}. Type in `admin prune {months} confirm` to remove them.",
+        pruned = "Got it! I removed {users} users who weren't active in the last {months} months.",
+        denied_manage_changes = "You are not allowed to abandon or restore changes. A bot admin can grant you the permission with `grant {permission} <your email>`.",
+        denied_review = "You are not allowed to vote or comment on changes. A bot admin can grant you the permission with `grant {permission} <your email>`.",
+        permission_granted = "Got it! {email} has the permission `{permission}`.",
+        permission_already_granted = "{email} already has the permission `{permission}`.",
+        permission_revoked = "Got it! {email} doesn't have the permission `{permission}` anymore.",
+        permission_not_granted = "{email} doesn't have the permission `{permission}`.",
+        admin_in_config = "{email} is an admin in the configuration of the bot, I can't change that.",
+        admin_granted = "Got it! {email} is a bot admin now.",
+        admin_already = "{email} is already a bot admin.",
+        admin_revoked = "Got it! {email} isn't a bot admin anymore.",
+        admin_not = "{email} isn't a bot admin.",
+        rooms_only_group = "Room subscriptions can only be managed in group rooms.",
+        room_subscribed = "Got it! I will post review activity of `{project}` in this room.",
+        room_already_subscribed = "This room is already subscribed to `{project}`.",
+        room_unsubscribed = "Got it! I will stop posting review activity of `{project}` in this room.",
+        room_not_subscribed = "This room is not subscribed to `{project}`.",
+        webex_account_missing = "I could not find a Webex account with the email {email}. Notifications are sent to the email of your Gerrit account, so they may never arrive.",
+        webex_email_mismatch = "Your Webex account uses the email {webex_email}, but you wrote from {email}. Make sure your Gerrit account uses the email of your Webex account, otherwise notifications may never arrive.",
+        linked = "Got it! You will get notifications about the Gerrit account {email}.",
+        link_already = "The Gerrit account {email} is already linked to you.",
+        link_taken = "The Gerrit account {email} belongs to another user. Ask a bot admin for help.",
+        link_pending = "Got it! A bot admin has to approve linking the Gerrit account {email} first.",
+        link_requested = "{user} wants to link the Gerrit account {email}. Approve it with `approve link {user}`.",
+        link_approved = "Got it! The Gerrit account {email} is linked to {user}.",
+        no_pending_link = "{user} isn't waiting for a linked Gerrit account.",
+        unlinked = "Got it! No more notifications about the Gerrit account {email}.",
+        not_linked = "The Gerrit account {email} isn't linked to you.",
+        none = "none",
+        unknown_help_topic = "I don't know the command `{topic}`. Type in **help** to see all commands.",
+        no_teams = "There are no teams. Create one with `team <name> add <emails>`.",
+        teams_status = "The teams are {teams}.",
+        team_status = "Team `{team}`\n* Members: {members}\n* Subscriptions: {subscriptions}",
+        unknown_team = "There is no team `{team}`.",
+        team_members_added = "Got it! Added {count} member(s) to the team `{team}`.",
+        team_members_removed = "Got it! Removed {count} member(s) from the team `{team}`.",
+        team_subscribed = "Got it! The team `{team}` will get notifications about the {subscription}.",
+        team_already_subscribed = "The team `{team}` is already subscribed to the {subscription}.",
+        team_unsubscribed = "Got it! The team `{team}` won't get notifications about the {subscription} anymore.",
+        team_not_subscribed = "The team `{team}` is not subscribed to the {subscription}.",
+        team_deleted = "Got it! The team `{team}` is deleted.",
+        invitation = "Hi {name}! I am GerritBot. You were just involved in the change {change} (**{subject}**) on Gerrit. I can notify you about review activity on your changes: just type in **enable**. For more information, type in **help**. I won't write you again unless you ask me to.",
+    },
+    de = {
+        enabled = "Alles klar! Viel Spaß beim Reviewen!",
+        disabled = "Alles klar! Ich bin ab jetzt still.",
+        state_enabled = "aktiviert",
+        state_disabled = "deaktiviert",
+        flag_set = "Flag {flag} **{state}**",
+        language_status = "Ich antworte dir auf `{language}`. Verfügbare Sprachen sind {languages}.",
+        language_set = "Alles klar! Ich antworte dir ab jetzt auf `{language}`.",
+        language_unknown = "Ich spreche kein `{language}`. Verfügbare Sprachen sind {languages}.",
+        reset_confirm = "Das stellt die Standard-Flags wieder her und entfernt deinen Filter, deine Branches, Topics, Stimmen-Schwelle, Snooze, Ruhezeiten, Zeitzone, Zusammenfassung und Sprache. Wenn du sicher bist, antworte mit `reset confirm`.",
+        reset_done = "Alles klar! Deine Einstellungen sind wieder auf den Standardwerten.",
+        reset_nothing = "Du hast bereits die Standardeinstellungen.",
+        undo_nothing = "Es gibt nichts rückgängig zu machen.",
+        settings_export = "Deine Einstellungen, wiederherzustellen mit `settings import <json>`:\n```json\n{settings}\n```",
+        settings_imported = "Alles klar! Deine Einstellungen sind importiert.",
+        settings_invalid = "Das sind keine gültigen Einstellungen: {error}",
+        undo_flags = "Alles klar! Deine vorherigen Flags sind wiederhergestellt.",
+        undo_filter = "Alles klar! Dein vorheriger Filter `{filter}` ist wiederhergestellt.",
+        undo_no_filter = "Alles klar! Dein Filter ist wieder entfernt.",
+        test_not_notified = "Du würdest nicht über Reviews deiner Changes benachrichtigt. Schreib **enable** oder aktiviere die Benachrichtigungen mit `enable <flag>`.",
+        test_filtered = "Du würdest nicht über das Beispiel-Review benachrichtigt, dein Filter verwirft die Benachrichtigung.",
+        filter_status = "Für dich ist folgender Filter eingerichtet: `{filter}`. Er ist **{state}**, passende Nachrichten werden **{mode}**.",
+        filter_dropped = "verworfen",
+        filter_kept = "behalten, alle anderen verworfen",
+        no_filter = "Für dich ist kein Filter eingerichtet.",
+        filter_added = "Filter erfolgreich hinzugefügt und aktiviert.",
+        filter_invalid = "Dein Filter ist ungültig. Bitte prüfe den regulären Ausdruck. Die Syntax ist hier beschrieben: https://doc.rust-lang.org/regex/regex/index.html#syntax",
+        filter_enabled = "Filter erfolgreich aktiviert. Folgender Filter ist eingerichtet: {filter}",
+        filter_disabled = "Filter erfolgreich deaktiviert.",
+        filter_enable_missing = "Der Filter kann nicht aktiviert werden, da keiner eingerichtet ist. Nutze `filter <regex>`, um einen Filter hinzuzufügen.",
+        filter_disable_missing = "Der Filter muss nicht deaktiviert werden, da keiner eingerichtet ist.",
+        filter_mode_drop = "Alles klar! Nachrichten, die zu deinem Filter passen, werden verworfen.",
+        filter_mode_keep = "Alles klar! Nur Nachrichten, die zu deinem Filter passen, werden zugestellt.",
+        filter_mode_missing = "Der Filtermodus kann nicht gesetzt werden, da kein Filter eingerichtet ist. Nutze `filter <regex>`, um einen Filter hinzuzufügen.",
+        snoozed = "Alles klar! Ich halte deine Benachrichtigungen bis {until} zurück und sage dir danach, was du verpasst hast.",
+        not_snoozing = "Du pausierst gerade nicht.",
+        welcome_back = "Willkommen zurück! {summary}",
+        welcome_back_nothing_missed = "Willkommen zurück! Du hast nichts verpasst.",
+        quiet_hours_status = "Deine Ruhezeiten sind {quiet_hours} ({timezone}).",
+        no_quiet_hours = "Du hast keine Ruhezeiten.",
+        quiet_hours_set = "Alles klar! Ich halte deine Benachrichtigungen während {quiet_hours} ({timezone}) zurück und schicke sie dir danach. Nutze `timezone <name or offset>`, um deine Zeitzone zu setzen.",
+        quiet_hours_removed = "Alles klar! Keine Ruhezeiten mehr.",
+        quiet_hours_over = "Deine Ruhezeiten sind vorbei, in der Zwischenzeit ist das passiert:\n\n{notifications}",
+        timezone_status = "Deine Zeitzone ist {timezone}.",
+        timezone_set = "Alles klar! Deine Zeitzone ist {timezone}.",
+        digest_status = "Du bekommst täglich um {time} ({timezone}) eine Zusammenfassung mit bisher {count} Benachrichtigung(en).",
+        no_digest = "Du bekommst Benachrichtigungen sofort.",
+        digest_set = "Alles klar! Ich schicke dir deine Benachrichtigungen einmal täglich um {time} ({timezone}). Nutze `timezone <name or offset>`, um deine Zeitzone zu setzen.",
+        digest_removed = "Alles klar! Ich benachrichtige dich wieder sofort.",
+        branches_status = "Du bekommst nur Benachrichtigungen über Changes auf den Branches {branches}.",
+        all_branches = "Du bekommst Benachrichtigungen über Changes auf allen Branches.",
+        branches_set = "Alles klar! Du bekommst nur noch Benachrichtigungen über Changes auf den Branches {branches}.",
+        all_branches_set = "Alles klar! Du bekommst Benachrichtigungen über Changes auf allen Branches.",
+        votes_status = "Du bekommst Benachrichtigungen über Reviews mit {votes}.",
+        votes_all = "allen Stimmen",
+        votes_below = "Stimmen unter {threshold}",
+        label_votes_all = "allen Stimmen auf `{label}`",
+        label_votes_below = "Stimmen auf `{label}` unter {threshold}",
+        label_votes_set = "Alles klar! Du bekommst Benachrichtigungen über Reviews mit {votes}.",
+        all_votes = "Du bekommst Benachrichtigungen über alle Stimmen.",
+        votes_set = "Alles klar! Du bekommst nur noch Benachrichtigungen über Reviews mit Stimmen unter {threshold}.",
+        all_votes_set = "Alles klar! Du bekommst Benachrichtigungen über alle Stimmen auf allen Labels.",
+        topics_status = "Du hast die Topics {topics} abonniert.",
+        no_topics = "Du hast keine Topics abonniert.",
+        topic_subscribed = "Alles klar! Ich benachrichtige dich über Aktivität auf Changes mit dem Topic `{topic}`.",
+        topic_already_subscribed = "Du hast das Topic `{topic}` bereits abonniert.",
+        topic_unsubscribed = "Alles klar! Keine Benachrichtigungen mehr über das Topic `{topic}`.",
+        topic_not_subscribed = "Du hast das Topic `{topic}` nicht abonniert.",
+        voting_disabled = "Abstimmen aus dem Chat ist nicht aktiviert.",
+        change_not_found = "Ich konnte den Change {change} nicht finden.",
+        gerrit_query_failed = "Ich konnte Gerrit nicht abfragen: {error}",
+        review_failed = "Ich konnte den Change {change} nicht bewerten: {error}",
+        vote_not_allowed = "Du kannst aus dem Chat nicht mit {vote} abstimmen. Erlaubt sind {allowed}.",
+        voted = "Mit {vote} für den Change {change} abgestimmt.",
+        commented = "Den Change {change} kommentiert:\n\n{quote}",
+        abandoned = "Den Change {change} verworfen.",
+        restored = "Den Change {change} wiederhergestellt.",
+        permission_denied = "⛔ Keine Berechtigung. {reason}",
+        denied_permissions = "Nur Bot-Admins können Berechtigungen erteilen und entziehen.",
+        denied_roles = "Nur Bot-Admins können andere Benutzer zu Admins machen.",
+        denied_stats = "Nur Bot-Admins können die Statistiken sehen.",
+        denied_rooms = "Nur Bot-Admins können Raum-Abonnements verwalten.",
+        denied_links = "Nur Bot-Admins können verknüpfte Gerrit-Konten bestätigen.",
+        denied_teams = "Nur Bot-Admins können Teams verwalten.",
+        denied_restore = "Nur Bot-Admins können Backups wiederherstellen.",
+        restore_unknown = "Es gibt kein Backup {backup}, ich behalte {backups} Backups.",
+        restore_failed = "Ich konnte Backup {backup} nicht wiederherstellen, Details stehen im Log.",
+        restored = "Alles klar! Ich habe Backup {backup} mit {users} Benutzern wiederhergestellt.",
+        denied_export_import = "Nur Bot-Admins können den Zustand exportieren und importieren.",
+        state_exported = "Alles klar! Ich schreibe den Zustand mit {users} Benutzern nach `{path}` im Export-Verzeichnis.",
+        state_export_disabled = "Das Exportieren und Importieren des Zustands ist nicht aktiviert.",
+        state_export_invalid_name = "`{path}` ist kein Dateiname. Gib den Namen einer Datei im Export-Verzeichnis an, ohne `/`, `\\` oder `..`.",
+        state_import_invalid = "Ich kann `{path}` nicht importieren: {error}",
+        state_import_confirm = "`{path}` hat {users} Benutzer und {teams} Teams. Schreib `admin import {path} confirm`, um den aktuellen Zustand damit zu ersetzen.",
+        state_imported = "Alles klar! Ich habe den Zustand mit {users} Benutzern aus `{path}` importiert.",
+        denied_state_file = "Nur Bot-Admins können den Zustand neu laden und speichern.",
+        state_file_modified = "Jemand hat `{path}` geändert, während ich laufe, deshalb speichere ich den Zustand nicht mehr darin. Schreib `admin reload`, um die geänderte Datei zu verwenden, oder `admin save`, um sie zu überschreiben.",
+        state_reloaded = "Alles klar! Ich habe den Zustand mit {users} Benutzern aus `{path}` neu geladen.",
+        state_overwritten = "Alles klar! Ich habe den Zustand mit {users} Benutzern in `{path}` gespeichert.",
+        denied_prune = "Nur Bot-Admins können inaktive Benutzer entfernen.",
+        prune_nothing = "Alle Benutzer waren in den letzten {months} Monaten aktiv.",
+        prune_confirm = "{users} Benutzer waren in den letzten {months} Monaten nicht aktiv: {emails}. Schreib `admin prune {months} confirm`, um sie zu entfernen.",
+        pruned = "Alles klar! Ich habe {users} Benutzer entfernt, die in den letzten {months} Monaten nicht aktiv waren.",
+        denied_manage_changes = "Du darfst keine Changes verwerfen oder wiederherstellen. Ein Bot-Admin kann dir die Berechtigung mit `grant {permission} <deine E-Mail>` erteilen.",
+        denied_review = "Du darfst keine Changes bewerten oder kommentieren. Ein Bot-Admin kann dir die Berechtigung mit `grant {permission} <deine E-Mail>` erteilen.",
+        permission_granted = "Alles klar! {email} hat die Berechtigung `{permission}`.",
+        permission_already_granted = "{email} hat die Berechtigung `{permission}` bereits.",
+        permission_revoked = "Alles klar! {email} hat die Berechtigung `{permission}` nicht mehr.",
+        permission_not_granted = "{email} hat die Berechtigung `{permission}` nicht.",
+        admin_in_config = "{email} ist in der Konfiguration des Bots als Admin eingetragen, das kann ich nicht ändern.",
+        admin_granted = "Alles klar! {email} ist jetzt Bot-Admin.",
+        admin_already = "{email} ist bereits Bot-Admin.",
+        admin_revoked = "Alles klar! {email} ist kein Bot-Admin mehr.",
+        admin_not = "{email} ist kein Bot-Admin.",
+        rooms_only_group = "Raum-Abonnements können nur in Gruppenräumen verwaltet werden.",
+        room_subscribed = "Alles klar! Ich poste die Review-Aktivität von `{project}` in diesem Raum.",
+        room_already_subscribed = "Dieser Raum hat `{project}` bereits abonniert.",
+        room_unsubscribed = "Alles klar! Ich poste keine Review-Aktivität von `{project}` mehr in diesem Raum.",
+        room_not_subscribed = "Dieser Raum hat `{project}` nicht abonniert.",
+        webex_account_missing = "Ich konnte kein Webex-Konto mit der E-Mail {email} finden. Benachrichtigungen gehen an die E-Mail deines Gerrit-Kontos und kommen daher vielleicht nie an.",
+        webex_email_mismatch = "Dein Webex-Konto nutzt die E-Mail {webex_email}, du hast aber von {email} geschrieben. Stelle sicher, dass dein Gerrit-Konto die E-Mail deines Webex-Kontos nutzt, sonst kommen Benachrichtigungen vielleicht nie an.",
+        linked = "Alles klar! Du bekommst Benachrichtigungen zum Gerrit-Konto {email}.",
+        link_already = "Das Gerrit-Konto {email} ist bereits mit dir verknüpft.",
+        link_taken = "Das Gerrit-Konto {email} gehört einem anderen Nutzer. Frag einen Bot-Admin um Hilfe.",
+        link_pending = "Alles klar! Ein Bot-Admin muss die Verknüpfung mit dem Gerrit-Konto {email} erst bestätigen.",
+        link_requested = "{user} möchte das Gerrit-Konto {email} verknüpfen. Bestätige es mit `approve link {user}`.",
+        link_approved = "Alles klar! Das Gerrit-Konto {email} ist mit {user} verknüpft.",
+        no_pending_link = "{user} wartet auf kein verknüpftes Gerrit-Konto.",
+        unlinked = "Alles klar! Keine Benachrichtigungen mehr zum Gerrit-Konto {email}.",
+        not_linked = "Das Gerrit-Konto {email} ist nicht mit dir verknüpft.",
+        none = "keine",
+        unknown_help_topic = "Ich kenne den Befehl `{topic}` nicht. Schreib **help**, um alle Befehle zu sehen.",
+        no_teams = "Es gibt keine Teams. Erstelle eines mit `team <name> add <emails>`.",
+        teams_status = "Die Teams sind {teams}.",
+        team_status = "Team `{team}`\n* Mitglieder: {members}\n* Abonnements: {subscriptions}",
+        unknown_team = "Es gibt kein Team `{team}`.",
+        team_members_added = "Alles klar! {count} Mitglied(er) zum Team `{team}` hinzugefügt.",
+        team_members_removed = "Alles klar! {count} Mitglied(er) aus dem Team `{team}` entfernt.",
+        team_subscribed = "Alles klar! Das Team `{team}` bekommt Benachrichtigungen zu {subscription}.",
+        team_already_subscribed = "Das Team `{team}` hat {subscription} bereits abonniert.",
+        team_unsubscribed = "Alles klar! Das Team `{team}` bekommt keine Benachrichtigungen mehr zu {subscription}.",
+        team_not_subscribed = "Das Team `{team}` hat {subscription} nicht abonniert.",
+        team_deleted = "Alles klar! Das Team `{team}` ist gelöscht.",
+        invitation = "Hallo {name}! Ich bin GerritBot. Du warst gerade am Change {change} (**{subject}**) in Gerrit beteiligt. Ich kann dich über Review-Aktivität auf deinen Changes benachrichtigen: schreib einfach **enable**. Mehr Informationen bekommst du mit **help**. Ich schreibe dir nicht wieder, außer du bittest mich darum.",
+    },
+}
+
+function format_reply(reply)
+    local replies = REPLIES[reply.language] or {}
+    local text = replies[reply.id] or REPLIES.en[reply.id]
+    if not text then
+        return nil
+    end
+    return (text:gsub("{([%w_]+)}", function(name)
+        return reply.args[name]
+    end))
+end
+
 function format_greeting()
     return [=[
 Hi. I am GerritBot. I can watch Gerrit reviews for you, and notify you about new +1/-1's.
@@ -323,13 +1053,19 @@ local FLAG_DESCRIPTIONS = {
     notify_review_inline_comments = "Toggle notifications messages for inline comments.",
     notify_review_responses = "Toggle notification on follow up comments to earlier review comments.",
     notify_reviewer_added = "Toggle notification messages when added as reviewer.",
-    notify_change_abandoned = "Toggle notification when a change is abandoned.",
+    notify_change_abandoned = "Toggle notification when a change is abandoned or deleted.",
+    notify_cc = "Toggle notification about changes you are CC'd on.",
+    notify_submittable = "Toggle notification when your change becomes ready to submit.",
+    notify_uploaded_by_me = "Toggle notification for reviews of patchsets you uploaded to changes of others.",
+    notify_merge_conflicts = "Toggle notification when your change has a merge conflict and needs a rebase.",
+    notify_stale_reviews = "Toggle reminders about your changes without activity for a while.",
+    notify_review_reminders = "Toggle reminders about changes you were added to as reviewer, but didn't vote on yet.",
     notify_change_merged = "Toggle notification when a change is merged.",
 }
 
 local FLAG_SINGLE_LINE_FORMAT = "* `%s` -- %s"
 
-function format_help()
+local function help_enable()
     local flags = {}
 
     for flag_name, flag_description in pairs(FLAG_DESCRIPTIONS) do
@@ -339,31 +1075,303 @@ function format_help()
     table.sort(flags)
 
     return [=[
-Commands:
-
 `enable` -- I will start notifying you.
 
 `disable` -- I will stop notifying you.
 
 `enable <flag>`, `disable <flag>` -- Enable or disable specific behavior.  The following flags are available:
 
-]=] .. table.concat(flags, "\n") .. [=[
-
+]=] .. table.concat(flags, "\n")
+end
 
+local function help_filter()
+    return [=[
 `filter <regex>` -- Filter all messages by applying the specified regex pattern. If the pattern matches, the message is filtered. The pattern is applied to the full text I send to you. Be aware, to send this command **not** in markdown mode, otherwise, Spark would eat some special characters in the pattern. For regex specification, cf. https://docs.rs/regex/0.2.10/regex/#syntax.
 
 `filter enable` -- Enable the filtering of messages with the configured filter.
 
 `filter disable` -- Disable the filtering of messages with the configured filter.
 
-`status` -- Show if I am notifying you, and a little bit more information. 😉
+`filter mode keep`, `filter mode drop` -- Only deliver the messages matching the filter, or drop them (default).]=]
+end
+
+local function help_branch()
+    return [=[
+`branch only <branch>...` -- Only notify you about changes on these branches. A `*` in a branch matches any characters, e.g. `release/*`.
+
+`branch all` -- Notify you about changes on all branches again.
+
+`branch` -- Show the branches you get notifications about.]=]
+end
+
+local function help_votes()
+    return [=[
+`votes negative`, `votes below <value>` -- Only notify you about reviews with votes below 0 or the value, e.g. `votes below +2`. `votes all` notifies you about all votes again, `votes` shows the setting.
+
+`votes <label> negative|below <value>|all` -- Choose which votes on a label you get notifications about, e.g. `votes Verified negative` and `votes Code-Review all`.]=]
+end
+
+local function help_snooze()
+    return [=[
+`snooze <duration>` -- Hold back your notifications for a while, e.g. `snooze 30m`, `snooze 2h` or `snooze until tomorrow` (8:00 in your timezone). Afterwards, I will tell you what you missed.
+
+`unsnooze` -- Stop snoozing.]=]
+end
+
+local function help_quiet_hours()
+    return [=[
+`quiet hours <from>-<to> [weekends]` -- Hold back your notifications every day during these hours, e.g. `quiet hours 19:00-08:00 weekends`, and send them afterwards. `quiet hours off` removes them.]=]
+end
+
+local function help_digest()
+    return [=[
+`digest at <time>` -- Instead of notifying you right away, send you all notifications once a day at this time, e.g. `digest at 17:00`. `digest off` goes back to notifying you right away.]=]
+end
+
+local function help_timezone()
+    return [=[
+`timezone <name or offset>` -- Set your timezone for the quiet hours, the digest and the times I show you, e.g. `timezone Europe/Berlin` or `timezone UTC+2`.]=]
+end
+
+local function help_language()
+    return [=[
+`language [<code>]` -- Show or set the language of my replies and notifications, e.g. `language de`.]=]
+end
+
+local function help_status()
+    return [=[
+`status` -- Show if I am notifying you, and a little bit more information. 😉]=]
+end
+
+local function help_test()
+    return [=[
+`test` -- Show how I would notify you about a review of one of your changes, with your flags, filter and the format script. The review is made up.]=]
+end
+
+local function help_settings()
+    return [=[
+`settings` -- List all your flags and settings, e.g. your filter, branches and topics.
+
+`settings export` -- Show your settings as JSON, e.g. to move them to another instance of me or to share them with your teammates.
+
+`settings import <json>` -- Replace your settings with exported ones.]=]
+end
+
+local function help_reset()
+    return [=[
+`reset` -- Restore the default flags, and remove your filter, branches, topics, snooze and other settings. Asks for confirmation with `reset confirm` first.]=]
+end
+
+local function help_undo()
+    return [=[
+`undo` -- Undo your last change of the flags, filter, branches or topics, e.g. a filter with a typo. Type in `undo` again to redo it.]=]
+end
+
+local function help_review()
+    return [=[
+`review <change> [<label>]<value> [<message>]` -- Vote on the current patch set of the change, e.g. `review 12345 +1` or `review 12345 Verified-1 Fails on Windows`. The label defaults to `Code-Review`. Only available if the admin allowed the label and value, and granted you the permission with `grant review <email>`.]=]
+end
+
+local function help_reviews()
+    return [=[
+`reviews` -- List your open changes with their votes.]=]
+end
+
+local function help_queue()
+    return [=[
+`queue` -- List the changes waiting for your vote, oldest first.]=]
+end
+
+local function help_show()
+    return [=[
+`show <change>` -- Show the details of the change, e.g. its votes and reviewers.]=]
+end
+
+local function help_comment()
+    return [=[
+`comment <change> <text>` -- Comment on the current patch set of the change. Only if a bot admin granted you the permission with `grant review <email>`.]=]
+end
+
+local function help_abandon()
+    return [=[
+`abandon <change> [<reason>]`, `restore <change>` -- Abandon or restore the change. Only if a bot admin granted you the permission with `grant manage_changes <email>`; `revoke manage_changes <email>` takes it back.]=]
+end
+
+local function help_admins()
+    return [=[
+`grant admin <email>`, `revoke admin <email>` -- Make the user a bot admin, or take the role away. Admins may run all commands and have all permissions. Only for bot admins.]=]
+end
+
+local function help_link()
+    return [=[
+`link gerrit <email>`, `unlink gerrit <email>` -- Get notifications about the Gerrit account with this email, if it doesn't use the email of your Webex account. Link as many accounts as you use; `alias add <email>` and `alias remove <email>` do the same. A bot admin may have to approve it with `approve link <your email>`.]=]
+end
+
+local function help_subscribe()
+    return [=[
+`subscribe topic <topic>`, `unsubscribe topic <topic>` -- Get notified about activity on all changes with this Gerrit topic, even if you don't take part in them. `topics` lists your subscriptions.
+
+`subscribe room <project>`, `unsubscribe room <project>` -- Post review activity of the project in the group room the command is sent in. Only for bot admins.]=]
+end
+
+local function help_admin_stats()
+    return [=[
+`admin stats` -- Show statistics about the users, the sent messages and the connection to Gerrit. Only for bot admins.
+
+`admin restore <n>` -- Replace the settings of all users with the `n`-th last saved ones, e.g. after an accidental `reset`. Only for bot admins.
+
+`admin export [<file>]` -- Write the users, rooms and teams to a file in the export directory on the host of the bot, e.g. to move the bot to another host. Only for bot admins, and only if the export directory is configured.
 
-`help` -- This message
+`admin import <file>` -- Show what the file in the export directory contains, and replace the users, rooms and teams with it when confirmed with `admin import <file> confirm`. Only for bot admins.
+
+`admin reload`, `admin save` -- If the state file was edited while the bot runs, the bot stops saving to it. Use the edited file, or overwrite it with the state of the bot. Only for bot admins.
+
+`admin prune <months>` -- List the users who neither got a notification nor sent a command in the last months, e.g. people who left, and remove them when confirmed with `admin prune <months> confirm`. Only for bot admins.]=]
+end
+
+local function help_team()
+    return [=[
+`team <name> add <emails>`, `team <name> remove <emails>` -- Manage the members of a team. `teams` lists the teams, `team <name>` shows a team, and `team <name> delete` deletes it. Only for bot admins.
+
+`team <name> subscribe project <project> [branch <branch>]`, `team <name> subscribe topic <topic>` -- Notify all members of the team about activity on the changes, including members who join later. Unsubscribe with `team <name> unsubscribe ...`. Only for bot admins.]=]
+end
+
+-- Help of the commands, in the order of the full help. `help <topic>` shows
+-- the entry with the topic as one of its keywords.
+local COMMAND_HELP = {
+    { keywords = { "enable", "disable", "flags" }, format = help_enable },
+    { keywords = { "filter" }, format = help_filter },
+    { keywords = { "branch" }, format = help_branch },
+    { keywords = { "votes" }, format = help_votes },
+    { keywords = { "snooze", "unsnooze" }, format = help_snooze },
+    { keywords = { "quiet" }, format = help_quiet_hours },
+    { keywords = { "digest" }, format = help_digest },
+    { keywords = { "timezone" }, format = help_timezone },
+    { keywords = { "language" }, format = help_language },
+    { keywords = { "status" }, format = help_status },
+    { keywords = { "test" }, format = help_test },
+    { keywords = { "settings" }, format = help_settings },
+    { keywords = { "reset" }, format = help_reset },
+    { keywords = { "undo" }, format = help_undo },
+    { keywords = { "review" }, format = help_review },
+    { keywords = { "reviews" }, format = help_reviews },
+    { keywords = { "queue" }, format = help_queue },
+    { keywords = { "show" }, format = help_show },
+    { keywords = { "comment" }, format = help_comment },
+    { keywords = { "abandon", "restore" }, format = help_abandon },
+    { keywords = { "grant", "revoke" }, format = help_admins },
+    { keywords = { "link", "unlink", "approve", "alias" }, format = help_link },
+    { keywords = { "subscribe", "unsubscribe", "topics" }, format = help_subscribe },
+    { keywords = { "admin", "stats" }, format = help_admin_stats },
+    { keywords = { "team", "teams" }, format = help_team },
+}
+
+function format_help()
+    local sections = {}
+    for _, entry in ipairs(COMMAND_HELP) do
+        table.insert(sections, entry.format())
+    end
+
+    return "Commands:\n\n" .. table.concat(sections, "\n\n") .. [=[
+
+
+`help`, `help <topic>` -- This message, or only the help of a command, e.g. `help filter` or `help subscribe`.
 
 This project is open source, feel free to help us at: https://github.com/boxdot/gerritbot-rs
 ]=]
 end
 
+function format_command_help(help)
+    for _, entry in ipairs(COMMAND_HELP) do
+        for _, keyword in ipairs(entry.keywords) do
+            if keyword == help.topic then
+                return entry.format()
+            end
+        end
+    end
+    return nil
+end
+
+local function format_code_list(items)
+    local quoted = {}
+    for _, item in ipairs(items) do
+        table.insert(quoted, "`" .. item .. "`")
+    end
+    return table.concat(quoted, ", ")
+end
+
+local function on_off(value)
+    return value and "on" or "off"
+end
+
+function format_settings(settings)
+    local lines = {
+        string.format(
+            "Notifications are **%s**. Your flags:\n",
+            settings.enabled and "enabled" or "disabled"
+        ),
+    }
+    local flag_names = {}
+    for flag_name in pairs(settings.flags) do
+        table.insert(flag_names, flag_name)
+    end
+    table.sort(flag_names)
+    for _, flag_name in ipairs(flag_names) do
+        table.insert(lines, string.format("* `%s`: %s", flag_name, on_off(settings.flags[flag_name])))
+    end
+    table.insert(lines, "")
+
+    local filter = settings.filter
+    if filter then
+        table.insert(lines, string.format(
+            "Filter: `%s` (%s, mode %s)",
+            filter.regex,
+            filter.enabled and "enabled" or "disabled",
+            filter.mode
+        ))
+    else
+        table.insert(lines, "Filter: none")
+    end
+
+    if #settings.branches > 0 then
+        table.insert(lines, "Branches: " .. format_code_list(settings.branches))
+    else
+        table.insert(lines, "Branches: all")
+    end
+    if #settings.topics > 0 then
+        table.insert(lines, "Topics: " .. format_code_list(settings.topics))
+    else
+        table.insert(lines, "Topics: none")
+    end
+    local votes = { settings.vote_threshold and "below " .. settings.vote_threshold or "all" }
+    local labels = {}
+    for label in pairs(settings.label_votes) do
+        table.insert(labels, label)
+    end
+    table.sort(labels)
+    for _, label in ipairs(labels) do
+        table.insert(votes, string.format("`%s` %s", label, settings.label_votes[label]))
+    end
+    table.insert(lines, "Votes: " .. table.concat(votes, ", "))
+    if #settings.gerrit_emails > 0 then
+        table.insert(lines, "Linked Gerrit accounts: " .. table.concat(settings.gerrit_emails, ", "))
+    end
+
+    table.insert(lines, "Timezone: " .. settings.timezone)
+    table.insert(lines, "Quiet hours: " .. (settings.quiet_hours or "none"))
+    if settings.digest then
+        table.insert(lines, "Digest: daily at " .. settings.digest)
+    else
+        table.insert(lines, "Digest: off")
+    end
+    table.insert(lines, "Language: " .. (settings.language or "en"))
+    if settings.snoozed_until then
+        table.insert(lines, "Snoozed until " .. settings.snoozed_until)
+    end
+
+    return table.concat(lines, "\n")
+end
+
 function format_status(status_details, user_flags)
     local enabled = status_details.user_enabled
     local other_count = status_details.enabled_user_count - (enabled and 1 or 0)
@@ -400,6 +1408,14 @@ function format_status(status_details, user_flags)
         flags_string = "No flags are enabled for you."
     end
 
+    if status_details.notifications > 0 then
+        flags_string = flags_string .. string.format(
+            "\n\nI sent you %d notification(s) so far, the last one on %s.",
+            status_details.notifications,
+            format_time(status_details.last_notification)
+        )
+    end
+
     return string.format(
         "Notifications for you are **%s**. I am notifying %s.\n\n%s",
         status_details.user_enabled and "enabled" or "disabled",
@@ -1,35 +1,290 @@
-use rlua::{prelude::*, StdLib as LuaStdLib};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use regex::Regex;
+use rlua::{prelude::*, HookTriggers, StdLib as LuaStdLib};
 use serde::Serialize;
 
 use gerritbot_gerrit as gerrit;
+use gerritbot_spark as spark;
 
-use crate::state::{User, NOTIFICATION_FLAGS};
+use crate::state::{Digest, FilterMode, Timezone, User, UserFlag, NOTIFICATION_FLAGS, ROOM_FLAGS};
+use crate::templates::Templates;
 use crate::version::VersionInfo;
 use crate::IsHuman;
 
 pub const DEFAULT_FORMAT_SCRIPT: &str = include_str!("format.lua");
 
+/// Number of Lua instructions a format script may run for a message.
+const INSTRUCTION_LIMIT: usize = 10_000_000;
+/// Number of instructions between the checks of the limit.
+const INSTRUCTION_CHECK_INTERVAL: u32 = 10_000;
+/// Memory a format script may use in bytes.
+const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Runs the Gerrit queries of the format scripts.
+type GerritQuery = Arc<Mutex<dyn FnMut(&str) -> Result<Vec<gerrit::Change>, String> + Send>>;
+
 pub trait MessageInput: Serialize {
     const FORMAT_FUNCTION: &'static str;
+
+    /// Change the input is about, to pick the format script of its project,
+    /// and for the message if the script fails.
+    fn change(&self) -> Option<&gerrit::Change> {
+        None
+    }
+}
+
+/// Message produced by a format function. Format functions return either
+/// the markdown as string, or a table with the fields `markdown`, `text` and
+/// `card`, of which `markdown` or `text` is required.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub markdown: String,
+    /// Plain text shown by clients that can't render markdown.
+    pub text: Option<String>,
+    /// Adaptive Card sent along with the message.
+    pub card: Option<serde_json::Value>,
 }
 
-impl<'a> MessageInput for &'a gerrit::CommentAddedEvent {
+impl From<String> for Message {
+    fn from(markdown: String) -> Self {
+        Self {
+            markdown,
+            text: None,
+            card: None,
+        }
+    }
+}
+
+impl std::ops::Deref for Message {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.markdown
+    }
+}
+
+impl Message {
+    fn from_lua<'lua>(
+        value: LuaValue<'lua>,
+        lua: rlua::Context<'lua>,
+    ) -> Result<Option<Self>, String> {
+        let table = match value {
+            LuaNil => return Ok(None),
+            LuaValue::Table(table) => table,
+            value => {
+                return Option::<String>::from_lua(value, lua)
+                    .map(|message| message.map(Message::from))
+                    .map_err(|e| format!("failed to convert formatting result: {}", e))
+            }
+        };
+        let field = |name: &str| -> Result<Option<String>, String> {
+            table
+                .get(name)
+                .map_err(|e| format!("invalid {} in formatting result: {}", name, e))
+        };
+        let markdown = field("markdown")?;
+        let text = field("text")?;
+        let card = match table.get::<_, LuaValue>("card") {
+            Ok(LuaNil) => None,
+            Ok(card) => Some(
+                rlua_serde::from_value(card)
+                    .map_err(|e| format!("failed to convert card: {}", e))?,
+            ),
+            Err(e) => return Err(format!("invalid card in formatting result: {}", e)),
+        };
+        let markdown = markdown
+            .or_else(|| text.clone())
+            .ok_or("formatting result has neither markdown nor text")?;
+        Ok(Some(Self {
+            markdown,
+            text,
+            card,
+        }))
+    }
+}
+
+/// Name of the optional Lua function producing an Adaptive Card for the input.
+fn card_function_name<I: MessageInput>() -> String {
+    format!("{}_card", I::FORMAT_FUNCTION)
+}
+
+impl MessageInput for &gerrit::CommentAddedEvent {
     const FORMAT_FUNCTION: &'static str = "format_comment_added";
+
+    fn change(&self) -> Option<&gerrit::Change> {
+        Some(&self.change)
+    }
 }
 
-impl<'a> MessageInput for &'a gerrit::ReviewerAddedEvent {
+impl MessageInput for &gerrit::ReviewerAddedEvent {
     const FORMAT_FUNCTION: &'static str = "format_reviewer_added";
+
+    fn change(&self) -> Option<&gerrit::Change> {
+        Some(&self.change)
+    }
 }
 
-impl<'a> MessageInput for &'a gerrit::ChangeMergedEvent {
+impl MessageInput for &gerrit::ChangeMergedEvent {
     const FORMAT_FUNCTION: &'static str = "format_change_merged";
+
+    fn change(&self) -> Option<&gerrit::Change> {
+        Some(&self.change)
+    }
 }
 
-impl<'a> MessageInput for &'a gerrit::ChangeAbandonedEvent {
+impl MessageInput for &gerrit::ChangeAbandonedEvent {
     const FORMAT_FUNCTION: &'static str = "format_change_abandoned";
+
+    fn change(&self) -> Option<&gerrit::Change> {
+        Some(&self.change)
+    }
+}
+
+impl MessageInput for &gerrit::ChangeDeletedEvent {
+    const FORMAT_FUNCTION: &'static str = "format_change_deleted";
+
+    fn change(&self) -> Option<&gerrit::Change> {
+        Some(&self.change)
+    }
+}
+
+/// A change that just became submittable with the review of `reviewer`.
+#[derive(Serialize)]
+pub struct ChangeSubmittable<'a> {
+    pub change: &'a gerrit::Change,
+    pub reviewer: &'a gerrit::User,
+}
+
+impl MessageInput for ChangeSubmittable<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_change_submittable";
+
+    fn change(&self) -> Option<&gerrit::Change> {
+        Some(self.change)
+    }
+}
+
+/// An open change that can't be merged anymore without a rebase.
+#[derive(Serialize)]
+pub struct MergeConflict<'a> {
+    pub change: &'a gerrit::Change,
+}
+
+impl MessageInput for MergeConflict<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_merge_conflict";
+
+    fn change(&self) -> Option<&gerrit::Change> {
+        Some(self.change)
+    }
 }
 
-impl<'a> MessageInput for &'a VersionInfo {
+/// Open changes of a user without activity for a number of days.
+#[derive(Serialize)]
+pub struct StaleReviews<'a> {
+    pub changes: Vec<&'a gerrit::Change>,
+    pub days: u32,
+}
+
+impl MessageInput for StaleReviews<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_stale_reviews";
+}
+
+/// Reminder for a reviewer who didn't vote on a change yet.
+#[derive(Serialize)]
+pub struct ReviewReminder<'a> {
+    pub change: &'a gerrit::Change,
+    /// When the reviewer was added.
+    #[serde(with = "gerrit::timestamp")]
+    pub added: DateTime<Utc>,
+    /// Number of the reminder, counted from 1.
+    pub sent: u32,
+    #[serde(with = "gerrit::timestamp")]
+    pub now: DateTime<Utc>,
+}
+
+impl MessageInput for ReviewReminder<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_review_reminder";
+
+    fn change(&self) -> Option<&gerrit::Change> {
+        Some(self.change)
+    }
+}
+
+impl MessageInput for &Digest {
+    const FORMAT_FUNCTION: &'static str = "format_digest";
+}
+
+/// Open changes of a user.
+#[derive(Serialize)]
+pub struct Reviews<'a> {
+    pub changes: &'a [gerrit::Change],
+}
+
+impl MessageInput for Reviews<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_reviews";
+}
+
+/// Changes waiting for the review of a user.
+#[derive(Serialize)]
+pub struct ReviewQueue<'a> {
+    pub changes: &'a [gerrit::Change],
+    /// To tell the age of the changes.
+    #[serde(with = "gerrit::timestamp")]
+    pub now: DateTime<Utc>,
+}
+
+impl MessageInput for ReviewQueue<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_queue";
+}
+
+#[derive(Serialize)]
+pub struct ChangeDetails<'a> {
+    pub change: &'a gerrit::Change,
+}
+
+impl MessageInput for ChangeDetails<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_change_details";
+
+    fn change(&self) -> Option<&gerrit::Change> {
+        Some(self.change)
+    }
+}
+
+#[derive(Serialize)]
+pub struct AdminStats {
+    pub user_count: usize,
+    pub enabled_user_count: usize,
+    /// Number of users notified or sending commands in the last
+    /// `active_days` days.
+    pub active_user_count: usize,
+    pub active_days: i64,
+    /// Number of users per notification flag.
+    pub flag_counts: BTreeMap<String, usize>,
+    pub messages_sent: u64,
+    pub messages_failed: u64,
+    pub gerrit_events: u64,
+    pub gerrit_errors: u64,
+    pub gerrit_connected: bool,
+    #[serde(with = "gerrit::timestamp")]
+    pub started: DateTime<Utc>,
+    #[serde(with = "gerrit::timestamp::option")]
+    pub last_gerrit_event: Option<DateTime<Utc>>,
+    #[serde(with = "gerrit::timestamp::option")]
+    pub last_gerrit_error: Option<DateTime<Utc>>,
+    #[serde(with = "gerrit::timestamp")]
+    pub now: DateTime<Utc>,
+}
+
+impl MessageInput for AdminStats {
+    const FORMAT_FUNCTION: &'static str = "format_admin_stats";
+}
+
+impl MessageInput for &VersionInfo {
     const FORMAT_FUNCTION: &'static str = "format_version_info";
 }
 
@@ -40,10 +295,33 @@ impl MessageInput for HelpMessage {
     const FORMAT_FUNCTION: &'static str = "format_help";
 }
 
+/// Help of a single command, or of a few related ones.
+#[derive(Serialize)]
+struct CommandHelp<'a> {
+    topic: &'a str,
+}
+
+impl MessageInput for CommandHelp<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_command_help";
+}
+
+/// Canned reply to a command from the `REPLIES` catalog of the format
+/// script.
+#[derive(Serialize)]
+struct CannedReply<'a> {
+    id: &'a str,
+    language: Option<&'a str>,
+    args: BTreeMap<&'a str, String>,
+}
+
+impl MessageInput for CannedReply<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_reply";
+}
+
 #[derive(Serialize)]
 pub struct GreetingMessage;
 
-impl<'a> MessageInput for GreetingMessage {
+impl MessageInput for GreetingMessage {
     const FORMAT_FUNCTION: &'static str = "format_greeting";
 }
 
@@ -51,28 +329,133 @@ impl<'a> MessageInput for GreetingMessage {
 struct StatusDetails {
     user_enabled: bool,
     enabled_user_count: usize,
+    /// Number of notifications sent to the user.
+    notifications: u64,
+    #[serde(with = "gerrit::timestamp::option")]
+    last_notification: Option<DateTime<Utc>>,
 }
 
 impl MessageInput for StatusDetails {
     const FORMAT_FUNCTION: &'static str = "format_status";
 }
 
+#[derive(Serialize)]
+struct SettingsDetails<'a> {
+    enabled: bool,
+    /// All notification flags, and whether they are on.
+    flags: BTreeMap<String, bool>,
+    filter: Option<FilterDetails<'a>>,
+    branches: &'a [String],
+    topics: Vec<&'a str>,
+    /// Only votes below it are notified about.
+    vote_threshold: Option<i32>,
+    /// Which votes on the labels are notified about, e.g. `below 0`.
+    label_votes: BTreeMap<&'a str, String>,
+    /// Linked Gerrit emails.
+    gerrit_emails: Vec<&'a spark::EmailRef>,
+    timezone: String,
+    quiet_hours: Option<String>,
+    digest: Option<String>,
+    snoozed_until: Option<String>,
+    /// Language code of the replies, if not the default.
+    language: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct FilterDetails<'a> {
+    regex: &'a str,
+    enabled: bool,
+    mode: FilterMode,
+}
+
+impl MessageInput for SettingsDetails<'_> {
+    const FORMAT_FUNCTION: &'static str = "format_settings";
+}
+
 pub struct Formatter {
-    lua: Lua,
+    lua: Script,
+    /// Format scripts of the projects matching the regular expressions. The
+    /// first match is used, and functions it lacks are taken from the main
+    /// script.
+    project_scripts: Vec<(Regex, Script)>,
+    /// Exposed to the format scripts as `gerrit_query`.
+    gerrit_query: Option<GerritQuery>,
+    /// Used instead of the functions of the main format script.
+    templates: Option<Templates>,
+    /// Exposed to the format script as `gerrit_base_url`.
+    gerrit_base_url: Option<String>,
+    adaptive_cards: bool,
 }
 
 impl Default for Formatter {
     fn default() -> Self {
         Self {
-            lua: load_format_script(DEFAULT_FORMAT_SCRIPT).unwrap(),
+            lua: Script::load(DEFAULT_FORMAT_SCRIPT).unwrap(),
+            project_scripts: Vec::new(),
+            gerrit_query: None,
+            templates: None,
+            gerrit_base_url: None,
+            adaptive_cards: false,
         }
     }
 }
 
-fn load_format_script(script_source: &str) -> Result<Lua, String> {
-    let lua_std_lib = LuaStdLib::BASE | LuaStdLib::STRING | LuaStdLib::TABLE;
-    let lua = Lua::new_with(lua_std_lib);
-    lua.context(|context| -> Result<(), String> {
+/// Lua environment of a format script. A buggy script must not hold up the
+/// bot, so it may only run `INSTRUCTION_LIMIT` instructions for a message and
+/// use `MEMORY_LIMIT` bytes.
+struct Script {
+    lua: Lua,
+    /// Instructions run since the start of the current call, roughly.
+    instructions: Arc<AtomicUsize>,
+}
+
+impl Script {
+    fn load(script_source: &str) -> Result<Self, String> {
+        let lua_std_lib = LuaStdLib::BASE | LuaStdLib::STRING | LuaStdLib::TABLE;
+        let lua = Lua::new_with(lua_std_lib);
+        lua.set_memory_limit(Some(MEMORY_LIMIT));
+        let instructions = Arc::new(AtomicUsize::new(0));
+        let hook_instructions = instructions.clone();
+        lua.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(INSTRUCTION_CHECK_INTERVAL),
+                ..Default::default()
+            },
+            move |_, _| {
+                let count = hook_instructions
+                    .fetch_add(INSTRUCTION_CHECK_INTERVAL as usize, Ordering::SeqCst);
+                if count >= INSTRUCTION_LIMIT {
+                    Err(LuaError::RuntimeError(
+                        "instruction limit exceeded".to_string(),
+                    ))
+                } else {
+                    Ok(())
+                }
+            },
+        );
+        let script = Self { lua, instructions };
+        load_format_script(&script, script_source)?;
+        Ok(script)
+    }
+
+    /// Run the function in the Lua context, with a new instruction budget.
+    fn context<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(rlua::Context) -> R,
+    {
+        self.instructions.store(0, Ordering::SeqCst);
+        self.lua.context(f)
+    }
+
+    /// Whether the last call failed since it exceeded a limit.
+    fn limit_exceeded(&self, error: &str) -> bool {
+        self.instructions.load(Ordering::SeqCst) > INSTRUCTION_LIMIT
+            || error.contains("memory error")
+    }
+}
+
+fn load_format_script(script: &Script, script_source: &str) -> Result<(), String> {
+    script.context(|context| -> Result<(), String> {
         let globals = context.globals();
 
         let is_human = context
@@ -86,6 +469,48 @@ fn load_format_script(script_source: &str) -> Result<Lua, String> {
             .set("is_human", is_human)
             .map_err(|e| format!("failed to set is_human function: {}", e))?;
 
+        let escape_markdown = context
+            .create_function(|_, text: String| Ok(spark::escape_markdown(&text)))
+            .map_err(|e| format!("failed to create escape_markdown function: {}", e))?;
+
+        globals
+            .set("escape_markdown", escape_markdown)
+            .map_err(|e| format!("failed to set escape_markdown function: {}", e))?;
+
+        let truncate = context
+            .create_function(|_, (text, max_chars): (String, usize)| Ok(truncate(&text, max_chars)))
+            .map_err(|e| format!("failed to create truncate function: {}", e))?;
+
+        globals
+            .set("truncate", truncate)
+            .map_err(|e| format!("failed to set truncate function: {}", e))?;
+
+        // uses the `gerrit_base_url` set for the message
+        let change_url = context
+            .create_function(|context, change: LuaTable| {
+                let base_url: Option<String> = context.globals().get("gerrit_base_url")?;
+                match base_url {
+                    Some(base_url) => {
+                        let number: u32 = change.get("number")?;
+                        Ok(format!("{}/c/{}", base_url, number))
+                    }
+                    None => change.get("url"),
+                }
+            })
+            .map_err(|e| format!("failed to create change_url function: {}", e))?;
+
+        globals
+            .set("change_url", change_url)
+            .map_err(|e| format!("failed to set change_url function: {}", e))?;
+
+        let relative_time = context
+            .create_function(|_, timestamp: i64| Ok(relative_time(timestamp, Utc::now())))
+            .map_err(|e| format!("failed to create relative_time function: {}", e))?;
+
+        globals
+            .set("relative_time", relative_time)
+            .map_err(|e| format!("failed to set relative_time function: {}", e))?;
+
         context
             .load(script_source)
             .set_name("format.lua")
@@ -94,36 +519,201 @@ fn load_format_script(script_source: &str) -> Result<Lua, String> {
             .map_err(|err| format!("syntax error: {}", err))?;
 
         Ok(())
-    })?;
-    Ok(lua)
+    })
 }
 
-fn get_flags_table<'lua>(user: &User, lua: rlua::Context<'lua>) -> rlua::Result<rlua::Table<'lua>> {
-    lua.create_table_from(NOTIFICATION_FLAGS.iter().cloned().filter_map(|flag| {
-        if user.has_flag(flag) {
-            Some((flag.to_string(), true))
-        } else {
-            None
-        }
-    }))
+/// The text shortened to at most `max_chars` characters, ending with `…` if
+/// it was shortened.
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    if max_chars > 0 {
+        truncated.push('…');
+    }
+    truncated
+}
+
+/// How long ago the Unix timestamp is, or how long until it, e.g. `3d ago`
+/// or `in 2h`.
+fn relative_time(timestamp: i64, now: DateTime<Utc>) -> String {
+    let seconds = now.timestamp() - timestamp;
+    let minutes = seconds.abs() / 60;
+    let age = if minutes < 1 {
+        return "just now".to_string();
+    } else if minutes < 60 {
+        format!("{}m", minutes)
+    } else if minutes < 24 * 60 {
+        format!("{}h", minutes / 60)
+    } else {
+        format!("{}d", minutes / (24 * 60))
+    };
+    if seconds < 0 {
+        format!("in {}", age)
+    } else {
+        format!("{} ago", age)
+    }
+}
+
+/// Message about a change, if the format script exceeded its limits.
+fn fallback_message(change: &gerrit::Change) -> String {
+    format!(
+        "[{}]({}) ({}): There is new activity on this change.",
+        spark::escape_markdown(&change.subject),
+        change.url,
+        spark::escape_markdown(&change.project)
+    )
+}
+
+fn set_gerrit_query(lua: &Script, gerrit_query: GerritQuery) -> Result<(), String> {
+    lua.context(|context| {
+        let function = context
+            .create_function(move |context, search: String| {
+                let result = (gerrit_query.lock().unwrap())(&search);
+                match result {
+                    Ok(changes) => Ok((Some(rlua_serde::to_value(context, changes)?), None)),
+                    Err(e) => Ok((None, Some(e))),
+                }
+            })
+            .map_err(|e| format!("failed to create gerrit_query function: {}", e))?;
+        context
+            .globals()
+            .set("gerrit_query", function)
+            .map_err(|e| format!("failed to set gerrit_query function: {}", e))
+    })
+}
+
+fn get_flags_table<'lua>(
+    flags: &[UserFlag],
+    lua: rlua::Context<'lua>,
+) -> rlua::Result<rlua::Table<'lua>> {
+    lua.create_table_from(flags.iter().map(|flag| (flag.to_string(), true)))
+}
+
+/// Notification flags set for the user, and the additional ones.
+fn user_flags(user: &User, additional_flags: &[UserFlag]) -> Vec<UserFlag> {
+    NOTIFICATION_FLAGS
+        .iter()
+        .cloned()
+        .filter(|&flag| user.has_flag(flag) || additional_flags.contains(&flag))
+        .collect()
 }
 
 impl Formatter {
     pub fn new(format_script: &str) -> Result<Self, String> {
         Ok(Self {
-            lua: load_format_script(format_script)?,
+            lua: Script::load(format_script)?,
+            project_scripts: Vec::new(),
+            gerrit_query: None,
+            templates: None,
+            gerrit_base_url: None,
+            adaptive_cards: false,
         })
     }
 
+    /// Use another format script for the projects matching the regular
+    /// expression.
+    pub fn add_project_script(&mut self, project: &str, format_script: &str) -> Result<(), String> {
+        let project = Regex::new(project)
+            .map_err(|e| format!("invalid project pattern of format script: {}", e))?;
+        let lua = Script::load(format_script)
+            .map_err(|e| format!("format script of {}: {}", project, e))?;
+        if let Some(gerrit_query) = &self.gerrit_query {
+            set_gerrit_query(&lua, gerrit_query.clone())?;
+        }
+        self.project_scripts.push((project, lua));
+        Ok(())
+    }
+
+    /// Let the format scripts query Gerrit with `gerrit_query(q)`, which
+    /// returns the changes, or `nil` and the error.
+    pub fn set_gerrit_query<F>(&mut self, gerrit_query: F) -> Result<(), String>
+    where
+        F: FnMut(&str) -> Result<Vec<gerrit::Change>, String> + Send + 'static,
+    {
+        let gerrit_query: GerritQuery = Arc::new(Mutex::new(gerrit_query));
+        set_gerrit_query(&self.lua, gerrit_query.clone())?;
+        for (_, lua) in &self.project_scripts {
+            set_gerrit_query(lua, gerrit_query.clone())?;
+        }
+        self.gerrit_query = Some(gerrit_query);
+        Ok(())
+    }
+
+    /// Use the templates instead of the functions of the main format script
+    /// they are named after.
+    pub fn set_templates(&mut self, templates: Templates) {
+        self.templates = Some(templates);
+    }
+
+    /// Lua environment of the format script for the input, which has the
+    /// function.
+    fn lua_for<I: MessageInput>(&self, input: &I, function_name: &str) -> &Script {
+        input
+            .change()
+            .and_then(|change| {
+                self.project_scripts
+                    .iter()
+                    .find(|(pattern, _)| pattern.is_match(&change.project))
+            })
+            .map(|(_, lua)| lua)
+            .filter(|lua| {
+                lua.context(|lua| {
+                    matches!(lua.globals().get(function_name), Ok(LuaValue::Function(_)))
+                })
+            })
+            .unwrap_or(&self.lua)
+    }
+
+    /// Enable Adaptive Cards produced by the `<format function>_card`
+    /// functions of the format script.
+    pub fn set_adaptive_cards(&mut self, enabled: bool) {
+        self.adaptive_cards = enabled;
+    }
+
+    /// Set the Gerrit web URL links are built from. Without it, the URL is
+    /// derived from the change URLs reported by Gerrit.
+    pub fn set_gerrit_base_url(&mut self, base_url: Option<String>) {
+        self.gerrit_base_url = base_url.map(|url| url.trim_end_matches('/').to_string());
+    }
+
     fn format_lua<I>(
         lua: rlua::Context,
-        user: Option<&User>,
+        gerrit_base_url: Option<&str>,
+        flags: Option<&[UserFlag]>,
+        timezone: Option<Timezone>,
+        locale: Option<&str>,
         input: I,
-    ) -> Result<Option<String>, String>
+    ) -> Result<Option<Message>, String>
     where
         I: MessageInput,
     {
         let globals = lua.globals();
+        globals
+            .set("gerrit_base_url", gerrit_base_url)
+            .map_err(|e| format!("failed to set gerrit_base_url: {}", e))?;
+        // times are shown in the time zone of the user, or in UTC
+        let timezone = timezone
+            .map(|timezone| -> LuaResult<LuaTable> {
+                let table = lua.create_table()?;
+                table.set("name", timezone.to_string())?;
+                table.set(
+                    "utc_offset",
+                    timezone.offset_at(Utc::now()).local_minus_utc(),
+                )?;
+                Ok(table)
+            })
+            .transpose()
+            .map_err(|e| format!("failed to create timezone table: {}", e))?;
+        globals
+            .set("timezone", timezone)
+            .map_err(|e| format!("failed to set timezone: {}", e))?;
+        // messages are in the language of the user, or in English
+        globals
+            .set("locale", locale)
+            .map_err(|e| format!("failed to set locale: {}", e))?;
+
         let function_name = I::FORMAT_FUNCTION;
 
         let format_function: LuaFunction = globals
@@ -133,8 +723,8 @@ impl Formatter {
         let format_args = (
             rlua_serde::to_value(lua, input)
                 .map_err(|e| format!("failed to serialize event: {}", e))?,
-            if let Some(user) = user {
-                get_flags_table(user, lua)
+            if let Some(flags) = flags {
+                get_flags_table(flags, lua)
                     .map(LuaValue::Table)
                     .map_err(|err| format!("failed to create flags table: {}", err))?
             } else {
@@ -146,8 +736,7 @@ impl Formatter {
             .call::<_, LuaValue>(format_args)
             .map_err(|err| format!("lua formatting function failed: {}", err))?;
 
-        FromLua::from_lua(result, lua)
-            .map_err(|e| format!("failed to convert formatting result: {}", e))
+        Message::from_lua(result, lua)
     }
 
     pub fn format_message<I: MessageInput>(
@@ -155,8 +744,122 @@ impl Formatter {
         user: Option<&User>,
         input: I,
     ) -> Result<Option<String>, String> {
-        self.lua
-            .context(move |lua| Formatter::format_lua(lua, user, input))
+        self.format_message_with_flags(user, &[], input)
+    }
+
+    /// Format a message for the user as if the additional flags were set,
+    /// e.g. the default flags of the project of a change.
+    pub fn format_message_with_flags<I: MessageInput>(
+        &self,
+        user: Option<&User>,
+        additional_flags: &[UserFlag],
+        input: I,
+    ) -> Result<Option<String>, String> {
+        self.format_notification(user, additional_flags, input)
+            .map(|message| message.map(|message| message.markdown))
+    }
+
+    /// Format a notification for the user with all parts the format function
+    /// returned. The card is dropped if cards are disabled.
+    pub fn format_notification<I: MessageInput>(
+        &self,
+        user: Option<&User>,
+        additional_flags: &[UserFlag],
+        input: I,
+    ) -> Result<Option<Message>, String> {
+        let flags = user.map(|user| user_flags(user, additional_flags));
+        let timezone = user.map(User::timezone);
+        let locale = user.and_then(User::language);
+        let message = self.format_with_fallback(flags.as_deref(), timezone, locale, input)?;
+        Ok(message.map(|message| Message {
+            card: message.card.filter(|_| self.adaptive_cards),
+            ..message
+        }))
+    }
+
+    /// Format a message for a group room subscribed to the project of the
+    /// input. Rooms get the notifications selected by `ROOM_FLAGS`.
+    pub fn format_room_message<I: MessageInput>(&self, input: I) -> Result<Option<String>, String> {
+        self.format_with_fallback(Some(ROOM_FLAGS), None, None, input)
+            .map(|message| message.map(|message| message.markdown))
+    }
+
+    /// Format the input. If the format script exceeds its limits on a
+    /// message about a change, a minimal message is used instead.
+    fn format_with_fallback<I: MessageInput>(
+        &self,
+        flags: Option<&[UserFlag]>,
+        timezone: Option<Timezone>,
+        locale: Option<&str>,
+        input: I,
+    ) -> Result<Option<Message>, String> {
+        let gerrit_base_url = self.gerrit_base_url.as_deref();
+        let script = self.lua_for(&input, I::FORMAT_FUNCTION);
+        // project scripts take precedence over the templates
+        if let Some(templates) = &self.templates {
+            if std::ptr::eq(script, &self.lua) && templates.has(I::FORMAT_FUNCTION) {
+                return templates
+                    .render(
+                        I::FORMAT_FUNCTION,
+                        input,
+                        flags,
+                        timezone,
+                        locale,
+                        gerrit_base_url,
+                    )
+                    .map(|message| message.map(Message::from));
+            }
+        }
+        let fallback = input.change().map(fallback_message);
+        script
+            .context(move |lua| {
+                Formatter::format_lua(lua, gerrit_base_url, flags, timezone, locale, input)
+            })
+            .or_else(|e| match fallback {
+                Some(message) if script.limit_exceeded(&e) => {
+                    warn!("format script exceeded its limits: {}", e);
+                    Ok(Some(message.into()))
+                }
+                _ => Err(e),
+            })
+    }
+
+    /// Format an Adaptive Card for the input. `None` if cards are disabled or
+    /// the format script has no card function for the input.
+    pub fn format_card<I: MessageInput>(
+        &self,
+        input: I,
+    ) -> Result<Option<serde_json::Value>, String> {
+        if !self.adaptive_cards {
+            return Ok(None);
+        }
+
+        let gerrit_base_url = self.gerrit_base_url.as_deref();
+        let function_name = card_function_name::<I>();
+        self.lua_for(&input, &function_name).context(move |lua| {
+            let globals = lua.globals();
+            globals
+                .set("gerrit_base_url", gerrit_base_url)
+                .map_err(|e| format!("failed to set gerrit_base_url: {}", e))?;
+
+            let card_function: LuaFunction = match globals.get(function_name.as_str()) {
+                Ok(LuaValue::Function(function)) => function,
+                _ => return Ok(None),
+            };
+
+            let input = rlua_serde::to_value(lua, input)
+                .map_err(|e| format!("failed to serialize event: {}", e))?;
+            let card = card_function
+                .call::<_, LuaValue>(input)
+                .map_err(|err| format!("lua card function failed: {}", err))?;
+
+            match card {
+                LuaNil => Ok(None),
+                card => rlua_serde::from_value(card)
+                    .map(Some)
+                    .map_err(|e| format!("failed to convert card: {}", e)),
+            }
+        })
     }
 
     pub fn format_status(
@@ -171,6 +874,52 @@ impl Formatter {
                     .map(|u| u.has_any_flag(NOTIFICATION_FLAGS))
                     .unwrap_or(false),
                 enabled_user_count,
+                notifications: user.map_or(0, |u| u.activity().notifications),
+                last_notification: user.and_then(|u| u.activity().last_notification),
+            },
+        )
+    }
+
+    pub fn format_settings(&self, user: Option<&User>) -> Result<Option<String>, String> {
+        let timezone = user.map(User::timezone).unwrap_or_default();
+        self.format_message(
+            None,
+            SettingsDetails {
+                enabled: user.is_some_and(|u| u.has_any_flag(NOTIFICATION_FLAGS)),
+                flags: NOTIFICATION_FLAGS
+                    .iter()
+                    .map(|&flag| (flag.to_string(), user.is_some_and(|u| u.has_flag(flag))))
+                    .collect(),
+                filter: user.and_then(User::filter).map(|filter| FilterDetails {
+                    regex: filter.regex.as_str(),
+                    enabled: filter.enabled,
+                    mode: filter.mode,
+                }),
+                branches: user.map(User::branches).unwrap_or_default(),
+                topics: user.map(|u| u.topics().collect()).unwrap_or_default(),
+                vote_threshold: user.and_then(User::vote_threshold),
+                label_votes: user
+                    .map(|u| {
+                        u.label_votes()
+                            .iter()
+                            .map(|(label, policy)| (label.as_str(), policy.to_string()))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                gerrit_emails: user
+                    .map(|u| u.gerrit_emails().collect())
+                    .unwrap_or_default(),
+                timezone: timezone.to_string(),
+                quiet_hours: user
+                    .and_then(User::quiet_hours)
+                    .map(|quiet_hours| quiet_hours.to_string()),
+                digest: user
+                    .and_then(User::digest)
+                    .map(|digest| digest.time_of_day()),
+                snoozed_until: user
+                    .and_then(User::snooze)
+                    .map(|snooze| timezone.format_time(snooze.until())),
+                language: user.and_then(User::language),
             },
         )
     }
@@ -182,10 +931,47 @@ impl Formatter {
     pub fn format_help(&self) -> Result<Option<String>, String> {
         self.format_message(None, HelpMessage)
     }
+
+    /// Format the help of the topic. `None` if there is no such topic.
+    pub fn format_command_help(&self, topic: &str) -> Result<Option<String>, String> {
+        self.format_message(None, CommandHelp { topic })
+    }
+
+    /// Format the canned reply with the id in the language, or in English if
+    /// the language has no such reply.
+    pub fn format_reply(
+        &self,
+        language: Option<&str>,
+        id: &str,
+        args: &[(&str, &dyn fmt::Display)],
+    ) -> Result<Option<String>, String> {
+        let args = args
+            .iter()
+            .map(|(name, value)| (*name, value.to_string()))
+            .collect();
+        self.format_message(None, CannedReply { id, language, args })
+    }
+
+    /// Languages of the canned replies in the format script.
+    pub fn languages(&self) -> Vec<String> {
+        self.lua.context(|lua| {
+            let replies: LuaTable = match lua.globals().get("REPLIES") {
+                Ok(LuaValue::Table(replies)) => replies,
+                _ => return Vec::new(),
+            };
+            let mut languages: Vec<String> = replies
+                .pairs::<String, LuaValue>()
+                .filter_map(|pair| pair.ok().map(|(language, _)| language))
+                .collect();
+            languages.sort();
+            languages
+        })
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use chrono::TimeZone as _;
     use lazy_static::lazy_static;
 
     use gerritbot_spark as spark;
@@ -242,6 +1028,321 @@ mod test {
         );
     }
 
+    #[test]
+    fn format_approval_in_language_of_user() {
+        let mut user = FORMAT_TEST_USER.clone();
+        user.set_language(Some("de".to_string()));
+        let event = get_event();
+        let res = Formatter::default().format_message(Some(&user), &event);
+        assert!(res
+            .unwrap()
+            .unwrap()
+            .contains("👍 +2 (Code-Review) von [Approver]"));
+
+        // languages without notification texts fall back to English
+        user.set_language(Some("fr".to_string()));
+        let res = Formatter::default().format_message(Some(&user), &event);
+        assert!(res
+            .unwrap()
+            .unwrap()
+            .contains("👍 +2 (Code-Review) from [Approver]"));
+    }
+
+    #[test]
+    fn lua_utility_functions() {
+        let mut formatter = Formatter::new(
+            r#"
+            function format_comment_added(event, flags)
+                return string.format(
+                    "%s %s %s",
+                    truncate(event.change.subject, 6),
+                    change_url(event.change),
+                    relative_time(event.eventCreatedOn)
+                )
+            end
+            "#,
+        )
+        .unwrap();
+        let event = get_event();
+        let message = formatter
+            .format_message(Some(&FORMAT_TEST_USER), &event)
+            .unwrap()
+            .unwrap();
+        assert!(message.starts_with("Some … http://localhost/42 "));
+        assert!(message.ends_with(" ago"));
+
+        formatter.set_gerrit_base_url(Some("https://example.com/gerrit".to_string()));
+        let message = formatter
+            .format_message(Some(&FORMAT_TEST_USER), &event)
+            .unwrap()
+            .unwrap();
+        assert!(message.starts_with("Some … https://example.com/gerrit/c/49 "));
+    }
+
+    #[test]
+    fn truncate_text() {
+        assert_eq!(truncate("Fix typo", 8), "Fix typo");
+        assert_eq!(truncate("Fix typo", 5), "Fix …");
+        assert_eq!(truncate("Größe ändern", 7), "Größe …");
+        assert_eq!(truncate("Fix typo", 0), "");
+    }
+
+    #[test]
+    fn format_relative_time() {
+        let now = Utc.ymd(2020, 2, 28).and_hms(12, 0, 0);
+        let timestamp = now.timestamp();
+        assert_eq!(relative_time(timestamp - 30, now), "just now");
+        assert_eq!(relative_time(timestamp - 5 * 60, now), "5m ago");
+        assert_eq!(relative_time(timestamp - 3 * 3600, now), "3h ago");
+        assert_eq!(relative_time(timestamp - 2 * 86400, now), "2d ago");
+        assert_eq!(relative_time(timestamp + 2 * 3600, now), "in 2h");
+    }
+
+    #[test]
+    fn format_with_project_script() {
+        let mut formatter = Formatter::default();
+        formatter
+            .add_project_script(
+                "^demo-",
+                r#"
+                function format_comment_added(event, flags)
+                    return "Review of " .. event.change.project
+                end
+                "#,
+            )
+            .unwrap();
+        let mut event = get_event();
+        assert_eq!(
+            formatter.format_message(Some(&FORMAT_TEST_USER), &event),
+            Ok(Some("Review of demo-project".to_string()))
+        );
+        // functions missing in the project script are taken from the main one
+        let details = formatter
+            .format_message(
+                None,
+                ChangeDetails {
+                    change: &event.change,
+                },
+            )
+            .unwrap();
+        assert!(details.unwrap().contains("Some review."));
+
+        event.change.project = "other-project".to_string();
+        let message = formatter
+            .format_message(Some(&FORMAT_TEST_USER), &event)
+            .unwrap();
+        assert!(message.unwrap().contains("+2 (Code-Review)"));
+
+        assert!(formatter.add_project_script("(", "").is_err());
+        assert!(formatter
+            .add_project_script("^docs/", "syntax error")
+            .is_err());
+    }
+
+    #[test]
+    fn format_change_details_with_topic() {
+        let mut formatter = Formatter::default();
+        let (mut change, _) = get_change_with_comments();
+        change.topic = Some("version".to_string());
+        let mut related = change.clone();
+        related.number = 2;
+        related.subject = "Update the changelog".to_string();
+        let changes = vec![change.clone(), related];
+        let topic_changes = changes.clone();
+        formatter
+            .set_gerrit_query(move |search| {
+                assert_eq!(search, r#"topic:"version" status:open"#);
+                Ok(topic_changes.clone())
+            })
+            .unwrap();
+        let details = formatter
+            .format_message(None, ChangeDetails { change: &change })
+            .unwrap()
+            .unwrap();
+        assert!(details.ends_with("Same topic: [Update the changelog](http://localhost:8080/1)"));
+
+        formatter
+            .set_gerrit_query(|_| Err("query timed out".to_string()))
+            .unwrap();
+        let details = formatter
+            .format_message(None, ChangeDetails { change: &change })
+            .unwrap()
+            .unwrap();
+        assert!(!details.contains("Same topic"));
+
+        // topics containing quotes are put in braces
+        change.topic = Some(r#"the "next" version"#.to_string());
+        formatter
+            .set_gerrit_query(move |search| match search {
+                r#"topic:{the "next" version} status:open"# => Ok(changes.clone()),
+                _ => Err(format!("unexpected query: {}", search)),
+            })
+            .unwrap();
+        let details = formatter
+            .format_message(None, ChangeDetails { change: &change })
+            .unwrap()
+            .unwrap();
+        assert!(details.contains("Same topic: [Update the changelog]"));
+    }
+
+    #[test]
+    fn format_with_templates() {
+        let mut templates = BTreeMap::new();
+        templates.insert(
+            "format_comment_added".to_string(),
+            "{% if flags.notify_review_approvals %}{{ change.subject | escape_markdown }} \
+             reviewed by {{ author.name }}{% endif %}"
+                .to_string(),
+        );
+        let mut formatter = Formatter::default();
+        formatter.set_templates(Templates::new(&templates).unwrap());
+        let mut event = get_event();
+        event.change.subject = "Some *review*".to_string();
+        assert_eq!(
+            formatter.format_message(Some(&FORMAT_TEST_USER), &event),
+            Ok(Some(r"Some \*review\* reviewed by Approver".to_string()))
+        );
+        // an empty result is no message
+        assert_eq!(formatter.format_message(None, &event), Ok(None));
+        // other messages are formatted by the format script
+        let details = formatter
+            .format_message(
+                None,
+                ChangeDetails {
+                    change: &event.change,
+                },
+            )
+            .unwrap();
+        assert!(details.unwrap().contains("Some \\*review\\*"));
+
+        templates.insert("format_help".to_string(), "{{ missing".to_string());
+        assert!(Templates::new(&templates).is_err());
+    }
+
+    #[test]
+    fn format_message_parts() {
+        let mut formatter = Formatter::new(
+            r#"
+            function format_comment_added(event, flags)
+                return {
+                    markdown = "**" .. event.change.subject .. "**",
+                    text = event.change.subject,
+                    card = { type = "AdaptiveCard", version = "1.2" },
+                }
+            end
+
+            function format_change_merged(event, flags)
+                return { text = "Merged " .. event.change.subject }
+            end
+
+            function format_change_abandoned(event, flags)
+                return { card = {} }
+            end
+            "#,
+        )
+        .unwrap();
+        let event = get_event();
+        let message = formatter
+            .format_notification(Some(&FORMAT_TEST_USER), &[], &event)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.markdown, "**Some review.**");
+        assert_eq!(message.text.as_deref(), Some("Some review."));
+        // cards are disabled by default
+        assert_eq!(message.card, None);
+        assert_eq!(
+            formatter.format_message(Some(&FORMAT_TEST_USER), &event),
+            Ok(Some("**Some review.**".to_string()))
+        );
+
+        formatter.set_adaptive_cards(true);
+        let message = formatter
+            .format_notification(Some(&FORMAT_TEST_USER), &[], &event)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            message.card,
+            Some(serde_json::json!({ "type": "AdaptiveCard", "version": "1.2" }))
+        );
+
+        // the text is the markdown if there is no markdown
+        let merged = gerrit::ChangeMergedEvent {
+            change: event.change.clone(),
+            patchset: event.patchset.clone(),
+            submitter: event.author.clone(),
+            new_revision: event.patchset.revision.clone(),
+            created_on: event.created_on,
+        };
+        assert_eq!(
+            formatter.format_message(Some(&FORMAT_TEST_USER), &merged),
+            Ok(Some("Merged Some review.".to_string()))
+        );
+        let abandoned = gerrit::ChangeAbandonedEvent {
+            change: event.change.clone(),
+            patchset: event.patchset.clone(),
+            abandoner: event.author.clone(),
+            reason: None,
+            created_on: event.created_on,
+        };
+        assert!(formatter
+            .format_message(Some(&FORMAT_TEST_USER), &abandoned)
+            .is_err());
+    }
+
+    #[test]
+    fn format_script_limits() {
+        let mut formatter = Formatter::default();
+        formatter
+            .add_project_script(
+                "^demo-",
+                r#"
+                function format_comment_added(event, flags)
+                    while true do end
+                end
+
+                function format_change_details(details)
+                    local s = "x"
+                    while true do s = s .. s end
+                end
+
+                function format_change_merged(event, flags)
+                    return "Merged " .. event.change.subject
+                end
+                "#,
+            )
+            .unwrap();
+        let event = get_event();
+        assert_eq!(
+            formatter.format_message(Some(&FORMAT_TEST_USER), &event),
+            Ok(Some(
+                "[Some review.](http://localhost/42) (demo-project): There is new activity on this change."
+                    .to_string()
+            ))
+        );
+        let details = formatter.format_message(
+            None,
+            ChangeDetails {
+                change: &event.change,
+            },
+        );
+        assert!(details.unwrap().unwrap().contains("There is new activity"));
+
+        // the limits apply to each message on its own
+        let merged = gerrit::ChangeMergedEvent {
+            change: event.change.clone(),
+            patchset: event.patchset.clone(),
+            submitter: event.author.clone(),
+            new_revision: event.patchset.revision.clone(),
+            created_on: event.created_on,
+        };
+        assert_eq!(
+            formatter.format_message(Some(&FORMAT_TEST_USER), &merged),
+            Ok(Some("Merged Some review.".to_string()))
+        );
+
+        assert!(Formatter::new("while true do end").is_err());
+    }
+
     #[test]
     fn format_approval_unknown_labels() {
         let mut event = get_event();
@@ -267,6 +1368,7 @@ mod test {
                 value: "1".to_string(),
                 old_value: None,
                 by: None,
+                granted_on: None,
             });
         }
         let res = Formatter::default().format_message(Some(&FORMAT_TEST_USER), &event);
@@ -278,6 +1380,41 @@ mod test {
         );
     }
 
+    #[test]
+    fn format_approval_submittable() {
+        let mut event = get_event();
+        event.change.submit_records = Some(vec![gerrit::SubmitRecord {
+            status: gerrit::SubmitStatus::OK,
+        }]);
+        let res = Formatter::default()
+            .format_message(Some(&FORMAT_TEST_USER), &event)
+            .expect("format failed")
+            .expect("no message");
+        assert!(res.contains("🏁 Submittable"), "not submittable: {:?}", res);
+
+        // submit requirements take precedence over submit records
+        event.change.submit_requirements = Some(vec![gerrit::SubmitRequirement {
+            name: "Code-Review".to_string(),
+            status: gerrit::SubmitRequirementStatus::UNSATISFIED,
+            is_legacy: false,
+        }]);
+        assert_eq!(event.change.is_submittable(), Some(false));
+        let res = Formatter::default()
+            .format_message(Some(&FORMAT_TEST_USER), &event)
+            .expect("format failed")
+            .expect("no message");
+        assert!(!res.contains("🏁 Submittable"), "submittable: {:?}", res);
+
+        event.change.submit_requirements.as_mut().unwrap()[0].status =
+            gerrit::SubmitRequirementStatus::SATISFIED;
+        assert_eq!(event.change.is_submittable(), Some(true));
+        let res = Formatter::default()
+            .format_message(Some(&FORMAT_TEST_USER), &event)
+            .expect("format failed")
+            .expect("no message");
+        assert!(res.contains("🏁 Submittable"), "not submittable: {:?}", res);
+    }
+
     #[test]
     fn format_approval_no_approvals() {
         let mut event = get_event();
@@ -288,6 +1425,180 @@ mod test {
         assert_eq!(res, Ok(None));
     }
 
+    #[test]
+    fn format_escapes_markdown() {
+        let mut event = get_event();
+        event.change.subject = "Fix *all* [bugs] in my_module".to_string();
+        event.comment = "Patch Set 1: Code-Review+2\n\nLooks `good` to me_".to_string();
+        let res = Formatter::default()
+            .format_message(Some(&FORMAT_TEST_USER), &event)
+            .expect("format failed")
+            .expect("no message");
+        assert!(
+            res.starts_with(r"[Fix \*all\* \[bugs\] in my\_module](http://localhost/42)"),
+            "subject not escaped: {:?}",
+            res
+        );
+        assert!(
+            res.ends_with(r"> Looks \`good\` to me\_"),
+            "comment not escaped: {:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn format_with_gerrit_base_url() {
+        let event = get_event();
+        let mut formatter = Formatter::default();
+        formatter.set_gerrit_base_url(Some("https://example.com/gerrit/".to_string()));
+        let res = formatter
+            .format_message(Some(&FORMAT_TEST_USER), &event)
+            .expect("format failed")
+            .expect("no message");
+        assert!(
+            res.starts_with(
+                "[Some review.](https://example.com/gerrit/c/49) \
+                 ([demo-project](https://example.com/gerrit/q/project:demo-project+status:open))"
+            ),
+            "wrong links: {:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn format_card_disabled_by_default() {
+        let event = get_event();
+        assert_eq!(Formatter::default().format_card(&event), Ok(None));
+    }
+
+    #[test]
+    fn format_comment_added_card() {
+        let event = get_event();
+        let mut formatter = Formatter::default();
+        formatter.set_adaptive_cards(true);
+        let card = formatter
+            .format_card(&event)
+            .expect("format failed")
+            .expect("no card");
+
+        assert_eq!(card["type"], "AdaptiveCard");
+        assert_eq!(card["body"][0]["text"], "Some review.");
+        assert_eq!(card["body"][2]["facts"][0]["title"], "Code-Review");
+        assert_eq!(card["body"][2]["facts"][0]["value"], "+2");
+        assert_eq!(card["actions"][0]["url"], "http://localhost/42");
+    }
+
+    #[test]
+    fn format_reviews() {
+        let (mut change, patchset) = get_change_with_comments();
+        let approvals = r#"[{"type":"Code-Review","description":"Code-Review","value":"1"}]"#;
+        change.current_patch_set = Some(gerrit::Patchset {
+            approvals: Some(serde_json::from_str(approvals).unwrap()),
+            ..patchset
+        });
+        let changes = [change];
+
+        let res = Formatter::default()
+            .format_message(None, Reviews { changes: &changes })
+            .expect("format failed");
+        assert_eq!(
+            res.as_deref(),
+            Some("Your open changes:\n\n* [Bump version to 0.6.0](http://localhost:8080/1) ([gerritbot-rs](http://localhost:8080/q/project:gerritbot-rs+status:open)) 👌 +1 (Code-Review)")
+        );
+
+        let res = Formatter::default()
+            .format_message(None, Reviews { changes: &[] })
+            .expect("format failed");
+        assert_eq!(res.as_deref(), Some("You don't have any open changes."));
+    }
+
+    #[test]
+    fn format_queue() {
+        let (change, _) = get_change_with_comments();
+        let changes = [change];
+        let now = gerrit::timestamp::from_epoch_seconds(1524584729 + 3 * 24 * 3600).unwrap();
+
+        let res = Formatter::default()
+            .format_message(
+                None,
+                ReviewQueue {
+                    changes: &changes,
+                    now,
+                },
+            )
+            .expect("format failed");
+        assert_eq!(
+            res.as_deref(),
+            Some("Changes waiting for your review, oldest first:\n\n* [Bump version to 0.6.0](http://localhost:8080/1) ([gerritbot-rs](http://localhost:8080/q/project:gerritbot-rs+status:open)) by [Administrator](http://localhost:8080/q/owner:admin@example.com+status:open), 3d old")
+        );
+    }
+
+    #[test]
+    fn format_settings() {
+        let email = spark::EmailRef::new("some@example.com");
+        let mut state = State::new();
+        state.set_flag(email, UserFlag::NotifyChangeMerged, false);
+        state.add_filter(email, "^WIP").unwrap();
+        state.subscribe_topic(email, "release");
+        state.set_timezone(email, "UTC+2".parse().unwrap());
+
+        let res = Formatter::default()
+            .format_settings(state.find_user(email))
+            .expect("format failed")
+            .expect("no settings");
+        assert!(res.starts_with("Notifications are **enabled**. Your flags:"));
+        assert!(res.contains("* `notify_change_merged`: off\n"));
+        assert!(res.contains("* `notify_review_approvals`: on\n"));
+        assert!(res.contains("Filter: `^WIP` (enabled, mode drop)\n"));
+        assert!(res.contains("Branches: all\nTopics: `release`\nVotes: all\n"));
+        assert!(res.contains("Timezone: UTC+02:00\nQuiet hours: none\nDigest: off"));
+        assert!(!res.contains("Snoozed"));
+
+        let res = Formatter::default().format_settings(None).unwrap().unwrap();
+        assert!(res.starts_with("Notifications are **disabled**."));
+        assert!(res.contains("* `notify_review_approvals`: off\n"));
+    }
+
+    #[test]
+    fn format_change_details() {
+        let (mut change, patchset) = get_change_with_comments();
+        let approvals = r#"[{"type":"Code-Review","value":"-1","by":{"name":"jdoe","email":"john.doe@localhost"}},{"type":"Verified","value":"0"}]"#;
+        change.current_patch_set = Some(gerrit::Patchset {
+            approvals: Some(serde_json::from_str(approvals).unwrap()),
+            ..patchset
+        });
+        change.all_reviewers = Some(vec![gerrit::User {
+            name: Some("jdoe".to_string()),
+            username: None,
+            email: Some("john.doe@localhost".to_string()),
+        }]);
+
+        let res = Formatter::default()
+            .format_message(None, ChangeDetails { change: &change })
+            .expect("format failed")
+            .expect("no details");
+        assert_eq!(
+            res,
+            "[Bump version to 0.6.0](http://localhost:8080/1) ([gerritbot-rs](http://localhost:8080/q/project:gerritbot-rs+status:open))\n\n\
+             Owner: [Administrator](http://localhost:8080/q/owner:admin@example.com+status:open)\n\
+             Status: NEW\n\
+             Created: 2018-04-24 15:45 UTC\n\
+             Patch set: 1 (+2, -2)\n\
+             Votes: ✋ -1 (Code-Review) by [jdoe](http://localhost:8080/q/reviewer:john.doe@localhost+status:open)\n\
+             Reviewers: [jdoe](http://localhost:8080/q/reviewer:john.doe@localhost+status:open)"
+        );
+
+        // times are shown in the timezone of the user
+        let email = spark::EmailRef::new("some@example.com");
+        let mut state = State::new();
+        state.set_timezone(email, "Europe/Berlin".parse().unwrap());
+        let res = Formatter::default()
+            .format_message(state.find_user(email), ChangeDetails { change: &change })
+            .expect("format failed")
+            .expect("no details");
+        assert!(res.contains("\nCreated: 2018-04-24 17:45 Europe/Berlin\n"));
+    }
+
     #[test]
     fn test_format_comments() {
         let mut event = get_event();
@@ -303,4 +1614,44 @@ mod test {
 
         assert!(res.ends_with("`/COMMIT_MSG`\n\n> [Line 1](http://localhost:8080/#/c/1/1//COMMIT_MSG@1) by [jdoe](http://localhost:8080/q/reviewer:john.doe@localhost+status:open): This is a multiline\n> comment\n> on some change.\n"), "no inline comments: {:?}", res);
     }
+
+    #[test]
+    fn format_command_help() {
+        let formatter = Formatter::default();
+        let res = formatter
+            .format_command_help("filter")
+            .expect("format failed")
+            .expect("no help");
+        assert!(res.starts_with("`filter <regex>`"), "{:?}", res);
+        assert!(!res.contains("`snooze"), "{:?}", res);
+        let res = formatter
+            .format_command_help("unsnooze")
+            .expect("format failed");
+        assert!(res.is_some_and(|help| help.contains("`snooze")));
+        let res = formatter
+            .format_command_help("nonsense")
+            .expect("format failed");
+        assert_eq!(res, None);
+    }
+
+    #[test]
+    fn format_status_with_notifications() {
+        use chrono::TimeZone as _;
+
+        let mut state = State::new();
+        let email = spark::EmailRef::new("some@example.com");
+        state.add_user(email);
+        state.notification_sent(email, Utc.ymd(2020, 2, 28).and_hms(19, 0, 0));
+        let res = Formatter::default()
+            .format_status(state.find_user(email), 1)
+            .expect("format failed")
+            .expect("no status");
+        assert!(
+            res.ends_with(
+                "I sent you 1 notification(s) so far, the last one on 2020-02-28 19:00 UTC."
+            ),
+            "{:?}",
+            res
+        );
+    }
 }
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use gerritbot_gerrit as gerrit;
+
+/// Time the result of a query is reused for.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+/// Number of cached query results at most.
+const CACHE_CAPACITY: usize = 100;
+/// Number of changes returned by a query at most.
+const MAX_CHANGES: usize = 25;
+/// Number of queries fetched for a message at most.
+const MAX_MISSING: usize = 5;
+
+/// Gerrit queries of the format scripts, run with `gerrit_query(q)`. Scripts
+/// can only query changes. As they run while the bot is locked, they never
+/// wait for Gerrit: `gerrit_query` returns cached results only. While missed
+/// queries are recorded, they are fetched before the message is formatted
+/// again, otherwise they just fail. The results are cached for a while.
+pub struct FormatQueries {
+    timeout: Duration,
+    cache: HashMap<String, (Instant, Vec<gerrit::Change>)>,
+    /// Whether missed queries are recorded.
+    recording: bool,
+    /// Queries missed since recording started.
+    missing: Vec<String>,
+    /// Errors of the last fetch, reported once.
+    failed: HashMap<String, String>,
+}
+
+impl FormatQueries {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            cache: HashMap::new(),
+            recording: false,
+            missing: Vec::new(),
+            failed: HashMap::new(),
+        }
+    }
+
+    /// Queries taking longer fail.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// The cached result of the query. Missing results are remembered to be
+    /// fetched if recording.
+    pub fn get(&mut self, search: &str) -> Result<Vec<gerrit::Change>, String> {
+        if let Some(e) = self.failed.remove(search) {
+            return Err(e);
+        }
+        if let Some((fetched, changes)) = self.cache.get(search) {
+            if Instant::now() < *fetched + CACHE_TTL {
+                return Ok(changes.clone());
+            }
+        }
+        if self.recording
+            && self.missing.len() < MAX_MISSING
+            && !self.missing.iter().any(|s| s == search)
+        {
+            self.missing.push(search.to_string());
+        }
+        Err("query result not fetched yet".to_string())
+    }
+
+    /// Record the queries missed from now on.
+    pub fn record_missing(&mut self) {
+        self.recording = true;
+        self.missing.clear();
+        self.failed.clear();
+    }
+
+    /// Stop recording and take the missed queries, together with the Gerrit
+    /// queries fetching them.
+    pub fn take_missing(&mut self) -> Vec<(String, gerrit::Query)> {
+        self.recording = false;
+        self.failed.clear();
+        self.missing
+            .drain(..)
+            .map(|search| {
+                let query = gerrit::Query::new(format!("({}) limit:{}", search, MAX_CHANGES));
+                (search, query)
+            })
+            .collect()
+    }
+
+    /// Store the fetched result of a query.
+    pub fn insert(&mut self, search: String, result: Result<Vec<gerrit::Change>, String>) {
+        let mut changes = match result {
+            Ok(changes) => changes,
+            Err(e) => {
+                self.failed.insert(search, e);
+                return;
+            }
+        };
+        changes.truncate(MAX_CHANGES);
+
+        let now = Instant::now();
+        self.cache
+            .retain(|_, (fetched, _)| now < *fetched + CACHE_TTL);
+        if self.cache.len() < CACHE_CAPACITY {
+            self.cache.insert(search, (now, changes));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_queries_are_fetched_once() {
+        let mut queries = FormatQueries::new(Duration::from_secs(1));
+        queries.record_missing();
+        assert!(queries.get("topic:foo").is_err());
+        assert!(queries.get("topic:foo").is_err());
+        assert!(queries.get("topic:bar").is_err());
+
+        let missing = queries.take_missing();
+        assert_eq!(
+            missing
+                .iter()
+                .map(|(_, query)| query.to_command())
+                .collect::<Vec<_>>(),
+            vec![
+                "gerrit query --format=JSON '(topic:foo) limit:25'",
+                "gerrit query --format=JSON '(topic:bar) limit:25'",
+            ]
+        );
+        assert!(queries.take_missing().is_empty());
+
+        queries.record_missing();
+        queries.insert("topic:foo".to_string(), Ok(Vec::new()));
+        queries.insert("topic:bar".to_string(), Err("query timed out".to_string()));
+        assert_eq!(queries.get("topic:foo").map(|c| c.len()), Ok(0));
+        assert_eq!(
+            queries.get("topic:bar").map(|c| c.len()),
+            Err("query timed out".to_string())
+        );
+        assert!(queries.take_missing().is_empty());
+        // the error is only reported once
+        queries.record_missing();
+        assert!(queries.get("topic:bar").is_err());
+        assert_eq!(queries.take_missing().len(), 1);
+    }
+
+    #[test]
+    fn missing_queries_are_only_recorded_on_request() {
+        let mut queries = FormatQueries::new(Duration::from_secs(1));
+        assert!(queries.get("topic:foo").is_err());
+        queries.record_missing();
+        assert!(queries.take_missing().is_empty());
+    }
+}
@@ -1,13 +1,16 @@
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::convert::{self, identity};
-use std::fs::File;
+use std::fmt;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use futures::{future::Future, stream, stream::Stream};
+use chrono::{DateTime, Utc};
+use futures::future::{self, Future};
+use futures::{stream, stream::Stream};
 use lazy_static::lazy_static;
-use log::{debug, error};
+use log::{debug, error, warn};
 use regex::Regex;
 
 use gerritbot_gerrit as gerrit;
@@ -16,31 +19,207 @@ use gerritbot_spark as spark;
 pub mod args;
 mod command;
 mod format;
+mod format_query;
+mod project_flags;
 mod rate_limit;
+mod reminder;
+mod render;
+mod sample;
 mod state;
+mod stats;
+mod templates;
+mod tracker;
 mod version;
 
-use command::Command;
+use command::{Aliases, Command};
 use format::Formatter;
 pub use format::DEFAULT_FORMAT_SCRIPT;
+use format_query::FormatQueries;
+pub use project_flags::ProjectFlags;
 use rate_limit::RateLimiter;
-pub use state::State;
-use state::{User, UserFlag, NOTIFICATION_FLAGS, REVIEW_COMMENT_FLAGS};
+use reminder::{ReviewerReminderSchedule, StaleReviewReminder};
+pub use render::render_event;
+use state::{
+    Digest, FilterMode, Permission, Role, Settings, StateWriter, Undo, User, UserFlag,
+    NOTIFICATION_FLAGS, REVIEW_COMMENT_FLAGS,
+};
+pub use state::{State, StateLock};
+use stats::Stats;
+use templates::Templates;
+use tracker::ChangeTracker;
 use version::VERSION_INFO;
 
-pub trait GerritCommandRunner {}
+/// Number of messages sent at a time by default.
+const DEFAULT_SEND_CONCURRENCY: usize = 10;
 
-impl GerritCommandRunner for gerrit::CommandRunner {}
+/// Time after which sending a message is given up by default.
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of tasks waiting for Gerrit or Webex at a time. Tasks done right
+/// away, like replies, are not held up by them.
+const TASK_CONCURRENCY: usize = 16;
+
+/// Number of changes listed at most, e.g. by the `reviews` command.
+const MAX_LISTED_CHANGES: usize = 25;
+
+/// File the state is saved to, next to its backups.
+const STATE_FILENAME: &str = "state.json";
+
+/// Minimum time between two rotations of the state backups by default.
+const DEFAULT_STATE_BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Days within which users count as active in the admin statistics.
+const ACTIVE_USER_DAYS: i64 = 7;
+
+/// How often snoozes are checked for being over.
+const SNOOZE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often stale reviews are checked by default.
+const DEFAULT_STALE_REVIEW_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Number of reminders a reviewer gets at most by default.
+const DEFAULT_REVIEWER_REMINDER_MAX: u32 = 3;
+
+/// Language of the replies for users that didn't choose one.
+const DEFAULT_LANGUAGE: &str = "en";
+
+pub trait GerritCommandRunner {
+    type QueryFuture: Future<Item = Vec<gerrit::Change>, Error = gerrit::Error> + Send + 'static;
+    type CommandFuture: Future<Item = String, Error = gerrit::Error> + Send + 'static;
+
+    fn query(&mut self, query: &gerrit::Query) -> Self::QueryFuture;
+
+    /// Run a command on the Gerrit server, e.g. `gerrit review`.
+    fn run_command(&mut self, command: String) -> Self::CommandFuture;
+}
+
+impl GerritCommandRunner for gerrit::CommandRunner {
+    type QueryFuture = Box<dyn Future<Item = Vec<gerrit::Change>, Error = gerrit::Error> + Send>;
+    type CommandFuture = Box<dyn Future<Item = String, Error = gerrit::Error> + Send>;
+
+    fn query(&mut self, query: &gerrit::Query) -> Self::QueryFuture {
+        Box::new(gerrit::CommandRunner::query(self, query))
+    }
+
+    fn run_command(&mut self, command: String) -> Self::CommandFuture {
+        Box::new(gerrit::CommandRunner::run_command(self, command))
+    }
+}
 
 pub trait SparkClient: Clone {
-    type ReplyFuture: Future<Item = (), Error = spark::Error> + Send;
+    /// Resolves to the id of the sent message, if the client knows it.
+    type ReplyFuture: Future<Item = Option<spark::MessageId>, Error = spark::Error> + Send;
     fn send_message(&self, email: &spark::EmailRef, msg: &str) -> Self::ReplyFuture;
+
+    /// Post a message in a group room.
+    fn send_room_message(&self, room_id: &spark::RoomIdRef, msg: &str) -> Self::ReplyFuture;
+
+    /// Send a message with an optional plain text fallback and Adaptive Card
+    /// as reply to an optional parent message. Clients that support neither
+    /// cards nor threads only get the message.
+    fn send_notification(
+        &self,
+        email: &spark::EmailRef,
+        msg: &str,
+        _text: Option<&str>,
+        _card: Option<&serde_json::Value>,
+        _parent_id: Option<&spark::MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        self.send_message(email, msg)
+    }
+
+    /// Append a message to an earlier notification sent to the user instead
+    /// of sending a new one. Clients that can't edit messages send it as new
+    /// message.
+    fn update_notification(
+        &self,
+        email: &spark::EmailRef,
+        _message_id: &spark::MessageIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture {
+        self.send_message(email, msg)
+    }
+
+    /// Look up the Webex person with the email. Returns `None` if the client
+    /// can't look up people.
+    fn find_person(&self, _email: &spark::EmailRef) -> Option<PersonFuture> {
+        None
+    }
 }
 
+/// Resolves to the Webex person found, if any.
+pub type PersonFuture =
+    Box<dyn Future<Item = Option<spark::PersonDetails>, Error = spark::Error> + Send>;
+
 impl SparkClient for spark::Client {
-    type ReplyFuture = Box<dyn Future<Item = (), Error = spark::Error> + Send>;
+    type ReplyFuture =
+        Box<dyn Future<Item = Option<spark::MessageId>, Error = spark::Error> + Send>;
     fn send_message(&self, email: &spark::EmailRef, msg: &str) -> Self::ReplyFuture {
-        Box::new(self.send_message(email, msg))
+        Box::new(
+            self.send_message(email, msg)
+                .map(|message| Some(message.id)),
+        )
+    }
+
+    fn send_room_message(&self, room_id: &spark::RoomIdRef, msg: &str) -> Self::ReplyFuture {
+        Box::new(
+            self.send_message(room_id, msg)
+                .map(|message| Some(message.id)),
+        )
+    }
+
+    fn send_notification(
+        &self,
+        email: &spark::EmailRef,
+        msg: &str,
+        text: Option<&str>,
+        card: Option<&serde_json::Value>,
+        parent_id: Option<&spark::MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        let attachments: Vec<_> = card
+            .cloned()
+            .map(spark::Attachment::adaptive_card)
+            .into_iter()
+            .collect();
+        let parameters = spark::CreateMessageParameters {
+            target: email.into(),
+            text,
+            markdown: Some(msg),
+            html: None,
+            attachments: card.map(|_| &attachments[..]),
+            files: None,
+            parent_id,
+        };
+        Box::new(
+            self.create_message(parameters)
+                .map(|message| Some(message.id)),
+        )
+    }
+
+    fn update_notification(
+        &self,
+        _email: &spark::EmailRef,
+        message_id: &spark::MessageIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture {
+        let client = self.clone();
+        let msg = msg.to_string();
+        Box::new(
+            self.get_message(message_id)
+                .and_then(move |message| {
+                    let markdown = message.markdown.unwrap_or(message.text);
+                    client.update_message(
+                        &message.id,
+                        &message.room_id,
+                        &format!("{}\n\n{}", markdown, msg),
+                    )
+                })
+                .map(|message| Some(message.id)),
+        )
+    }
+
+    fn find_person(&self, email: &spark::EmailRef) -> Option<PersonFuture> {
+        Some(Box::new(self.find_person(email)))
     }
 }
 
@@ -66,7 +245,28 @@ impl convert::From<serde_json::Error> for BotError {
 pub struct Builder {
     state: State,
     rate_limiter: RateLimiter,
+    change_tracker: ChangeTracker,
     formatter: Formatter,
+    format_queries: Option<std::sync::Arc<std::sync::Mutex<FormatQueries>>>,
+    gerrit_base_url: Option<String>,
+    adaptive_cards: bool,
+    edit_notifications: bool,
+    skip_account_link_verification: bool,
+    auto_invite: bool,
+    invite_opt_out: Vec<spark::Email>,
+    admins: Vec<spark::Email>,
+    review_labels: BTreeMap<String, Vec<i32>>,
+    aliases: Aliases,
+    send_concurrency: Option<usize>,
+    send_timeout: Option<Duration>,
+    merge_conflict_check_interval: Option<Duration>,
+    stale_review_reminder: Option<StaleReviewReminder>,
+    reviewer_reminder_schedule: Option<ReviewerReminderSchedule>,
+    project_flags: Vec<ProjectFlags>,
+    state_backups: usize,
+    state_backup_interval: Option<Duration>,
+    state_export_dir: Option<PathBuf>,
+    autosave_interval: Option<Duration>,
 }
 
 impl Builder {
@@ -84,6 +284,13 @@ impl Builder {
         }
     }
 
+    pub fn with_change_tracker(self, capacity: usize, expiration: Duration) -> Self {
+        Self {
+            change_tracker: ChangeTracker::with_expiry_duration_and_capacity(expiration, capacity),
+            ..self
+        }
+    }
+
     pub fn with_format_script(self, script_source: &str) -> Result<Self, String> {
         Ok(Self {
             formatter: Formatter::new(script_source)?,
@@ -91,30 +298,320 @@ impl Builder {
         })
     }
 
+    /// Format the messages about changes of the projects matching the regular
+    /// expression with another format script.
+    pub fn with_project_format_script(
+        mut self,
+        project: &str,
+        script_source: &str,
+    ) -> Result<Self, String> {
+        self.formatter.add_project_script(project, script_source)?;
+        Ok(self)
+    }
+
+    /// Format the messages with templates named after the format functions
+    /// they replace.
+    pub fn with_format_templates(
+        mut self,
+        templates: &BTreeMap<String, String>,
+    ) -> Result<Self, String> {
+        self.formatter.set_templates(Templates::new(templates)?);
+        Ok(self)
+    }
+
+    /// Let the format scripts query Gerrit with `gerrit_query(q)`. Queries
+    /// are only run for the changes listed and shown to users, and the ones
+    /// taking longer than the timeout fail.
+    pub fn with_format_queries(mut self, timeout: Duration) -> Result<Self, String> {
+        let format_queries =
+            std::sync::Arc::new(std::sync::Mutex::new(FormatQueries::new(timeout)));
+        let queries = format_queries.clone();
+        self.formatter
+            .set_gerrit_query(move |search| queries.lock().unwrap().get(search))?;
+        self.format_queries = Some(format_queries);
+        Ok(self)
+    }
+
+    /// Build links to Gerrit from this URL instead of the change URLs.
+    pub fn with_gerrit_base_url(self, base_url: String) -> Self {
+        Self {
+            gerrit_base_url: Some(base_url),
+            ..self
+        }
+    }
+
+    /// Send Adaptive Cards along with the notifications.
+    pub fn with_adaptive_cards(self) -> Self {
+        Self {
+            adaptive_cards: true,
+            ..self
+        }
+    }
+
+    /// Append notifications about merged and abandoned changes to the first
+    /// notification the user got about the change.
+    pub fn with_edited_notifications(self) -> Self {
+        Self {
+            edit_notifications: true,
+            ..self
+        }
+    }
+
+    /// Gerrit emails linked by users are linked right away instead of
+    /// needing the approval of an admin.
+    pub fn without_account_link_verification(self) -> Self {
+        Self {
+            skip_account_link_verification: true,
+            ..self
+        }
+    }
+
+    /// Invite people involved in Gerrit events who don't use the bot yet,
+    /// except the ones who opted out.
+    pub fn with_auto_invite(self, opt_out: Vec<spark::Email>) -> Self {
+        Self {
+            auto_invite: true,
+            invite_opt_out: opt_out,
+            ..self
+        }
+    }
+
+    /// Users allowed to run admin commands, e.g. room subscriptions.
+    pub fn with_admins(self, admins: Vec<spark::Email>) -> Self {
+        Self { admins, ..self }
+    }
+
+    /// Aliases of commands, e.g. `mute` for `disable`, resolved before the
+    /// commands are parsed.
+    pub fn with_aliases(self, aliases: BTreeMap<String, String>) -> Self {
+        Self {
+            aliases: Aliases::new(aliases),
+            ..self
+        }
+    }
+
+    /// Labels and their values users may vote with from chat. Voting is
+    /// disabled without any.
+    pub fn with_review_labels(self, review_labels: BTreeMap<String, Vec<i32>>) -> Self {
+        Self {
+            review_labels,
+            ..self
+        }
+    }
+
+    /// Send up to this many messages at a time. Defaults to 10.
+    pub fn with_send_concurrency(self, send_concurrency: usize) -> Self {
+        Self {
+            send_concurrency: Some(send_concurrency.max(1)),
+            ..self
+        }
+    }
+
+    /// Give up sending a message after this time. Defaults to 5 seconds.
+    pub fn with_send_timeout(self, send_timeout: Duration) -> Self {
+        Self {
+            send_timeout: Some(send_timeout),
+            ..self
+        }
+    }
+
+    /// Regularly look for open changes with merge conflicts, and tell their
+    /// owners.
+    pub fn with_merge_conflict_check(self, interval: Duration) -> Self {
+        Self {
+            merge_conflict_check_interval: Some(interval),
+            ..self
+        }
+    }
+
+    /// Remind owners of open changes without any activity for the given
+    /// number of days. The changes are checked once per interval, by default
+    /// once a day.
+    pub fn with_stale_review_reminders(self, days: u32, interval: Option<Duration>) -> Self {
+        let interval = interval.unwrap_or(DEFAULT_STALE_REVIEW_CHECK_INTERVAL);
+        Self {
+            stale_review_reminder: chrono::Duration::from_std(interval)
+                .ok()
+                .map(|interval| StaleReviewReminder::new(days, interval)),
+            ..self
+        }
+    }
+
+    /// Remind reviewers who didn't vote on a change after the given number of
+    /// days. The time between the reminders doubles, up to a maximum number
+    /// of reminders, by default 3.
+    pub fn with_reviewer_reminders(self, days: u32, max: Option<u32>) -> Self {
+        Self {
+            reviewer_reminder_schedule: Some(ReviewerReminderSchedule::new(
+                days,
+                max.unwrap_or(DEFAULT_REVIEWER_REMINDER_MAX),
+            )),
+            ..self
+        }
+    }
+
+    /// Flags enabled by default for changes of some projects or branches.
+    pub fn with_project_flags(self, project_flags: Vec<ProjectFlags>) -> Self {
+        Self {
+            project_flags,
+            ..self
+        }
+    }
+
+    /// Keep the given number of previous saves of the state, to be restored
+    /// by admins.
+    pub fn with_state_backups(self, state_backups: usize) -> Self {
+        Self {
+            state_backups,
+            ..self
+        }
+    }
+
+    /// Rotate the backups of the state at most once per interval, instead of
+    /// once per hour.
+    pub fn with_state_backup_interval(self, interval: Duration) -> Self {
+        Self {
+            state_backup_interval: Some(interval),
+            ..self
+        }
+    }
+
+    /// Let admins export and import the state as files in the directory.
+    pub fn with_state_export_dir(self, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            state_export_dir: Some(dir.into()),
+            ..self
+        }
+    }
+
+    /// Save the state once per interval, also if nothing asked for it.
+    pub fn with_autosave(self, interval: Duration) -> Self {
+        Self {
+            autosave_interval: Some(interval),
+            ..self
+        }
+    }
+
     pub fn build<G, S>(self, gerrit_command_runner: G, spark_client: S) -> Bot<G, S> {
         let Self {
-            formatter,
+            mut formatter,
+            format_queries,
             rate_limiter,
+            change_tracker,
             state,
+            gerrit_base_url,
+            adaptive_cards,
+            edit_notifications,
+            skip_account_link_verification,
+            auto_invite,
+            invite_opt_out,
+            admins,
+            review_labels,
+            aliases,
+            send_concurrency,
+            send_timeout,
+            merge_conflict_check_interval,
+            stale_review_reminder,
+            reviewer_reminder_schedule,
+            project_flags,
+            state_backups,
+            state_backup_interval,
+            state_export_dir,
+            autosave_interval,
         } = self;
+        formatter.set_gerrit_base_url(gerrit_base_url);
+        formatter.set_adaptive_cards(adaptive_cards);
 
         Bot {
             gerrit_command_runner,
             spark_client,
             rate_limiter,
+            change_tracker,
             formatter,
+            format_queries,
             state,
+            edit_notifications,
+            verify_account_links: !skip_account_link_verification,
+            auto_invite,
+            invite_opt_out,
+            admins,
+            review_labels,
+            aliases,
+            send_concurrency: send_concurrency.unwrap_or(DEFAULT_SEND_CONCURRENCY),
+            send_timeout: send_timeout.unwrap_or(DEFAULT_SEND_TIMEOUT),
+            merge_conflict_check_interval,
+            last_merge_conflict_check: None,
+            stale_review_reminder,
+            reviewer_reminder_schedule,
+            project_flags,
+            stats: Stats::new(Utc::now()),
+            state_writer: None,
+            state_backups,
+            state_backup_interval: state_backup_interval.unwrap_or(DEFAULT_STATE_BACKUP_INTERVAL),
+            state_export_dir,
+            autosave_interval,
+            last_autosave: None,
         }
     }
 }
 
-fn spark_message_to_action(message: spark::Message) -> Action {
+fn spark_message_to_action(message: spark::Message, aliases: &Aliases) -> Action {
+    // in group rooms, commands may mention other people, too
+    let text = match message.room_type {
+        spark::RoomType::Group => message.text_without_mentions(),
+        spark::RoomType::Direct => message.text,
+    };
     let sender = message.person_email;
-    let text = message.text;
+    let room = Some(CommandRoom {
+        id: message.room_id,
+        room_type: message.room_type,
+    });
+
+    match aliases.parse(&text) {
+        Ok(command) => Action::RunCommand {
+            sender,
+            room,
+            command,
+        },
+        Err(()) => Action::UnknownCommand { sender, room },
+    }
+}
 
-    match text.parse() {
-        Ok(command) => Action::RunCommand { sender, command },
-        Err(()) => Action::UnknownCommand { sender },
+/// Transform a card action into a bot action. Buttons of cards trigger
+/// commands by submitting them as `command` input, e.g.
+/// `{ "type": "Action.Submit", "data": { "command": "status" } }`. The reply
+/// is sent directly to the person who clicked.
+fn spark_attachment_action_to_action(
+    action: spark::AttachmentAction,
+    aliases: &Aliases,
+) -> Option<Action> {
+    let command = match action.inputs.get("command").and_then(|c| c.as_str()) {
+        Some(command) => aliases.parse(command).ok(),
+        None => None,
+    };
+    match command {
+        Some(command) => Some(Action::RunCommand {
+            sender: action.person_email,
+            room: None,
+            command,
+        }),
+        None => {
+            debug!("Ignoring card action without command: {:?}", action.inputs);
+            None
+        }
+    }
+}
+
+/// Transform a spark event into a bot action.
+fn spark_event_to_action(event: spark::Event, aliases: &Aliases) -> Option<Action> {
+    match event {
+        spark::Event::Message(message) => Some(spark_message_to_action(message, aliases)),
+        spark::Event::AttachmentAction(action) => {
+            spark_attachment_action_to_action(action, aliases)
+        }
+        spark::Event::Membership(membership) => Some(Action::Greet {
+            room_id: membership.room_id,
+        }),
     }
 }
 
@@ -125,6 +622,11 @@ fn gerrit_event_to_action(event: gerrit::Event) -> Option<Action> {
         gerrit::Event::ReviewerAdded(event) => Some(Action::ReviewerAdded(Box::new(event))),
         gerrit::Event::ChangeMerged(event) => Some(Action::ChangeMerged(Box::new(event))),
         gerrit::Event::ChangeAbandoned(event) => Some(Action::ChangeAbandoned(Box::new(event))),
+        gerrit::Event::ChangeDeleted(event) => Some(Action::ChangeDeleted(Box::new(event))),
+        gerrit::Event::Unknown { event_type, .. } => {
+            debug!("Ignoring unknown gerrit event: {}", event_type);
+            None
+        }
     }
 }
 
@@ -166,14 +668,17 @@ pub fn request_extended_gerrit_info(event: &gerrit::Event) -> Cow<'static, [gerr
             // comments.
             if owner_name == approver_name {
                 extended_info.push(gerrit::ExtendedInfo::AllApprovals);
+                extended_info.push(gerrit::ExtendedInfo::AllReviewers);
             }
 
             // Could be smarter here by checking for old_value and if the value
             // is positive.
             extended_info.push(gerrit::ExtendedInfo::SubmitRecords);
+            extended_info.push(gerrit::ExtendedInfo::SubmitRequirements);
         }
         gerrit::Event::ChangeMerged(_) | gerrit::Event::ChangeAbandoned(_) => {
             extended_info.push(gerrit::ExtendedInfo::AllApprovals);
+            extended_info.push(gerrit::ExtendedInfo::AllReviewers);
         }
         _ => (),
     }
@@ -181,12 +686,51 @@ pub fn request_extended_gerrit_info(event: &gerrit::Event) -> Cow<'static, [gerr
     Cow::Owned(extended_info)
 }
 
+/// Guess if the author added inline comments by looking for the "(N comments)"
+/// summary Gerrit adds to the message. Only the comments not reported yet are
+/// attached to the event.
+fn maybe_has_inline_comments(event: &gerrit::CommentAddedEvent) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\(\d+\s+(inline\s+)?comments?\)").unwrap();
+    }
+    RE.is_match(&event.comment)
+}
+
 pub struct Bot<G = gerrit::CommandRunner, S = spark::Client> {
     state: State,
     rate_limiter: RateLimiter,
+    change_tracker: ChangeTracker,
     formatter: format::Formatter,
+    /// Results of the Gerrit queries of the format scripts.
+    format_queries: Option<std::sync::Arc<std::sync::Mutex<FormatQueries>>>,
     gerrit_command_runner: G,
     spark_client: S,
+    edit_notifications: bool,
+    verify_account_links: bool,
+    auto_invite: bool,
+    invite_opt_out: Vec<spark::Email>,
+    admins: Vec<spark::Email>,
+    review_labels: BTreeMap<String, Vec<i32>>,
+    aliases: Aliases,
+    send_concurrency: usize,
+    send_timeout: Duration,
+    merge_conflict_check_interval: Option<Duration>,
+    last_merge_conflict_check: Option<DateTime<Utc>>,
+    stale_review_reminder: Option<StaleReviewReminder>,
+    reviewer_reminder_schedule: Option<ReviewerReminderSchedule>,
+    project_flags: Vec<ProjectFlags>,
+    stats: Stats,
+    /// Number of previous saves of the state which are kept.
+    state_backups: usize,
+    /// Minimum time between two rotations of the backups.
+    state_backup_interval: Duration,
+    /// Directory of the state files exported and imported by admins.
+    state_export_dir: Option<PathBuf>,
+    autosave_interval: Option<Duration>,
+    last_autosave: Option<DateTime<Utc>>,
+    /// Saves the state in the background while running; otherwise it is
+    /// saved right away.
+    state_writer: Option<StateWriter>,
 }
 
 impl<G, S> Bot<G, S>
@@ -195,736 +739,4553 @@ where
     S: SparkClient,
 {
     pub fn run(
-        self,
-        gerrit_events: impl Stream<Item = gerrit::Event, Error = ()> + Send,
-        spark_messages: impl Stream<Item = spark::Message, Error = ()> + Send,
+        mut self,
+        gerrit_events: impl Stream<Item = gerrit::Event, Error = gerrit::Error> + Send,
+        spark_events: impl Stream<Item = spark::Event, Error = ()> + Send,
     ) -> impl Future<Item = (), Error = ()> {
-        let _ = &self.gerrit_command_runner;
         let spark_client = self.spark_client.clone();
-        let gerrit_actions = gerrit_events.filter_map(gerrit_event_to_action);
-        let spark_actions = spark_messages.map(spark_message_to_action);
+        let lookup_client = self.spark_client.clone();
+        let send_concurrency = self.send_concurrency;
+        let send_timeout = self.send_timeout;
+        let aliases = self.aliases.clone();
+        self.state_writer = Some(StateWriter::spawn(
+            STATE_FILENAME,
+            self.state_backups,
+            self.state_backup_interval,
+        ));
         let bot_for_action = std::sync::Arc::new(std::sync::Mutex::new(self));
         let bot_for_task = bot_for_action.clone();
+        let bot_for_reply = bot_for_action.clone();
+        let bot_for_error = bot_for_action.clone();
+        let bot_for_send_error = bot_for_action.clone();
+        let bot_for_shutdown = bot_for_action.clone();
+        let gerrit_actions = gerrit_events
+            // log and skip errors, the event stream reconnects by itself
+            .then(move |result| {
+                Ok(result
+                    .map_err(|e| {
+                        if e.is_transient() {
+                            warn!("gerrit event stream error: {}", e)
+                        } else {
+                            error!("gerrit event stream error: {}", e)
+                        }
+                        bot_for_error.lock().unwrap().stats.gerrit_error(Utc::now());
+                    })
+                    .ok())
+            })
+            .filter_map(|event| event.and_then(gerrit_event_to_action));
+        let spark_actions =
+            spark_events.filter_map(move |event| spark_event_to_action(event, &aliases));
+        // regularly end snoozes that are over; stop on timer errors
+        let ticks = tokio::timer::Interval::new(
+            std::time::Instant::now() + SNOOZE_CHECK_INTERVAL,
+            SNOOZE_CHECK_INTERVAL,
+        )
+        .then(|result| {
+            Ok(result
+                .map_err(|e| error!("snooze timer failed: {}", e))
+                .ok())
+        })
+        .take_while(|tick| Ok(tick.is_some()))
+        .map(|_| Some(Action::Tick(Utc::now())));
 
         gerrit_actions
             .select(spark_actions)
+            .map(Some)
+            // the ticks end with the other actions
+            .chain(stream::once(Ok(None)))
+            .select(ticks)
+            .take_while(|action| Ok(action.is_some()))
+            .filter_map(identity)
             .map(move |action| bot_for_action.lock().unwrap().update(action))
             .map(stream::iter_ok)
             .flatten()
-            .filter_map(move |task| bot_for_task.lock().unwrap().handle_task(task))
+            .map(move |task| match task {
+                Task::VerifyUser(email) => {
+                    future::Either::A(match lookup_client.find_person(&email) {
+                        Some(lookup) => {
+                            let bot = bot_for_task.clone();
+                            future::Either::A(
+                                // don't hold up other tasks for too long
+                                tokio::timer::Timeout::new(lookup, Duration::from_secs(5)).then(
+                                    move |result| {
+                                        let person = match result {
+                                            Ok(person) => person,
+                                            Err(e) => {
+                                                error!("failed to look up {}: {}", email, e);
+                                                return Ok(Vec::new());
+                                            }
+                                        };
+                                        let mut bot = bot.lock().unwrap();
+                                        let tasks = bot.verify_user(email, person);
+                                        Ok(tasks
+                                            .into_iter()
+                                            .filter_map(|task| bot.handle_task(task))
+                                            .collect())
+                                    },
+                                ),
+                            )
+                        }
+                        None => future::Either::B(future::ok(Vec::new())),
+                    })
+                }
+                Task::Review(review) => future::Either::B(future::Either::A(future::Either::A(
+                    review_change(bot_for_task.clone(), review),
+                ))),
+                Task::ListChanges(list) => future::Either::B(future::Either::A(future::Either::B(
+                    list_changes(bot_for_task.clone(), list),
+                ))),
+                Task::CheckMergeConflicts(query) => {
+                    future::Either::B(future::Either::B(future::Either::A(query_changes(
+                        bot_for_task.clone(),
+                        query,
+                        Action::MergeConflicts,
+                    ))))
+                }
+                Task::CheckStaleReviews(query) => {
+                    future::Either::B(future::Either::B(future::Either::A(query_changes(
+                        bot_for_task.clone(),
+                        query,
+                        Action::StaleReviews,
+                    ))))
+                }
+                task => future::Either::B(future::Either::B(future::Either::B(future::ok(
+                    bot_for_task
+                        .lock()
+                        .unwrap()
+                        .handle_task(task)
+                        .into_iter()
+                        .collect(),
+                )))),
+            })
+            .buffer_unordered(TASK_CONCURRENCY)
+            .map(stream::iter_ok)
+            .flatten()
             .map(move |response| {
                 debug!("Replying with: {}", response.message);
-                spark_client.send_message(&response.email, &response.message)
+                let Response {
+                    recipient,
+                    message,
+                    text,
+                    card,
+                    change_number,
+                    parent_id,
+                    appended_to,
+                } = response;
+                let bot = bot_for_reply.clone();
+                match &recipient {
+                    Recipient::Person(email) => match &appended_to {
+                        Some(message_id) => {
+                            spark_client.update_notification(email, message_id, &message)
+                        }
+                        None => spark_client.send_notification(
+                            email,
+                            &message,
+                            text.as_deref(),
+                            card.as_ref(),
+                            parent_id.as_deref(),
+                        ),
+                    },
+                    Recipient::Room(room_id) => spark_client.send_room_message(room_id, &message),
+                }
+                .map(move |message_id| {
+                    let mut bot = bot.lock().unwrap();
+                    let now = Utc::now();
+                    bot.stats.message_sent(now);
+                    if let (Some(_), Recipient::Person(email)) = (change_number, &recipient) {
+                        bot.state.notification_sent(email, now);
+                    }
+                    // thread later notifications about the change under
+                    // this one
+                    if let (Some(change_number), Some(message_id), Recipient::Person(email)) =
+                        (change_number, message_id, recipient)
+                    {
+                        bot.change_tracker
+                            .add_thread(change_number, email, message_id);
+                    }
+                })
             })
-            .map(|send_future| {
-                // try sending a message for up to 5 seconds, then give up
-                tokio::timer::Timeout::new(send_future, Duration::from_secs(5))
+            .map(move |send_future| {
+                let bot = bot_for_send_error.clone();
+                // try sending a message for a while, then give up
+                tokio::timer::Timeout::new(send_future, send_timeout)
                     // log and suppress errors
-                    .or_else(|e| {
+                    .or_else(move |e| {
                         error!("failed to send spark message: {}", e);
+                        bot.lock().unwrap().stats.message_failed();
                         Ok(())
                     })
             })
-            // try sending up to 10 messages at a time
-            .buffer_unordered(10)
+            .buffer_unordered(send_concurrency)
             .for_each(|()| Ok(()))
+            .then(move |result| {
+                // write the latest state before shutting down
+                let state_writer = bot_for_shutdown.lock().unwrap().state_writer.take();
+                if let Some(state_writer) = state_writer {
+                    state_writer.finish();
+                }
+                result
+            })
     }
 
     /// Action controller
     /// Return an optional message to send to the user
     fn update(&mut self, action: Action) -> Vec<Task> {
-        match action {
-            Action::RunCommand { sender, command } => self.run_command(sender, command),
-            Action::UnknownCommand { sender } => self
+        // remember the latest gerrit event so missed events can be recovered
+        // after a restart
+        let save_event_created_on = action
+            .event_created_on()
+            .map(|created_on| {
+                self.stats.gerrit_event(Utc::now());
+                self.state.update_last_event_created_on(created_on)
+            })
+            .unwrap_or(false);
+
+        self.track_participants(&action);
+        let save_review_reminders = self.track_review_reminders(&action);
+        let invitations = self.invitations(&action);
+
+        let mut tasks: Vec<Task> = match action {
+            Action::RunCommand {
+                sender,
+                room,
+                command,
+            } => {
+                let group_room_id = room.as_ref().and_then(CommandRoom::group_room_id).cloned();
+                let tasks = self
+                    .run_command(sender.clone(), room.as_ref(), command)
+                    .into_iter()
+                    .map(|task| task.in_room(group_room_id.as_ref()))
+                    .collect();
+                self.state.command_received(&sender, Utc::now());
+                tasks
+            }
+            Action::UnknownCommand { sender, room } => {
+                let group_room_id = room.as_ref().and_then(CommandRoom::group_room_id);
+                self.formatter
+                    .format_greeting()
+                    .map_err(|e| error!("failed to format message: {}", e))
+                    .ok()
+                    .into_iter()
+                    .flatten()
+                    .map(|message| Task::Reply(Response::new(sender.clone(), message)))
+                    .map(|task| task.in_room(group_room_id))
+                    .collect()
+            }
+            Action::Tick(now) => {
+                let mut tasks: Vec<Task> = self
+                    .state
+                    .expired_snoozes(now)
+                    .into_iter()
+                    .flat_map(|email| self.end_snooze(email, false))
+                    .collect();
+                let released = self.state.release_held_notifications(now);
+                let digests = self.state.take_due_digests(now);
+                let review_reminders = self.review_reminders(now);
+                if !released.is_empty() || !digests.is_empty() || !review_reminders.is_empty() {
+                    tasks.push(Task::Save);
+                }
+                tasks.extend(review_reminders);
+                tasks.extend(
+                    released.into_iter().map(|(email, notifications)| {
+                        self.held_notifications(email, notifications)
+                    }),
+                );
+                tasks.extend(
+                    digests
+                        .into_iter()
+                        .filter_map(|(email, digest)| self.digest(email, &digest)),
+                );
+                tasks.extend(self.merge_conflict_check(now));
+                tasks.extend(self.stale_review_check(now));
+                tasks.extend(self.autosave(now));
+                tasks.extend(self.state_file_check());
+                tasks
+            }
+            Action::StaleReviews(changes) => self.stale_review_reminders(&changes),
+            Action::MergeConflicts(changes) => {
+                let mut tasks: Vec<Task> = changes
+                    .iter()
+                    .filter(|change| !self.state.is_known_merge_conflict(change.number))
+                    .filter_map(|change| {
+                        let (email, message) = self.get_merge_conflict_msg(change)?;
+                        Some(self.notification(change.number, email, message, None))
+                    })
+                    .collect();
+                let merge_conflicts = changes.iter().map(|change| change.number).collect();
+                if self.state.set_merge_conflicts(merge_conflicts) {
+                    tasks.push(Task::Save);
+                }
+                tasks
+            }
+            Action::Greet { room_id } => self
                 .formatter
                 .format_greeting()
                 .map_err(|e| error!("failed to format message: {}", e))
                 .ok()
                 .into_iter()
                 .flatten()
-                .map(|message| Task::Reply(Response::new(sender.clone(), message)))
-                .collect(),
-            Action::CommentAdded(event) => self
-                .get_comment_messages(event)
-                .into_iter()
-                .map(|(email, message)| Task::Reply(Response::new(email, message)))
-                .collect(),
-            Action::ReviewerAdded(event) => self
-                .get_reviewer_added_msg(&event)
-                .map(|(user, message)| Task::Reply(Response::new(user.email().to_owned(), message)))
-                .into_iter()
-                .collect(),
-            Action::ChangeMerged(event) => self
-                .get_change_merged_messages(&event)
-                .into_iter()
-                .map(|(email, message)| Task::Reply(Response::new(email, message)))
-                .into_iter()
-                .collect(),
-            Action::ChangeAbandoned(event) => self
-                .get_change_abandoned_messages(&event)
-                .into_iter()
-                .map(|(email, message)| Task::Reply(Response::new(email, message)))
-                .into_iter()
+                .map(|message| Task::Reply(Response::to_room(room_id.clone(), message)))
                 .collect(),
-        }
-    }
-
-    fn run_command(&mut self, sender: spark::Email, command: Command) -> Vec<Task> {
-        match command {
-            Command::Enable => {
-                self.state.enable(&sender, true);
-                vec![
-                    Task::Save,
-                    Task::Reply(Response::new(sender, "Got it! Happy reviewing!")),
-                ]
+            Action::CommentAdded(event) => {
+                let card = self.format_card(&*event);
+                let change_number = event.change.number;
+                let room_tasks = self.get_room_messages(&event.change, &*event);
+                let submittable_msg = self.get_submittable_msg(&event);
+                self.get_comment_messages(event)
+                    .into_iter()
+                    .chain(submittable_msg)
+                    .map(|(email, message)| {
+                        self.notification(change_number, email, message, card.clone())
+                    })
+                    .chain(room_tasks)
+                    .collect()
             }
-            Command::Disable => {
-                self.state.enable(&sender, false);
-                vec![
-                    Task::Save,
-                    Task::Reply(Response::new(sender, "Got it! I will stay silent.")),
-                ]
+            Action::ReviewerAdded(event) => {
+                let card = self.format_card(&*event);
+                self.get_reviewer_added_msg(&event)
+                    .map(|(user, message)| (user.email().to_owned(), message))
+                    .map(|(email, message)| {
+                        self.notification(event.change.number, email, message, card)
+                    })
+                    .into_iter()
+                    .collect()
             }
-            Command::Help => self
-                .formatter
-                .format_help()
-                .map_err(|e| error!("failed to format help: {}", e))
-                .ok()
-                .into_iter()
-                .flatten()
-                .map(|message| Task::Reply(Response::new(sender.clone(), message)))
-                .collect(),
-            Command::Version => self
-                .formatter
-                .format_message(None, &VERSION_INFO)
-                .map_err(|e| error!("failed to format version: {}", e))
-                .ok()
-                .and_then(identity)
-                .map(|version_message| Task::Reply(Response::new(sender, version_message)))
-                .into_iter()
-                .collect(),
-            Command::Status => self
-                .status_for(&sender)
-                .map(|status| Task::Reply(Response::new(sender, status)))
-                .into_iter()
-                .collect(),
-            Command::FilterStatus => {
-                let resp =
-                    if let Some((filter_str, filter_enabled)) = self.state.get_filter(&sender) {
-                        format!(
-                            "The following filter is configured for you: `{}`. It is **{}**.",
-                            filter_str,
-                            if filter_enabled {
-                                "enabled"
-                            } else {
-                                "disabled"
-                            }
-                        )
-                    } else {
-                        "No filter is configured for you.".to_string()
-                    };
-
-                vec![Task::Reply(Response::new(sender, resp))]
+            Action::ChangeMerged(event) => {
+                let card = self.format_card(&*event);
+                let mut tasks: Vec<Task> = self
+                    .get_change_merged_messages(&event)
+                    .into_iter()
+                    .map(|(email, message)| {
+                        self.final_notification(event.change.number, email, message, card.clone())
+                    })
+                    .collect();
+                tasks.extend(self.get_room_messages(&event.change, &*event));
+                self.change_tracker.forget(&event.change);
+                tasks
             }
-            Command::FilterAdd(filter) => {
-                let resp = self.state.add_filter(&sender, &filter).map(
-                |()|
-                "Filter successfully added and enabled."
-            ).unwrap_or(
-                "Your provided filter is invalid. Please double-check the regex you provided. Specifications of the regex are here: https://doc.rust-lang.org/regex/regex/index.html#syntax");
-                vec![Task::Reply(Response::new(sender, resp.to_string()))]
+            Action::ChangeAbandoned(event) => {
+                let card = self.format_card(&*event);
+                let mut tasks: Vec<Task> = self
+                    .get_change_abandoned_messages(&event)
+                    .into_iter()
+                    .map(|(email, message)| {
+                        self.final_notification(event.change.number, email, message, card.clone())
+                    })
+                    .collect();
+                tasks.extend(self.get_room_messages(&event.change, &*event));
+                self.change_tracker.forget(&event.change);
+                tasks
             }
-            Command::FilterEnable(enable) => {
-                let resp = self.state.enable_and_get_filter(&sender, enable).map(
-                |filter|
-                if enable {
-                format!(
-                    "Filter successfully enabled. The following filter is configured: {}",
-                    filter
-                )
-                } else {
-                    "Filter successfully disabled.".to_string()
-                }
-            ).unwrap_or_else(||
-                             if enable {
-                                 "Cannot enable filter since there is none configured. User `filter <regex>` to add a new filter.".to_string()
-                             } else {
-                                 "No need to disable the filter since there is none configured.".to_string()
-                             }
-                );
+            Action::ChangeDeleted(event) => {
+                let card = self.format_card(&*event);
+                let mut tasks: Vec<Task> = self
+                    .get_change_deleted_messages(&event)
+                    .into_iter()
+                    .map(|(email, message)| {
+                        self.notification(event.change.number, email, message, card.clone())
+                    })
+                    .collect();
+                tasks.extend(self.get_room_messages(&event.change, &*event));
+                // nothing will happen to the change anymore
+                self.change_tracker.forget(&event.change);
+                self.rate_limiter.forget(&event.change);
+                tasks
+            }
+        };
 
-                vec![Task::Save, Task::Reply(Response::new(sender, resp))]
-            }
-            Command::SetFlag(flag, enable) => {
-                self.state.set_flag(&sender, flag, enable);
-                vec![
-                    Task::Save,
-                    Task::Reply(Response::new(
-                        sender,
-                        format!(
-                            "Flag {} **{}**",
-                            flag,
-                            if enable { "enabled" } else { "disabled" }
-                        ),
-                    )),
-                ]
+        let invited = !invitations.is_empty();
+        tasks.extend(invitations);
+
+        // hold back the notifications of snoozing users, users in digest mode
+        // and users in their quiet hours
+        let now = Utc::now();
+        let state = &mut self.state;
+        let mut held_back_notifications = false;
+        tasks.retain(|task| match task {
+            Task::Reply(Response {
+                recipient: Recipient::Person(email),
+                change_number: Some(change_number),
+                message,
+                ..
+            }) => {
+                let held_back = state.add_missed_notification(email, message, now)
+                    || state.add_to_digest(email, *change_number, message)
+                    || state.hold_notification(email, message, now);
+                held_back_notifications |= held_back;
+                !held_back
             }
+            _ => true,
+        });
+
+        if save_event_created_on || held_back_notifications || invited || save_review_reminders {
+            tasks.push(Task::Save);
         }
+
+        tasks
     }
 
-    fn handle_task(&mut self, task: Task) -> Option<Response> {
-        debug!("New task {:#?}", task);
-        match task {
-            Task::Reply(response) => Some(response),
-            Task::Save => {
-                self.save("state.json")
-                    .map_err(|err| {
-                        error!("Could not save state: {:?}", err);
-                    })
-                    .ok();
-                None
+    /// End the snooze of the user, and tell them what they missed. Without
+    /// missed notifications, the user is only told if `always_reply` is set.
+    fn end_snooze(&mut self, email: spark::Email, always_reply: bool) -> Vec<Task> {
+        let summary = match self.state.end_snooze(&email) {
+            Some(snooze) => snooze.summary(),
+            None if always_reply => return vec![self.reply(email, "not_snoozing", &[])],
+            None => return Vec::new(),
+        };
+        let reply = match summary {
+            Some(summary) => self.reply(email, "welcome_back", &[("summary", &summary)]),
+            None if always_reply => self.reply(email, "welcome_back_nothing_missed", &[]),
+            None => return vec![Task::Save],
+        };
+        vec![Task::Save, reply]
+    }
+
+    /// Canned reply from the catalog of the format script, in the language of
+    /// the user.
+    fn text(
+        &self,
+        email: &spark::EmailRef,
+        id: &str,
+        args: &[(&str, &dyn fmt::Display)],
+    ) -> String {
+        let language = self.state.find_user(email).and_then(User::language);
+        match self.formatter.format_reply(language, id, args) {
+            Ok(Some(text)) => text,
+            Ok(None) => {
+                error!("reply {} is missing in the format script", id);
+                id.to_string()
+            }
+            Err(e) => {
+                error!("failed to format reply {}: {}", id, e);
+                id.to_string()
             }
         }
     }
 
-    /// Return iterator of users which might be interested in an event.
-    fn interested_users<'bot, 'event, 'result>(
-        &'bot self,
-        change: &'event gerrit::Change,
-        patchset: &'event gerrit::Patchset,
-    ) -> impl Iterator<Item = &'bot User> + 'result
-    where
-        'bot: 'result,
-        'event: 'result,
-    {
-        // TODO: this function currently only considers users that added an
-        // approval on the patchset in question. Ideally, users that approved
-        // (or event commented) on any patchset might be considered interested.
-        // For that to work with the current model for each event we'd have to
-        // query gerrit with the --all-reviewers flag to get all reviewers of a
-        // change (or even --patch-sets to get all patchsets) as well. This
-        // might create quite some load on the gerrit server. We can already get
-        // almost all of that information by subscribing to events. This would
-        // holding more state and tracking changes, reviewers etc.
-        let _ = change;
-        patchset
-            .approvals
-            .iter()
-            .flatten()
-            .filter_map(|approval| approval.by.as_ref())
-            .chain(std::iter::once(&change.owner))
-            .filter(|user| user.is_human())
-            .filter_map(|user| user.spark_email())
-            .filter_map(move |email| self.state.find_user(email))
+    fn reply(&self, email: spark::Email, id: &str, args: &[(&str, &dyn fmt::Display)]) -> Task {
+        let text = self.text(&email, id, args);
+        Task::Reply(Response::new(email, text))
     }
 
-    fn get_comment_response_messages(
+    /// Which votes, on a label or all labels, are notified about.
+    fn votes_text(
         &self,
-        event: Box<gerrit::CommentAddedEvent>,
-    ) -> Vec<(spark::Email, String)> {
-        self.interested_users(&event.change, &event.patchset)
-            .filter(|user| Some(user.email()) != event.author.spark_email())
-            .filter(|user| user.has_flag(UserFlag::NotifyReviewResponses))
-            .filter_map(|user| {
-                self.formatter
-                    .format_message(Some(user), &*event)
-                    .map_err(|e| error!("message formatting failed: {}", e))
-                    .unwrap_or(None)
-                    .filter(|message| !self.state.is_filtered(user, message))
-                    .map(|message| (user.email().to_owned(), message))
-            })
-            .collect()
+        email: &spark::EmailRef,
+        label: Option<&str>,
+        threshold: Option<i32>,
+    ) -> String {
+        match (label, threshold) {
+            (None, None) => self.text(email, "votes_all", &[]),
+            (None, Some(threshold)) => {
+                self.text(email, "votes_below", &[("threshold", &threshold)])
+            }
+            (Some(label), None) => self.text(email, "label_votes_all", &[("label", &label)]),
+            (Some(label), Some(threshold)) => self.text(
+                email,
+                "label_votes_below",
+                &[("label", &label), ("threshold", &threshold)],
+            ),
+        }
     }
 
-    fn get_approvals_msg(
-        &mut self,
-        event: Box<gerrit::CommentAddedEvent>,
-    ) -> Option<(spark::Email, String)> {
-        let approvals = event.approvals.as_deref().unwrap_or(&[][..]);
-        let owner_email = event.change.owner.spark_email()?;
+    /// Reply to a command the sender is not allowed to run.
+    fn permission_denied(
+        &self,
+        sender: spark::Email,
+        reason_id: &str,
+        args: &[(&str, &dyn fmt::Display)],
+    ) -> Vec<Task> {
+        let reason = self.text(&sender, reason_id, args);
+        vec![self.reply(sender, "permission_denied", &[("reason", &reason)])]
+    }
 
-        // try to find the user and check it is enabled
-        let user = self
-            .state
-            .find_user(owner_email)
-            .filter(|user| user.has_any_flag(REVIEW_COMMENT_FLAGS))?;
+    /// Batch of the notifications held back during the quiet hours of the
+    /// user.
+    fn held_notifications(&self, email: spark::Email, notifications: Vec<String>) -> Task {
+        let notifications = notifications.join("\n\n---\n\n");
+        self.reply(
+            email,
+            "quiet_hours_over",
+            &[("notifications", &notifications)],
+        )
+    }
 
-        // filter all messages that were already sent to the user recently
-        if !approvals.is_empty() && self.rate_limiter.limit(user, &*event) {
-            debug!("Filtered approval due to cache hit.");
-            return None;
-        }
+    /// Daily digest of the notifications of the user.
+    fn digest(&self, email: spark::Email, digest: &Digest) -> Option<Task> {
+        self.formatter
+            .format_message(self.state.find_user(&email), digest)
+            .map_err(|e| error!("failed to format digest: {}", e))
+            .ok()
+            .flatten()
+            .map(|message| Task::Reply(Response::new(email, message)))
+    }
 
+    /// Adaptive Card sent along with all notifications about the event.
+    fn format_card<I: format::MessageInput>(&self, input: I) -> Option<serde_json::Value> {
         self.formatter
-            .format_message(Some(user), &*event)
-            .unwrap_or_else(|e| {
-                error!("message formatting failed: {}", e);
-                None
-            })
-            .filter(|msg| {
-                // if user has configured and enabled a filter try to apply it
-                !self.state.is_filtered(user, msg)
-            })
-            .map(|m| (owner_email.to_owned(), m))
+            .format_card(input)
+            .map_err(|e| error!("card formatting failed: {}", e))
+            .ok()
+            .flatten()
     }
 
-    fn get_comment_messages(
-        &mut self,
-        event: Box<gerrit::CommentAddedEvent>,
-    ) -> Vec<(spark::Email, String)> {
-        debug!("Incoming approvals: {:#?}", event);
-        let owner_email = event.change.owner.spark_email();
-        let approver_email = event.author.spark_email();
+    /// Notification about a change. It is threaded under the first
+    /// notification the user got about the change.
+    fn notification(
+        &self,
+        change_number: u32,
+        email: spark::Email,
+        message: format::Message,
+        card: Option<serde_json::Value>,
+    ) -> Task {
+        let parent_id = self
+            .change_tracker
+            .thread(change_number, &email)
+            .map(ToOwned::to_owned);
+        Task::Reply(
+            Response::notification(email, message, card).in_thread(change_number, parent_id),
+        )
+    }
 
-        if owner_email == approver_email {
-            self.get_comment_response_messages(event)
-        } else {
-            self.get_approvals_msg(event).into_iter().collect()
+    /// Last notification about a change. If editing notifications is
+    /// enabled, it is appended to the first notification the user got about
+    /// the change. Cards are only sent with new messages.
+    fn final_notification(
+        &self,
+        change_number: u32,
+        email: spark::Email,
+        message: format::Message,
+        card: Option<serde_json::Value>,
+    ) -> Task {
+        match self.change_tracker.thread(change_number, &email) {
+            Some(message_id) if self.edit_notifications => Task::Reply(
+                Response::new(email, message.markdown)
+                    .in_thread(change_number, None)
+                    .appended_to(message_id.to_owned()),
+            ),
+            _ => self.notification(change_number, email, message, card),
         }
     }
 
-    fn get_reviewer_added_msg(
-        &mut self,
-        event: &gerrit::ReviewerAddedEvent,
-    ) -> Option<(&User, String)> {
-        let reviewer_email = spark::EmailRef::new(event.reviewer.email.as_ref()?);
-        let user = self
+    /// Notifications for the group rooms subscribed to the project of the
+    /// change.
+    fn get_room_messages<I: format::MessageInput>(
+        &self,
+        change: &gerrit::Change,
+        input: I,
+    ) -> Vec<Task> {
+        if self
             .state
-            .find_user(reviewer_email)
-            .filter(|user| user.has_flag(UserFlag::NotifyReviewerAdded))?;
-
-        // filter all messages that were already sent to the user recently
-        if self.rate_limiter.limit(user, event) {
-            debug!("Filtered reviewer-added due to cache hit.");
-            return None;
+            .subscribed_rooms(&change.project)
+            .next()
+            .is_none()
+        {
+            return Vec::new();
         }
 
-        let message = self
-            .formatter
-            .format_message(Some(user), event)
-            .map_err(|e| error!("formatting reviewer added failed: {}", e))
-            .ok()??;
+        let message = match self.formatter.format_room_message(input) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Vec::new(),
+            Err(e) => {
+                error!("failed to format room message: {}", e);
+                return Vec::new();
+            }
+        };
 
-        Some((user, message))
+        self.state
+            .subscribed_rooms(&change.project)
+            .map(|room| Task::Reply(Response::to_room(room.id().to_owned(), message.clone())))
+            .collect()
     }
 
-    fn get_change_merged_messages(
-        &mut self,
-        event: &gerrit::ChangeMergedEvent,
-    ) -> Vec<(spark::Email, String)> {
-        self.interested_users(&event.change, &event.patchset)
-            .filter(|user| event.submitter.spark_email() != Some(user.email()))
-            .filter(|user| user.has_flag(UserFlag::NotifyChangeMerged))
-            .filter_map(|user| {
-                self.formatter
-                    .format_message(Some(user), event)
-                    .map_err(|e| error!("message formatting failed: {}", e))
-                    .ok()
-                    .and_then(identity)
-                    .filter(|message| !self.state.is_filtered(user, message))
-                    .map(|message| (user.email().to_owned(), message))
-            })
-            .collect()
+    /// Remember reviewers and commenters of changes, so they are notified
+    /// about later events of the change.
+    fn track_participants(&mut self, action: &Action) {
+        match action {
+            Action::CommentAdded(event) => {
+                self.change_tracker
+                    .add_participant(&event.change, &event.author);
+            }
+            Action::ReviewerAdded(event) => {
+                self.change_tracker
+                    .add_participant(&event.change, &event.reviewer);
+            }
+            _ => (),
+        }
     }
 
-    fn get_change_abandoned_messages(
-        &mut self,
-        event: &gerrit::ChangeAbandonedEvent,
-    ) -> Vec<(spark::Email, String)> {
-        self.interested_users(&event.change, &event.patchset)
-            .filter(|user| event.abandoner.spark_email() != Some(user.email()))
-            .filter(|user| user.has_flag(UserFlag::NotifyChangeAbandoned))
-            .filter_map(|user| {
-                self.formatter
-                    .format_message(Some(user), event)
+    /// Start reminding added reviewers of the change, and stop once they
+    /// voted or the change is closed. Returns whether the reminders changed.
+    fn track_review_reminders(&mut self, action: &Action) -> bool {
+        let schedule = match self.reviewer_reminder_schedule {
+            Some(schedule) => schedule,
+            None => return false,
+        };
+        match action {
+            Action::ReviewerAdded(event) => {
+                let email = match event.reviewer.spark_email() {
+                    Some(email) if event.reviewer.is_human() => email,
+                    _ => return false,
+                };
+                let user = self
+                    .state
+                    .find_user(email)
+                    .filter(|user| user.has_flag(UserFlag::NotifyReviewReminders))
+                    .filter(|user| user.is_interested_in_branch(&event.change.branch));
+                let (user, delay) = match (user, schedule.delay(0)) {
+                    (Some(user), Some(delay)) => (user.email().to_owned(), delay),
+                    _ => return false,
+                };
+                self.state.add_review_reminder(
+                    &event.change,
+                    &user,
+                    event.created_on,
+                    event.created_on + delay,
+                )
+            }
+            Action::CommentAdded(event) => {
+                let voted = event
+                    .approvals
+                    .iter()
+                    .flatten()
+                    .any(|approval| approval.value != "0");
+                match event.author.spark_email() {
+                    Some(email) if voted => {
+                        let email = self
+                            .state
+                            .find_user(email)
+                            .map_or(email, |user| user.email());
+                        let email = email.to_owned();
+                        self.state
+                            .remove_review_reminders(event.change.number, Some(&email))
+                    }
+                    _ => false,
+                }
+            }
+            Action::ChangeMerged(event) => self
+                .state
+                .remove_review_reminders(event.change.number, None),
+            Action::ChangeAbandoned(event) => self
+                .state
+                .remove_review_reminders(event.change.number, None),
+            Action::ChangeDeleted(event) => self
+                .state
+                .remove_review_reminders(event.change.number, None),
+            _ => false,
+        }
+    }
+
+    /// Reminders for the reviewers who didn't vote yet, if they are due.
+    fn review_reminders(&mut self, now: DateTime<Utc>) -> Vec<Task> {
+        let schedule = match self.reviewer_reminder_schedule {
+            Some(schedule) => schedule,
+            None => return Vec::new(),
+        };
+        self.state
+            .take_due_review_reminders(now, |sent| schedule.delay(sent))
+            .into_iter()
+            .filter_map(|reminder| {
+                let user = self
+                    .state
+                    .find_user(&reminder.reviewer)
+                    .filter(|user| user.has_flag(UserFlag::NotifyReviewReminders))?;
+                let message = self
+                    .formatter
+                    .format_notification(
+                        Some(user),
+                        &[],
+                        format::ReviewReminder {
+                            change: &reminder.change,
+                            added: reminder.added,
+                            sent: reminder.sent,
+                            now,
+                        },
+                    )
                     .map_err(|e| error!("message formatting failed: {}", e))
                     .ok()
                     .and_then(identity)
-                    .filter(|message| !self.state.is_filtered(user, message))
-                    .map(|message| (user.email().to_owned(), message))
+                    .filter(|message| !self.state.is_filtered(user, message))?;
+                Some(self.notification(reminder.change.number, reminder.reviewer, message, None))
             })
             .collect()
     }
 
-    pub fn save<P>(&self, filename: P) -> Result<(), BotError>
-    where
-        P: AsRef<Path>,
-    {
-        let f = File::create(filename)?;
-        serde_json::to_writer(f, &self.state)?;
-        Ok(())
-    }
-
-    fn status_for(&self, email: &spark::EmailRef) -> Option<String> {
-        let user = self.state.find_user(email);
-        let enabled_user_count = self
-            .state
-            .users()
-            .filter(|u| u.has_any_flag(NOTIFICATION_FLAGS))
-            .count();
-        self.formatter
-            .format_status(user, enabled_user_count)
-            .map_err(|e| error!("formatting status failed: {}", e))
-            .ok()?
+    /// Invite the people involved in a Gerrit event who don't use the bot
+    /// yet. Everyone is invited only once.
+    fn invitations(&mut self, action: &Action) -> Vec<Task> {
+        if !self.auto_invite {
+            return Vec::new();
+        }
+        let change = match action.change() {
+            Some(change) => change,
+            None => return Vec::new(),
+        };
+        let mut tasks = Vec::new();
+        for user in action.involved_users() {
+            let email = match user.spark_email() {
+                Some(email) if user.is_human() => email,
+                _ => continue,
+            };
+            if self
+                .invite_opt_out
+                .iter()
+                .any(|opt_out| **opt_out == *email)
+                || self.state.find_user(email).is_some()
+                || self.rate_limiter.limit_invitation(email)
+                || !self.state.invite(email)
+            {
+                continue;
+            }
+            let name = user
+                .name
+                .as_deref()
+                .or(user.email.as_deref())
+                .unwrap_or_default();
+            tasks.push(self.reply(
+                email.to_owned(),
+                "invitation",
+                &[
+                    ("name", &name),
+                    ("change", &change.number),
+                    ("subject", &change.subject),
+                ],
+            ));
+        }
+        tasks
     }
-}
 
-#[derive(Debug)]
-enum Action {
-    RunCommand {
+    fn run_command(
+        &mut self,
         sender: spark::Email,
+        room: Option<&CommandRoom>,
         command: Command,
-    },
-    UnknownCommand {
-        sender: spark::Email,
-    },
-    CommentAdded(Box<gerrit::CommentAddedEvent>),
-    ReviewerAdded(Box<gerrit::ReviewerAddedEvent>),
-    ChangeMerged(Box<gerrit::ChangeMergedEvent>),
-    ChangeAbandoned(Box<gerrit::ChangeAbandonedEvent>),
-}
-
-#[derive(Debug)]
-struct Response {
-    pub email: spark::Email,
-    pub message: String,
-}
-
-impl Response {
-    pub fn new<A>(email: spark::Email, message: A) -> Response
-    where
-        A: Into<String>,
-    {
-        Response {
-            email,
-            message: message.into(),
-        }
+    ) -> Vec<Task> {
+        let group_room_id = room.and_then(CommandRoom::group_room_id);
+        match command {
+            Command::Enable => {
+                self.state.enable(&sender, true);
+                vec![
+                    Task::Save,
+                    self.reply(sender.clone(), "enabled", &[]),
+                    Task::VerifyUser(sender),
+                ]
+            }
+            Command::Disable => {
+                self.state.enable(&sender, false);
+                vec![Task::Save, self.reply(sender, "disabled", &[])]
+            }
+            Command::Help(None) => self
+                .formatter
+                .format_help()
+                .map_err(|e| error!("failed to format help: {}", e))
+                .ok()
+                .into_iter()
+                .flatten()
+                .map(|message| Task::Reply(Response::new(sender.clone(), message)))
+                .collect(),
+            Command::Help(Some(topic)) => match self.formatter.format_command_help(&topic) {
+                Ok(Some(message)) => vec![Task::Reply(Response::new(sender, message))],
+                Ok(None) => vec![self.reply(sender, "unknown_help_topic", &[("topic", &topic)])],
+                Err(e) => {
+                    error!("failed to format help: {}", e);
+                    Vec::new()
+                }
+            },
+            Command::Version => self
+                .formatter
+                .format_message(None, &VERSION_INFO)
+                .map_err(|e| error!("failed to format version: {}", e))
+                .ok()
+                .and_then(identity)
+                .map(|version_message| Task::Reply(Response::new(sender, version_message)))
+                .into_iter()
+                .collect(),
+            Command::Test => self.test_notification(sender),
+            Command::Status => self
+                .status_for(&sender)
+                .map(|status| Task::Reply(Response::new(sender, status)))
+                .into_iter()
+                .collect(),
+            Command::Settings => self
+                .formatter
+                .format_settings(self.state.find_user(&sender))
+                .map_err(|e| error!("failed to format settings: {}", e))
+                .ok()
+                .and_then(identity)
+                .map(|settings| Task::Reply(Response::new(sender, settings)))
+                .into_iter()
+                .collect(),
+            Command::ExportSettings => {
+                let settings = self.state.export_settings(&sender);
+                match serde_json::to_string_pretty(&settings) {
+                    Ok(settings) => {
+                        vec![self.reply(sender, "settings_export", &[("settings", &settings)])]
+                    }
+                    Err(e) => {
+                        error!("failed to export settings: {}", e);
+                        Vec::new()
+                    }
+                }
+            }
+            Command::ImportSettings(json) => {
+                let settings: Settings = match serde_json::from_str(&json) {
+                    Ok(settings) => settings,
+                    Err(e) => {
+                        return vec![self.reply(sender, "settings_invalid", &[("error", &e)])]
+                    }
+                };
+                let previous = self.state.import_settings(&sender, settings, Utc::now());
+                let mut tasks = vec![
+                    Task::Save,
+                    self.reply(sender.clone(), "settings_imported", &[]),
+                ];
+                // deliver the collected notifications right away
+                tasks.extend(
+                    previous
+                        .filter(|digest| digest.count() > 0)
+                        .and_then(|digest| self.digest(sender, &digest)),
+                );
+                tasks
+            }
+            Command::Reset { confirmed: false } => vec![self.reply(sender, "reset_confirm", &[])],
+            Command::Reset { confirmed: true } => {
+                // reply in the language from before the reset
+                let reply = self.reply(sender.clone(), "reset_done", &[]);
+                if self.state.reset_settings(&sender) {
+                    vec![Task::Save, reply]
+                } else {
+                    vec![self.reply(sender, "reset_nothing", &[])]
+                }
+            }
+            Command::Undo => {
+                let reply = match self.state.undo(&sender) {
+                    Some(Undo::Enabled(true)) => self.reply(sender, "enabled", &[]),
+                    Some(Undo::Enabled(false)) => self.reply(sender, "disabled", &[]),
+                    Some(Undo::Flags(_)) => self.reply(sender, "undo_flags", &[]),
+                    Some(Undo::Filter(Some(filter))) => {
+                        self.reply(sender, "undo_filter", &[("filter", &filter.regex.as_str())])
+                    }
+                    Some(Undo::Filter(None)) => self.reply(sender, "undo_no_filter", &[]),
+                    Some(Undo::Topic {
+                        topic,
+                        subscribed: true,
+                    }) => self.reply(sender, "topic_subscribed", &[("topic", &topic)]),
+                    Some(Undo::Topic {
+                        topic,
+                        subscribed: false,
+                    }) => self.reply(sender, "topic_unsubscribed", &[("topic", &topic)]),
+                    Some(Undo::Branches(branches)) if branches.is_empty() => {
+                        self.reply(sender, "all_branches_set", &[])
+                    }
+                    Some(Undo::Branches(branches)) => {
+                        let branches = format_code_list(&branches);
+                        self.reply(sender, "branches_set", &[("branches", &branches)])
+                    }
+                    None => return vec![self.reply(sender, "undo_nothing", &[])],
+                };
+                vec![Task::Save, reply]
+            }
+            Command::LanguageStatus => {
+                let language = self
+                    .state
+                    .find_user(&sender)
+                    .and_then(User::language)
+                    .unwrap_or(DEFAULT_LANGUAGE)
+                    .to_string();
+                let languages = format_code_list(&self.formatter.languages());
+                vec![self.reply(
+                    sender,
+                    "language_status",
+                    &[("language", &language), ("languages", &languages)],
+                )]
+            }
+            Command::SetLanguage(language) => {
+                let languages = self.formatter.languages();
+                if !languages.contains(&language) {
+                    let languages = format_code_list(&languages);
+                    return vec![self.reply(
+                        sender,
+                        "language_unknown",
+                        &[("language", &language), ("languages", &languages)],
+                    )];
+                }
+                let stored = Some(language.clone()).filter(|l| l != DEFAULT_LANGUAGE);
+                self.state.set_language(&sender, stored);
+                vec![
+                    Task::Save,
+                    self.reply(sender, "language_set", &[("language", &language)]),
+                ]
+            }
+            Command::FilterStatus => {
+                let filter_mode = self
+                    .state
+                    .find_user(&sender)
+                    .and_then(User::filter)
+                    .map(|f| f.mode)
+                    .unwrap_or_default();
+                let reply = match self.state.get_filter(&sender) {
+                    Some((filter, filter_enabled)) => {
+                        let state = self.text(
+                            &sender,
+                            if filter_enabled {
+                                "state_enabled"
+                            } else {
+                                "state_disabled"
+                            },
+                            &[],
+                        );
+                        let mode = self.text(
+                            &sender,
+                            match filter_mode {
+                                FilterMode::Drop => "filter_dropped",
+                                FilterMode::Keep => "filter_kept",
+                            },
+                            &[],
+                        );
+                        self.reply(
+                            sender,
+                            "filter_status",
+                            &[("filter", &filter), ("state", &state), ("mode", &mode)],
+                        )
+                    }
+                    None => self.reply(sender, "no_filter", &[]),
+                };
+                vec![reply]
+            }
+            Command::FilterAdd(filter) => {
+                let id = match self.state.add_filter(&sender, &filter) {
+                    Ok(()) => "filter_added",
+                    Err(_) => "filter_invalid",
+                };
+                vec![self.reply(sender, id, &[])]
+            }
+            Command::FilterEnable(enable) => {
+                let filter = self
+                    .state
+                    .enable_and_get_filter(&sender, enable)
+                    .map(|filter| filter.to_string());
+                let reply = match filter {
+                    Some(filter) if enable => {
+                        self.reply(sender, "filter_enabled", &[("filter", &filter)])
+                    }
+                    Some(_) => self.reply(sender, "filter_disabled", &[]),
+                    None if enable => self.reply(sender, "filter_enable_missing", &[]),
+                    None => self.reply(sender, "filter_disable_missing", &[]),
+                };
+                vec![Task::Save, reply]
+            }
+            Command::SetFilterMode(mode) => {
+                if self.state.set_filter_mode(&sender, mode) {
+                    let id = match mode {
+                        FilterMode::Drop => "filter_mode_drop",
+                        FilterMode::Keep => "filter_mode_keep",
+                    };
+                    vec![Task::Save, self.reply(sender, id, &[])]
+                } else {
+                    vec![self.reply(sender, "filter_mode_missing", &[])]
+                }
+            }
+            Command::Snooze(duration) => {
+                let timezone = self
+                    .state
+                    .find_user(&sender)
+                    .map(User::timezone)
+                    .unwrap_or_default();
+                let until = duration.end(Utc::now(), &timezone);
+                self.state.snooze(&sender, until);
+                let until = timezone.format_time(until);
+                vec![
+                    Task::Save,
+                    self.reply(sender, "snoozed", &[("until", &until)]),
+                ]
+            }
+            Command::Unsnooze => self.end_snooze(sender, true),
+            Command::QuietHoursStatus => {
+                let user = self.state.find_user(&sender);
+                let reply = match user.and_then(User::quiet_hours) {
+                    Some(quiet_hours) => {
+                        let timezone = user.map(User::timezone).unwrap_or_default();
+                        self.reply(
+                            sender,
+                            "quiet_hours_status",
+                            &[("quiet_hours", quiet_hours), ("timezone", &timezone)],
+                        )
+                    }
+                    None => self.reply(sender, "no_quiet_hours", &[]),
+                };
+                vec![reply]
+            }
+            Command::SetQuietHours(Some(quiet_hours)) => {
+                let timezone = self
+                    .state
+                    .set_quiet_hours(&sender, Some(quiet_hours))
+                    .timezone();
+                vec![
+                    Task::Save,
+                    self.reply(
+                        sender,
+                        "quiet_hours_set",
+                        &[("quiet_hours", &quiet_hours), ("timezone", &timezone)],
+                    ),
+                ]
+            }
+            Command::SetQuietHours(None) => {
+                self.state.set_quiet_hours(&sender, None);
+                let mut tasks = vec![
+                    Task::Save,
+                    self.reply(sender.clone(), "quiet_hours_removed", &[]),
+                ];
+                // deliver the held back notifications right away
+                let released = self.state.release_held_notifications(Utc::now());
+                tasks.extend(
+                    released
+                        .into_iter()
+                        .filter(|(email, _)| *email == sender)
+                        .map(|(email, notifications)| {
+                            self.held_notifications(email, notifications)
+                        }),
+                );
+                tasks
+            }
+            Command::TimezoneStatus => {
+                let timezone = self
+                    .state
+                    .find_user(&sender)
+                    .map(User::timezone)
+                    .unwrap_or_default();
+                vec![self.reply(sender, "timezone_status", &[("timezone", &timezone)])]
+            }
+            Command::SetTimezone(timezone) => {
+                self.state.set_timezone(&sender, timezone);
+                vec![
+                    Task::Save,
+                    self.reply(sender, "timezone_set", &[("timezone", &timezone)]),
+                ]
+            }
+            Command::DigestStatus => {
+                let user = self.state.find_user(&sender);
+                let reply = match user.and_then(User::digest) {
+                    Some(digest) => {
+                        let timezone = user.map(User::timezone).unwrap_or_default();
+                        self.reply(
+                            sender,
+                            "digest_status",
+                            &[
+                                ("time", &digest.time_of_day()),
+                                ("timezone", &timezone),
+                                ("count", &digest.count()),
+                            ],
+                        )
+                    }
+                    None => self.reply(sender, "no_digest", &[]),
+                };
+                vec![reply]
+            }
+            Command::SetDigest(Some(time)) => {
+                self.state.set_digest(&sender, Some(time), Utc::now());
+                let user = self.state.find_user(&sender).expect("user was added");
+                let time_of_day = user.digest().map(Digest::time_of_day).unwrap_or_default();
+                let timezone = user.timezone();
+                vec![
+                    Task::Save,
+                    self.reply(
+                        sender,
+                        "digest_set",
+                        &[("time", &time_of_day), ("timezone", &timezone)],
+                    ),
+                ]
+            }
+            Command::SetDigest(None) => {
+                let previous = self.state.set_digest(&sender, None, Utc::now());
+                let mut tasks = vec![
+                    Task::Save,
+                    self.reply(sender.clone(), "digest_removed", &[]),
+                ];
+                // deliver the collected notifications right away
+                tasks.extend(
+                    previous
+                        .filter(|digest| digest.count() > 0)
+                        .and_then(|digest| self.digest(sender, &digest)),
+                );
+                tasks
+            }
+            Command::BranchStatus => {
+                let reply = match self.state.find_user(&sender).map(User::branches) {
+                    Some(branches) if !branches.is_empty() => {
+                        let branches = format_code_list(branches);
+                        self.reply(sender, "branches_status", &[("branches", &branches)])
+                    }
+                    _ => self.reply(sender, "all_branches", &[]),
+                };
+                vec![reply]
+            }
+            Command::SetBranches(branches) => {
+                let formatted_branches = format_code_list(&branches);
+                let is_empty = branches.is_empty();
+                self.state.set_branches(&sender, branches);
+                let reply = if is_empty {
+                    self.reply(sender, "all_branches_set", &[])
+                } else {
+                    self.reply(sender, "branches_set", &[("branches", &formatted_branches)])
+                };
+                vec![Task::Save, reply]
+            }
+            Command::VotesStatus => {
+                let user = self.state.find_user(&sender);
+                let threshold = user.and_then(User::vote_threshold);
+                let label_votes = user.map(User::label_votes).cloned().unwrap_or_default();
+                if threshold.is_none() && label_votes.is_empty() {
+                    return vec![self.reply(sender, "all_votes", &[])];
+                }
+                let votes: Vec<String> = std::iter::once(self.votes_text(&sender, None, threshold))
+                    .chain(label_votes.iter().map(|(label, policy)| {
+                        self.votes_text(&sender, Some(label), policy.threshold())
+                    }))
+                    .collect();
+                vec![self.reply(sender, "votes_status", &[("votes", &votes.join(", "))])]
+            }
+            Command::SetVoteThreshold(threshold) => {
+                self.state.set_vote_threshold(&sender, threshold);
+                let reply = match threshold {
+                    Some(threshold) => {
+                        self.reply(sender, "votes_set", &[("threshold", &threshold)])
+                    }
+                    None => self.reply(sender, "all_votes_set", &[]),
+                };
+                vec![Task::Save, reply]
+            }
+            Command::SetLabelVotes { label, policy } => {
+                let votes = self.votes_text(&sender, Some(&label), policy.threshold());
+                self.state.set_label_vote_policy(&sender, label, policy);
+                vec![
+                    Task::Save,
+                    self.reply(sender, "label_votes_set", &[("votes", &votes)]),
+                ]
+            }
+            Command::SetFlag(flag, enable) => {
+                self.state.set_flag(&sender, flag, enable);
+                let state = self.text(
+                    &sender,
+                    if enable {
+                        "state_enabled"
+                    } else {
+                        "state_disabled"
+                    },
+                    &[],
+                );
+                vec![
+                    Task::Save,
+                    self.reply(sender, "flag_set", &[("flag", &flag), ("state", &state)]),
+                ]
+            }
+            Command::Review {
+                change_number,
+                label,
+                value,
+                message,
+            } => self.review(sender, change_number, &label, value, message),
+            Command::Reviews => {
+                let emails = self.gerrit_emails(&sender);
+                let query = gerrit::Query::new(format!(
+                    "{} status:open limit:{}",
+                    any_of("owner", &emails),
+                    MAX_LISTED_CHANGES
+                ))
+                .current_patch_set()
+                .with_submit_records();
+                vec![Task::ListChanges(ListChangesTask {
+                    recipient: Recipient::Person(sender.clone()),
+                    email: sender,
+                    query,
+                    list: ChangeList::Reviews,
+                })]
+            }
+            Command::Queue => {
+                let emails = self.gerrit_emails(&sender);
+                let not_owned: Vec<String> = emails
+                    .iter()
+                    .map(|email| format!("-owner:{}", email))
+                    .collect();
+                let query = gerrit::Query::new(format!(
+                    "{} status:open {} limit:{}",
+                    any_of("reviewer", &emails),
+                    not_owned.join(" "),
+                    MAX_LISTED_CHANGES
+                ))
+                .current_patch_set()
+                .with_submit_records();
+                vec![Task::ListChanges(ListChangesTask {
+                    recipient: Recipient::Person(sender.clone()),
+                    email: sender,
+                    query,
+                    list: ChangeList::Queue,
+                })]
+            }
+            Command::Show(change_number) => {
+                let query = gerrit::Query::change(change_number)
+                    .current_patch_set()
+                    .with_submit_records()
+                    .with_all_reviewers();
+                vec![Task::ListChanges(ListChangesTask {
+                    recipient: Recipient::Person(sender.clone()),
+                    email: sender,
+                    query,
+                    list: ChangeList::Details(change_number),
+                })]
+            }
+            Command::Comment {
+                change_number,
+                message,
+            } => {
+                if !self.has_permission(&sender, Permission::Review) {
+                    return self.permission_denied(
+                        sender,
+                        "denied_review",
+                        &[("permission", &Permission::Review)],
+                    );
+                }
+                let message = markdown_to_plain_text(&message);
+                let quote: Vec<String> =
+                    message.lines().map(|line| format!("> {}", line)).collect();
+                let review = gerrit::Review::new()
+                    .message(format!("{}\n\nCommented from chat by {}.", message, sender));
+                let confirmation = self.text(
+                    &sender,
+                    "commented",
+                    &[("change", &change_number), ("quote", &quote.join("\n"))],
+                );
+                vec![Task::Review(ReviewTask {
+                    recipient: Recipient::Person(sender.clone()),
+                    email: sender,
+                    change_number,
+                    review,
+                    confirmation,
+                })]
+            }
+            Command::Abandon {
+                change_number,
+                reason,
+            } => {
+                let signature = format!("Abandoned from chat by {}.", sender);
+                let message = match reason {
+                    Some(reason) => format!("{}\n\n{}", markdown_to_plain_text(&reason), signature),
+                    None => signature,
+                };
+                let confirmation = self.text(&sender, "abandoned", &[("change", &change_number)]);
+                self.manage_change(
+                    sender,
+                    change_number,
+                    gerrit::Review::new().abandon().message(message),
+                    confirmation,
+                )
+            }
+            Command::Restore { change_number } => {
+                let message = format!("Restored from chat by {}.", sender);
+                let confirmation = self.text(&sender, "restored", &[("change", &change_number)]);
+                self.manage_change(
+                    sender,
+                    change_number,
+                    gerrit::Review::new().restore().message(message),
+                    confirmation,
+                )
+            }
+            Command::SetPermission {
+                email,
+                permission,
+                granted,
+            } => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_permissions", &[]);
+                }
+                let changed = self.state.set_permission(&email, permission, granted);
+                let id = match (granted, changed) {
+                    (true, true) => "permission_granted",
+                    (true, false) => "permission_already_granted",
+                    (false, true) => "permission_revoked",
+                    (false, false) => "permission_not_granted",
+                };
+                let reply = self.reply(
+                    sender,
+                    id,
+                    &[("email", &email), ("permission", &permission)],
+                );
+                if changed {
+                    vec![Task::Save, reply]
+                } else {
+                    vec![reply]
+                }
+            }
+            Command::SetRole { email, role } => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_roles", &[]);
+                }
+                if role.is_user() && self.admins.contains(&email) {
+                    return vec![self.reply(sender, "admin_in_config", &[("email", &email)])];
+                }
+                let changed = self.state.set_role(&email, role);
+                let id = match (role, changed) {
+                    (Role::Admin, true) => "admin_granted",
+                    (Role::Admin, false) => "admin_already",
+                    (Role::User, true) => "admin_revoked",
+                    (Role::User, false) => "admin_not",
+                };
+                let reply = self.reply(sender, id, &[("email", &email)]);
+                if changed {
+                    vec![Task::Save, reply]
+                } else {
+                    vec![reply]
+                }
+            }
+            Command::TopicStatus => {
+                let topics: Vec<&str> = self
+                    .state
+                    .find_user(&sender)
+                    .map(|user| user.topics().collect())
+                    .unwrap_or_default();
+                let reply = if topics.is_empty() {
+                    self.reply(sender, "no_topics", &[])
+                } else {
+                    let topics = format_code_list(&topics);
+                    self.reply(sender, "topics_status", &[("topics", &topics)])
+                };
+                vec![reply]
+            }
+            Command::SubscribeTopic(topic) => {
+                if self.state.subscribe_topic(&sender, &topic) {
+                    vec![
+                        Task::Save,
+                        self.reply(sender, "topic_subscribed", &[("topic", &topic)]),
+                    ]
+                } else {
+                    vec![self.reply(sender, "topic_already_subscribed", &[("topic", &topic)])]
+                }
+            }
+            Command::UnsubscribeTopic(topic) => {
+                if self.state.unsubscribe_topic(&sender, &topic) {
+                    vec![
+                        Task::Save,
+                        self.reply(sender, "topic_unsubscribed", &[("topic", &topic)]),
+                    ]
+                } else {
+                    vec![self.reply(sender, "topic_not_subscribed", &[("topic", &topic)])]
+                }
+            }
+            Command::LinkGerrit(gerrit_email) => {
+                let owner = self.state.find_user(&gerrit_email).map(User::email);
+                if owner == Some(&*sender) {
+                    return vec![self.reply(sender, "link_already", &[("email", &gerrit_email)])];
+                }
+                if owner.is_some() {
+                    return vec![self.reply(sender, "link_taken", &[("email", &gerrit_email)])];
+                }
+                if self.verify_account_links && !self.is_admin(&sender) {
+                    self.state.request_gerrit_link(&sender, &gerrit_email);
+                    let mut tasks = vec![
+                        Task::Save,
+                        self.reply(sender.clone(), "link_pending", &[("email", &gerrit_email)]),
+                    ];
+                    tasks.extend(self.admin_emails().into_iter().map(|admin| {
+                        self.reply(
+                            admin,
+                            "link_requested",
+                            &[("user", &sender), ("email", &gerrit_email)],
+                        )
+                    }));
+                    return tasks;
+                }
+                self.state.link_gerrit_email(&sender, &gerrit_email);
+                vec![
+                    Task::Save,
+                    self.reply(sender, "linked", &[("email", &gerrit_email)]),
+                ]
+            }
+            Command::UnlinkGerrit(gerrit_email) => {
+                if self.state.unlink_gerrit_email(&sender, &gerrit_email) {
+                    vec![
+                        Task::Save,
+                        self.reply(sender, "unlinked", &[("email", &gerrit_email)]),
+                    ]
+                } else {
+                    vec![self.reply(sender, "not_linked", &[("email", &gerrit_email)])]
+                }
+            }
+            Command::ApproveLink(email) => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_links", &[]);
+                }
+                match self.state.approve_gerrit_link(&email) {
+                    Some(gerrit_email) => vec![
+                        Task::Save,
+                        self.reply(
+                            sender,
+                            "link_approved",
+                            &[("user", &email), ("email", &gerrit_email)],
+                        ),
+                        self.reply(email.clone(), "linked", &[("email", &gerrit_email)]),
+                    ],
+                    None => vec![self.reply(sender, "no_pending_link", &[("user", &email)])],
+                }
+            }
+            Command::SubscribeRoom(project) => {
+                self.manage_room_subscription(sender, group_room_id, &project, true)
+            }
+            Command::UnsubscribeRoom(project) => {
+                self.manage_room_subscription(sender, group_room_id, &project, false)
+            }
+            Command::AdminStats => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_stats", &[]);
+                }
+                self.admin_stats(Utc::now())
+                    .map(|stats| Task::Reply(Response::new(sender, stats)))
+                    .into_iter()
+                    .collect()
+            }
+            Command::ExportState(path) => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_export_import", &[]);
+                }
+                let name = path.unwrap_or_else(|| {
+                    format!("state-export-{}.json", Utc::now().format("%Y%m%d-%H%M%S"))
+                });
+                let path = match self.export_path(&sender, &name) {
+                    Ok(path) => path,
+                    Err(reply) => return reply,
+                };
+                let users = self.state.num_users();
+                vec![
+                    Task::Export(path),
+                    self.reply(
+                        sender,
+                        "state_exported",
+                        &[("path", &name), ("users", &users)],
+                    ),
+                ]
+            }
+            Command::ImportState { path, confirmed } => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_export_import", &[]);
+                }
+                let file = match self.export_path(&sender, &path) {
+                    Ok(file) => file,
+                    Err(reply) => return reply,
+                };
+                let state = State::load(&file)
+                    .map_err(|e| format!("{:?}", e))
+                    .and_then(|state| state.validate().map(|()| state));
+                let state = match state {
+                    Ok(state) => state,
+                    Err(error) => {
+                        return vec![self.reply(
+                            sender,
+                            "state_import_invalid",
+                            &[("path", &path), ("error", &error)],
+                        )]
+                    }
+                };
+                let users = state.num_users();
+                let teams = state.teams().count();
+                if !confirmed {
+                    return vec![self.reply(
+                        sender,
+                        "state_import_confirm",
+                        &[("path", &path), ("users", &users), ("teams", &teams)],
+                    )];
+                }
+                self.state = state;
+                vec![
+                    Task::Save,
+                    self.reply(
+                        sender,
+                        "state_imported",
+                        &[("path", &path), ("users", &users)],
+                    ),
+                ]
+            }
+            Command::ReloadState => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_state_file", &[]);
+                }
+                let state = State::load(STATE_FILENAME)
+                    .map_err(|e| format!("{:?}", e))
+                    .and_then(|state| state.validate().map(|()| state));
+                match state {
+                    Ok(state) => {
+                        self.state = state;
+                        let users = self.state.num_users();
+                        vec![
+                            Task::Overwrite,
+                            self.reply(
+                                sender,
+                                "state_reloaded",
+                                &[("path", &STATE_FILENAME), ("users", &users)],
+                            ),
+                        ]
+                    }
+                    Err(error) => vec![self.reply(
+                        sender,
+                        "state_import_invalid",
+                        &[("path", &STATE_FILENAME), ("error", &error)],
+                    )],
+                }
+            }
+            Command::OverwriteState => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_state_file", &[]);
+                }
+                let users = self.state.num_users();
+                vec![
+                    Task::Overwrite,
+                    self.reply(
+                        sender,
+                        "state_overwritten",
+                        &[("path", &STATE_FILENAME), ("users", &users)],
+                    ),
+                ]
+            }
+            Command::PruneUsers { months, confirmed } => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_prune", &[]);
+                }
+                let before = Utc::now() - chrono::Duration::days(30 * i64::from(months));
+                if !confirmed {
+                    let emails: Vec<String> = self
+                        .state
+                        .inactive_users(before)
+                        .map(|user| user.email().to_string())
+                        .collect();
+                    if emails.is_empty() {
+                        return vec![self.reply(sender, "prune_nothing", &[("months", &months)])];
+                    }
+                    let users = emails.len();
+                    let emails = emails.join(", ");
+                    return vec![self.reply(
+                        sender,
+                        "prune_confirm",
+                        &[("users", &users), ("months", &months), ("emails", &emails)],
+                    )];
+                }
+                let users = self.state.remove_inactive_users(before).len();
+                if users == 0 {
+                    return vec![self.reply(sender, "prune_nothing", &[("months", &months)])];
+                }
+                vec![
+                    Task::Save,
+                    self.reply(sender, "pruned", &[("users", &users), ("months", &months)]),
+                ]
+            }
+            Command::RestoreState(backup) => {
+                if !self.is_admin(&sender) {
+                    return self.permission_denied(sender, "denied_restore", &[]);
+                }
+                if backup == 0 || backup > self.state_backups {
+                    let backups = self.state_backups;
+                    return vec![self.reply(
+                        sender,
+                        "restore_unknown",
+                        &[("backup", &backup), ("backups", &backups)],
+                    )];
+                }
+                match State::load(state::backup_filename(Path::new(STATE_FILENAME), backup)) {
+                    Ok(state) => {
+                        self.state = state;
+                        let users = self.state.num_users();
+                        vec![
+                            Task::Save,
+                            self.reply(
+                                sender,
+                                "restored",
+                                &[("backup", &backup), ("users", &users)],
+                            ),
+                        ]
+                    }
+                    Err(e) => {
+                        error!("failed to restore backup {}: {:?}", backup, e);
+                        vec![self.reply(sender, "restore_failed", &[("backup", &backup)])]
+                    }
+                }
+            }
+            command @ (Command::Teams
+            | Command::TeamStatus(_)
+            | Command::SetTeamMembers { .. }
+            | Command::SetTeamSubscription { .. }
+            | Command::DeleteTeam(_)) => self.manage_team(sender, command),
+        }
+    }
+
+    /// Notification about a made-up review of a change of the user, passed
+    /// through the flags and the filter of the user like a real one.
+    fn test_notification(&self, sender: spark::Email) -> Vec<Task> {
+        let event = sample::comment_added_event(self.gerrit_email(&sender), Utc::now());
+        let user = match self.state.find_user(&sender) {
+            Some(user) if self.has_any_flag(user, REVIEW_COMMENT_FLAGS, &event.change) => user,
+            _ => return vec![self.reply(sender, "test_not_notified", &[])],
+        };
+        match self.format_change_message(user, &event.change, &event) {
+            Some(message) => {
+                let card = self.format_card(&event);
+                vec![Task::Reply(Response::notification(sender, message, card))]
+            }
+            None => vec![self.reply(sender, "test_filtered", &[])],
+        }
+    }
+
+    /// Team commands, only for admins.
+    fn manage_team(&mut self, sender: spark::Email, command: Command) -> Vec<Task> {
+        if !self.is_admin(&sender) {
+            return self.permission_denied(sender, "denied_teams", &[]);
+        }
+        let (changed, reply) = match command {
+            Command::Teams => {
+                let teams: Vec<&str> = self.state.teams().map(|team| team.name()).collect();
+                let reply = if teams.is_empty() {
+                    self.reply(sender, "no_teams", &[])
+                } else {
+                    let teams = format_code_list(&teams);
+                    self.reply(sender, "teams_status", &[("teams", &teams)])
+                };
+                (false, reply)
+            }
+            Command::TeamStatus(team) => {
+                let reply = match self.state.find_team(&team) {
+                    Some(team) => {
+                        let none = self.text(&sender, "none", &[]);
+                        let members: Vec<String> =
+                            team.members().map(ToString::to_string).collect();
+                        let members = if members.is_empty() {
+                            none.clone()
+                        } else {
+                            members.join(", ")
+                        };
+                        let subscriptions: Vec<String> =
+                            team.subscriptions().map(ToString::to_string).collect();
+                        let subscriptions = if subscriptions.is_empty() {
+                            none
+                        } else {
+                            subscriptions.join(", ")
+                        };
+                        self.reply(
+                            sender.clone(),
+                            "team_status",
+                            &[
+                                ("team", &team.name()),
+                                ("members", &members),
+                                ("subscriptions", &subscriptions),
+                            ],
+                        )
+                    }
+                    None => self.reply(sender, "unknown_team", &[("team", &team)]),
+                };
+                (false, reply)
+            }
+            Command::SetTeamMembers {
+                team,
+                emails,
+                added: true,
+            } => {
+                let count = self.state.add_team_members(&team, &emails);
+                let reply = self.reply(
+                    sender,
+                    "team_members_added",
+                    &[("team", &team), ("count", &count)],
+                );
+                (count > 0, reply)
+            }
+            Command::SetTeamMembers {
+                team,
+                emails,
+                added: false,
+            } => match self.state.remove_team_members(&team, &emails) {
+                Some(count) => {
+                    let reply = self.reply(
+                        sender,
+                        "team_members_removed",
+                        &[("team", &team), ("count", &count)],
+                    );
+                    (count > 0, reply)
+                }
+                None => (
+                    false,
+                    self.reply(sender, "unknown_team", &[("team", &team)]),
+                ),
+            },
+            Command::SetTeamSubscription {
+                team,
+                subscription,
+                subscribed,
+            } => {
+                let changed = if subscribed {
+                    self.state.subscribe_team(&team, subscription.clone())
+                } else {
+                    self.state.unsubscribe_team(&team, &subscription)
+                };
+                let id = match (subscribed, changed) {
+                    (_, None) => "unknown_team",
+                    (true, Some(true)) => "team_subscribed",
+                    (true, Some(false)) => "team_already_subscribed",
+                    (false, Some(true)) => "team_unsubscribed",
+                    (false, Some(false)) => "team_not_subscribed",
+                };
+                let reply = self.reply(
+                    sender,
+                    id,
+                    &[("team", &team), ("subscription", &subscription)],
+                );
+                (changed == Some(true), reply)
+            }
+            Command::DeleteTeam(team) => {
+                let deleted = self.state.delete_team(&team);
+                let id = if deleted {
+                    "team_deleted"
+                } else {
+                    "unknown_team"
+                };
+                (deleted, self.reply(sender, id, &[("team", &team)]))
+            }
+            _ => return Vec::new(),
+        };
+        if changed {
+            vec![Task::Save, reply]
+        } else {
+            vec![reply]
+        }
+    }
+
+    /// Vote on a change on behalf of the user, if the label and value are
+    /// allowed.
+    fn review(
+        &self,
+        sender: spark::Email,
+        change_number: u32,
+        label: &str,
+        value: i32,
+        message: Option<String>,
+    ) -> Vec<Task> {
+        if self.review_labels.is_empty() {
+            return vec![self.reply(sender, "voting_disabled", &[])];
+        }
+        if !self.has_permission(&sender, Permission::Review) {
+            return self.permission_denied(
+                sender,
+                "denied_review",
+                &[("permission", &Permission::Review)],
+            );
+        }
+        let allowed_label = self
+            .review_labels
+            .iter()
+            .find(|(allowed_label, _)| allowed_label.eq_ignore_ascii_case(label))
+            .filter(|(_, allowed_values)| allowed_values.contains(&value))
+            .map(|(allowed_label, _)| allowed_label);
+        let label = match allowed_label {
+            Some(label) => label,
+            None => {
+                let allowed: Vec<String> = self
+                    .review_labels
+                    .iter()
+                    .flat_map(|(label, values)| {
+                        values
+                            .iter()
+                            .map(move |value| format!("{}{:+}", label, value))
+                    })
+                    .collect();
+                let vote = format!("{}{:+}", label, value);
+                let allowed = format_code_list(&allowed);
+                return vec![self.reply(
+                    sender,
+                    "vote_not_allowed",
+                    &[("vote", &vote), ("allowed", &allowed)],
+                )];
+            }
+        };
+
+        let signature = format!("Voted from chat by {}.", sender);
+        let vote = format!("{}{:+}", label, value);
+        let confirmation = self.text(
+            &sender,
+            "voted",
+            &[("vote", &vote), ("change", &change_number)],
+        );
+        let review = gerrit::Review::new()
+            .label(label.as_str(), value)
+            .message(match message {
+                Some(message) => format!("{}\n\n{}", message, signature),
+                None => signature,
+            });
+        vec![Task::Review(ReviewTask {
+            recipient: Recipient::Person(sender.clone()),
+            email: sender,
+            change_number,
+            review,
+            confirmation,
+        })]
+    }
+
+    /// Admins are listed in the configuration, or were made admins by
+    /// another admin.
+    fn is_admin(&self, email: &spark::EmailRef) -> bool {
+        self.admins.iter().any(|admin| admin == email)
+            || self
+                .state
+                .find_user(email)
+                .is_some_and(|user| user.role() == Role::Admin)
+    }
+
+    /// Email of the user in Gerrit, which may be a linked one.
+    fn gerrit_email<'a>(&'a self, email: &'a spark::EmailRef) -> &'a spark::EmailRef {
+        self.state
+            .find_user(email)
+            .map_or(email, User::gerrit_email)
+    }
+
+    /// Emails of the user in Gerrit: the linked ones, or the one of the
+    /// Webex account.
+    fn gerrit_emails<'a>(&'a self, email: &'a spark::EmailRef) -> Vec<&'a spark::EmailRef> {
+        let linked: Vec<_> = self
+            .state
+            .find_user(email)
+            .into_iter()
+            .flat_map(User::gerrit_emails)
+            .collect();
+        if linked.is_empty() {
+            vec![email]
+        } else {
+            linked
+        }
+    }
+
+    /// Admins from the configuration, and the users made admins.
+    fn admin_emails(&self) -> Vec<spark::Email> {
+        let mut admins = self.admins.clone();
+        admins.extend(
+            self.state
+                .users()
+                .filter(|user| user.role() == Role::Admin)
+                .map(|user| user.email().to_owned())
+                .filter(|email| !self.admins.contains(email)),
+        );
+        admins
+    }
+
+    /// File in the export directory with the name given by an admin. Only
+    /// bare file names are accepted, so admins can't read or write other
+    /// files on the host.
+    fn export_path(&self, sender: &spark::EmailRef, name: &str) -> Result<PathBuf, Vec<Task>> {
+        let dir = self
+            .state_export_dir
+            .as_ref()
+            .ok_or_else(|| vec![self.reply(sender.to_owned(), "state_export_disabled", &[])])?;
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+            return Err(vec![self.reply(
+                sender.to_owned(),
+                "state_export_invalid_name",
+                &[("path", &name)],
+            )]);
+        }
+        Ok(dir.join(name))
+    }
+
+    /// Admins have all permissions.
+    fn has_permission(&self, email: &spark::EmailRef, permission: Permission) -> bool {
+        self.is_admin(email)
+            || self
+                .state
+                .find_user(email)
+                .is_some_and(|user| user.has_permission(permission))
+    }
+
+    /// Abandon or restore a change on behalf of the user, if they have the
+    /// permission to.
+    fn manage_change(
+        &self,
+        sender: spark::Email,
+        change_number: u32,
+        review: gerrit::Review,
+        confirmation: String,
+    ) -> Vec<Task> {
+        if !self.has_permission(&sender, Permission::ManageChanges) {
+            return self.permission_denied(
+                sender,
+                "denied_manage_changes",
+                &[("permission", &Permission::ManageChanges)],
+            );
+        }
+        vec![Task::Review(ReviewTask {
+            recipient: Recipient::Person(sender.clone()),
+            email: sender,
+            change_number,
+            review,
+            confirmation,
+        })]
+    }
+
+    /// Subscribe or unsubscribe the group room the command was sent in. Only
+    /// admins are allowed to do this.
+    fn manage_room_subscription(
+        &mut self,
+        sender: spark::Email,
+        room_id: Option<&spark::RoomId>,
+        project: &str,
+        subscribe: bool,
+    ) -> Vec<Task> {
+        let room_id = match room_id {
+            Some(room_id) => room_id,
+            None => {
+                return vec![self.reply(sender, "rooms_only_group", &[])];
+            }
+        };
+        if !self.is_admin(&sender) {
+            return self.permission_denied(sender, "denied_rooms", &[]);
+        }
+
+        let changed = if subscribe {
+            self.state.subscribe_room(room_id, project)
+        } else {
+            self.state.unsubscribe_room(room_id, project)
+        };
+        let id = match (subscribe, changed) {
+            (true, true) => "room_subscribed",
+            (true, false) => "room_already_subscribed",
+            (false, true) => "room_unsubscribed",
+            (false, false) => "room_not_subscribed",
+        };
+
+        let reply = self.reply(sender, id, &[("project", &project)]);
+        if changed {
+            vec![Task::Save, reply]
+        } else {
+            vec![reply]
+        }
+    }
+
+    fn handle_task(&mut self, task: Task) -> Option<Response> {
+        debug!("New task {:#?}", task);
+        match task {
+            Task::Reply(response) => Some(response),
+            Task::Export(path) => {
+                match &self.state_writer {
+                    Some(state_writer) => state_writer.export(self.state.clone(), path),
+                    None => {
+                        if let Err(err) = self.state.save(&path) {
+                            error!("Could not export state to {}: {:?}", path.display(), err);
+                        }
+                    }
+                }
+                None
+            }
+            Task::Save | Task::Overwrite => {
+                match &self.state_writer {
+                    Some(state_writer) if matches!(task, Task::Overwrite) => {
+                        state_writer.overwrite(self.state.clone())
+                    }
+                    Some(state_writer) => state_writer.save(self.state.clone()),
+                    None => {
+                        if let Err(err) = self.save(STATE_FILENAME) {
+                            error!("Could not save state: {:?}", err);
+                        }
+                    }
+                }
+                None
+            }
+            // handled in `run`, since they need to wait for a lookup or a
+            // Gerrit command
+            Task::VerifyUser(_)
+            | Task::Review(_)
+            | Task::ListChanges(_)
+            | Task::CheckMergeConflicts(_)
+            | Task::CheckStaleReviews(_) => None,
+        }
+    }
+
+    /// Check the result of looking up a user that enabled notifications in
+    /// Webex. Notifications are sent to the email of the Gerrit account, so
+    /// users are warned if it doesn't belong to their Webex account.
+    fn verify_user(
+        &mut self,
+        email: spark::Email,
+        person: Option<spark::PersonDetails>,
+    ) -> Vec<Task> {
+        let person = match person {
+            Some(person) => person,
+            None => {
+                return vec![self.reply(
+                    email.clone(),
+                    "webex_account_missing",
+                    &[("email", &email)],
+                )];
+            }
+        };
+
+        let mut tasks = Vec::new();
+        if !person.emails.contains(&email) {
+            let webex_email = person
+                .emails
+                .first()
+                .map(|webex_email| webex_email.to_string())
+                .unwrap_or_default();
+            tasks.push(self.reply(
+                email.clone(),
+                "webex_email_mismatch",
+                &[("webex_email", &webex_email), ("email", &email)],
+            ));
+        }
+        if self.state.set_person_id(&email, person.id) {
+            tasks.push(Task::Save);
+        }
+        tasks
+    }
+
+    /// Default flags of the project of the change that apply to the user.
+    fn project_flags(&self, user: &User, change: &gerrit::Change) -> Vec<UserFlag> {
+        if !user.has_default_flags() {
+            return Vec::new();
+        }
+        self.project_flags
+            .iter()
+            .filter(|project_flags| project_flags.matches(change))
+            .flat_map(|project_flags| project_flags.flags())
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the user has the flag, or it is a default flag of the project
+    /// of the change.
+    fn has_flag(&self, user: &User, flag: UserFlag, change: &gerrit::Change) -> bool {
+        self.has_any_flag(user, &[flag], change)
+    }
+
+    fn has_any_flag(&self, user: &User, flags: &[UserFlag], change: &gerrit::Change) -> bool {
+        user.has_any_flag(flags)
+            || self
+                .project_flags(user, change)
+                .iter()
+                .any(|flag| flags.contains(flag))
+    }
+
+    /// Format a message about the change, with the default flags of its
+    /// project.
+    fn format_change_message<I: format::MessageInput>(
+        &self,
+        user: &User,
+        change: &gerrit::Change,
+        input: I,
+    ) -> Option<format::Message> {
+        self.formatter
+            .format_notification(Some(user), &self.project_flags(user, change), input)
+            .map_err(|e| error!("message formatting failed: {}", e))
+            .ok()
+            .and_then(identity)
+            .filter(|message| !self.state.is_filtered(user, message))
+    }
+
+    /// Return iterator of users which might be interested in an event.
+    fn interested_users<'bot, 'event, 'result>(
+        &'bot self,
+        change: &'event gerrit::Change,
+        patchset: Option<&'event gerrit::Patchset>,
+    ) -> impl Iterator<Item = &'bot User> + 'result
+    where
+        'bot: 'result,
+        'event: 'result,
+    {
+        // Users that approved the patchset in question, the owner and
+        // participants seen by the change tracker are considered interested,
+        // as well as the subscribers of the topic of the change and the
+        // members of subscribed teams. The other
+        // reviewers of the change (if they were fetched with
+        // `ExtendedInfo::AllReviewers`) are CC'd, and only interested with the
+        // `notify_cc` flag.
+        let participants: Vec<&spark::EmailRef> = patchset
+            .into_iter()
+            .flat_map(|patchset| patchset.approvals.iter().flatten())
+            .filter_map(|approval| approval.by.as_ref())
+            .chain(std::iter::once(&change.owner))
+            .filter(|user| user.is_human())
+            .filter_map(|user| user.spark_email())
+            .chain(self.change_tracker.participants(change))
+            .collect();
+        let cc = change
+            .all_reviewers
+            .iter()
+            .flatten()
+            .filter(|user| user.is_human())
+            .filter_map(|user| user.spark_email())
+            .filter(|email| !participants.contains(email))
+            .filter_map(|email| self.state.find_user(email))
+            .filter(|user| self.has_flag(user, UserFlag::NotifyCc, change));
+        let mut seen = HashSet::new();
+        let users: Vec<&User> = participants
+            .iter()
+            .filter_map(|email| self.state.find_user(*email))
+            .chain(cc)
+            .chain(self.subscribers(change))
+            // users with linked Gerrit emails may be found more than once
+            .filter(|user| seen.insert(user.email()))
+            .filter(|user| user.is_interested_in_branch(&change.branch))
+            .collect();
+        users.into_iter()
+    }
+
+    fn get_comment_response_messages(
+        &self,
+        event: Box<gerrit::CommentAddedEvent>,
+    ) -> Vec<(spark::Email, format::Message)> {
+        self.interested_users(&event.change, Some(&event.patchset))
+            .filter(|user| !is_user(user, &event.author))
+            .filter(|user| self.has_flag(user, UserFlag::NotifyReviewResponses, &event.change))
+            .filter_map(|user| {
+                self.format_change_message(user, &event.change, &*event)
+                    .map(|message| (user.email().to_owned(), message))
+            })
+            .collect()
+    }
+
+    fn get_approvals_msg(
+        &mut self,
+        event: Box<gerrit::CommentAddedEvent>,
+    ) -> Option<(spark::Email, format::Message)> {
+        let approvals = event.approvals.as_deref().unwrap_or(&[][..]);
+        let owner_email = event.change.owner.spark_email()?;
+
+        // try to find the user and check it is enabled
+        let user = self
+            .state
+            .find_user(owner_email)
+            .filter(|user| self.has_any_flag(user, REVIEW_COMMENT_FLAGS, &event.change))
+            .filter(|user| user.is_interested_in_branch(&event.change.branch))
+            .filter(|user| user.is_interested_in_approvals(approvals))?;
+
+        // filter all messages that were already sent to the user recently
+        if !approvals.is_empty() && self.rate_limiter.limit(user, &*event) {
+            debug!("Filtered approval due to cache hit.");
+            return None;
+        }
+
+        self.format_change_message(user, &event.change, &*event)
+            .map(|m| (user.email().to_owned(), m))
+    }
+
+    fn get_comment_messages(
+        &mut self,
+        event: Box<gerrit::CommentAddedEvent>,
+    ) -> Vec<(spark::Email, format::Message)> {
+        debug!("Incoming approvals: {:#?}", event);
+        let owner_email = event.change.owner.spark_email();
+        let approver_email = event.author.spark_email();
+
+        let subscriber_messages = self.get_subscriber_comment_messages(&event);
+        let uploader_message = self.get_uploader_msg(&event);
+        let mut messages: Vec<_> = if owner_email == approver_email {
+            self.get_comment_response_messages(event)
+        } else {
+            self.get_approvals_msg(event).into_iter().collect()
+        };
+        for (email, message) in uploader_message.into_iter().chain(subscriber_messages) {
+            if !messages.iter().any(|(recipient, _)| *recipient == email) {
+                messages.push((email, message));
+            }
+        }
+        messages
+    }
+
+    /// Reviews of a patchset for its uploader, if it isn't the owner of the
+    /// change.
+    fn get_uploader_msg(
+        &mut self,
+        event: &gerrit::CommentAddedEvent,
+    ) -> Option<(spark::Email, format::Message)> {
+        let uploader = &event.patchset.uploader;
+        if !uploader.is_human()
+            || uploader.is_same_user(&event.change.owner)
+            || uploader.is_same_user(&event.author)
+        {
+            return None;
+        }
+
+        let user = self
+            .state
+            .find_user(uploader.spark_email()?)
+            .filter(|user| self.has_flag(user, UserFlag::NotifyUploadedByMe, &event.change))
+            .filter(|user| user.is_interested_in_branch(&event.change.branch))
+            .filter(|user| {
+                user.is_interested_in_approvals(event.approvals.as_deref().unwrap_or_default())
+            })?;
+
+        // filter all messages that were already sent to the user recently
+        if event.approvals.as_ref().is_some_and(|a| !a.is_empty())
+            && self.rate_limiter.limit(user, event)
+        {
+            debug!("Filtered approval for the uploader due to cache hit.");
+            return None;
+        }
+
+        self.format_change_message(user, &event.change, event)
+            .map(|message| (user.email().to_owned(), message))
+    }
+
+    /// Subscribers of the topic of the change, and the members of the teams
+    /// subscribed to the change.
+    fn subscribers(&self, change: &gerrit::Change) -> Vec<&User> {
+        let mut seen = HashSet::new();
+        change
+            .topic
+            .iter()
+            .flat_map(|topic| self.state.topic_subscribers(topic))
+            .chain(self.state.team_subscribers(change))
+            .filter_map(|user| self.state.find_user(user.email()))
+            .filter(|user| seen.insert(user.email()))
+            .collect()
+    }
+
+    /// Comments on changes for the subscribers of the topic and the
+    /// subscribed teams.
+    fn get_subscriber_comment_messages(
+        &self,
+        event: &gerrit::CommentAddedEvent,
+    ) -> Vec<(spark::Email, format::Message)> {
+        self.subscribers(&event.change)
+            .into_iter()
+            .filter(|user| !is_user(user, &event.author))
+            .filter(|user| self.has_any_flag(user, NOTIFICATION_FLAGS, &event.change))
+            .filter(|user| user.is_interested_in_branch(&event.change.branch))
+            .filter_map(|user| {
+                self.format_change_message(user, &event.change, event)
+                    .map(|message| (user.email().to_owned(), message))
+            })
+            .collect()
+    }
+
+    /// Query the open changes with merge conflicts of the users who want to
+    /// know, if the last check is long enough ago.
+    fn merge_conflict_check(&mut self, now: DateTime<Utc>) -> Option<Task> {
+        let interval = chrono::Duration::from_std(self.merge_conflict_check_interval?).ok()?;
+        if self
+            .last_merge_conflict_check
+            .is_some_and(|last_check| now < last_check + interval)
+        {
+            return None;
+        }
+        self.last_merge_conflict_check = Some(now);
+
+        let owners: Vec<String> = self
+            .state
+            .users()
+            .filter(|user| user.has_flag(UserFlag::NotifyMergeConflicts))
+            .flat_map(|user| std::iter::once(user.email()).chain(user.gerrit_emails()))
+            .map(|email| format!("owner:{}", email))
+            .collect();
+        if owners.is_empty() {
+            return None;
+        }
+        Some(Task::CheckMergeConflicts(gerrit::Query::new(format!(
+            "status:open -is:mergeable ({})",
+            owners.join(" OR ")
+        ))))
+    }
+
+    /// Save the state regularly, since not all of its changes are saved right
+    /// away. The first save is one interval after the start.
+    fn autosave(&mut self, now: DateTime<Utc>) -> Option<Task> {
+        let interval = chrono::Duration::from_std(self.autosave_interval?).ok()?;
+        match self.last_autosave {
+            Some(last_autosave) if now < last_autosave + interval => None,
+            Some(_) => {
+                self.last_autosave = Some(now);
+                Some(Task::Save)
+            }
+            None => {
+                self.last_autosave = Some(now);
+                None
+            }
+        }
+    }
+
+    /// Tell the admins if the state isn't saved anymore, since the state file
+    /// was modified by someone else.
+    fn state_file_check(&self) -> Vec<Task> {
+        let modified = self
+            .state_writer
+            .as_ref()
+            .is_some_and(StateWriter::take_modified);
+        if !modified {
+            return Vec::new();
+        }
+        self.admin_emails()
+            .into_iter()
+            .map(|admin| self.reply(admin, "state_file_modified", &[("path", &STATE_FILENAME)]))
+            .collect()
+    }
+
+    /// Query the stale changes of the users who want to be reminded, if
+    /// it's time for it.
+    fn stale_review_check(&mut self, now: DateTime<Utc>) -> Option<Task> {
+        let reminder = self.stale_review_reminder.as_mut()?;
+        let owners = self
+            .state
+            .users()
+            .filter(|user| user.has_flag(UserFlag::NotifyStaleReviews))
+            .flat_map(|user| std::iter::once(user.email()).chain(user.gerrit_emails()));
+        reminder.check(now, owners).map(Task::CheckStaleReviews)
+    }
+
+    /// Remind the owners of the stale changes, with a single message each.
+    fn stale_review_reminders(&self, changes: &[gerrit::Change]) -> Vec<Task> {
+        let days = match &self.stale_review_reminder {
+            Some(reminder) => reminder.days(),
+            None => return Vec::new(),
+        };
+        let mut changes_by_owner: BTreeMap<&spark::EmailRef, Vec<&gerrit::Change>> =
+            BTreeMap::new();
+        for change in changes {
+            let user = change
+                .owner
+                .spark_email()
+                .and_then(|email| self.state.find_user(email))
+                .filter(|user| user.has_flag(UserFlag::NotifyStaleReviews))
+                .filter(|user| user.is_interested_in_branch(&change.branch));
+            if let Some(user) = user {
+                changes_by_owner
+                    .entry(user.email())
+                    .or_default()
+                    .push(change);
+            }
+        }
+
+        changes_by_owner
+            .into_iter()
+            .filter_map(|(email, changes)| {
+                let user = self.state.find_user(email)?;
+                self.formatter
+                    .format_message(Some(user), format::StaleReviews { changes, days })
+                    .map_err(|e| error!("message formatting failed: {}", e))
+                    .ok()
+                    .and_then(identity)
+                    .filter(|message| !self.state.is_filtered(user, message))
+                    .map(|message| Task::Reply(Response::new(email.to_owned(), message)))
+            })
+            .collect()
+    }
+
+    /// Tell the owner that the change has a merge conflict.
+    fn get_merge_conflict_msg(
+        &self,
+        change: &gerrit::Change,
+    ) -> Option<(spark::Email, format::Message)> {
+        let user = self
+            .state
+            .find_user(change.owner.spark_email()?)
+            .filter(|user| user.has_flag(UserFlag::NotifyMergeConflicts))
+            .filter(|user| user.is_interested_in_branch(&change.branch))?;
+        self.formatter
+            .format_notification(Some(user), &[], format::MergeConflict { change })
+            .map_err(|e| error!("message formatting failed: {}", e))
+            .ok()
+            .and_then(identity)
+            .filter(|message| !self.state.is_filtered(user, message))
+            .map(|message| (user.email().to_owned(), message))
+    }
+
+    /// Tell the owner that the review made the change submittable.
+    fn get_submittable_msg(
+        &mut self,
+        event: &gerrit::CommentAddedEvent,
+    ) -> Option<(spark::Email, format::Message)> {
+        // only the transition to submittable is of interest; without submit
+        // records, it is unknown
+        let submittable = event.change.is_submittable()?;
+        if !self
+            .change_tracker
+            .set_submittable(&event.change, submittable)
+        {
+            return None;
+        }
+
+        let owner_email = event.change.owner.spark_email()?;
+        let user = self
+            .state
+            .find_user(owner_email)
+            .filter(|user| self.has_flag(user, UserFlag::NotifySubmittable, &event.change))
+            .filter(|user| user.is_interested_in_branch(&event.change.branch))?;
+
+        // filter all messages that were already sent to the user recently
+        if self.rate_limiter.limit_submittable(user, &event.change) {
+            debug!("Filtered submittable due to cache hit.");
+            return None;
+        }
+
+        self.formatter
+            .format_notification(
+                Some(user),
+                &[],
+                format::ChangeSubmittable {
+                    change: &event.change,
+                    reviewer: &event.author,
+                },
+            )
+            .map_err(|e| error!("message formatting failed: {}", e))
+            .ok()
+            .and_then(identity)
+            .filter(|message| !self.state.is_filtered(user, message))
+            .map(|message| (user.email().to_owned(), message))
+    }
+
+    fn get_reviewer_added_msg(
+        &mut self,
+        event: &gerrit::ReviewerAddedEvent,
+    ) -> Option<(&User, format::Message)> {
+        let reviewer_email = spark::EmailRef::new(event.reviewer.email.as_ref()?);
+        let user = self
+            .state
+            .find_user(reviewer_email)
+            .filter(|user| self.has_flag(user, UserFlag::NotifyReviewerAdded, &event.change))
+            .filter(|user| user.is_interested_in_branch(&event.change.branch))?;
+
+        // filter all messages that were already sent to the user recently
+        if self.rate_limiter.limit(user, event) {
+            debug!("Filtered reviewer-added due to cache hit.");
+            return None;
+        }
+
+        let message = self
+            .formatter
+            .format_notification(Some(user), &[], event)
+            .map_err(|e| error!("formatting reviewer added failed: {}", e))
+            .ok()??;
+
+        Some((user, message))
+    }
+
+    fn get_change_merged_messages(
+        &mut self,
+        event: &gerrit::ChangeMergedEvent,
+    ) -> Vec<(spark::Email, format::Message)> {
+        self.interested_users(&event.change, Some(&event.patchset))
+            .filter(|user| !is_user(user, &event.submitter))
+            .filter(|user| self.has_flag(user, UserFlag::NotifyChangeMerged, &event.change))
+            .filter_map(|user| {
+                self.formatter
+                    .format_notification(Some(user), &[], event)
+                    .map_err(|e| error!("message formatting failed: {}", e))
+                    .ok()
+                    .and_then(identity)
+                    .filter(|message| !self.state.is_filtered(user, message))
+                    .map(|message| (user.email().to_owned(), message))
+            })
+            .collect()
+    }
+
+    fn get_change_abandoned_messages(
+        &mut self,
+        event: &gerrit::ChangeAbandonedEvent,
+    ) -> Vec<(spark::Email, format::Message)> {
+        self.interested_users(&event.change, Some(&event.patchset))
+            .filter(|user| !is_user(user, &event.abandoner))
+            .filter(|user| self.has_flag(user, UserFlag::NotifyChangeAbandoned, &event.change))
+            .filter_map(|user| {
+                self.formatter
+                    .format_notification(Some(user), &[], event)
+                    .map_err(|e| error!("message formatting failed: {}", e))
+                    .ok()
+                    .and_then(identity)
+                    .filter(|message| !self.state.is_filtered(user, message))
+                    .map(|message| (user.email().to_owned(), message))
+            })
+            .collect()
+    }
+
+    fn get_change_deleted_messages(
+        &mut self,
+        event: &gerrit::ChangeDeletedEvent,
+    ) -> Vec<(spark::Email, format::Message)> {
+        self.interested_users(&event.change, None)
+            .filter(|user| !is_user(user, &event.deleter))
+            .filter(|user| self.has_flag(user, UserFlag::NotifyChangeAbandoned, &event.change))
+            .filter_map(|user| {
+                self.formatter
+                    .format_notification(Some(user), &[], event)
+                    .map_err(|e| error!("message formatting failed: {}", e))
+                    .ok()
+                    .and_then(identity)
+                    .filter(|message| !self.state.is_filtered(user, message))
+                    .map(|message| (user.email().to_owned(), message))
+            })
+            .collect()
+    }
+
+    pub fn save<P>(&self, filename: P) -> Result<(), BotError>
+    where
+        P: AsRef<Path>,
+    {
+        self.state.save_with_backups(filename, self.state_backups)
+    }
+
+    fn admin_stats(&self, now: DateTime<Utc>) -> Option<String> {
+        let flag_counts = NOTIFICATION_FLAGS
+            .iter()
+            .map(|&flag| {
+                let count = self.state.users().filter(|u| u.has_flag(flag)).count();
+                (flag.to_string(), count)
+            })
+            .collect();
+        let stats = format::AdminStats {
+            user_count: self.state.num_users(),
+            enabled_user_count: self
+                .state
+                .users()
+                .filter(|u| u.has_any_flag(NOTIFICATION_FLAGS))
+                .count(),
+            active_user_count: self
+                .state
+                .users()
+                .filter_map(|u| u.activity().last_active())
+                .filter(|&last_active| now - last_active < chrono::Duration::days(ACTIVE_USER_DAYS))
+                .count(),
+            active_days: ACTIVE_USER_DAYS,
+            flag_counts,
+            messages_sent: self.stats.messages_sent_last_day(now),
+            messages_failed: self.stats.failed_messages(),
+            gerrit_events: self.stats.gerrit_events(),
+            gerrit_errors: self.stats.gerrit_errors(),
+            gerrit_connected: self.stats.gerrit_connected(),
+            started: self.stats.started(),
+            last_gerrit_event: self.stats.last_gerrit_event(),
+            last_gerrit_error: self.stats.last_gerrit_error(),
+            now,
+        };
+        self.formatter
+            .format_message(None, stats)
+            .map_err(|e| error!("formatting admin stats failed: {}", e))
+            .ok()?
+    }
+
+    fn status_for(&self, email: &spark::EmailRef) -> Option<String> {
+        let user = self.state.find_user(email);
+        let enabled_user_count = self
+            .state
+            .users()
+            .filter(|u| u.has_any_flag(NOTIFICATION_FLAGS))
+            .count();
+        self.formatter
+            .format_status(user, enabled_user_count)
+            .map_err(|e| error!("formatting status failed: {}", e))
+            .ok()?
+    }
+}
+
+#[derive(Debug)]
+enum Action {
+    RunCommand {
+        sender: spark::Email,
+        /// Room the command was sent in; none for card actions.
+        room: Option<CommandRoom>,
+        command: Command,
+    },
+    UnknownCommand {
+        sender: spark::Email,
+        /// Room the message was sent in.
+        room: Option<CommandRoom>,
+    },
+    /// The bot was added to a room.
+    Greet {
+        room_id: spark::RoomId,
+    },
+    /// Regular check of snoozes that are over.
+    Tick(DateTime<Utc>),
+    /// Open changes with merge conflicts found by a regular check.
+    MergeConflicts(Vec<gerrit::Change>),
+    /// Open changes without activity found by a regular check.
+    StaleReviews(Vec<gerrit::Change>),
+    CommentAdded(Box<gerrit::CommentAddedEvent>),
+    ReviewerAdded(Box<gerrit::ReviewerAddedEvent>),
+    ChangeMerged(Box<gerrit::ChangeMergedEvent>),
+    ChangeAbandoned(Box<gerrit::ChangeAbandonedEvent>),
+    ChangeDeleted(Box<gerrit::ChangeDeletedEvent>),
+}
+
+impl Action {
+    /// Change the underlying Gerrit event is about, if any.
+    fn change(&self) -> Option<&gerrit::Change> {
+        match self {
+            Action::RunCommand { .. }
+            | Action::UnknownCommand { .. }
+            | Action::Greet { .. }
+            | Action::Tick(_)
+            | Action::MergeConflicts(_)
+            | Action::StaleReviews(_) => None,
+            Action::CommentAdded(event) => Some(&event.change),
+            Action::ReviewerAdded(event) => Some(&event.change),
+            Action::ChangeMerged(event) => Some(&event.change),
+            Action::ChangeAbandoned(event) => Some(&event.change),
+            Action::ChangeDeleted(event) => Some(&event.change),
+        }
+    }
+
+    /// The owner of the change and whoever caused the underlying Gerrit
+    /// event.
+    fn involved_users(&self) -> Vec<&gerrit::User> {
+        let actor = match self {
+            Action::RunCommand { .. }
+            | Action::UnknownCommand { .. }
+            | Action::Greet { .. }
+            | Action::Tick(_)
+            | Action::MergeConflicts(_)
+            | Action::StaleReviews(_) => return Vec::new(),
+            Action::CommentAdded(event) => &event.author,
+            Action::ReviewerAdded(event) => &event.reviewer,
+            Action::ChangeMerged(event) => &event.submitter,
+            Action::ChangeAbandoned(event) => &event.abandoner,
+            Action::ChangeDeleted(event) => &event.deleter,
+        };
+        let owner = self.change().map(|change| &change.owner);
+        std::iter::once(actor)
+            .chain(owner.filter(|owner| !owner.is_same_user(actor)))
+            .collect()
+    }
+
+    /// Creation time of the underlying Gerrit event, if any.
+    fn event_created_on(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Action::RunCommand { .. }
+            | Action::UnknownCommand { .. }
+            | Action::Greet { .. }
+            | Action::Tick(_)
+            | Action::MergeConflicts(_)
+            | Action::StaleReviews(_) => None,
+            Action::CommentAdded(event) => Some(event.created_on),
+            Action::ReviewerAdded(event) => Some(event.created_on),
+            Action::ChangeMerged(event) => Some(event.created_on),
+            Action::ChangeAbandoned(event) => Some(event.created_on),
+            Action::ChangeDeleted(event) => Some(event.created_on),
+        }
+    }
+}
+
+/// Strip the common Markdown markup from a chat message, so it reads well as
+/// plain text in Gerrit.
+fn markdown_to_plain_text(text: &str) -> String {
+    lazy_static! {
+        static ref MARKUP: [(Regex, &'static str); 7] = [
+            // headings
+            (Regex::new(r"(?m)^#{1,6}\s+").unwrap(), ""),
+            // links
+            (Regex::new(r"\[([^\]]*)\]\(([^)]*)\)").unwrap(), "$1 ($2)"),
+            (Regex::new(r"`([^`]*)`").unwrap(), "$1"),
+            (Regex::new(r"\*\*(.+?)\*\*|__(.+?)__").unwrap(), "$1$2"),
+            (Regex::new(r"~~(.+?)~~").unwrap(), "$1"),
+            (Regex::new(r"(^|[^\\*])\*([^\s*](?:[^*]*[^\s\\*])?)\*").unwrap(), "$1$2"),
+            // escaped characters
+            (Regex::new(r"\\([\\`*_\[\]~<>#])").unwrap(), "$1"),
+        ];
+    }
+    MARKUP
+        .iter()
+        .fold(text.trim().to_string(), |text, (regex, replacement)| {
+            regex.replace_all(&text, *replacement).into_owned()
+        })
+}
+
+/// List branch patterns or topics as inline code, e.g. `` `master`,
+/// `release/*` ``.
+fn format_code_list<S: AsRef<str>>(items: &[S]) -> String {
+    items
+        .iter()
+        .map(|item| format!("`{}`", item.as_ref()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Gerrit query for changes matching the operator with any of the emails,
+/// e.g. `(owner:a@example.com OR owner:b@example.com)`.
+fn any_of(operator: &str, emails: &[&spark::EmailRef]) -> String {
+    let terms: Vec<String> = emails
+        .iter()
+        .map(|email| format!("{}:{}", operator, email))
+        .collect();
+    match &terms[..] {
+        [term] => term.clone(),
+        terms => format!("({})", terms.join(" OR ")),
+    }
+}
+
+/// Room a command was sent in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CommandRoom {
+    id: spark::RoomId,
+    room_type: spark::RoomType,
+}
+
+impl CommandRoom {
+    /// Commands sent in group rooms are answered in the room, the others
+    /// directly.
+    fn group_room_id(&self) -> Option<&spark::RoomId> {
+        match self.room_type {
+            spark::RoomType::Group => Some(&self.id),
+            spark::RoomType::Direct => None,
+        }
+    }
+}
+
+/// Recipient of a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Recipient {
+    Person(spark::Email),
+    Room(spark::RoomId),
+}
+
+#[derive(Debug)]
+struct Response {
+    pub recipient: Recipient,
+    pub message: String,
+    /// Plain text fallback of the markdown message.
+    pub text: Option<String>,
+    /// Adaptive Card sent along with the message.
+    pub card: Option<serde_json::Value>,
+    /// Change the message is about.
+    pub change_number: Option<u32>,
+    /// Earlier message the message is a reply to.
+    pub parent_id: Option<spark::MessageId>,
+    /// Earlier notification the message is appended to instead of sending a
+    /// new one.
+    pub appended_to: Option<spark::MessageId>,
+}
+
+impl Response {
+    pub fn new<A>(email: spark::Email, message: A) -> Response
+    where
+        A: Into<String>,
+    {
+        Response {
+            recipient: Recipient::Person(email),
+            message: message.into(),
+            text: None,
+            card: None,
+            change_number: None,
+            parent_id: None,
+            appended_to: None,
+        }
+    }
+
+    /// Message posted in a group room.
+    pub fn to_room<A>(room_id: spark::RoomId, message: A) -> Response
+    where
+        A: Into<String>,
+    {
+        Response {
+            recipient: Recipient::Room(room_id),
+            ..Response::new(spark::Email::default(), message)
+        }
+    }
+
+    /// Notification with all parts produced by the format script. A card of
+    /// the message takes precedence over the card of the event.
+    pub fn notification(
+        email: spark::Email,
+        message: format::Message,
+        card: Option<serde_json::Value>,
+    ) -> Response {
+        Response {
+            text: message.text,
+            card: message.card.or(card),
+            ..Response::new(email, message.markdown)
+        }
+    }
+
+    pub fn in_thread(self, change_number: u32, parent_id: Option<spark::MessageId>) -> Response {
+        Response {
+            change_number: Some(change_number),
+            parent_id,
+            ..self
+        }
+    }
+
+    pub fn appended_to(self, message_id: spark::MessageId) -> Response {
+        Response {
+            appended_to: Some(message_id),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Task {
+    Reply(Response),
+    Save,
+    /// Save the state even if the state file was modified by someone else.
+    Overwrite,
+    /// Write the state to the file in the export directory.
+    Export(PathBuf),
+    /// Check that the user exists in Webex.
+    VerifyUser(spark::Email),
+    Review(ReviewTask),
+    ListChanges(ListChangesTask),
+    /// Look for open changes with merge conflicts.
+    CheckMergeConflicts(gerrit::Query),
+    /// Look for open changes without activity.
+    CheckStaleReviews(gerrit::Query),
+}
+
+/// Changes found by a Gerrit query, listed or shown to the user.
+#[derive(Debug)]
+struct ListChangesTask {
+    recipient: Recipient,
+    /// User the list is for.
+    email: spark::Email,
+    query: gerrit::Query,
+    list: ChangeList,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ChangeList {
+    /// Open changes of the user.
+    Reviews,
+    /// Changes waiting for the vote of the user.
+    Queue,
+    /// Details of the change with the number.
+    Details(u32),
+}
+
+/// Pass the changes found by a regular check to the bot.
+fn query_changes<G, S>(
+    bot: std::sync::Arc<std::sync::Mutex<Bot<G, S>>>,
+    query: gerrit::Query,
+    action: fn(Vec<gerrit::Change>) -> Action,
+) -> impl Future<Item = Vec<Response>, Error = ()>
+where
+    G: GerritCommandRunner,
+    S: SparkClient,
+{
+    let query_result = bot.lock().unwrap().gerrit_command_runner.query(&query);
+    query_result.then(move |result| {
+        let changes = match result {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("failed to query {}: {}", query.to_command(), e);
+                return Ok(Vec::new());
+            }
+        };
+        let mut bot = bot.lock().unwrap();
+        let tasks = bot.update(action(changes));
+        Ok(tasks
+            .into_iter()
+            .filter_map(|task| bot.handle_task(task))
+            .collect())
+    })
+}
+
+/// Whether the Gerrit user is the user, or one of its linked Gerrit
+/// accounts.
+fn is_user(user: &User, gerrit_user: &gerrit::User) -> bool {
+    gerrit_user
+        .spark_email()
+        .is_some_and(|email| user.has_email(email))
+}
+
+/// Whether the user voted on the current patch set of the change.
+fn has_voted(change: &gerrit::Change, email: &spark::EmailRef) -> bool {
+    change
+        .current_patch_set
+        .iter()
+        .flat_map(|patchset| patchset.approvals.iter().flatten())
+        .filter(|approval| approval.value.parse::<i32>().unwrap_or(0) != 0)
+        .filter_map(|approval| approval.by.as_ref())
+        .any(|user| user.spark_email() == Some(email))
+}
+
+/// Run the query and reply with the list of the found changes.
+fn list_changes<G, S>(
+    bot: std::sync::Arc<std::sync::Mutex<Bot<G, S>>>,
+    task: ListChangesTask,
+) -> impl Future<Item = Vec<Response>, Error = ()>
+where
+    G: GerritCommandRunner,
+    S: SparkClient,
+{
+    let ListChangesTask {
+        recipient,
+        email,
+        query,
+        list,
+    } = task;
+    let query_result = bot.lock().unwrap().gerrit_command_runner.query(&query);
+    query_result.then(move |result| match result {
+        Ok(mut changes) => {
+            if let ChangeList::Queue = list {
+                let gerrit_email = {
+                    let bot = bot.lock().unwrap();
+                    let user = bot.state.find_user(&email);
+                    user.map_or(&*email, User::gerrit_email).to_owned()
+                };
+                changes.retain(|change| !has_voted(change, &gerrit_email));
+                // oldest first
+                changes.sort_by_key(|change| (change.created_on.is_none(), change.created_on));
+            }
+            let format = move |bot: &Bot<G, S>| {
+                let user = bot.state.find_user(&email);
+                match list {
+                    ChangeList::Reviews => bot
+                        .formatter
+                        .format_message(user, format::Reviews { changes: &changes }),
+                    ChangeList::Queue => bot.formatter.format_message(
+                        user,
+                        format::ReviewQueue {
+                            changes: &changes,
+                            now: Utc::now(),
+                        },
+                    ),
+                    ChangeList::Details(change_number) => {
+                        match changes.iter().find(|change| change.number == change_number) {
+                            Some(change) => bot
+                                .formatter
+                                .format_message(user, format::ChangeDetails { change }),
+                            None => Ok(Some(bot.text(
+                                &email,
+                                "change_not_found",
+                                &[("change", &change_number)],
+                            ))),
+                        }
+                    }
+                }
+            };
+            future::Either::A(
+                format_with_queries(bot, format).map(move |message| match message {
+                    Ok(Some(message)) => vec![Response {
+                        recipient,
+                        ..Response::new(spark::Email::default(), message)
+                    }],
+                    Ok(None) => Vec::new(),
+                    Err(e) => {
+                        error!("failed to format changes: {}", e);
+                        Vec::new()
+                    }
+                }),
+            )
+        }
+        Err(e) => {
+            error!("failed to query changes: {}", e);
+            let message = bot
+                .lock()
+                .unwrap()
+                .text(&email, "gerrit_query_failed", &[("error", &e)]);
+            future::Either::B(future::ok(vec![Response {
+                recipient,
+                ..Response::new(spark::Email::default(), message)
+            }]))
+        }
+    })
+}
+
+/// Format a message with the bot. If the format script missed results of
+/// Gerrit queries, they are fetched without holding the lock of the bot, and
+/// the message is formatted again.
+fn format_with_queries<G, S, F, T>(
+    bot: std::sync::Arc<std::sync::Mutex<Bot<G, S>>>,
+    format: F,
+) -> impl Future<Item = T, Error = ()>
+where
+    G: GerritCommandRunner,
+    S: SparkClient,
+    F: Fn(&Bot<G, S>) -> T,
+{
+    let mut locked_bot = bot.lock().unwrap();
+    let format_queries = match locked_bot.format_queries.clone() {
+        Some(format_queries) => format_queries,
+        None => return future::Either::A(future::ok(format(&locked_bot))),
+    };
+
+    format_queries.lock().unwrap().record_missing();
+    let message = format(&locked_bot);
+    let (missing, timeout) = {
+        let mut format_queries = format_queries.lock().unwrap();
+        (format_queries.take_missing(), format_queries.timeout())
+    };
+    if missing.is_empty() {
+        return future::Either::A(future::ok(message));
+    }
+
+    // a query is dropped on timeout, so it isn't run if it is still queued
+    let fetches: Vec<_> = missing
+        .into_iter()
+        .map(|(search, query)| {
+            let query_result = locked_bot.gerrit_command_runner.query(&query);
+            tokio::timer::Timeout::new(query_result, timeout).then(move |result| {
+                let result = result.map_err(|e| match e.into_inner() {
+                    Some(e) => format!("query failed: {}", e),
+                    None => "query timed out".to_string(),
+                });
+                Ok((search, result))
+            })
+        })
+        .collect();
+    drop(locked_bot);
+
+    future::Either::B(future::join_all(fetches).map(move |results| {
+        {
+            let mut format_queries = format_queries.lock().unwrap();
+            for (search, result) in results {
+                format_queries.insert(search, result);
+            }
+        }
+        format(&bot.lock().unwrap())
+    }))
+}
+
+/// Review of the current patch set of a change, done with the Gerrit account
+/// of the bot on behalf of a user.
+#[derive(Debug)]
+struct ReviewTask {
+    /// Where to report the result.
+    recipient: Recipient,
+    /// User the review is done for.
+    email: spark::Email,
+    change_number: u32,
+    review: gerrit::Review,
+    /// Reply if the review succeeded.
+    confirmation: String,
+}
+
+/// Run the review and report the result.
+fn review_change<G, S>(
+    bot: std::sync::Arc<std::sync::Mutex<Bot<G, S>>>,
+    task: ReviewTask,
+) -> impl Future<Item = Vec<Response>, Error = ()>
+where
+    G: GerritCommandRunner,
+    S: SparkClient,
+{
+    let ReviewTask {
+        recipient,
+        email,
+        change_number,
+        review,
+        confirmation,
+    } = task;
+    let query = gerrit::Query::change(change_number).current_patch_set();
+    let query_result = bot.lock().unwrap().gerrit_command_runner.query(&query);
+    let bot_for_reply = bot.clone();
+    query_result
+        // `None` if the change doesn't exist
+        .map_err(Some)
+        .and_then(move |changes| {
+            let patchset_number = changes
+                .into_iter()
+                .find(|change| change.number == change_number)
+                .and_then(|change| change.current_patch_set)
+                .map(|patchset| patchset.number);
+            match patchset_number {
+                Some(patchset_number) => future::Either::A(
+                    bot.lock()
+                        .unwrap()
+                        .gerrit_command_runner
+                        .run_command(review.to_command(change_number, patchset_number))
+                        .map_err(Some),
+                ),
+                None => future::Either::B(future::err(None)),
+            }
+        })
+        .then(move |result| {
+            let message = match result {
+                Ok(ref output) if output.trim().is_empty() => confirmation,
+                // echo what Gerrit said
+                Ok(output) => format!("{}\n\n```\n{}\n```", confirmation, output.trim()),
+                Err(None) => bot_for_reply.lock().unwrap().text(
+                    &email,
+                    "change_not_found",
+                    &[("change", &change_number)],
+                ),
+                Err(Some(e)) => {
+                    error!("failed to review change {}: {}", change_number, e);
+                    bot_for_reply.lock().unwrap().text(
+                        &email,
+                        "review_failed",
+                        &[("change", &change_number), ("error", &e)],
+                    )
+                }
+            };
+            Ok(vec![Response {
+                recipient,
+                ..Response::new(spark::Email::default(), message)
+            }])
+        })
+}
+
+impl Task {
+    /// Post replies in the group room, if any.
+    fn in_room(self, room_id: Option<&spark::RoomId>) -> Task {
+        match (self, room_id) {
+            (Task::Reply(response), Some(room_id)) => Task::Reply(Response {
+                recipient: Recipient::Room(room_id.clone()),
+                ..response
+            }),
+            (Task::Review(review), Some(room_id)) => Task::Review(ReviewTask {
+                recipient: Recipient::Room(room_id.clone()),
+                ..review
+            }),
+            (Task::ListChanges(list), Some(room_id)) => Task::ListChanges(ListChangesTask {
+                recipient: Recipient::Room(room_id.clone()),
+                ..list
+            }),
+            (task, _) => task,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Borrow;
+    use std::fmt::Debug;
+    use std::thread;
+    use std::time::Duration;
+
+    use chrono::Timelike as _;
+    use futures::future;
+    use spectral::prelude::*;
+    use speculate::speculate;
+
+    use spark::EmailRef;
+
+    use crate::state::VotePolicy;
+
+    use super::*;
+
+    struct TestGerritCommandRunner;
+    impl GerritCommandRunner for TestGerritCommandRunner {
+        type QueryFuture = future::FutureResult<Vec<gerrit::Change>, gerrit::Error>;
+        type CommandFuture = future::FutureResult<String, gerrit::Error>;
+        fn query(&mut self, _query: &gerrit::Query) -> Self::QueryFuture {
+            future::ok(Vec::new())
+        }
+        fn run_command(&mut self, _command: String) -> Self::CommandFuture {
+            future::ok(String::new())
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestSparkClient;
+
+    type TestBot = Bot<TestGerritCommandRunner, TestSparkClient>;
+
+    impl SparkClient for TestSparkClient {
+        type ReplyFuture = future::FutureResult<Option<spark::MessageId>, spark::Error>;
+        fn send_message(&self, _email: &EmailRef, _msg: &str) -> Self::ReplyFuture {
+            future::ok(None)
+        }
+        fn send_room_message(&self, _room_id: &spark::RoomIdRef, _msg: &str) -> Self::ReplyFuture {
+            future::ok(None)
+        }
+    }
+
+    impl TestBot {
+        fn add_user(&mut self, email: &str) {
+            self.state.add_user(EmailRef::new(email));
+        }
+
+        fn enable(&mut self, email: &str, enabled: bool) {
+            self.state.enable(EmailRef::new(email), enabled);
+        }
+    }
+
+    fn new_bot() -> TestBot {
+        Builder::new(State::new()).build(TestGerritCommandRunner, TestSparkClient)
+    }
+
+    fn new_bot_with_msg_cache(capacity: usize, expiration: Duration) -> TestBot {
+        Builder::new(State::new())
+            .with_msg_cache(capacity, expiration)
+            .build(TestGerritCommandRunner, TestSparkClient)
+    }
+
+    #[allow(dead_code)]
+    trait UserAssertions {
+        fn has_email(&mut self, expected: &str);
+        fn has_any_flag<I, F>(&self, flags: I)
+        where
+            I: IntoIterator<Item = F> + Debug + Clone,
+            F: Borrow<UserFlag>;
+        fn has_no_flag<I, F>(&self, flags: I)
+        where
+            I: IntoIterator<Item = F> + Debug + Clone,
+            F: Borrow<UserFlag>;
+    }
+
+    impl<'s> UserAssertions for spectral::Spec<'s, &User> {
+        fn has_email(&mut self, expected: &str) {
+            let actual = self.subject.email();
+            let expected = EmailRef::new(expected);
+            if actual != expected {
+                spectral::AssertionFailure::from_spec(self)
+                    .with_expected(format!("user with email <{}>", expected))
+                    .with_actual(format!("<{}>", actual))
+                    .fail();
+            }
+        }
+
+        fn has_any_flag<I, F>(&self, flags: I)
+        where
+            I: IntoIterator<Item = F> + Debug + Clone,
+            F: Borrow<UserFlag>,
+        {
+            if !self.subject.has_any_flag(flags.clone()) {
+                spectral::AssertionFailure::from_spec(self)
+                    .with_expected(format!("user has at least one of the flags {:?}", flags))
+                    .with_actual("it has none of the given flags".to_string())
+                    .fail();
+            }
+        }
+
+        fn has_no_flag<I, F>(&self, flags: I)
+        where
+            I: IntoIterator<Item = F> + Debug + Clone,
+            F: Borrow<UserFlag>,
+        {
+            if self.subject.has_any_flag(flags.clone()) {
+                spectral::AssertionFailure::from_spec(self)
+                    .with_expected(format!("user has none of the flags {:?}", flags))
+                    .with_actual("it has at least one of the given flags".to_string())
+                    .fail();
+            }
+        }
+    }
+
+    trait HasItemMatchingAssertion<'s, T: 's> {
+        fn has_item_matching<P>(&mut self, predicate: P)
+        where
+            P: FnMut(&'s T) -> bool;
+    }
+
+    impl<'s, T: 's, I> HasItemMatchingAssertion<'s, T> for spectral::Spec<'s, I>
+    where
+        T: std::fmt::Debug,
+        &'s I: IntoIterator<Item = &'s T>,
+    {
+        fn has_item_matching<P>(&mut self, predicate: P)
+        where
+            P: FnMut(&'s T) -> bool,
+        {
+            let subject = self.subject;
+
+            if !subject.into_iter().any(predicate) {
+                spectral::AssertionFailure::from_spec(self)
+                    .with_expected(
+                        "iterator to contain an item matching the given predicate".to_string(),
+                    )
+                    .with_actual("it did not".to_string())
+                    .fail();
+            }
+        }
+    }
+
+    speculate! {
+        before {
+            let bot = new_bot();
+        }
+
+        describe "when a user is added" {
+            before {
+                let mut bot = bot;
+                bot.add_user("some@example.com");
+            }
+
+            before {
+                assert_that!(bot.state.users().count()).is_equal_to(1);
+            }
+
+            it "has the expected attributes" {
+                let user = bot.state.users().next().unwrap();
+                assert_that!(user).has_email("some@example.com");
+                assert_that!(user).has_email("some@example.com");
+                assert_that!(user).has_any_flag(NOTIFICATION_FLAGS);
+            }
+
+            test "enabled status response" {
+                let resp = bot.status_for(EmailRef::new("some@example.com"));
+                assert_that!(resp).is_some().contains("enabled");
+            }
+
+            test "disabled status response" {
+                bot.enable("some@example.com", false);
+                let resp = bot.status_for(EmailRef::new("some@example.com"));
+                assert_that!(resp).is_some().contains("disabled");
+            }
+
+            test "existing user can be enabled" {
+                bot.enable("some@example.com", true);
+                let users: Vec<_> = bot.state.users().collect();
+                assert_that!(users)
+                    .has_item_matching(
+                        |u| u.email() == EmailRef::new("some@example.com")
+                            && u.has_any_flag(NOTIFICATION_FLAGS));
+                assert_that!(bot.state.users().count()).is_equal_to(1);
+            }
+
+            test "existing can be disabled" {
+                bot.enable("some@example.com", false);
+                let users: Vec<_> = bot.state.users().collect();
+                assert_that!(users)
+                    .has_item_matching(
+                        |u| u.email() == EmailRef::new("some@example.com")
+                            && !u.has_any_flag(NOTIFICATION_FLAGS));
+                assert_that!(bot.state.users().count()).is_equal_to(1);
+            }
+        }
+
+        test "non-existing user is automatically added when enabled" {
+            assert_that!(bot.state.users().count()).is_equal_to(0);
+            let mut bot = bot;
+            bot.enable("some@example.com", true);
+            let users: Vec<_> = bot.state.users().collect();
+            assert_that!(users)
+                .has_item_matching(
+                    |u| u.email() == EmailRef::new("some@example.com")
+
+                        && u.has_any_flag(NOTIFICATION_FLAGS));
+            assert_that!(bot.state.users().count()).is_equal_to(1);
+        }
+
+        test "non-existing user is automatically added when disabled" {
+            assert_that!(bot.state.users().count()).is_equal_to(0);
+            let mut bot = bot;
+            bot.enable("some@example.com", false);
+            let users: Vec<_> = bot.state.users().collect();
+            assert_that!(users)
+                .has_item_matching(
+                    |u| u.email() == EmailRef::new("some@example.com")
+                        && !u.has_any_flag(NOTIFICATION_FLAGS));
+            assert_that!(bot.state.users().count()).is_equal_to(1);
+        }
+
+        test "unknown user gets disabled status response" {
+            let resp = bot.status_for(EmailRef::new("some_non_existent_id"));
+            assert_that!(resp).is_some().contains("disabled");
+        }
+    }
+
+    const EVENT_JSON: &str = r#"
+{"author":{"name":"Approver","username":"approver","email":"approver@approvers.com"},"approvals":[{"type":"Code-Review","description":"Code-Review","value":"2","oldValue":"-1"}],"comment":"Patch Set 1: Code-Review+2\n\nJust a buggy script. FAILURE\n\nAnd more problems. FAILURE","patchSet":{"number":1,"revision":"49a65998c02eda928559f2d0b586c20bc8e37b10","parents":["fb1909b4eda306985d2bbce769310e5a50a98cf5"],"ref":"refs/changes/42/42/1","uploader":{"name":"Author","email":"author@example.com","username":"Author"},"createdOn":1494165142,"author":{"name":"Author","email":"author@example.com","username":"Author"},"isDraft":false,"kind":"REWORK","sizeInsertions":0,"sizeDeletions":0},"change":{"project":"demo-project","branch":"master","id":"Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14","number":49,"subject":"Some review.","owner":{"name":"Author","email":"author@example.com","username":"author"},"url":"http://localhost/42","commitMessage":"Some review.\n\nChange-Id: Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14\n","status":"NEW"},"project":"demo-project","refName":"refs/heads/master","changeKey":{"id":"Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14"},"type":"comment-added","eventCreatedOn":1499190282}"#;
+
+    fn get_event() -> gerrit::CommentAddedEvent {
+        let event: Result<gerrit::Event, _> = serde_json::from_str(EVENT_JSON);
+        match event.expect("failed to decode event") {
+            gerrit::Event::CommentAdded(event) => event,
+            event => panic!("wrong type of event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn review_from_chat() {
+        let sender = spark::Email::new("author@example.com".to_string());
+        let review = |change_number, label: &str, value| Action::RunCommand {
+            sender: sender.clone(),
+            room: None,
+            command: Command::Review {
+                change_number,
+                label: label.to_string(),
+                value,
+                message: None,
+            },
+        };
+
+        let mut bot = new_bot();
+        let tasks = bot.update(review(42, "Code-Review", 1));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message == "Voting from chat is not enabled."
+        ));
+
+        let mut bot = Builder::new(State::new())
+            .with_review_labels(
+                vec![("Code-Review".to_string(), vec![-1, 1])]
+                    .into_iter()
+                    .collect(),
+            )
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let tasks = bot.update(review(42, "Code-Review", 1));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("You are not allowed to vote")
+        ));
+
+        bot.state.set_permission(&sender, Permission::Review, true);
+        let tasks = bot.update(review(42, "Code-Review", 2));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.starts_with("You can't vote Code-Review+2")
+        ));
+
+        let mut tasks = bot.update(review(42, "code-review", -1));
+        let task = match tasks.pop() {
+            Some(Task::Review(task)) if tasks.is_empty() => task,
+            task => panic!("unexpected task: {:?}", task),
+        };
+        assert_eq!(task.confirmation, "Voted Code-Review-1 on the change 42.");
+        assert_eq!(
+            task.review.to_command(42, 1),
+            "gerrit review --label 'Code-Review=-1' \
+             --message 'Voted from chat by author@example.com.' 42,1"
+        );
+
+        // the test runner doesn't know any changes
+        let bot = std::sync::Arc::new(std::sync::Mutex::new(bot));
+        let responses = review_change(bot, task).wait().unwrap();
+        assert!(matches!(
+            &responses[..],
+            [response] if response.message == "I could not find the change 42."
+                && response.recipient == Recipient::Person(sender.clone())
+        ));
+    }
+
+    #[test]
+    fn comment_from_chat() {
+        let sender = spark::Email::new("author@example.com".to_string());
+        let comment = || Action::RunCommand {
+            sender: sender.clone(),
+            room: None,
+            command: Command::Comment {
+                change_number: 42,
+                message: "**Nice**, but see [docs](http://docs)\nand `main.rs`".to_string(),
+            },
+        };
+        let mut bot = new_bot();
+        let tasks = bot.update(comment());
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("You are not allowed to vote")
+        ));
+
+        bot.state.set_permission(&sender, Permission::Review, true);
+        let mut tasks = bot.update(comment());
+        let task = match tasks.pop() {
+            Some(Task::Review(task)) if tasks.is_empty() => task,
+            task => panic!("unexpected task: {:?}", task),
+        };
+        assert_eq!(
+            task.confirmation,
+            "Commented on the change 42:\n\n> Nice, but see docs (http://docs)\n> and main.rs"
+        );
+        assert!(task.review.to_command(42, 1).starts_with(
+            "gerrit review --message 'Nice, but see docs (http://docs)\nand main.rs\n\n\
+             Commented from chat by author@example.com.'"
+        ));
+    }
+
+    #[test]
+    fn abandon_needs_permission() {
+        let admin = spark::Email::new("admin@example.com".to_string());
+        let author = spark::Email::new("author@example.com".to_string());
+        let mut bot = Builder::new(State::new())
+            .with_admins(vec![admin.clone()])
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let run = |sender: &spark::Email, command| Action::RunCommand {
+            sender: sender.clone(),
+            room: None,
+            command,
+        };
+        let abandon = || Command::Abandon {
+            change_number: 42,
+            reason: Some("obsolete".to_string()),
+        };
+        let grant = |granted| Command::SetPermission {
+            email: author.clone(),
+            permission: Permission::ManageChanges,
+            granted,
+        };
+
+        let tasks = bot.update(run(&author, abandon()));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("You are not allowed")
+        ));
+
+        // only admins may grant permissions
+        bot.update(run(&author, grant(true)));
+        let tasks = bot.update(run(&author, abandon()));
+        assert!(matches!(&tasks[..], [Task::Reply(_)]));
+
+        let tasks = bot.update(run(&admin, grant(true)));
+        assert!(matches!(&tasks[..], [Task::Save, Task::Reply(_)]));
+        let tasks = bot.update(run(&author, abandon()));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Review(task)]
+                if task.confirmation == "Abandoned the change 42."
+                    && task.review.to_command(42, 1)
+                        == "gerrit review --abandon --message \
+                            'obsolete\n\nAbandoned from chat by author@example.com.' 42,1"
+        ));
+
+        bot.update(run(&admin, grant(false)));
+        let tasks = bot.update(run(&author, Command::Restore { change_number: 42 }));
+        assert!(matches!(&tasks[..], [Task::Reply(_)]));
+    }
+
+    #[test]
+    fn reviews_listed() {
+        let mut bot = new_bot();
+        let mut tasks = bot.update(Action::RunCommand {
+            sender: spark::Email::new("author@example.com".to_string()),
+            room: None,
+            command: Command::Reviews,
+        });
+        let task = match tasks.pop() {
+            Some(Task::ListChanges(task)) if tasks.is_empty() => task,
+            task => panic!("unexpected task: {:?}", task),
+        };
+        assert_eq!(
+            task.query.to_command(),
+            "gerrit query --format=JSON --current-patch-set --submit-records \
+             'owner:author@example.com status:open limit:25'"
+        );
+
+        // the test runner doesn't know any changes
+        let bot = std::sync::Arc::new(std::sync::Mutex::new(bot));
+        let responses = list_changes(bot, task).wait().unwrap();
+        assert!(matches!(
+            &responses[..],
+            [response] if response.message == "You don't have any open changes."
+        ));
+    }
+
+    #[test]
+    fn format_queries_are_fetched_outside_of_the_bot() {
+        #[derive(Clone)]
+        struct TopicGerritCommandRunner(Vec<gerrit::Change>);
+        impl GerritCommandRunner for TopicGerritCommandRunner {
+            type QueryFuture = future::FutureResult<Vec<gerrit::Change>, gerrit::Error>;
+            type CommandFuture = future::FutureResult<String, gerrit::Error>;
+            fn query(&mut self, _query: &gerrit::Query) -> Self::QueryFuture {
+                future::ok(self.0.clone())
+            }
+            fn run_command(&mut self, _command: String) -> Self::CommandFuture {
+                future::ok(String::new())
+            }
+        }
+
+        let mut change = get_event().change;
+        change.topic = Some("version".to_string());
+        let mut related = change.clone();
+        related.number = change.number + 1;
+        related.subject = "Update the changelog".to_string();
+        let runner = TopicGerritCommandRunner(vec![change.clone(), related]);
+        let bot = Builder::new(State::new())
+            .with_format_queries(Duration::from_secs(1))
+            .unwrap()
+            .build(runner, TestSparkClient);
+
+        let sender = spark::Email::new("author@example.com".to_string());
+        let task = ListChangesTask {
+            recipient: Recipient::Person(sender.clone()),
+            email: sender,
+            query: gerrit::Query::change(change.number),
+            list: ChangeList::Details(change.number),
+        };
+        let bot = std::sync::Arc::new(std::sync::Mutex::new(bot));
+        let responses = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(list_changes(bot, task))
+            .unwrap();
+        assert!(matches!(
+            &responses[..],
+            [response] if response.message.contains("Same topic: [Update the changelog]")
+        ));
+    }
+
+    #[test]
+    fn changes_listed_for_all_linked_emails() {
+        let mut bot = new_bot();
+        let sender = spark::Email::new("author@corp.example.com".to_string());
+        bot.state.add_user(&sender);
+        for gerrit_email in &["author@example.com", "author@old.example.com"] {
+            bot.state
+                .link_gerrit_email(&sender, EmailRef::new(gerrit_email));
+        }
+        let mut query = |command| {
+            let mut tasks = bot.update(Action::RunCommand {
+                sender: sender.clone(),
+                room: None,
+                command,
+            });
+            match tasks.pop() {
+                Some(Task::ListChanges(task)) if tasks.is_empty() => task.query.to_command(),
+                task => panic!("unexpected task: {:?}", task),
+            }
+        };
+        assert!(query(Command::Reviews).ends_with(
+            "'(owner:author@example.com OR owner:author@old.example.com) status:open limit:25'"
+        ));
+        assert!(query(Command::Queue).ends_with(
+            "'(reviewer:author@example.com OR reviewer:author@old.example.com) status:open \
+             -owner:author@example.com -owner:author@old.example.com limit:25'"
+        ));
+    }
+
+    #[test]
+    fn has_voted_on_current_patch_set() {
+        let mut change = get_event().change;
+        let email = EmailRef::new("approver@approvers.com");
+        assert!(!has_voted(&change, email));
+
+        let mut patchset = get_event().patchset;
+        patchset.approvals = Some(get_event().approvals.unwrap());
+        let approval = &mut patchset.approvals.as_mut().unwrap()[0];
+        approval.by = Some(get_event().author);
+        change.current_patch_set = Some(patchset.clone());
+        assert!(has_voted(&change, email));
+        assert!(!has_voted(&change, EmailRef::new("author@example.com")));
+
+        // a removed vote doesn't count
+        patchset.approvals.as_mut().unwrap()[0].value = "0".to_string();
+        change.current_patch_set = Some(patchset);
+        assert!(!has_voted(&change, email));
+    }
+
+    #[test]
+    fn markdown_to_plain_text() {
+        assert_eq!(super::markdown_to_plain_text("plain text"), "plain text");
+        assert_eq!(
+            super::markdown_to_plain_text("# Title\n*a* __b__ ~~c~~ `d_e` 2 * 3 * 4"),
+            "Title\na b c d_e 2 * 3 * 4"
+        );
+        assert_eq!(super::markdown_to_plain_text(r"\*not bold\*"), "*not bold*");
+    }
+
+    #[test]
+    fn send_limits() {
+        let bot = new_bot();
+        assert_eq!(bot.send_concurrency, DEFAULT_SEND_CONCURRENCY);
+        assert_eq!(bot.send_timeout, DEFAULT_SEND_TIMEOUT);
+
+        let bot = Builder::new(State::new())
+            .with_send_concurrency(0)
+            .with_send_timeout(Duration::from_secs(30))
+            .build(TestGerritCommandRunner, TestSparkClient);
+        assert_eq!(bot.send_concurrency, 1);
+        assert_eq!(bot.send_timeout, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn get_approvals_msg_for_empty_bot() {
+        // bot does not have the user => no message
+        let mut bot = new_bot();
+        let res = bot.get_approvals_msg(Box::new(get_event()));
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn get_approvals_msg_for_same_author_and_approver() {
+        // the approval is from the author => no message
+        let mut bot = new_bot();
+        bot.state.add_user(EmailRef::new("approver@example.com"));
+        let res = bot.get_approvals_msg(Box::new(get_event()));
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn get_approvals_msg_for_user_with_disabled_notifications() {
+        // the approval is for the user with disabled notifications
+        // => no message
+        let mut bot = new_bot();
+        bot.state.add_user(EmailRef::new("author@example.com"));
+        bot.enable("author@example.com", false);
+        let res = bot.get_approvals_msg(Box::new(get_event()));
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn get_approvals_msg_for_user_with_enabled_notifications() {
+        // the approval is for the user with enabled notifications
+        // => message
+        let mut bot = new_bot();
+        bot.state.add_user(EmailRef::new("author@example.com"));
+        let res = bot.get_approvals_msg(Box::new(get_event()));
+        assert!(res.is_some());
+        let (email, msg) = res.unwrap();
+        assert_eq!(email, EmailRef::new("author@example.com"));
+        assert!(msg.contains("Some review."));
+    }
+
+    #[test]
+    fn get_approvals_msg_for_linked_gerrit_account() {
+        // the owner of the change is a Gerrit account linked by the user
+        // => message to the email of the user
+        let admin = spark::Email::new("admin@example.com".to_string());
+        let mut bot = Builder::new(State::new())
+            .with_admins(vec![admin.clone()])
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let email = spark::Email::new("author@corp.example.com".to_string());
+        let gerrit_email = spark::Email::new("author@example.com".to_string());
+
+        let tasks = bot.update(Action::RunCommand {
+            sender: email.clone(),
+            room: None,
+            command: Command::LinkGerrit(gerrit_email.clone()),
+        });
+        assert!(matches!(
+            &tasks[..],
+            [Task::Save, Task::Reply(_), Task::Reply(request)]
+                if request.message.contains("`approve link author@corp.example.com`")
+        ));
+        assert!(bot.get_approvals_msg(Box::new(get_event())).is_none());
+
+        let tasks = bot.update(Action::RunCommand {
+            sender: admin,
+            room: None,
+            command: Command::ApproveLink(email.clone()),
+        });
+        assert!(matches!(
+            &tasks[..],
+            [Task::Save, Task::Reply(_), Task::Reply(_)]
+        ));
+        let (recipient, msg) = bot.get_approvals_msg(Box::new(get_event())).unwrap();
+        assert_eq!(recipient, email);
+        assert!(msg.contains("Some review."));
     }
-}
 
-#[derive(Debug)]
-enum Task {
-    Reply(Response),
-    Save,
-}
+    #[test]
+    fn link_gerrit_without_verification() {
+        let mut bot = Builder::new(State::new())
+            .without_account_link_verification()
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let email = spark::Email::new("author@corp.example.com".to_string());
 
-/// Guess if the change might have comments by looking for a specially formatted
-/// comment.
-fn maybe_has_inline_comments(event: &gerrit::CommentAddedEvent) -> bool {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"\(\d+\scomments?\)").unwrap();
+        let tasks = bot.update(Action::RunCommand {
+            sender: email.clone(),
+            room: None,
+            command: Command::LinkGerrit(spark::Email::new("author@example.com".to_string())),
+        });
+        assert!(matches!(&tasks[..], [Task::Save, Task::Reply(_)]));
+        let (recipient, _) = bot.get_approvals_msg(Box::new(get_event())).unwrap();
+        assert_eq!(recipient, email);
     }
-    RE.is_match(&event.comment)
-}
 
-#[cfg(test)]
-mod test {
-    use std::borrow::Borrow;
-    use std::fmt::Debug;
-    use std::thread;
-    use std::time::Duration;
+    #[test]
+    fn autosave_once_per_interval() {
+        let mut bot = Builder::new(State::new())
+            .with_autosave(Duration::from_secs(10 * 60))
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let now = Utc::now();
+        let is_saved = |tasks: Vec<Task>| tasks.iter().any(|task| matches!(task, Task::Save));
 
-    use futures::future;
-    use spectral::prelude::*;
-    use speculate::speculate;
+        assert!(!is_saved(bot.update(Action::Tick(now))));
+        let now = now + chrono::Duration::minutes(9);
+        assert!(!is_saved(bot.update(Action::Tick(now))));
+        let now = now + chrono::Duration::minutes(1);
+        assert!(is_saved(bot.update(Action::Tick(now))));
+        let now = now + chrono::Duration::minutes(1);
+        assert!(!is_saved(bot.update(Action::Tick(now))));
+    }
 
-    use spark::EmailRef;
+    #[test]
+    fn export_and_import_state() {
+        let admin = spark::Email::new("admin@example.com".to_string());
+        let command = |command| Action::RunCommand {
+            sender: admin.clone(),
+            room: None,
+            command,
+        };
+        let export = |name: &str| command(Command::ExportState(Some(name.to_string())));
 
-    use super::*;
+        let mut bot = Builder::new(State::new())
+            .with_admins(vec![admin.clone()])
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let tasks = bot.update(export("users.json"));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("is not enabled")
+        ));
 
-    struct TestGerritCommandRunner;
-    impl GerritCommandRunner for TestGerritCommandRunner {}
+        let dir = std::env::temp_dir();
+        let mut bot = Builder::new(State::new())
+            .with_admins(vec![admin.clone()])
+            .with_state_export_dir(&dir)
+            .build(TestGerritCommandRunner, TestSparkClient);
+        bot.state.add_user(&admin);
+        bot.state
+            .add_user(spark::EmailRef::new("author@example.com"));
+        for name in &["/etc/passwd", "../state.json", "exports\\state.json"] {
+            let tasks = bot.update(export(name));
+            assert!(matches!(
+                &tasks[..],
+                [Task::Reply(response)] if response.message.contains("is not a file name")
+            ));
+        }
 
-    #[derive(Clone)]
-    struct TestSparkClient;
+        let path = format!("gerritbot-export-{}.json", std::process::id());
+        let mut tasks = bot.update(export(&path));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Export(file), Task::Reply(response)]
+                if *file == dir.join(&path) && response.message.contains("with 2 users")
+        ));
+        bot.handle_task(tasks.remove(0));
 
-    type TestBot = Bot<TestGerritCommandRunner, TestSparkClient>;
+        bot.state = State::new();
+        let import = |confirmed| {
+            command(Command::ImportState {
+                path: path.clone(),
+                confirmed,
+            })
+        };
+        let tasks = bot.update(import(false));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("has 2 users and 0 teams")
+        ));
+        assert_eq!(bot.state.num_users(), 0);
+        let tasks = bot.update(import(true));
+        assert!(matches!(&tasks[..], [Task::Save, Task::Reply(_)]));
+        assert_eq!(bot.state.num_users(), 2);
 
-    impl SparkClient for TestSparkClient {
-        type ReplyFuture = future::FutureResult<(), spark::Error>;
-        fn send_message(&self, _email: &EmailRef, _msg: &str) -> Self::ReplyFuture {
-            future::ok(())
-        }
+        let user = r#"{"email": "admin@example.com", "enabled": true}"#;
+        let file = dir.join(&path);
+        std::fs::write(&file, format!(r#"{{"users": [{}, {}]}}"#, user, user)).unwrap();
+        let tasks = bot.update(import(true));
+        std::fs::remove_file(&file).unwrap();
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("is added twice")
+        ));
+        assert_eq!(bot.state.num_users(), 2);
     }
 
-    impl TestBot {
-        fn add_user(&mut self, email: &str) {
-            self.state.add_user(EmailRef::new(email));
-        }
+    #[test]
+    fn prune_inactive_users() {
+        let admin = spark::Email::new("admin@example.com".to_string());
+        let mut bot = Builder::new(State::new())
+            .with_admins(vec![admin.clone()])
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let author = spark::EmailRef::new("author@example.com");
+        bot.state.add_user(author);
+        bot.state
+            .command_received(author, Utc::now() - chrono::Duration::days(200));
+        let prune = |sender: &spark::EmailRef, confirmed| Action::RunCommand {
+            sender: sender.to_owned(),
+            room: None,
+            command: Command::PruneUsers {
+                months: 6,
+                confirmed,
+            },
+        };
 
-        fn enable(&mut self, email: &str, enabled: bool) {
-            self.state.enable(EmailRef::new(email), enabled);
-        }
-    }
+        let tasks = bot.update(prune(&admin, false));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("author@example.com")
+        ));
+        assert_eq!(bot.state.num_users(), 1);
+        let tasks = bot.update(prune(&admin, true));
+        assert!(matches!(&tasks[..], [Task::Save, Task::Reply(_)]));
+        assert_eq!(bot.state.num_users(), 0);
 
-    fn new_bot() -> TestBot {
-        Builder::new(State::new()).build(TestGerritCommandRunner, TestSparkClient)
+        // sending a command, even a denied one, counts as activity
+        bot.state.add_user(author);
+        bot.state
+            .command_received(author, Utc::now() - chrono::Duration::days(200));
+        let tasks = bot.update(prune(author, true));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("Only bot admins")
+        ));
+        let tasks = bot.update(prune(&admin, true));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("All users were active")
+        ));
+        assert_eq!(bot.state.num_users(), 1);
     }
 
-    fn new_bot_with_msg_cache(capacity: usize, expiration: Duration) -> TestBot {
-        Builder::new(State::new())
-            .with_msg_cache(capacity, expiration)
-            .build(TestGerritCommandRunner, TestSparkClient)
+    #[test]
+    fn restore_state_only_by_admins() {
+        let admin = spark::Email::new("admin@example.com".to_string());
+        let mut bot = Builder::new(State::new())
+            .with_admins(vec![admin.clone()])
+            .with_state_backups(3)
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let restore = |sender: &spark::Email, backup| Action::RunCommand {
+            sender: sender.clone(),
+            room: None,
+            command: Command::RestoreState(backup),
+        };
+
+        let author = spark::Email::new("author@example.com".to_string());
+        let tasks = bot.update(restore(&author, 1));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("Only bot admins")
+        ));
+
+        let tasks = bot.update(restore(&admin, 4));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message == "There is no backup 4, I keep 3 backups."
+        ));
     }
 
-    trait UserAssertions {
-        fn has_email(&mut self, expected: &str);
-        fn has_any_flag<I, F>(&self, flags: I)
-        where
-            I: IntoIterator<Item = F> + Debug + Clone,
-            F: Borrow<UserFlag>;
-        fn has_no_flag<I, F>(&self, flags: I)
-        where
-            I: IntoIterator<Item = F> + Debug + Clone,
-            F: Borrow<UserFlag>;
+    #[test]
+    fn invites_people_involved_in_gerrit_events() {
+        let mut bot = Builder::new(State::new())
+            .with_auto_invite(vec![spark::Email::new(
+                "approver@approvers.com".to_string(),
+            )])
+            .build(TestGerritCommandRunner, TestSparkClient);
+
+        // the approver opted out => only the author is invited
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(invitation), Task::Save]
+                if invitation.recipient == Recipient::Person(spark::Email::new("author@example.com".to_string()))
+                    && invitation.message.starts_with("Hi Author!")
+        ));
+
+        // only once
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(tasks.is_empty());
     }
 
-    impl<'s> UserAssertions for spectral::Spec<'s, &User> {
-        fn has_email(&mut self, expected: &str) {
-            let actual = self.subject.email();
-            let expected = EmailRef::new(expected);
-            if actual != expected {
-                spectral::AssertionFailure::from_spec(self)
-                    .with_expected(format!("user with email <{}>", expected))
-                    .with_actual(format!("<{}>", actual))
-                    .fail();
-            }
-        }
+    #[test]
+    fn get_approvals_msg_for_user_with_enabled_notifications_and_filter() {
+        // the approval is for the user with enabled notifications
+        // => message
+        let mut bot = new_bot();
+        bot.state.add_user(EmailRef::new("author@example.com"));
 
-        fn has_any_flag<I, F>(&self, flags: I)
-        where
-            I: IntoIterator<Item = F> + Debug + Clone,
-            F: Borrow<UserFlag>,
         {
-            if !self.subject.has_any_flag(flags.clone()) {
-                spectral::AssertionFailure::from_spec(self)
-                    .with_expected(format!("user has at least one of the flags {:?}", flags))
-                    .with_actual("it has none of the given flags".to_string())
-                    .fail();
-            }
+            let res = bot
+                .state
+                .add_filter(EmailRef::new("author@example.com"), ".*Code-Review.*");
+            assert!(res.is_ok());
+            let res = bot.get_approvals_msg(Box::new(get_event()));
+            assert!(res.is_none());
         }
-
-        fn has_no_flag<I, F>(&self, flags: I)
-        where
-            I: IntoIterator<Item = F> + Debug + Clone,
-            F: Borrow<UserFlag>,
         {
-            if self.subject.has_any_flag(flags.clone()) {
-                spectral::AssertionFailure::from_spec(self)
-                    .with_expected(format!("user has none of the flags {:?}", flags))
-                    .with_actual("it has at least one of the given flags".to_string())
-                    .fail();
-            }
+            let res = bot
+                .state
+                .enable_and_get_filter(EmailRef::new("author@example.com"), false);
+            assert!(res.is_some());
+            let res = bot.get_approvals_msg(Box::new(get_event()));
+            assert!(res.is_some());
+            let (user, msg) = res.unwrap();
+            assert_eq!(user, EmailRef::new("author@example.com"));
+            assert!(msg.contains("Some review."));
+        }
+        {
+            let res = bot
+                .state
+                .enable_and_get_filter(EmailRef::new("author@example.com"), true);
+            assert!(res.is_some());
+            let res = bot.state.add_filter(
+                EmailRef::new("author@example.com"),
+                "some_non_matching_filter",
+            );
+            assert!(res.is_ok());
+            let res = bot.get_approvals_msg(Box::new(get_event()));
+            assert!(res.is_some());
+            let (email, msg) = res.unwrap();
+            assert_eq!(email, EmailRef::new("author@example.com"));
+            assert!(msg.contains("Some review."));
         }
     }
 
-    trait HasItemMatchingAssertion<'s, T: 's> {
-        fn has_item_matching<P>(&mut self, predicate: P)
-        where
-            P: FnMut(&'s T) -> bool;
+    #[test]
+    fn get_approvals_msg_for_user_with_branch_patterns() {
+        // the change is on master => message only if master matches
+        let mut bot = new_bot();
+        bot.state.add_user(EmailRef::new("author@example.com"));
+
+        bot.state.set_branches(
+            EmailRef::new("author@example.com"),
+            vec!["release/*".to_string()],
+        );
+        let res = bot.get_approvals_msg(Box::new(get_event()));
+        assert!(res.is_none());
+
+        bot.state.set_branches(
+            EmailRef::new("author@example.com"),
+            vec!["release/*".to_string(), "mast*".to_string()],
+        );
+        let res = bot.get_approvals_msg(Box::new(get_event()));
+        assert!(res.is_some());
     }
 
-    impl<'s, T: 's, I> HasItemMatchingAssertion<'s, T> for spectral::Spec<'s, I>
-    where
-        T: std::fmt::Debug,
-        &'s I: IntoIterator<Item = &'s T>,
-    {
-        fn has_item_matching<P>(&mut self, predicate: P)
-        where
-            P: FnMut(&'s T) -> bool,
-        {
-            let subject = self.subject;
+    #[test]
+    fn get_approvals_msg_for_user_with_vote_threshold() {
+        // the review votes +2 => message only if +2 is below the threshold
+        let mut bot = new_bot();
+        let email = EmailRef::new("author@example.com");
+        bot.state.add_user(email);
 
-            if !subject.into_iter().any(predicate) {
-                spectral::AssertionFailure::from_spec(self)
-                    .with_expected(
-                        "iterator to contain an item matching the given predicate".to_string(),
-                    )
-                    .with_actual("it did not".to_string())
-                    .fail();
+        bot.state.set_vote_threshold(email, Some(0));
+        let res = bot.get_approvals_msg(Box::new(get_event()));
+        assert!(res.is_none());
+
+        bot.state.set_vote_threshold(email, Some(3));
+        let res = bot.get_approvals_msg(Box::new(get_event()));
+        assert!(res.is_some());
+
+        // the rule of the label takes precedence
+        bot.update(Action::RunCommand {
+            sender: email.to_owned(),
+            room: None,
+            command: Command::SetLabelVotes {
+                label: "code-review".to_string(),
+                policy: VotePolicy::Below(0),
+            },
+        });
+        let res = bot.get_approvals_msg(Box::new(get_event()));
+        assert!(res.is_none());
+
+        let tasks = bot.update(Action::RunCommand {
+            sender: email.to_owned(),
+            room: None,
+            command: Command::VotesStatus,
+        });
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(reply)]
+                if reply.message == "You get notifications about reviews with votes below 3, votes on `code-review` below 0."
+        ));
+    }
+
+    #[test]
+    fn get_submittable_msg_on_transition() {
+        // the owner is told once when the change becomes submittable
+        let mut bot = new_bot();
+        let email = EmailRef::new("author@example.com");
+        bot.state.add_user(email);
+        let event = |submittable: bool| {
+            let mut event = get_event();
+            event.change.submit_requirements = None;
+            event.change.submit_records = Some(vec![gerrit::SubmitRecord {
+                status: if submittable {
+                    gerrit::SubmitStatus::OK
+                } else {
+                    gerrit::SubmitStatus::NOT_READY
+                },
+            }]);
+            event
+        };
+
+        // the flag is off by default
+        assert!(bot.get_submittable_msg(&event(true)).is_none());
+        bot.change_tracker.forget(&get_event().change);
+
+        bot.state.set_flag(email, UserFlag::NotifySubmittable, true);
+        let (recipient, msg) = bot.get_submittable_msg(&event(true)).unwrap();
+        assert_eq!(recipient, email);
+        assert!(msg.contains("Ready to submit"));
+        assert!(bot.get_submittable_msg(&event(true)).is_none());
+
+        assert!(bot.get_submittable_msg(&event(false)).is_none());
+        assert!(bot.get_submittable_msg(&event(true)).is_some());
+    }
+
+    #[test]
+    fn merge_conflicts_are_notified_once() {
+        let mut bot = Builder::new(State::new())
+            .with_merge_conflict_check(Duration::from_secs(30 * 60))
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let email = EmailRef::new("author@example.com");
+        bot.state.add_user(email);
+        let now = Utc::now();
+
+        // nobody wants to know
+        assert!(bot.update(Action::Tick(now)).is_empty());
+
+        bot.state
+            .set_flag(email, UserFlag::NotifyMergeConflicts, true);
+        let tasks = bot.update(Action::Tick(now + chrono::Duration::minutes(1)));
+        assert_eq!(tasks.len(), 0);
+        let tasks = bot.update(Action::Tick(now + chrono::Duration::minutes(30)));
+        match &tasks[..] {
+            [Task::CheckMergeConflicts(query)] => assert_eq!(
+                *query,
+                gerrit::Query::new("status:open -is:mergeable (owner:author@example.com)")
+            ),
+            _ => panic!("unexpected tasks: {:?}", tasks),
+        }
+
+        let change = get_event().change;
+        let tasks = bot.update(Action::MergeConflicts(vec![change.clone()]));
+        match &tasks[..] {
+            [Task::Reply(response), Task::Save] => {
+                assert_eq!(response.recipient, Recipient::Person(email.to_owned()));
+                assert!(response.message.contains("Merge conflict"));
             }
+            _ => panic!("unexpected tasks: {:?}", tasks),
         }
+        assert!(bot
+            .update(Action::MergeConflicts(vec![change.clone()]))
+            .is_empty());
+
+        // resolved, and conflicting again
+        assert!(matches!(
+            &bot.update(Action::MergeConflicts(Vec::new()))[..],
+            [Task::Save]
+        ));
+        assert_eq!(bot.update(Action::MergeConflicts(vec![change])).len(), 2);
     }
 
-    speculate! {
-        before {
-            let bot = new_bot();
+    #[test]
+    fn stale_reviews_are_reminded_per_owner() {
+        let mut bot = Builder::new(State::new())
+            .with_stale_review_reminders(3, None)
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let email = EmailRef::new("author@example.com");
+        bot.state.add_user(email);
+
+        let tasks = bot.update(Action::Tick(Utc::now()));
+        match &tasks[..] {
+            [Task::CheckStaleReviews(query)] => assert_eq!(
+                *query,
+                gerrit::Query::new("status:open -is:wip age:3d (owner:author@example.com)")
+            ),
+            _ => panic!("unexpected tasks: {:?}", tasks),
         }
 
-        describe "when a user is added" {
-            before {
-                let mut bot = bot;
-                bot.add_user("some@example.com");
+        let change = get_event().change;
+        let mut other_change = change.clone();
+        other_change.number += 1;
+        other_change.subject = "Another stale change".to_string();
+        let tasks = bot.update(Action::StaleReviews(vec![change.clone(), other_change]));
+        match &tasks[..] {
+            [Task::Reply(response)] => {
+                assert_eq!(response.recipient, Recipient::Person(email.to_owned()));
+                assert!(response.message.contains("3 day(s)"));
+                assert!(response.message.contains("Another stale change"));
             }
+            _ => panic!("unexpected tasks: {:?}", tasks),
+        }
 
-            before {
-                assert_that!(bot.state.users().count()).is_equal_to(1);
-            }
+        bot.state
+            .set_flag(email, UserFlag::NotifyStaleReviews, false);
+        assert!(bot.update(Action::StaleReviews(vec![change])).is_empty());
+    }
 
-            it "has the expected attributes" {
-                let user = bot.state.users().next().unwrap();
-                assert_that!(user).has_email("some@example.com");
-                assert_that!(user).has_email("some@example.com");
-                assert_that!(user).has_any_flag(NOTIFICATION_FLAGS);
-            }
+    #[test]
+    fn reviewers_are_reminded_until_they_vote() {
+        let mut bot = Builder::new(State::new())
+            .with_reviewer_reminders(2, Some(2))
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let email = EmailRef::new("approver@approvers.com");
+        bot.state.add_user(email);
 
-            test "enabled status response" {
-                let resp = bot.status_for(EmailRef::new("some@example.com"));
-                assert_that!(resp).is_some().contains("enabled");
-            }
+        let comment_added = get_event();
+        let reviewer_added = gerrit::ReviewerAddedEvent {
+            change: comment_added.change.clone(),
+            patchset: comment_added.patchset.clone(),
+            reviewer: comment_added.author.clone(),
+            created_on: comment_added.created_on,
+        };
+        let added = reviewer_added.created_on;
+        let tasks = bot.update(Action::ReviewerAdded(Box::new(reviewer_added.clone())));
+        assert!(tasks.iter().any(|task| matches!(task, Task::Save)));
 
-            test "disabled status response" {
-                bot.enable("some@example.com", false);
-                let resp = bot.status_for(EmailRef::new("some@example.com"));
-                assert_that!(resp).is_some().contains("disabled");
-            }
+        let is_reminder = |tasks: &[Task]| {
+            tasks.iter().any(|task| {
+                matches!(task, Task::Reply(response)
+                    if response.recipient == Recipient::Person(email.to_owned())
+                        && response.message.contains("didn't vote yet"))
+            })
+        };
+        assert!(!is_reminder(
+            &bot.update(Action::Tick(added + chrono::Duration::days(1)))
+        ));
+        let now = added + chrono::Duration::days(2);
+        assert!(is_reminder(&bot.update(Action::Tick(now))));
+        assert!(!is_reminder(
+            &bot.update(Action::Tick(now + chrono::Duration::days(3)))
+        ));
+        let now = now + chrono::Duration::days(4);
+        assert!(is_reminder(&bot.update(Action::Tick(now))));
+        // the maximum number of reminders is reached
+        assert!(!is_reminder(
+            &bot.update(Action::Tick(now + chrono::Duration::days(30)))
+        ));
 
-            test "existing user can be enabled" {
-                bot.enable("some@example.com", true);
-                let users: Vec<_> = bot.state.users().collect();
-                assert_that!(users)
-                    .has_item_matching(
-                        |u| u.email() == EmailRef::new("some@example.com")
-                            && u.has_any_flag(NOTIFICATION_FLAGS));
-                assert_that!(bot.state.users().count()).is_equal_to(1);
-            }
+        // voting ends the reminders
+        bot.update(Action::ReviewerAdded(Box::new(reviewer_added)));
+        bot.update(Action::CommentAdded(Box::new(comment_added)));
+        assert!(!is_reminder(
+            &bot.update(Action::Tick(added + chrono::Duration::days(2)))
+        ));
+    }
+
+    #[test]
+    fn get_comment_messages_for_topic_subscribers() {
+        let mut bot = new_bot();
+        bot.state.add_user(EmailRef::new("author@example.com"));
+        let watcher = EmailRef::new("watcher@example.com");
+        bot.state.add_user(watcher);
+        assert!(bot.state.subscribe_topic(watcher, "wayland"));
+        assert!(!bot.state.subscribe_topic(watcher, "wayland"));
+
+        // the change has no topic
+        let messages = bot.get_comment_messages(Box::new(get_event()));
+        assert_eq!(messages.len(), 1);
+
+        let mut event = get_event();
+        event.change.topic = Some("wayland".to_string());
+        let messages = bot.get_comment_messages(Box::new(event.clone()));
+        assert!(matches!(
+            &messages[..],
+            [(owner, _), (subscriber, message)]
+                if owner == EmailRef::new("author@example.com")
+                    && subscriber == watcher
+                    && message.contains("Some review.")
+        ));
+
+        assert!(bot.state.unsubscribe_topic(watcher, "wayland"));
+        let messages = bot.get_comment_messages(Box::new(event));
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn get_comment_messages_for_team_members() {
+        let admin = spark::Email::new("admin@example.com".to_string());
+        let mut bot = Builder::new(State::new())
+            .with_admins(vec![admin.clone()])
+            .build(TestGerritCommandRunner, TestSparkClient);
+        bot.state.add_user(EmailRef::new("author@example.com"));
+        let member = EmailRef::new("member@example.com");
+        let run = |bot: &mut TestBot, sender: &spark::Email, command: &str| {
+            bot.update(Action::RunCommand {
+                sender: sender.clone(),
+                room: None,
+                command: command.parse().unwrap(),
+            })
+        };
+
+        let tasks = run(
+            &mut bot,
+            &spark::Email::new("someone@example.com".to_string()),
+            "team demo add member@example.com",
+        );
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("Only bot admins")
+        ));
+        let tasks = run(&mut bot, &admin, "team demo subscribe project demo-project");
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("no team `demo`")
+        ));
+        run(&mut bot, &admin, "team demo add admin@example.com");
+        let tasks = run(&mut bot, &admin, "team demo subscribe project demo-project");
+        assert!(matches!(&tasks[..], [Task::Save, Task::Reply(_)]));
+
+        // the member joins after the subscription, and uses the bot
+        run(&mut bot, &admin, "team demo add member@example.com");
+        let messages = bot.get_comment_messages(Box::new(get_event()));
+        assert_eq!(messages.len(), 1);
+        bot.state.add_user(member);
+        let messages = bot.get_comment_messages(Box::new(get_event()));
+        assert!(matches!(
+            &messages[..],
+            [(owner, _), (team_member, message)]
+                if owner == EmailRef::new("author@example.com")
+                    && team_member == member
+                    && message.contains("Some review.")
+        ));
+
+        let tasks = run(&mut bot, &admin, "team demo");
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)]
+                if response.message.contains("admin@example.com, member@example.com")
+                    && response.message.contains("project `demo-project`")
+        ));
+        run(&mut bot, &admin, "team demo delete");
+        assert_eq!(bot.get_comment_messages(Box::new(get_event())).len(), 1);
+    }
+
+    #[test]
+    fn get_comment_messages_for_uploader() {
+        // the patchset was uploaded by someone else than the owner => message
+        // to the uploader only with the notify_uploaded_by_me flag
+        let mut bot = new_bot();
+        bot.state.add_user(EmailRef::new("author@example.com"));
+        let uploader = EmailRef::new("uploader@example.com");
+        bot.state.add_user(uploader);
+
+        let mut event = get_event();
+        event.patchset.uploader = gerrit::User {
+            name: Some("Uploader".to_string()),
+            username: Some("uploader".to_string()),
+            email: Some("uploader@example.com".to_string()),
+        };
+        let messages = bot.get_comment_messages(Box::new(event.clone()));
+        assert_eq!(messages.len(), 1);
 
-            test "existing can be disabled" {
-                bot.enable("some@example.com", false);
-                let users: Vec<_> = bot.state.users().collect();
-                assert_that!(users)
-                    .has_item_matching(
-                        |u| u.email() == EmailRef::new("some@example.com")
-                            && !u.has_any_flag(NOTIFICATION_FLAGS));
-                assert_that!(bot.state.users().count()).is_equal_to(1);
-            }
-        }
+        bot.state
+            .set_flag(uploader, UserFlag::NotifyUploadedByMe, true);
+        let messages = bot.get_comment_messages(Box::new(event));
+        assert!(matches!(
+            &messages[..],
+            [(owner, _), (recipient, message)]
+                if owner == EmailRef::new("author@example.com")
+                    && recipient == uploader
+                    && message.contains("Some review.")
+        ));
+    }
 
-        test "non-existing user is automatically added when enabled" {
-            assert_that!(bot.state.users().count()).is_equal_to(0);
-            let mut bot = bot;
-            bot.enable("some@example.com", true);
-            let users: Vec<_> = bot.state.users().collect();
-            assert_that!(users)
-                .has_item_matching(
-                    |u| u.email() == EmailRef::new("some@example.com")
+    #[test]
+    fn snoozed_notifications_summarized_afterwards() {
+        let mut bot = new_bot();
+        let author = EmailRef::new("author@example.com");
+        bot.state.add_user(author);
+        bot.state
+            .snooze(author, Utc::now() + chrono::Duration::hours(1));
 
-                        && u.has_any_flag(NOTIFICATION_FLAGS));
-            assert_that!(bot.state.users().count()).is_equal_to(1);
-        }
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(!tasks.iter().any(|task| matches!(task, Task::Reply(_))));
+        assert!(matches!(&tasks[..], [Task::Save]));
 
-        test "non-existing user is automatically added when disabled" {
-            assert_that!(bot.state.users().count()).is_equal_to(0);
-            let mut bot = bot;
-            bot.enable("some@example.com", false);
-            let users: Vec<_> = bot.state.users().collect();
-            assert_that!(users)
-                .has_item_matching(
-                    |u| u.email() == EmailRef::new("some@example.com")
-                        && !u.has_any_flag(NOTIFICATION_FLAGS));
-            assert_that!(bot.state.users().count()).is_equal_to(1);
-        }
+        // the snooze is not over yet
+        let tasks = bot.update(Action::Tick(Utc::now()));
+        assert!(tasks.is_empty());
 
-        test "unknown user gets disabled status response" {
-            let resp = bot.status_for(EmailRef::new("some_non_existent_id"));
-            assert_that!(resp).is_some().contains("disabled");
-        }
+        let tasks = bot.update(Action::Tick(Utc::now() + chrono::Duration::hours(2)));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Save, Task::Reply(response)]
+                if response.recipient == Recipient::Person(author.to_owned())
+                    && response.message.contains("you missed 1 notification(s)")
+                    && response.message.contains("Some review.")
+        ));
+        assert!(bot.state.find_user(author).unwrap().snooze().is_none());
+
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(tasks.iter().any(|task| matches!(task, Task::Reply(_))));
     }
 
-    const EVENT_JSON: &str = r#"
-{"author":{"name":"Approver","username":"approver","email":"approver@approvers.com"},"approvals":[{"type":"Code-Review","description":"Code-Review","value":"2","oldValue":"-1"}],"comment":"Patch Set 1: Code-Review+2\n\nJust a buggy script. FAILURE\n\nAnd more problems. FAILURE","patchSet":{"number":1,"revision":"49a65998c02eda928559f2d0b586c20bc8e37b10","parents":["fb1909b4eda306985d2bbce769310e5a50a98cf5"],"ref":"refs/changes/42/42/1","uploader":{"name":"Author","email":"author@example.com","username":"Author"},"createdOn":1494165142,"author":{"name":"Author","email":"author@example.com","username":"Author"},"isDraft":false,"kind":"REWORK","sizeInsertions":0,"sizeDeletions":0},"change":{"project":"demo-project","branch":"master","id":"Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14","number":49,"subject":"Some review.","owner":{"name":"Author","email":"author@example.com","username":"author"},"url":"http://localhost/42","commitMessage":"Some review.\n\nChange-Id: Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14\n","status":"NEW"},"project":"demo-project","refName":"refs/heads/master","changeKey":{"id":"Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14"},"type":"comment-added","eventCreatedOn":1499190282}"#;
+    #[test]
+    fn reset_needs_confirmation() {
+        let mut bot = new_bot();
+        let author = spark::Email::new("author@example.com".to_string());
+        bot.state
+            .set_flag(&author, UserFlag::NotifyReviewApprovals, false);
+        bot.state.subscribe_topic(&author, "release");
+        bot.state
+            .snooze(&author, Utc::now() + chrono::Duration::hours(1));
+        let reset = |confirmed| Action::RunCommand {
+            sender: author.clone(),
+            room: None,
+            command: Command::Reset { confirmed },
+        };
 
-    fn get_event() -> gerrit::CommentAddedEvent {
-        let event: Result<gerrit::Event, _> = serde_json::from_str(EVENT_JSON);
-        match event.expect("failed to decode event") {
-            gerrit::Event::CommentAdded(event) => event,
-            event => panic!("wrong type of event: {:?}", event),
-        }
+        let tasks = bot.update(reset(false));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("`reset confirm`")
+        ));
+        assert!(!bot
+            .state
+            .find_user(&author)
+            .unwrap()
+            .has_flag(UserFlag::NotifyReviewApprovals));
+
+        let tasks = bot.update(reset(true));
+        assert!(matches!(&tasks[..], [Task::Save, Task::Reply(_)]));
+        let user = bot.state.find_user(&author).unwrap();
+        assert!(user.has_flag(UserFlag::NotifyReviewApprovals));
+        assert_eq!(user.topics().count(), 0);
+        assert!(user.snooze().is_none());
     }
 
     #[test]
-    fn get_approvals_msg_for_empty_bot() {
-        // bot does not have the user => no message
+    fn test_command_previews_notification() {
         let mut bot = new_bot();
-        let res = bot.get_approvals_msg(Box::new(get_event()));
-        assert!(res.is_none());
+        let author = spark::Email::new("author@example.com".to_string());
+        let test = || Action::RunCommand {
+            sender: author.clone(),
+            room: None,
+            command: Command::Test,
+        };
+
+        let tasks = bot.update(test());
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("wouldn't be notified about reviews")
+        ));
+
+        bot.state.add_user(&author);
+        let tasks = bot.update(test());
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)]
+                if response.recipient == Recipient::Person(author.clone())
+                    && response.message.contains("Sample change to preview notifications")
+        ));
+
+        bot.state.add_filter(&author, "Sample Reviewer").unwrap();
+        let tasks = bot.update(test());
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("your filter drops")
+        ));
     }
 
     #[test]
-    fn get_approvals_msg_for_same_author_and_approver() {
-        // the approval is from the author => no message
+    fn settings_export_and_import() {
         let mut bot = new_bot();
-        bot.state.add_user(EmailRef::new("approver@example.com"));
-        let res = bot.get_approvals_msg(Box::new(get_event()));
-        assert!(res.is_none());
+        let author = spark::Email::new("author@example.com".to_string());
+        let teammate = spark::Email::new("teammate@example.com".to_string());
+        bot.state.add_filter(&author, "Verified").unwrap();
+        bot.state
+            .set_branches(&author, vec!["release/*".to_string()]);
+        bot.state
+            .set_flag(&author, UserFlag::NotifyReviewApprovals, false);
+        let command = |sender: &spark::Email, command| Action::RunCommand {
+            sender: sender.clone(),
+            room: None,
+            command,
+        };
+
+        let tasks = bot.update(command(&author, Command::ExportSettings));
+        let json = match &tasks[..] {
+            [Task::Reply(response)] => response
+                .message
+                .split("```json\n")
+                .nth(1)
+                .and_then(|json| json.split("\n```").next())
+                .expect("no settings in the reply")
+                .to_string(),
+            tasks => panic!("unexpected tasks: {:?}", tasks),
+        };
+
+        let tasks = bot.update(command(&teammate, Command::ImportSettings(json)));
+        assert!(matches!(&tasks[..], [Task::Save, Task::Reply(_)]));
+        let user = bot.state.find_user(&teammate).unwrap();
+        assert_eq!(user.branches(), ["release/*"]);
+        assert!(!user.has_flag(UserFlag::NotifyReviewApprovals));
+        assert_eq!(bot.state.get_filter(&teammate), Some(("Verified", true)));
+
+        let tasks = bot.update(command(
+            &teammate,
+            Command::ImportSettings(r#"{"filter": {"regex": "(", "enabled": true}}"#.to_string()),
+        ));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.starts_with("These are no valid settings")
+        ));
+        assert_eq!(bot.state.get_filter(&teammate), Some(("Verified", true)));
     }
 
     #[test]
-    fn get_approvals_msg_for_user_with_disabled_notifications() {
-        // the approval is for the user with disabled notifications
-        // => no message
+    fn replies_in_language_of_user() {
         let mut bot = new_bot();
-        bot.state.add_user(EmailRef::new("author@example.com"));
-        bot.enable("author@example.com", false);
-        let res = bot.get_approvals_msg(Box::new(get_event()));
-        assert!(res.is_none());
+        let author = spark::Email::new("author@example.com".to_string());
+        let command = |command| Action::RunCommand {
+            sender: author.clone(),
+            room: None,
+            command,
+        };
+
+        let tasks = bot.update(command(Command::SetLanguage("tlh".to_string())));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("`de`, `en`")
+        ));
+
+        let tasks = bot.update(command(Command::SetLanguage("de".to_string())));
+        assert!(matches!(&tasks[..], [Task::Save, Task::Reply(_)]));
+        assert_eq!(
+            bot.state.find_user(&author).and_then(User::language),
+            Some("de")
+        );
+        let tasks = bot.update(command(Command::Disable));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Save, Task::Reply(response)]
+                if response.message == "Alles klar! Ich bin ab jetzt still."
+        ));
+
+        // the default language is not stored
+        bot.update(command(Command::SetLanguage("en".to_string())));
+        assert_eq!(bot.state.find_user(&author).and_then(User::language), None);
     }
 
     #[test]
-    fn get_approvals_msg_for_user_with_enabled_notifications() {
-        // the approval is for the user with enabled notifications
-        // => message
+    fn notifications_held_during_quiet_hours() {
         let mut bot = new_bot();
-        bot.state.add_user(EmailRef::new("author@example.com"));
-        let res = bot.get_approvals_msg(Box::new(get_event()));
-        assert!(res.is_some());
-        let (email, msg) = res.unwrap();
-        assert_eq!(email, EmailRef::new("author@example.com"));
-        assert!(msg.contains("Some review."));
+        let author = EmailRef::new("author@example.com");
+        bot.state.add_user(author);
+        let hour = Utc::now().hour();
+        let quiet_hours = format!("{}-{}", hour, (hour + 2) % 24).parse().unwrap();
+        bot.state.set_quiet_hours(author, Some(quiet_hours));
+
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(matches!(&tasks[..], [Task::Save]));
+        assert_eq!(
+            bot.state
+                .find_user(author)
+                .unwrap()
+                .held_notifications()
+                .len(),
+            1
+        );
+
+        // still quiet
+        let tasks = bot.update(Action::Tick(Utc::now()));
+        assert!(tasks.is_empty());
+
+        let tasks = bot.update(Action::Tick(Utc::now() + chrono::Duration::hours(3)));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Save, Task::Reply(response)]
+                if response.recipient == Recipient::Person(author.to_owned())
+                    && response.message.contains("Your quiet hours are over")
+                    && response.message.contains("Some review.")
+        ));
+        assert!(bot
+            .state
+            .find_user(author)
+            .unwrap()
+            .held_notifications()
+            .is_empty());
     }
 
     #[test]
-    fn get_approvals_msg_for_user_with_enabled_notifications_and_filter() {
-        // the approval is for the user with enabled notifications
-        // => message
+    fn notifications_collected_for_digest() {
         let mut bot = new_bot();
-        bot.state.add_user(EmailRef::new("author@example.com"));
+        let author = EmailRef::new("author@example.com");
+        bot.state.add_user(author);
+        let now = Utc::now();
+        let time = (now + chrono::Duration::hours(1)).num_seconds_from_midnight() / 60;
+        bot.state.set_digest(author, Some(time), now);
 
-        {
-            let res = bot
-                .state
-                .add_filter(EmailRef::new("author@example.com"), ".*Code-Review.*");
-            assert!(res.is_ok());
-            let res = bot.get_approvals_msg(Box::new(get_event()));
-            assert!(res.is_none());
-        }
-        {
-            let res = bot
-                .state
-                .enable_and_get_filter(EmailRef::new("author@example.com"), false);
-            assert!(res.is_some());
-            let res = bot.get_approvals_msg(Box::new(get_event()));
-            assert!(res.is_some());
-            let (user, msg) = res.unwrap();
-            assert_eq!(user, EmailRef::new("author@example.com"));
-            assert!(msg.contains("Some review."));
-        }
-        {
-            let res = bot
-                .state
-                .enable_and_get_filter(EmailRef::new("author@example.com"), true);
-            assert!(res.is_some());
-            let res = bot.state.add_filter(
-                EmailRef::new("author@example.com"),
-                "some_non_matching_filter",
-            );
-            assert!(res.is_ok());
-            let res = bot.get_approvals_msg(Box::new(get_event()));
-            assert!(res.is_some());
-            let (email, msg) = res.unwrap();
-            assert_eq!(email, EmailRef::new("author@example.com"));
-            assert!(msg.contains("Some review."));
-        }
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(matches!(&tasks[..], [Task::Save]));
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(matches!(&tasks[..], [Task::Save]));
+
+        // the digest is not due yet
+        let tasks = bot.update(Action::Tick(now + chrono::Duration::minutes(30)));
+        assert!(tasks.is_empty());
+
+        let tasks = bot.update(Action::Tick(now + chrono::Duration::minutes(90)));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Save, Task::Reply(response)]
+                if response.recipient == Recipient::Person(author.to_owned())
+                    && response.message.starts_with(
+                        "Your daily digest with 2 notification(s) about 1 change(s):"
+                    )
+                    && response.message.contains("Some review.")
+        ));
+        assert_eq!(
+            bot.state
+                .find_user(author)
+                .unwrap()
+                .digest()
+                .unwrap()
+                .count(),
+            0
+        );
+
+        // nothing to send on the next day
+        let tasks = bot.update(Action::Tick(now + chrono::Duration::hours(26)));
+        assert!(tasks.is_empty());
     }
 
     #[test]
@@ -997,13 +5358,603 @@ mod test {
     }
 
     #[test]
-    fn test_maybe_has_inline_comments() {
+    fn gerrit_event_updates_last_event_created_on() {
+        let mut bot = new_bot();
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert_eq!(
+            bot.state.last_event_created_on(),
+            gerrit::timestamp::from_epoch_seconds(1499190282)
+        );
+        assert!(tasks.iter().any(|task| matches!(task, Task::Save)));
+
+        // the same event again doesn't need to be saved
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(!tasks.iter().any(|task| matches!(task, Task::Save)));
+    }
+
+    #[test]
+    fn change_merged_msg_for_reviewer() {
+        let mut bot = new_bot();
+        bot.state.set_flag(
+            EmailRef::new("reviewer@example.com"),
+            UserFlag::NotifyChangeMerged,
+            true,
+        );
+
+        let comment_event = get_event();
+        let reviewer = gerrit::User {
+            name: Some("Reviewer".to_string()),
+            username: Some("reviewer".to_string()),
+            email: Some("reviewer@example.com".to_string()),
+        };
+        let mut change = comment_event.change;
+        change.all_reviewers = Some(vec![reviewer.clone(), reviewer.clone()]);
+        bot.change_tracker.add_participant(&change, &reviewer);
+        let event = gerrit::ChangeMergedEvent {
+            change,
+            patchset: comment_event.patchset,
+            submitter: comment_event.author,
+            new_revision: "49a65998c02eda928559f2d0b586c20bc8e37b10".to_string(),
+            created_on: comment_event.created_on,
+        };
+
+        let messages = bot.get_change_merged_messages(&event);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, EmailRef::new("reviewer@example.com"));
+    }
+
+    #[test]
+    fn change_merged_msg_for_cc() {
+        // a reviewer of the change that wasn't seen being added, commenting or
+        // voting is CC'd => message only with the notify_cc flag
+        let mut bot = new_bot();
+        let cc = EmailRef::new("cc@example.com");
+        bot.state.set_flag(cc, UserFlag::NotifyChangeMerged, true);
+
+        let comment_event = get_event();
+        let mut change = comment_event.change;
+        change.all_reviewers = Some(vec![gerrit::User {
+            name: Some("CC".to_string()),
+            username: Some("cc".to_string()),
+            email: Some("cc@example.com".to_string()),
+        }]);
+        let event = gerrit::ChangeMergedEvent {
+            change,
+            patchset: comment_event.patchset,
+            submitter: comment_event.author,
+            new_revision: "49a65998c02eda928559f2d0b586c20bc8e37b10".to_string(),
+            created_on: comment_event.created_on,
+        };
+
+        assert!(bot.get_change_merged_messages(&event).is_empty());
+
+        bot.state.set_flag(cc, UserFlag::NotifyCc, true);
+        let messages = bot.get_change_merged_messages(&event);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, cc);
+    }
+
+    #[test]
+    fn change_merged_msg_with_project_flags() {
+        let project_flags = ProjectFlags::new(
+            None,
+            Some("^release/"),
+            &["notify_change_merged".to_string()],
+        )
+        .unwrap();
+        let mut bot = Builder::new(State::new())
+            .with_project_flags(vec![project_flags])
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let owner = EmailRef::new("author@example.com");
+        bot.state.add_user(owner);
+
+        let comment_event = get_event();
+        let mut event = gerrit::ChangeMergedEvent {
+            change: comment_event.change,
+            patchset: comment_event.patchset,
+            submitter: comment_event.author,
+            new_revision: "49a65998c02eda928559f2d0b586c20bc8e37b10".to_string(),
+            created_on: comment_event.created_on,
+        };
+        assert!(bot.get_change_merged_messages(&event).is_empty());
+
+        event.change.branch = "release/1.0".to_string();
+        let messages = bot.get_change_merged_messages(&event);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, owner);
+
+        // users who changed their flags don't get the project flags
+        bot.state
+            .set_flag(owner, UserFlag::NotifyReviewComments, true);
+        assert!(bot.get_change_merged_messages(&event).is_empty());
+    }
+
+    #[test]
+    fn change_merged_msg_for_tracked_commenter() {
+        let mut bot = new_bot();
+        bot.state.set_flag(
+            EmailRef::new("approver@approvers.com"),
+            UserFlag::NotifyChangeMerged,
+            true,
+        );
+
+        let comment_event = get_event();
+        bot.update(Action::CommentAdded(Box::new(comment_event.clone())));
+
+        let event = gerrit::ChangeMergedEvent {
+            submitter: comment_event.change.owner.clone(),
+            change: comment_event.change,
+            patchset: comment_event.patchset,
+            new_revision: "49a65998c02eda928559f2d0b586c20bc8e37b10".to_string(),
+            created_on: comment_event.created_on,
+        };
+
+        let tasks = bot.update(Action::ChangeMerged(Box::new(event.clone())));
+        assert!(tasks.iter().any(|task| matches!(
+            task,
+            Task::Reply(response) if response.recipient == Recipient::Person(EmailRef::new("approver@approvers.com").to_owned())
+        )));
+
+        // participants of merged changes are forgotten
+        let tasks = bot.update(Action::ChangeMerged(Box::new(event)));
+        assert!(!tasks.iter().any(|task| matches!(task, Task::Reply(_))));
+    }
+
+    #[test]
+    fn change_deleted_msg_for_tracked_commenter() {
+        let mut bot = new_bot();
+        bot.state.set_flag(
+            EmailRef::new("approver@approvers.com"),
+            UserFlag::NotifyChangeAbandoned,
+            true,
+        );
+
+        let comment_event = get_event();
+        bot.update(Action::CommentAdded(Box::new(comment_event.clone())));
+
+        let event = gerrit::ChangeDeletedEvent {
+            deleter: comment_event.change.owner.clone(),
+            change: comment_event.change,
+            created_on: comment_event.created_on,
+        };
+
+        let tasks = bot.update(Action::ChangeDeleted(Box::new(event.clone())));
+        assert!(tasks.iter().any(|task| matches!(
+            task,
+            Task::Reply(response) if response.recipient == Recipient::Person(EmailRef::new("approver@approvers.com").to_owned())
+                && response.message.contains("Deleted by")
+        )));
+
+        // participants of deleted changes are forgotten
+        let tasks = bot.update(Action::ChangeDeleted(Box::new(event)));
+        assert!(!tasks.iter().any(|task| matches!(task, Task::Reply(_))));
+    }
+
+    #[test]
+    fn notification_threaded_under_first_message() {
+        let mut bot = new_bot();
+        let approver = EmailRef::new("approver@approvers.com");
+        bot.state
+            .set_flag(approver, UserFlag::NotifyChangeAbandoned, true);
+
+        let comment_event = get_event();
+        let change_number = comment_event.change.number;
+        bot.update(Action::CommentAdded(Box::new(comment_event.clone())));
+        assert!(bot.change_tracker.thread(change_number, approver).is_none());
+
+        let parent_id = spark::MessageId::new("first-message".to_string());
+        bot.change_tracker
+            .add_thread(change_number, approver.to_owned(), parent_id.clone());
+
+        let event = gerrit::ChangeDeletedEvent {
+            deleter: comment_event.change.owner.clone(),
+            change: comment_event.change,
+            created_on: comment_event.created_on,
+        };
+        let tasks = bot.update(Action::ChangeDeleted(Box::new(event)));
+        assert!(tasks.iter().any(|task| matches!(
+            task,
+            Task::Reply(response) if response.recipient == Recipient::Person(approver.to_owned())
+                && response.change_number == Some(change_number)
+                && response.parent_id.as_ref() == Some(&parent_id)
+        )));
+
+        // threads of deleted changes are forgotten
+        assert!(bot.change_tracker.thread(change_number, approver).is_none());
+    }
+
+    #[test]
+    fn notification_with_message_parts() {
+        let mut bot = Builder::new(State::new())
+            .with_format_script(
+                r#"
+                function format_comment_added(event, flags)
+                    return {
+                        markdown = "**" .. event.change.subject .. "**",
+                        text = event.change.subject,
+                        card = { type = "AdaptiveCard" },
+                    }
+                end
+                "#,
+            )
+            .unwrap()
+            .with_adaptive_cards()
+            .build(TestGerritCommandRunner, TestSparkClient);
+        bot.state.add_user(EmailRef::new("author@example.com"));
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response), Task::Save] if response.message == "**Some review.**"
+                && response.text.as_deref() == Some("Some review.")
+                && response.card == Some(serde_json::json!({ "type": "AdaptiveCard" }))
+        ));
+    }
+
+    #[test]
+    fn abandoned_notification_appended_to_first_message() {
+        let mut bot = Builder::new(State::new())
+            .with_edited_notifications()
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let approver = EmailRef::new("approver@approvers.com");
+        bot.state
+            .set_flag(approver, UserFlag::NotifyChangeAbandoned, true);
+
+        let comment_event = get_event();
+        let change_number = comment_event.change.number;
+        bot.update(Action::CommentAdded(Box::new(comment_event.clone())));
+
+        let first_id = spark::MessageId::new("first-message".to_string());
+        bot.change_tracker
+            .add_thread(change_number, approver.to_owned(), first_id.clone());
+
+        let event = gerrit::ChangeAbandonedEvent {
+            abandoner: comment_event.change.owner.clone(),
+            change: comment_event.change,
+            patchset: comment_event.patchset,
+            reason: None,
+            created_on: comment_event.created_on,
+        };
+        let tasks = bot.update(Action::ChangeAbandoned(Box::new(event)));
+        assert!(tasks.iter().any(|task| matches!(
+            task,
+            Task::Reply(response) if response.recipient == Recipient::Person(approver.to_owned())
+                && response.appended_to.as_ref() == Some(&first_id)
+                && response.parent_id.is_none()
+        )));
+    }
+
+    fn person(id: &str, email: &str) -> spark::PersonDetails {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "emails": [email],
+            "displayName": "Some Person",
+            "orgId": "org-id",
+            "created": "2019-03-26T20:23:32.000Z",
+            "type": "person",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn verify_user_stores_person_id() {
+        let mut bot = new_bot();
+        let email = spark::Email::new("author@example.com".to_string());
+        let tasks = bot.update(Action::RunCommand {
+            sender: email.clone(),
+            room: None,
+            command: Command::Enable,
+        });
+        assert!(tasks
+            .iter()
+            .any(|task| matches!(task, Task::VerifyUser(e) if *e == email)));
+
+        let tasks = bot.verify_user(email.clone(), Some(person("person-id", email.as_str())));
+        assert!(matches!(&tasks[..], [Task::Save]));
+        assert_eq!(
+            bot.state.find_user(&email).unwrap().person_id(),
+            Some(spark::PersonIdRef::new("person-id"))
+        );
+    }
+
+    #[test]
+    fn verify_user_warns_about_unknown_email() {
+        let mut bot = new_bot();
+        let email = spark::Email::new("author@example.com".to_string());
+        bot.update(Action::RunCommand {
+            sender: email.clone(),
+            room: None,
+            command: Command::Enable,
+        });
+
+        let tasks = bot.verify_user(email.clone(), None);
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("could not find")
+        ));
+
+        let tasks = bot.verify_user(
+            email.clone(),
+            Some(person("person-id", "other@example.com")),
+        );
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response), Task::Save] if response.message.contains("other@example.com")
+        ));
+    }
+
+    #[test]
+    fn command_in_group_room_answered_in_room() {
+        let mut bot = new_bot();
+        let room_id = spark::RoomId::new("group-room".to_string());
+        let action = spark_message_to_action(
+            spark::Message {
+                person_email: spark::Email::new("author@example.com".to_string()),
+                room_id: room_id.clone(),
+                room_type: spark::RoomType::Group,
+                text: "Gerrit Bot enable".to_string(),
+                html: Some(
+                    "<p><spark-mention data-object-type=\"person\" data-object-id=\"bot-id\">\
+                 Gerrit Bot</spark-mention> enable</p>"
+                        .to_string(),
+                ),
+                ..Default::default()
+            },
+            &Aliases::default(),
+        );
+
+        let tasks = bot.update(action);
+        assert!(tasks.iter().any(|task| matches!(
+            task,
+            Task::Reply(response) if response.recipient == Recipient::Room(room_id.clone())
+        )));
+        assert!(bot
+            .state
+            .find_user(EmailRef::new("author@example.com"))
+            .is_some());
+    }
+
+    #[test]
+    fn bot_added_to_room_sends_greeting() {
+        let mut bot = new_bot();
+        let room_id = spark::RoomId::new("new-room".to_string());
+        let action = spark_event_to_action(
+            spark::Event::Membership(spark::Membership {
+                room_id: room_id.clone(),
+                room_type: Some(spark::RoomType::Direct),
+                ..Default::default()
+            }),
+            &Aliases::default(),
+        )
+        .unwrap();
+
+        let tasks = bot.update(action);
+        assert_eq!(tasks.len(), 1);
+        assert!(matches!(
+            &tasks[0],
+            Task::Reply(response) if response.recipient == Recipient::Room(room_id.clone())
+        ));
+    }
+
+    #[test]
+    fn card_action_runs_command() {
+        let card_action = |inputs| {
+            spark::Event::AttachmentAction(spark::AttachmentAction {
+                id: spark::AttachmentActionId::new("action".to_string()),
+                action_type: "submit".to_string(),
+                message_id: Default::default(),
+                person_id: Default::default(),
+                room_id: spark::RoomId::new("direct-room".to_string()),
+                created: None,
+                inputs,
+                person_email: spark::Email::new("author@example.com".to_string()),
+            })
+        };
+
+        let aliases = Aliases::new(vec![("who".to_string(), "status".to_string())]);
+        let action = spark_event_to_action(
+            card_action(serde_json::json!({ "command": "who" })),
+            &aliases,
+        );
+        assert!(matches!(
+            action,
+            Some(Action::RunCommand {
+                room: None,
+                command: Command::Status,
+                ..
+            })
+        ));
+
+        assert!(spark_event_to_action(
+            card_action(serde_json::json!({ "command": "foo" })),
+            &aliases
+        )
+        .is_none());
+        assert!(spark_event_to_action(card_action(serde_json::json!({})), &aliases).is_none());
+    }
+
+    #[test]
+    fn subscribed_room_gets_review_activity() {
+        let admin = spark::Email::new("admin@example.com".to_string());
+        let room_id = spark::RoomId::new("team-room".to_string());
+        let mut bot = Builder::new(State::new())
+            .with_admins(vec![admin.clone()])
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let subscribe = |sender: &spark::Email| Action::RunCommand {
+            sender: sender.clone(),
+            room: Some(CommandRoom {
+                id: room_id.clone(),
+                room_type: spark::RoomType::Group,
+            }),
+            command: Command::SubscribeRoom("demo-project".to_string()),
+        };
+
+        // only admins may subscribe rooms
+        bot.update(subscribe(&spark::Email::new(
+            "author@example.com".to_string(),
+        )));
+        assert!(bot.state.find_room(&room_id).is_none());
+
+        let tasks = bot.update(subscribe(&admin));
+        assert!(matches!(tasks[0], Task::Save));
+        assert!(bot.state.find_room(&room_id).is_some());
+
+        let tasks = bot.update(Action::CommentAdded(Box::new(get_event())));
+        assert!(tasks.iter().any(|task| matches!(
+            task,
+            Task::Reply(response) if response.recipient == Recipient::Room(room_id.clone())
+                && response.message.contains("Code-Review")
+        )));
+    }
+
+    #[test]
+    fn admin_stats_only_for_admins() {
+        let admin = spark::Email::new("admin@example.com".to_string());
+        let mut state = State::new();
+        state.set_flag(&admin, UserFlag::NotifyChangeMerged, true);
+        let mut bot = Builder::new(state)
+            .with_admins(vec![admin.clone()])
+            .build(TestGerritCommandRunner, TestSparkClient);
+        let stats = |sender: &spark::Email| Action::RunCommand {
+            sender: sender.clone(),
+            room: None,
+            command: Command::AdminStats,
+        };
+
+        let tasks = bot.update(stats(&spark::Email::new("author@example.com".to_string())));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.starts_with("⛔ Permission denied. Only bot admins")
+        ));
+
+        // admins can make other users admins
+        let author = spark::Email::new("author@example.com".to_string());
+        let set_role = |role| Command::SetRole {
+            email: author.clone(),
+            role,
+        };
+        let tasks = bot.update(Action::RunCommand {
+            sender: admin.clone(),
+            room: None,
+            command: set_role(Role::Admin),
+        });
+        assert!(matches!(tasks[0], Task::Save));
+        assert!(
+            matches!(&bot.update(stats(&author))[..], [Task::Reply(response)]
+            if response.message.starts_with("**Bot statistics**"))
+        );
+        bot.update(Action::RunCommand {
+            sender: admin.clone(),
+            room: None,
+            command: set_role(Role::User),
+        });
+        assert!(!bot.is_admin(&author));
+
+        bot.update(Action::CommentAdded(Box::new(get_event())));
+        let tasks = bot.update(stats(&admin));
+        assert!(matches!(
+            &tasks[..],
+            [Task::Reply(response)] if response.message.contains("Users: 2, 2 with notifications enabled, 2 active in the last 7 days")
+                && response.message.contains("* `notify_change_merged`: 1")
+                && response.message.contains("Gerrit events processed: 1 (0 stream errors)")
+                && response.message.contains("✅ healthy")
+        ));
+    }
+
+    #[test]
+    fn inline_comments_requested_for_human_comments() {
         let mut event = get_event();
-        event.comment = "PatchSet 666: (2 comments)".to_string();
-        assert!(maybe_has_inline_comments(&event));
+        event.comment = "Patch Set 1:\n\n(2 comments)".to_string();
+        let bot_event = event.clone();
+        let event = gerrit::Event::CommentAdded(event);
+        assert!(
+            request_extended_gerrit_info(&event).contains(&gerrit::ExtendedInfo::InlineComments)
+        );
+
+        let event = gerrit::Event::CommentAdded(get_event());
+        assert!(
+            !request_extended_gerrit_info(&event).contains(&gerrit::ExtendedInfo::InlineComments)
+        );
+
+        let mut bot_event = bot_event;
+        bot_event.author.username = Some("ci-bot".to_string());
+        let event = gerrit::Event::CommentAdded(bot_event);
+        assert!(
+            !request_extended_gerrit_info(&event).contains(&gerrit::ExtendedInfo::InlineComments)
+        );
+    }
+
+    #[test]
+    fn replies_are_not_held_up_by_gerrit_queries() {
+        type Signal = std::sync::Arc<std::sync::Mutex<Option<futures::sync::oneshot::Sender<()>>>>;
+
+        /// Queries finish only after the signal.
+        struct SlowGerritCommandRunner(
+            std::sync::Arc<std::sync::Mutex<Option<futures::sync::oneshot::Receiver<()>>>>,
+        );
+        impl GerritCommandRunner for SlowGerritCommandRunner {
+            type QueryFuture =
+                Box<dyn Future<Item = Vec<gerrit::Change>, Error = gerrit::Error> + Send>;
+            type CommandFuture = future::FutureResult<String, gerrit::Error>;
+            fn query(&mut self, _query: &gerrit::Query) -> Self::QueryFuture {
+                let signal = self.0.lock().unwrap().take().unwrap();
+                Box::new(signal.then(|_| Ok(Vec::new())))
+            }
+            fn run_command(&mut self, _command: String) -> Self::CommandFuture {
+                future::ok(String::new())
+            }
+        }
+
+        /// Signals the queries to finish once the first message was sent.
+        #[derive(Clone)]
+        struct RecordingSparkClient {
+            messages: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+            signal: Signal,
+        }
+        impl SparkClient for RecordingSparkClient {
+            type ReplyFuture = future::FutureResult<Option<spark::MessageId>, spark::Error>;
+            fn send_message(&self, _email: &EmailRef, msg: &str) -> Self::ReplyFuture {
+                self.messages.borrow_mut().push(msg.to_string());
+                if let Some(signal) = self.signal.lock().unwrap().take() {
+                    let _ = signal.send(());
+                }
+                future::ok(None)
+            }
+            fn send_room_message(
+                &self,
+                _room_id: &spark::RoomIdRef,
+                msg: &str,
+            ) -> Self::ReplyFuture {
+                self.send_message(EmailRef::new("room@example.com"), msg)
+            }
+        }
+
+        let (signal, query_signal) = futures::sync::oneshot::channel();
+        let spark_client = RecordingSparkClient {
+            messages: Default::default(),
+            signal: std::sync::Arc::new(std::sync::Mutex::new(Some(signal))),
+        };
+        let gerrit_command_runner = SlowGerritCommandRunner(std::sync::Arc::new(
+            std::sync::Mutex::new(Some(query_signal)),
+        ));
+        let bot = Builder::new(State::new()).build(gerrit_command_runner, spark_client.clone());
 
-        event.comment = "Nope, colleague comment!".to_string();
-        assert!(!maybe_has_inline_comments(&event));
+        let spark_events = stream::iter_ok(vec!["show 42", "status"]).map(|text| {
+            spark::Event::Message(spark::Message {
+                person_email: spark::EmailRef::new("some@example.com").to_owned(),
+                text: text.to_string(),
+                ..Default::default()
+            })
+        });
+        let run = tokio::timer::Timeout::new(
+            bot.run(stream::empty(), spark_events),
+            Duration::from_secs(5),
+        );
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        assert!(runtime.block_on(run).is_ok());
+
+        let messages = spark_client.messages.replace(Vec::new());
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].contains("42"));
     }
 
     #[test]
@@ -1017,30 +5968,36 @@ mod test {
         }
 
         impl SparkClient for TestSparkClient {
-            type ReplyFuture = future::FutureResult<(), spark::Error>;
+            type ReplyFuture = future::FutureResult<Option<spark::MessageId>, spark::Error>;
             fn send_message(&self, _email: &EmailRef, _msg: &str) -> Self::ReplyFuture {
                 self.message_count.set(self.message_count.get() + 1);
 
-                future::err(spark::Error::IoError(std::io::Error::new(
-                    std::io::ErrorKind::Other,
+                future::err(spark::Error::IoError(std::io::Error::other(
                     "it did not work",
                 )))
             }
+            fn send_room_message(
+                &self,
+                _room_id: &spark::RoomIdRef,
+                msg: &str,
+            ) -> Self::ReplyFuture {
+                self.send_message(EmailRef::new("room@example.com"), msg)
+            }
         }
 
         let spark_client = TestSparkClient::default();
 
         let bot = Builder::new(State::new()).build(TestGerritCommandRunner, spark_client.clone());
 
-        let spark_messages = stream::repeat(spark::Message {
+        let spark_events = stream::repeat(spark::Event::Message(spark::Message {
             person_email: spark::EmailRef::new("some@example.com").to_owned(),
             text: "status".to_string(),
             ..Default::default()
-        })
+        }))
         .take(7);
         let gerrit_events = stream::empty();
 
-        assert_eq!(bot.run(gerrit_events, spark_messages).wait(), Ok(()));
+        assert_eq!(bot.run(gerrit_events, spark_events).wait(), Ok(()));
         assert_eq!(spark_client.message_count.get(), 7);
     }
 }
@@ -0,0 +1,57 @@
+use regex::Regex;
+
+use gerritbot_gerrit as gerrit;
+
+use crate::state::UserFlag;
+
+/// Notification flags enabled by default for changes of matching projects
+/// and branches. They apply to the users who didn't change their flags.
+#[derive(Debug, Clone)]
+pub struct ProjectFlags {
+    project: Option<Regex>,
+    branch: Option<Regex>,
+    flags: Vec<UserFlag>,
+}
+
+impl ProjectFlags {
+    /// Create the rule from regular expressions for the project and branch,
+    /// which match anything if not given, and the names of the flags.
+    pub fn new(
+        project: Option<&str>,
+        branch: Option<&str>,
+        flags: &[String],
+    ) -> Result<Self, String> {
+        let regex = |pattern: Option<&str>| {
+            pattern
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| format!("invalid project flags pattern: {}", e))
+        };
+        let flags = flags
+            .iter()
+            .map(|flag| {
+                flag.parse()
+                    .map_err(|_| format!("unknown flag in project flags: {}", flag))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            project: regex(project)?,
+            branch: regex(branch)?,
+            flags,
+        })
+    }
+
+    pub fn flags(&self) -> &[UserFlag] {
+        &self.flags
+    }
+
+    pub fn matches(&self, change: &gerrit::Change) -> bool {
+        self.project
+            .as_ref()
+            .is_none_or(|project| project.is_match(&change.project))
+            && self
+                .branch
+                .as_ref()
+                .is_none_or(|branch| branch.is_match(&change.branch))
+    }
+}
@@ -3,7 +3,7 @@ use std::time::Duration;
 use lru_time_cache::LruCache;
 
 use gerritbot_gerrit as gerrit;
-use gerritbot_spark::Email;
+use gerritbot_spark::{Email, EmailRef};
 
 use super::state::User;
 
@@ -35,6 +35,55 @@ impl RateLimiter {
             })
             .is_some()
     }
+
+    /// Whether an invitation to use the bot was sent to the email recently.
+    pub fn limit_invitation(&mut self, email: &EmailRef) -> bool {
+        self.cache
+            .as_mut()
+            .and_then(|cache| {
+                cache.insert(
+                    MsgCacheLine::Invitation {
+                        email: email.to_owned(),
+                    },
+                    (),
+                )
+            })
+            .is_some()
+    }
+
+    /// Whether the user was told recently that the change is submittable.
+    pub fn limit_submittable(&mut self, user: &User, change: &gerrit::Change) -> bool {
+        self.cache
+            .as_mut()
+            .and_then(|cache| {
+                cache.insert(
+                    MsgCacheLine::Submittable {
+                        email: user.email().to_owned(),
+                        change_number: change.number,
+                    },
+                    (),
+                )
+            })
+            .is_some()
+    }
+
+    /// Drop all cached messages about the change.
+    pub fn forget(&mut self, change: &gerrit::Change) {
+        if let Some(cache) = self.cache.as_mut() {
+            let subject = Subject::from_change(change);
+            let keys: Vec<MsgCacheLine> = cache
+                .peek_iter()
+                .map(|(key, _)| key)
+                .filter(|key| {
+                    key.subject() == Some(&subject) || key.change_number() == Some(change.number)
+                })
+                .cloned()
+                .collect();
+            for key in keys {
+                cache.remove(&key);
+            }
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -72,6 +121,30 @@ pub enum MsgCacheLine {
         email: Email,
         subject: Subject,
     },
+    Invitation {
+        email: Email,
+    },
+    Submittable {
+        email: Email,
+        change_number: u32,
+    },
+}
+
+impl MsgCacheLine {
+    fn subject(&self) -> Option<&Subject> {
+        match self {
+            MsgCacheLine::Approvals { subject, .. }
+            | MsgCacheLine::ReviewerAdded { subject, .. } => Some(subject),
+            MsgCacheLine::Invitation { .. } | MsgCacheLine::Submittable { .. } => None,
+        }
+    }
+
+    fn change_number(&self) -> Option<u32> {
+        match self {
+            MsgCacheLine::Submittable { change_number, .. } => Some(*change_number),
+            _ => None,
+        }
+    }
 }
 
 pub trait IntoCacheLine {
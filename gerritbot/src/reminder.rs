@@ -0,0 +1,118 @@
+use chrono::{DateTime, Duration, Utc};
+
+use gerritbot_gerrit as gerrit;
+
+/// Schedule of the reminders about open changes without any activity for a
+/// number of days.
+#[derive(Debug, Clone)]
+pub struct StaleReviewReminder {
+    /// Days without activity after which a change is stale.
+    days: u32,
+    /// Time between two checks for stale changes.
+    interval: Duration,
+    last_check: Option<DateTime<Utc>>,
+}
+
+impl StaleReviewReminder {
+    pub fn new(days: u32, interval: Duration) -> Self {
+        Self {
+            days,
+            interval,
+            last_check: None,
+        }
+    }
+
+    pub fn days(&self) -> u32 {
+        self.days
+    }
+
+    /// Query for the stale changes of the given owners, if the last check is
+    /// long enough ago.
+    pub fn check<I>(&mut self, now: DateTime<Utc>, owners: I) -> Option<gerrit::Query>
+    where
+        I: IntoIterator,
+        I::Item: std::fmt::Display,
+    {
+        if self
+            .last_check
+            .is_some_and(|last_check| now < last_check + self.interval)
+        {
+            return None;
+        }
+        let owners: Vec<String> = owners
+            .into_iter()
+            .map(|owner| format!("owner:{}", owner))
+            .collect();
+        if owners.is_empty() {
+            return None;
+        }
+        self.last_check = Some(now);
+        Some(gerrit::Query::new(format!(
+            "status:open -is:wip age:{}d ({})",
+            self.days,
+            owners.join(" OR ")
+        )))
+    }
+}
+
+/// Schedule of the reminders for reviewers who didn't vote on a change. The
+/// time between the reminders doubles, until the maximum number of reminders
+/// is reached.
+#[derive(Debug, Clone, Copy)]
+pub struct ReviewerReminderSchedule {
+    /// Days after which the first reminder is sent.
+    days: u32,
+    /// Number of reminders sent at most.
+    max: u32,
+}
+
+impl ReviewerReminderSchedule {
+    pub fn new(days: u32, max: u32) -> Self {
+        Self { days, max }
+    }
+
+    /// Time until the next reminder, after the number of reminders sent so
+    /// far. `None` if enough reminders were sent.
+    pub fn delay(&self, sent: u32) -> Option<Duration> {
+        if sent >= self.max {
+            return None;
+        }
+        let factor = 2i64.checked_pow(sent)?;
+        Some(Duration::days(i64::from(self.days).checked_mul(factor)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone as _;
+
+    use super::*;
+
+    #[test]
+    fn check() {
+        let now = Utc.ymd(2020, 2, 28).and_hms(12, 0, 0);
+        let mut reminder = StaleReviewReminder::new(3, Duration::hours(24));
+        assert!(reminder.check(now, Vec::<String>::new()).is_none());
+        assert_eq!(
+            reminder.check(now, vec!["a@example.com", "b@example.com"]),
+            Some(gerrit::Query::new(
+                "status:open -is:wip age:3d (owner:a@example.com OR owner:b@example.com)"
+            ))
+        );
+        assert!(reminder
+            .check(now + Duration::hours(23), vec!["a@example.com"])
+            .is_none());
+        assert!(reminder
+            .check(now + Duration::hours(24), vec!["a@example.com"])
+            .is_some());
+    }
+
+    #[test]
+    fn reviewer_reminder_delay() {
+        let schedule = ReviewerReminderSchedule::new(2, 3);
+        assert_eq!(schedule.delay(0), Some(Duration::days(2)));
+        assert_eq!(schedule.delay(1), Some(Duration::days(4)));
+        assert_eq!(schedule.delay(2), Some(Duration::days(8)));
+        assert_eq!(schedule.delay(3), None);
+    }
+}
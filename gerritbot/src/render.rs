@@ -0,0 +1,104 @@
+use gerritbot_gerrit as gerrit;
+use gerritbot_spark as spark;
+
+use crate::format::{self, Formatter};
+use crate::state::{State, NOTIFICATION_FLAGS};
+
+/// Result of a format function; `None` if it returned no message.
+pub type Rendered = Result<Option<String>, String>;
+
+/// Messages about a recorded Gerrit event, by the format functions that
+/// produce them, to try out a format script without running the bot. The
+/// messages are formatted for a user with all notifications enabled.
+pub fn render_event(
+    format_script: Option<&str>,
+    event: &str,
+) -> Result<Vec<(&'static str, Rendered)>, String> {
+    let formatter = match format_script {
+        Some(format_script) => Formatter::new(format_script)?,
+        None => Formatter::default(),
+    };
+    let event: gerrit::Event =
+        serde_json::from_str(event).map_err(|e| format!("invalid event: {}", e))?;
+
+    let mut state = State::new();
+    let email = spark::EmailRef::new("user@example.com");
+    for &flag in NOTIFICATION_FLAGS {
+        state.set_flag(email, flag, true);
+    }
+    let user = state.find_user(email);
+
+    let (name, message, change) = match &event {
+        gerrit::Event::CommentAdded(event) => (
+            <&gerrit::CommentAddedEvent as format::MessageInput>::FORMAT_FUNCTION,
+            formatter.format_message(user, event),
+            &event.change,
+        ),
+        gerrit::Event::ReviewerAdded(event) => (
+            <&gerrit::ReviewerAddedEvent as format::MessageInput>::FORMAT_FUNCTION,
+            formatter.format_message(user, event),
+            &event.change,
+        ),
+        gerrit::Event::ChangeMerged(event) => (
+            <&gerrit::ChangeMergedEvent as format::MessageInput>::FORMAT_FUNCTION,
+            formatter.format_message(user, event),
+            &event.change,
+        ),
+        gerrit::Event::ChangeAbandoned(event) => (
+            <&gerrit::ChangeAbandonedEvent as format::MessageInput>::FORMAT_FUNCTION,
+            formatter.format_message(user, event),
+            &event.change,
+        ),
+        gerrit::Event::ChangeDeleted(event) => (
+            <&gerrit::ChangeDeletedEvent as format::MessageInput>::FORMAT_FUNCTION,
+            formatter.format_message(user, event),
+            &event.change,
+        ),
+        gerrit::Event::Unknown { event_type, .. } => {
+            return Err(format!("no format function for {} events", event_type))
+        }
+    };
+
+    Ok(vec![
+        (name, message),
+        (
+            "format_change_details",
+            formatter.format_message(user, format::ChangeDetails { change }),
+        ),
+        (
+            "format_merge_conflict",
+            formatter.format_message(user, format::MergeConflict { change }),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EVENT_JSON: &str = r#"{"author":{"name":"Approver","username":"approver","email":"approver@approvers.com"},"approvals":[{"type":"Code-Review","description":"Code-Review","value":"2","oldValue":"-1"}],"comment":"Patch Set 1: Code-Review+2","patchSet":{"number":1,"revision":"49a65998c02eda928559f2d0b586c20bc8e37b10","parents":[],"ref":"refs/changes/42/42/1","uploader":{"name":"Author","email":"author@example.com","username":"Author"},"createdOn":1494165142,"author":{"name":"Author","email":"author@example.com","username":"Author"},"isDraft":false,"kind":"REWORK","sizeInsertions":0,"sizeDeletions":0},"change":{"project":"demo-project","branch":"master","id":"Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14","number":49,"subject":"Some review.","owner":{"name":"Author","email":"author@example.com","username":"author"},"url":"http://localhost/42","commitMessage":"Some review.\n","status":"NEW"},"project":"demo-project","refName":"refs/heads/master","changeKey":{"id":"Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14"},"type":"comment-added","eventCreatedOn":1499190282}"#;
+
+    #[test]
+    fn render_comment_added() {
+        let messages = render_event(None, EVENT_JSON).unwrap();
+        let names: Vec<_> = messages.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "format_comment_added",
+                "format_change_details",
+                "format_merge_conflict"
+            ]
+        );
+        assert!(matches!(
+            &messages[0].1,
+            Ok(Some(message)) if message.contains("+2 (Code-Review)")
+        ));
+
+        let script = r#"function format_comment_added(event, flags) return "review" end"#;
+        let messages = render_event(Some(script), EVENT_JSON).unwrap();
+        assert_eq!(messages[0].1, Ok(Some("review".to_string())));
+        assert!(messages[1].1.is_err());
+        assert!(render_event(Some(script), r#"{"type":"ref-updated"}"#).is_err());
+    }
+}
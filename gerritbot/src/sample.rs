@@ -0,0 +1,79 @@
+use chrono::{DateTime, Utc};
+
+use gerritbot_gerrit as gerrit;
+use gerritbot_spark as spark;
+
+const SAMPLE_CHANGE_ID: &str = "I0123456789abcdef0123456789abcdef01234567";
+const SAMPLE_REVISION: &str = "0123456789abcdef0123456789abcdef01234567";
+
+/// A made-up review of a change of the user, to preview how notifications
+/// look like.
+pub fn comment_added_event(
+    owner: &spark::EmailRef,
+    now: DateTime<Utc>,
+) -> gerrit::CommentAddedEvent {
+    let owner = owner.to_string();
+    let owner = gerrit::User {
+        name: owner.split('@').next().map(ToString::to_string),
+        username: None,
+        email: Some(owner),
+    };
+    let reviewer = gerrit::User {
+        name: Some("Sample Reviewer".to_string()),
+        username: Some("sample-reviewer".to_string()),
+        email: Some("sample-reviewer@example.com".to_string()),
+    };
+    let approvals = vec![gerrit::Approval {
+        approval_type: "Code-Review".to_string(),
+        description: Some("Code-Review".to_string()),
+        value: "1".to_string(),
+        old_value: Some("0".to_string()),
+        by: Some(reviewer.clone()),
+        granted_on: Some(now),
+    }];
+    let patchset = gerrit::Patchset {
+        number: 1,
+        revision: SAMPLE_REVISION.to_string(),
+        parents: Vec::new(),
+        reference: "refs/changes/00/0/1".to_string(),
+        uploader: owner.clone(),
+        created_on: now,
+        author: owner.clone(),
+        is_draft: false,
+        kind: "REWORK".to_string(),
+        size_insertions: 42,
+        size_deletions: -7,
+        comments: None,
+        approvals: Some(approvals.clone()),
+    };
+    let change = gerrit::Change {
+        project: "sample-project".to_string(),
+        branch: "master".to_string(),
+        id: SAMPLE_CHANGE_ID.to_string(),
+        number: 0,
+        subject: "Sample change to preview notifications".to_string(),
+        topic: None,
+        owner,
+        url: "https://gerrit.example.com/0".to_string(),
+        commit_message: format!(
+            "Sample change to preview notifications\n\nChange-Id: {}\n",
+            SAMPLE_CHANGE_ID
+        ),
+        created_on: Some(now),
+        status: gerrit::ChangeStatus::NEW,
+        current_patch_set: None,
+        patch_sets: None,
+        comments: None,
+        submit_records: None,
+        submit_requirements: None,
+        all_reviewers: None,
+    };
+    gerrit::CommentAddedEvent {
+        change,
+        patchset,
+        author: reviewer,
+        approvals: Some(approvals),
+        comment: "Patch Set 1: Code-Review+1\n\nLooks good, just a sample comment.".to_string(),
+        created_on: now,
+    }
+}
@@ -1,27 +1,97 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::path::Path;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, Duration, Utc};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use gerritbot_gerrit as gerrit;
 use gerritbot_spark as spark;
 
 use super::BotError;
 
+/// Number of notifications held back per user during quiet hours.
+const MAX_HELD_NOTIFICATIONS: usize = 100;
+
+mod activity;
+mod digest;
 mod filter;
 mod flags;
+mod lock;
+mod permission;
+mod quiet_hours;
+mod review_reminder;
+mod role;
+mod room;
+mod settings;
+mod snooze;
+mod team;
+mod timezone;
+mod undo;
 mod user;
+mod vote_policy;
+mod writer;
 
+pub use digest::Digest;
 use filter::Filter;
-pub use flags::{UserFlag, NOTIFICATION_FLAGS, REVIEW_COMMENT_FLAGS};
+pub use filter::FilterMode;
+pub use flags::{UserFlag, NOTIFICATION_FLAGS, REVIEW_COMMENT_FLAGS, ROOM_FLAGS};
+pub use lock::StateLock;
+pub use permission::Permission;
+pub use quiet_hours::QuietHours;
+pub use review_reminder::ReviewReminder;
+pub use role::Role;
+pub use room::Room;
+pub use settings::Settings;
+pub use snooze::Snooze;
+pub use team::{Team, TeamSubscription};
+pub use timezone::Timezone;
+pub use undo::Undo;
 pub use user::User;
+pub use vote_policy::VotePolicy;
+pub use writer::StateWriter;
+
+/// Name of the `n`-th backup of the state file.
+pub fn backup_filename(filename: &Path, n: usize) -> PathBuf {
+    let mut backup = filename.as_os_str().to_owned();
+    backup.push(format!(".{}", n));
+    PathBuf::from(backup)
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct State {
     users: Vec<User>,
+    /// Group rooms subscribed to projects.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    rooms: Vec<Room>,
+    /// Creation time of the last processed Gerrit event. Used to recover
+    /// events missed while the bot was not running.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        default,
+        with = "gerrit::timestamp::option"
+    )]
+    last_event_created_on: Option<DateTime<Utc>>,
+    /// Open changes known to have merge conflicts, whose owners were told.
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    merge_conflicts: BTreeSet<u32>,
+    /// Teams managed by admins, and their subscriptions.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    teams: Vec<Team>,
+    /// Reviewers who didn't vote yet, to be reminded of the changes.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    review_reminders: Vec<ReviewReminder>,
+    /// People who were invited to use the bot, each only once.
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    invited: BTreeSet<spark::Email>,
     #[serde(skip_serializing, skip_deserializing)]
     email_index: HashMap<spark::Email, usize>,
+    /// Linked Gerrit emails of the users. The emails of the users take
+    /// precedence.
+    #[serde(skip_serializing, skip_deserializing)]
+    gerrit_email_index: HashMap<spark::Email, usize>,
 }
 
 impl State {
@@ -43,9 +113,59 @@ impl State {
             .map_err(BotError::from)
     }
 
+    /// Save the state to a temporary file next to the file, which then
+    /// replaces it, so a crash while writing doesn't leave a truncated file.
+    pub fn save<P>(&self, filename: P) -> Result<(), BotError>
+    where
+        P: AsRef<Path>,
+    {
+        let filename = filename.as_ref();
+        let mut tmp_filename = filename.as_os_str().to_owned();
+        tmp_filename.push(".tmp");
+        let tmp_filename = PathBuf::from(tmp_filename);
+        let mut f = BufWriter::new(File::create(&tmp_filename)?);
+        serde_json::to_writer(&mut f, self)?;
+        f.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_filename, filename)?;
+        Ok(())
+    }
+
+    /// Save the state, and keep the previous `backups` saves as
+    /// `<filename>.1`, the latest one, to `<filename>.<backups>`.
+    pub fn save_with_backups<P>(&self, filename: P, backups: usize) -> Result<(), BotError>
+    where
+        P: AsRef<Path>,
+    {
+        let filename = filename.as_ref();
+        if backups > 0 && filename.exists() {
+            for n in (1..backups).rev() {
+                let backup = backup_filename(filename, n);
+                if backup.exists() {
+                    fs::rename(&backup, backup_filename(filename, n + 1))?;
+                }
+            }
+            fs::copy(filename, backup_filename(filename, 1))?;
+        }
+        self.save(filename)
+    }
+
+    /// Check a state loaded from a file before using it, e.g. for users
+    /// added twice.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut emails = std::collections::HashSet::new();
+        match self.users.iter().find(|user| !emails.insert(user.email())) {
+            Some(user) => Err(format!("user {} is added twice", user.email())),
+            None => Ok(()),
+        }
+    }
+
     fn index_users(&mut self) {
         for (user_pos, user) in self.users.iter().enumerate() {
             self.email_index.insert(user.email().to_owned(), user_pos);
+            for gerrit_email in user.gerrit_emails() {
+                self.gerrit_email_index
+                    .insert(gerrit_email.to_owned(), user_pos);
+            }
         }
     }
 
@@ -62,6 +182,84 @@ impl State {
         self.users.last_mut().unwrap()
     }
 
+    /// Remember that the person was invited to use the bot. Returns `false`
+    /// if they are a user already, or were invited before.
+    pub fn invite(&mut self, email: &spark::EmailRef) -> bool {
+        self.find_user(email).is_none() && self.invited.insert(email.to_owned())
+    }
+
+    pub fn is_known_merge_conflict(&self, change_number: u32) -> bool {
+        self.merge_conflicts.contains(&change_number)
+    }
+
+    /// Replace the changes known to have merge conflicts. Returns whether
+    /// they changed.
+    pub fn set_merge_conflicts(&mut self, merge_conflicts: BTreeSet<u32>) -> bool {
+        std::mem::replace(&mut self.merge_conflicts, merge_conflicts) != self.merge_conflicts
+    }
+
+    /// Remind the reviewer of the change at the given time, unless they are
+    /// reminded of it already.
+    pub fn add_review_reminder(
+        &mut self,
+        change: &gerrit::Change,
+        reviewer: &spark::EmailRef,
+        added: DateTime<Utc>,
+        next: DateTime<Utc>,
+    ) -> bool {
+        if self.review_reminders.iter().any(|reminder| {
+            reminder.change.number == change.number && *reminder.reviewer == *reviewer
+        }) {
+            return false;
+        }
+        self.review_reminders
+            .push(ReviewReminder::new(change, reviewer, added, next));
+        true
+    }
+
+    /// Stop reminding the reviewer, or all reviewers, of the change. Returns
+    /// whether any reminder was removed.
+    pub fn remove_review_reminders(
+        &mut self,
+        change_number: u32,
+        reviewer: Option<&spark::EmailRef>,
+    ) -> bool {
+        let len = self.review_reminders.len();
+        self.review_reminders.retain(|reminder| {
+            reminder.change.number != change_number
+                || reviewer.is_some_and(|reviewer| *reminder.reviewer != *reviewer)
+        });
+        self.review_reminders.len() != len
+    }
+
+    /// Take the due reminders. The reminders are rescheduled after the delay
+    /// for the number of reminders sent so far, or dropped without one.
+    pub fn take_due_review_reminders<F>(
+        &mut self,
+        now: DateTime<Utc>,
+        delay: F,
+    ) -> Vec<ReviewReminder>
+    where
+        F: Fn(u32) -> Option<Duration>,
+    {
+        let mut due = Vec::new();
+        self.review_reminders.retain_mut(|reminder| {
+            if reminder.next > now {
+                return true;
+            }
+            reminder.sent += 1;
+            due.push(reminder.clone());
+            match delay(reminder.sent) {
+                Some(delay) => {
+                    reminder.next = now + delay;
+                    true
+                }
+                None => false,
+            }
+        });
+        due
+    }
+
     fn find_or_add_user_by_email(&mut self, email: &spark::EmailRef) -> &mut User {
         let pos = self.users.iter().position(|u| u.email() == email);
         let user: &mut User = match pos {
@@ -71,24 +269,26 @@ impl State {
         user
     }
 
-    fn find_user_mut<P: ?Sized>(&mut self, email: &P) -> Option<&mut User>
+    fn find_user_mut<P>(&mut self, email: &P) -> Option<&mut User>
     where
         spark::Email: std::borrow::Borrow<P>,
-        P: std::hash::Hash + Eq,
+        P: std::hash::Hash + Eq + ?Sized,
     {
         self.email_index
             .get(email)
+            .or_else(|| self.gerrit_email_index.get(email))
             .cloned()
             .map(move |pos| &mut self.users[pos])
     }
 
-    pub fn find_user<P: ?Sized>(&self, email: &P) -> Option<&User>
+    pub fn find_user<P>(&self, email: &P) -> Option<&User>
     where
         spark::Email: std::borrow::Borrow<P>,
-        P: std::hash::Hash + Eq,
+        P: std::hash::Hash + Eq + ?Sized,
     {
         self.email_index
             .get(email)
+            .or_else(|| self.gerrit_email_index.get(email))
             .cloned()
             .map(|pos| &self.users[pos])
     }
@@ -99,6 +299,84 @@ impl State {
         user
     }
 
+    /// Restore the default settings of the user. Returns `false` for unknown
+    /// users.
+    pub fn reset_settings(&mut self, email: &spark::EmailRef) -> bool {
+        self.find_user_mut(email)
+            .map(User::reset_settings)
+            .is_some()
+    }
+
+    /// Settings of the user, or the default ones for unknown users.
+    pub fn export_settings(&self, email: &spark::EmailRef) -> Settings {
+        self.find_user(email)
+            .map(User::settings)
+            .unwrap_or_else(|| User::new(email.to_owned()).settings())
+    }
+
+    /// Replace the settings of the user with imported ones. Returns the
+    /// previous digest, if the imported settings don't have one.
+    pub fn import_settings(
+        &mut self,
+        email: &spark::EmailRef,
+        settings: Settings,
+        now: DateTime<Utc>,
+    ) -> Option<Digest> {
+        let digest = settings.digest;
+        self.find_or_add_user_by_email(email).set_settings(settings);
+        self.set_digest(email, digest, now)
+    }
+
+    /// Count a notification sent to the user.
+    pub fn notification_sent(&mut self, email: &spark::EmailRef, now: DateTime<Utc>) {
+        if let Some(user) = self.find_user_mut(email) {
+            let activity = user.activity_mut();
+            activity.notifications += 1;
+            activity.last_notification = Some(now);
+        }
+    }
+
+    /// Remember when the user last sent a command.
+    pub fn command_received(&mut self, email: &spark::EmailRef, now: DateTime<Utc>) {
+        if let Some(user) = self.find_user_mut(email) {
+            user.activity_mut().last_command = Some(now);
+        }
+    }
+
+    /// Users who were last notified or sent a command before the time. Users
+    /// without any recorded activity and admins are never inactive.
+    pub fn inactive_users(&self, before: DateTime<Utc>) -> impl Iterator<Item = &User> {
+        self.users.iter().filter(move |user| {
+            user.role() != Role::Admin
+                && user
+                    .activity()
+                    .last_active()
+                    .is_some_and(|last_active| last_active < before)
+        })
+    }
+
+    /// Remove the users inactive since the time. Returns their emails.
+    pub fn remove_inactive_users(&mut self, before: DateTime<Utc>) -> Vec<spark::Email> {
+        let removed: Vec<spark::Email> = self
+            .inactive_users(before)
+            .map(|user| user.email().to_owned())
+            .collect();
+        if !removed.is_empty() {
+            self.users
+                .retain(|user| !removed.iter().any(|email| email == user.email()));
+            self.email_index.clear();
+            self.gerrit_email_index.clear();
+            self.index_users();
+        }
+        removed
+    }
+
+    /// Undo the last change of the settings of the user. Returns the restored
+    /// setting, or `None` if there is nothing to undo.
+    pub fn undo(&mut self, email: &spark::EmailRef) -> Option<Undo> {
+        self.find_user_mut(email).and_then(User::undo)
+    }
+
     pub fn set_flag(&mut self, email: &spark::EmailRef, flag: UserFlag, value: bool) -> &User {
         let user = self.find_or_add_user_by_email(email);
         user.set_flag(flag, value);
@@ -111,19 +389,42 @@ impl State {
         user
     }
 
+    /// Remember the verified Webex id of a user. Returns `false` if there is
+    /// no such user.
+    pub fn set_person_id(&mut self, email: &spark::EmailRef, person_id: spark::PersonId) -> bool {
+        match self.find_user_mut(email) {
+            Some(user) => {
+                user.set_person_id(person_id);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn add_filter(
         &mut self,
         email: &spark::EmailRef,
         filter: &str,
     ) -> Result<(), regex::Error> {
         let user = self.find_or_add_user_by_email(email);
+        // keep the mode of the previous filter
+        let mode = user.filter().map(|f| f.mode).unwrap_or_default();
         user.set_filter(Filter {
             regex: Regex::new(filter)?,
             enabled: true,
+            mode,
         });
         Ok(())
     }
 
+    /// Set the mode of the filter of the user. Returns `false` if the user
+    /// doesn't exist or doesn't have a filter configured.
+    pub fn set_filter_mode(&mut self, email: &spark::EmailRef, mode: FilterMode) -> bool {
+        self.find_user_mut(email)
+            .map(|u| u.set_filter_mode(mode))
+            .unwrap_or(false)
+    }
+
     /// Get the filter for the given user given the user exists and has a filter
     /// configured.
     pub fn get_filter(&self, email: &spark::EmailRef) -> Option<(&str, bool)> {
@@ -148,14 +449,427 @@ impl State {
             .map(|f| f.regex.as_str())
     }
 
+    /// Only notify the user about changes on branches matching the patterns,
+    /// or on all branches if empty.
+    pub fn set_branches(&mut self, email: &spark::EmailRef, branches: Vec<String>) -> &User {
+        let user = self.find_or_add_user_by_email(email);
+        user.set_branches(branches);
+        user
+    }
+
+    /// Grant or revoke a permission of the user. Returns `false` if nothing
+    /// changed.
+    pub fn set_permission(
+        &mut self,
+        email: &spark::EmailRef,
+        permission: Permission,
+        granted: bool,
+    ) -> bool {
+        if granted {
+            self.find_or_add_user_by_email(email)
+                .set_permission(permission, true)
+        } else {
+            self.find_user_mut(email)
+                .map(|user| user.set_permission(permission, false))
+                .unwrap_or(false)
+        }
+    }
+
+    /// Change the role of the user. Returns `false` if nothing changed.
+    pub fn set_role(&mut self, email: &spark::EmailRef, role: Role) -> bool {
+        if role.is_user() && self.find_user(email).is_none() {
+            return false;
+        }
+        self.find_or_add_user_by_email(email).set_role(role)
+    }
+
+    /// Link a further Gerrit email to the user, so events about changes of
+    /// this email reach the user. Returns `false` if the email already
+    /// belongs to a user.
+    pub fn link_gerrit_email(
+        &mut self,
+        email: &spark::EmailRef,
+        gerrit_email: &spark::EmailRef,
+    ) -> bool {
+        if self.find_user(gerrit_email).is_some() {
+            return false;
+        }
+        self.find_or_add_user_by_email(email)
+            .link_gerrit_email(gerrit_email.to_owned());
+        let user_pos = self.email_index[email];
+        self.gerrit_email_index
+            .insert(gerrit_email.to_owned(), user_pos);
+        true
+    }
+
+    /// Returns `false` if the email wasn't linked to the user.
+    pub fn unlink_gerrit_email(
+        &mut self,
+        email: &spark::EmailRef,
+        gerrit_email: &spark::EmailRef,
+    ) -> bool {
+        let unlinked = self
+            .find_user_mut(email)
+            .map(|user| user.unlink_gerrit_email(gerrit_email))
+            .unwrap_or(false);
+        if unlinked {
+            self.gerrit_email_index.remove(gerrit_email);
+        }
+        unlinked
+    }
+
+    /// Remember the Gerrit email until an admin approves linking it.
+    pub fn request_gerrit_link(&mut self, email: &spark::EmailRef, gerrit_email: &spark::EmailRef) {
+        self.find_or_add_user_by_email(email)
+            .set_pending_gerrit_email(Some(gerrit_email.to_owned()));
+    }
+
+    /// Link the pending Gerrit email of the user. Returns the email if it was
+    /// linked.
+    pub fn approve_gerrit_link(&mut self, email: &spark::EmailRef) -> Option<spark::Email> {
+        let user = self.find_user_mut(email)?;
+        let gerrit_email = user.pending_gerrit_email()?.to_owned();
+        user.set_pending_gerrit_email(None);
+        let email = user.email().to_owned();
+        if self.link_gerrit_email(&email, &gerrit_email) {
+            Some(gerrit_email)
+        } else {
+            None
+        }
+    }
+
+    /// Subscribe the user to the Gerrit topic. Returns `false` if the user was
+    /// already subscribed.
+    pub fn subscribe_topic(&mut self, email: &spark::EmailRef, topic: &str) -> bool {
+        self.find_or_add_user_by_email(email).subscribe_topic(topic)
+    }
+
+    /// Unsubscribe the user from the Gerrit topic. Returns `false` if the
+    /// user wasn't subscribed.
+    pub fn unsubscribe_topic(&mut self, email: &spark::EmailRef, topic: &str) -> bool {
+        self.find_user_mut(email)
+            .map(|user| user.unsubscribe_topic(topic))
+            .unwrap_or(false)
+    }
+
+    /// Users subscribed to the topic.
+    pub fn topic_subscribers<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a User> {
+        self.users
+            .iter()
+            .filter(move |user| user.is_subscribed_to_topic(topic))
+    }
+
+    pub fn teams(&self) -> impl Iterator<Item = &Team> {
+        self.teams.iter()
+    }
+
+    /// Find a team by its name, ignoring the case.
+    pub fn find_team(&self, name: &str) -> Option<&Team> {
+        self.teams
+            .iter()
+            .find(|team| team.name().eq_ignore_ascii_case(name))
+    }
+
+    fn find_team_mut(&mut self, name: &str) -> Option<&mut Team> {
+        self.teams
+            .iter_mut()
+            .find(|team| team.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Add members to the team, which is created if it doesn't exist yet.
+    /// Returns the number of new members.
+    pub fn add_team_members(&mut self, name: &str, emails: &[spark::Email]) -> usize {
+        if self.find_team(name).is_none() {
+            self.teams.push(Team::new(name));
+        }
+        let team = self.find_team_mut(name).unwrap();
+        emails.iter().filter(|email| team.add_member(email)).count()
+    }
+
+    /// Remove members from the team. Returns the number of removed members,
+    /// or `None` if there is no such team.
+    pub fn remove_team_members(&mut self, name: &str, emails: &[spark::Email]) -> Option<usize> {
+        let team = self.find_team_mut(name)?;
+        Some(
+            emails
+                .iter()
+                .filter(|email| team.remove_member(email))
+                .count(),
+        )
+    }
+
+    /// Subscribe the team. Returns `false` if it was already subscribed, or
+    /// `None` if there is no such team.
+    pub fn subscribe_team(&mut self, name: &str, subscription: TeamSubscription) -> Option<bool> {
+        self.find_team_mut(name)
+            .map(|team| team.subscribe(subscription))
+    }
+
+    /// Unsubscribe the team. Returns `false` if it wasn't subscribed, or
+    /// `None` if there is no such team.
+    pub fn unsubscribe_team(
+        &mut self,
+        name: &str,
+        subscription: &TeamSubscription,
+    ) -> Option<bool> {
+        self.find_team_mut(name)
+            .map(|team| team.unsubscribe(subscription))
+    }
+
+    /// Returns `false` if there is no such team.
+    pub fn delete_team(&mut self, name: &str) -> bool {
+        let len = self.teams.len();
+        self.teams
+            .retain(|team| !team.name().eq_ignore_ascii_case(name));
+        self.teams.len() != len
+    }
+
+    /// Users in the teams subscribed to the change.
+    pub fn team_subscribers<'a>(
+        &'a self,
+        change: &'a gerrit::Change,
+    ) -> impl Iterator<Item = &'a User> {
+        self.teams
+            .iter()
+            .filter(move |team| team.is_subscribed(change))
+            .flat_map(Team::members)
+            .filter_map(move |email| self.find_user(email))
+    }
+
+    /// Hold back the notifications of the user until the given time. An
+    /// ongoing snooze is extended or shortened.
+    pub fn snooze(&mut self, email: &spark::EmailRef, until: DateTime<Utc>) -> &User {
+        let user = self.find_or_add_user_by_email(email);
+        match user.snooze_mut() {
+            Some(snooze) => snooze.set_until(until),
+            None => user.set_snooze(Snooze::new(until)),
+        }
+        user
+    }
+
+    /// End the snooze of the user and return it, if any.
+    pub fn end_snooze(&mut self, email: &spark::EmailRef) -> Option<Snooze> {
+        self.find_user_mut(email).and_then(User::take_snooze)
+    }
+
+    /// Users whose snooze is over.
+    pub fn expired_snoozes(&self, now: DateTime<Utc>) -> Vec<spark::Email> {
+        self.users
+            .iter()
+            .filter(|user| user.snooze().is_some_and(|snooze| snooze.is_over(now)))
+            .map(|user| user.email().to_owned())
+            .collect()
+    }
+
+    /// Remember a notification the user misses because of a snooze. Returns
+    /// `false` if the user is not snoozing.
+    pub fn add_missed_notification(
+        &mut self,
+        email: &spark::EmailRef,
+        message: &str,
+        now: DateTime<Utc>,
+    ) -> bool {
+        match self
+            .find_user_mut(email)
+            .and_then(User::snooze_mut)
+            .filter(|snooze| !snooze.is_over(now))
+        {
+            Some(snooze) => {
+                snooze.add_missed(message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_language(&mut self, email: &spark::EmailRef, language: Option<String>) -> &User {
+        let user = self.find_or_add_user_by_email(email);
+        user.set_language(language);
+        user
+    }
+
+    pub fn set_vote_threshold(
+        &mut self,
+        email: &spark::EmailRef,
+        vote_threshold: Option<i32>,
+    ) -> &User {
+        let user = self.find_or_add_user_by_email(email);
+        user.set_vote_threshold(vote_threshold);
+        user
+    }
+
+    pub fn set_label_vote_policy(
+        &mut self,
+        email: &spark::EmailRef,
+        label: String,
+        policy: VotePolicy,
+    ) -> &User {
+        let user = self.find_or_add_user_by_email(email);
+        user.set_label_vote_policy(label, policy);
+        user
+    }
+
+    pub fn set_timezone(&mut self, email: &spark::EmailRef, timezone: Timezone) -> &User {
+        let user = self.find_or_add_user_by_email(email);
+        user.set_timezone(timezone);
+        user
+    }
+
+    pub fn set_quiet_hours(
+        &mut self,
+        email: &spark::EmailRef,
+        quiet_hours: Option<QuietHours>,
+    ) -> &User {
+        let user = self.find_or_add_user_by_email(email);
+        user.set_quiet_hours(quiet_hours);
+        user
+    }
+
+    /// Hold back a notification if the user is in their quiet hours. Returns
+    /// `false` if the user is not.
+    pub fn hold_notification(
+        &mut self,
+        email: &spark::EmailRef,
+        message: &str,
+        now: DateTime<Utc>,
+    ) -> bool {
+        match self.find_user_mut(email).filter(|user| user.is_quiet(now)) {
+            Some(user) => {
+                user.hold_notification(message, MAX_HELD_NOTIFICATIONS);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take the held back notifications of the users whose quiet hours are
+    /// over.
+    pub fn release_held_notifications(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> Vec<(spark::Email, Vec<String>)> {
+        self.users
+            .iter_mut()
+            .filter(|user| !user.held_notifications().is_empty() && !user.is_quiet(now))
+            .map(|user| (user.email().to_owned(), user.take_held_notifications()))
+            .collect()
+    }
+
+    /// Collect the notifications of the user and deliver them once a day at
+    /// the given time in minutes after midnight, or, if none, stop doing so.
+    /// Returns the previous digest.
+    pub fn set_digest(
+        &mut self,
+        email: &spark::EmailRef,
+        time: Option<u32>,
+        now: DateTime<Utc>,
+    ) -> Option<Digest> {
+        let user = self.find_or_add_user_by_email(email);
+        match (time, user.digest_mut()) {
+            (Some(time), Some(digest)) => {
+                digest.set_time(time);
+                None
+            }
+            (Some(time), None) => user.set_digest(Some(Digest::new(time, now))),
+            (None, _) => user.set_digest(None),
+        }
+    }
+
+    /// Add a notification to the digest of the user. Returns `false` if the
+    /// user is not in digest mode.
+    pub fn add_to_digest(
+        &mut self,
+        email: &spark::EmailRef,
+        change_number: u32,
+        message: &str,
+    ) -> bool {
+        match self.find_user_mut(email).and_then(User::digest_mut) {
+            Some(digest) => {
+                digest.add(change_number, message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take the due digests. Users without collected notifications are
+    /// skipped; their next digest is scheduled anyway.
+    pub fn take_due_digests(&mut self, now: DateTime<Utc>) -> Vec<(spark::Email, Digest)> {
+        let mut digests = Vec::new();
+        for user in &mut self.users {
+            let timezone = user.timezone().offset_at(now);
+            if let Some(digest) = user
+                .digest_mut()
+                .filter(|digest| digest.next(&timezone) <= now)
+            {
+                let digest = digest.take(now);
+                if digest.count() > 0 {
+                    digests.push((user.email().to_owned(), digest));
+                }
+            }
+        }
+        digests
+    }
+
     pub fn users(&self) -> impl Iterator<Item = &User> + Clone {
         self.users.iter()
     }
 
+    pub fn find_room(&self, room_id: &spark::RoomIdRef) -> Option<&Room> {
+        self.rooms.iter().find(|room| room.id() == room_id)
+    }
+
+    /// Rooms subscribed to the project.
+    pub fn subscribed_rooms<'a>(&'a self, project: &'a str) -> impl Iterator<Item = &'a Room> {
+        self.rooms
+            .iter()
+            .filter(move |room| room.is_subscribed(project))
+    }
+
+    /// Subscribe the room to the project. Returns `false` if the room was
+    /// already subscribed.
+    pub fn subscribe_room(&mut self, room_id: &spark::RoomIdRef, project: &str) -> bool {
+        let pos = match self.rooms.iter().position(|room| room.id() == room_id) {
+            Some(pos) => pos,
+            None => {
+                self.rooms.push(Room::new(room_id.to_owned()));
+                self.rooms.len() - 1
+            }
+        };
+        self.rooms[pos].subscribe(project)
+    }
+
+    /// Unsubscribe the room from the project. Rooms without subscriptions are
+    /// forgotten. Returns `false` if the room wasn't subscribed.
+    pub fn unsubscribe_room(&mut self, room_id: &spark::RoomIdRef, project: &str) -> bool {
+        let pos = match self.rooms.iter().position(|room| room.id() == room_id) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let unsubscribed = self.rooms[pos].unsubscribe(project);
+        if !self.rooms[pos].has_subscriptions() {
+            self.rooms.remove(pos);
+        }
+        unsubscribed
+    }
+
+    pub fn last_event_created_on(&self) -> Option<DateTime<Utc>> {
+        self.last_event_created_on
+    }
+
+    /// Record the creation time of a processed Gerrit event. Returns `true` if
+    /// the event is newer than all events recorded before.
+    pub fn update_last_event_created_on(&mut self, created_on: DateTime<Utc>) -> bool {
+        if self.last_event_created_on < Some(created_on) {
+            self.last_event_created_on = Some(created_on);
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn is_filtered(&self, user: &User, msg: &str) -> bool {
-        user.filter()
-            .map(|f| f.enabled && f.regex.is_match(msg))
-            .unwrap_or(false)
+        user.filter().map(|f| f.is_filtered(msg)).unwrap_or(false)
     }
 }
 
@@ -272,6 +986,149 @@ mod test {
         assert_eq!(res, Some(".*some_word.*"));
     }
 
+    #[test]
+    fn filter_mode_keep() {
+        let mut state = State::new();
+        let email = EmailRef::new("some@example.com");
+        assert!(!state.set_filter_mode(email, FilterMode::Keep));
+
+        state.add_filter(email, "Code-Review").unwrap();
+        assert!(state.set_filter_mode(email, FilterMode::Keep));
+        let user = state.find_user(email).unwrap();
+        assert!(!state.is_filtered(user, "Code-Review+2"));
+        assert!(state.is_filtered(user, "merged"));
+
+        // the mode is kept when replacing the filter
+        state.add_filter(email, "merged").unwrap();
+        let user = state.find_user(email).unwrap();
+        assert!(!state.is_filtered(user, "merged"));
+
+        state.enable_and_get_filter(email, false);
+        let user = state.find_user(email).unwrap();
+        assert!(!state.is_filtered(user, "Code-Review+2"));
+    }
+
+    #[test]
+    fn save_with_backups() {
+        let filename =
+            std::env::temp_dir().join(format!("gerritbot-backups-{}.json", std::process::id()));
+        let mut state = State::new();
+        for email in &["a@example.com", "b@example.com", "c@example.com"] {
+            state.add_user(EmailRef::new(email));
+            state.save_with_backups(&filename, 2).unwrap();
+        }
+
+        let num_users = |n| {
+            State::load(backup_filename(&filename, n))
+                .unwrap()
+                .num_users()
+        };
+        assert_eq!(State::load(&filename).unwrap().num_users(), 3);
+        assert_eq!(num_users(1), 2);
+        assert_eq!(num_users(2), 1);
+        assert!(!backup_filename(&filename, 3).exists());
+
+        for n in 1..=2 {
+            fs::remove_file(backup_filename(&filename, n)).unwrap();
+        }
+        fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn activity_counters() {
+        let mut state = State::new();
+        let email = EmailRef::new("some@example.com");
+        let now = Utc::now();
+        state.notification_sent(email, now);
+        state.command_received(email, now);
+        assert!(state.find_user(email).is_none());
+
+        state.add_user(email);
+        state.notification_sent(email, now - Duration::hours(2));
+        state.notification_sent(email, now - Duration::hours(1));
+        state.command_received(email, now - Duration::hours(3));
+        let activity = state.find_user(email).unwrap().activity();
+        assert_eq!(activity.notifications, 2);
+        assert_eq!(activity.last_notification, Some(now - Duration::hours(1)));
+        assert_eq!(activity.last_active(), Some(now - Duration::hours(1)));
+    }
+
+    #[test]
+    fn remove_inactive_users() {
+        let mut state = State::new();
+        let now = Utc::now();
+        let inactive = EmailRef::new("inactive@example.com");
+        let active = EmailRef::new("active@example.com");
+        let admin = EmailRef::new("admin@example.com");
+        let unknown = EmailRef::new("unknown@example.com");
+        for email in &[inactive, active, admin, unknown] {
+            state.add_user(email);
+        }
+        state.link_gerrit_email(active, EmailRef::new("active@gerrit.example.com"));
+        state.set_role(admin, Role::Admin);
+        state.command_received(inactive, now - Duration::days(100));
+        state.notification_sent(active, now - Duration::days(100));
+        state.command_received(active, now - Duration::days(10));
+        state.command_received(admin, now - Duration::days(100));
+
+        let before = now - Duration::days(90);
+        assert_eq!(state.inactive_users(before).count(), 1);
+        assert_eq!(
+            state.remove_inactive_users(before),
+            vec![inactive.to_owned()]
+        );
+        assert_eq!(state.num_users(), 3);
+        assert!(state.find_user(inactive).is_none());
+        assert_eq!(
+            state
+                .find_user(EmailRef::new("active@gerrit.example.com"))
+                .map(User::email),
+            Some(active)
+        );
+        assert!(state.remove_inactive_users(before).is_empty());
+    }
+
+    #[test]
+    fn undo_last_change() {
+        let mut state = State::new();
+        let email = EmailRef::new("some@example.com");
+        assert!(state.undo(email).is_none());
+
+        state.add_filter(email, "Code-Review").unwrap();
+        state.add_filter(email, "Code-Reveiw").unwrap();
+        assert!(matches!(
+            state.undo(email),
+            Some(Undo::Filter(Some(filter))) if filter.regex.as_str() == "Code-Review"
+        ));
+        assert_eq!(state.get_filter(email), Some(("Code-Review", true)));
+        // undoing again redoes the change
+        assert!(state.undo(email).is_some());
+        assert_eq!(state.get_filter(email), Some(("Code-Reveiw", true)));
+
+        state.set_flag(email, UserFlag::NotifyReviewApprovals, false);
+        assert!(matches!(state.undo(email), Some(Undo::Flags(_))));
+        assert!(state
+            .find_user(email)
+            .unwrap()
+            .has_flag(UserFlag::NotifyReviewApprovals));
+
+        state.subscribe_topic(email, "release");
+        assert!(matches!(
+            state.undo(email),
+            Some(Undo::Topic {
+                subscribed: false,
+                ..
+            })
+        ));
+        assert!(!state
+            .find_user(email)
+            .unwrap()
+            .is_subscribed_to_topic("release"));
+
+        state.reset_settings(email);
+        assert!(state.undo(email).is_none());
+    }
+
     #[test]
     fn add_valid_filter_for_disabled_user() {
         let mut state = State::new();
@@ -286,6 +1143,122 @@ mod test {
         assert_eq!(res, Some(".*some_word.*"));
     }
 
+    #[test]
+    fn link_gerrit_email() {
+        let mut state = State::new();
+        let email = EmailRef::new("jdoe@corp.example.com");
+        let gerrit_email = EmailRef::new("john.doe@example.com");
+        state.add_user(EmailRef::new("other@example.com"));
+
+        state.request_gerrit_link(email, gerrit_email);
+        assert!(state.find_user(gerrit_email).is_none());
+        assert_eq!(
+            state.approve_gerrit_link(email).as_deref(),
+            Some(gerrit_email)
+        );
+        let user = state.find_user(gerrit_email).expect("linked user");
+        assert_eq!(user.email(), email);
+        assert_eq!(user.gerrit_email(), gerrit_email);
+        assert!(user.pending_gerrit_email().is_none());
+
+        // emails of users can't be linked
+        assert!(!state.link_gerrit_email(email, EmailRef::new("other@example.com")));
+        assert!(!state.link_gerrit_email(EmailRef::new("other@example.com"), gerrit_email));
+
+        // the links survive saving and loading
+        let json = serde_json::to_string(&state).unwrap();
+        let mut loaded: State = serde_json::from_str(&json).unwrap();
+        loaded.index_users();
+        assert_eq!(loaded.find_user(gerrit_email).map(User::email), Some(email));
+
+        assert!(state.unlink_gerrit_email(email, gerrit_email));
+        assert!(!state.unlink_gerrit_email(email, gerrit_email));
+        assert!(state.find_user(gerrit_email).is_none());
+        assert_eq!(state.find_user(email).unwrap().gerrit_email(), email);
+    }
+
+    #[test]
+    fn update_last_event_created_on() {
+        let time = |seconds| gerrit::timestamp::from_epoch_seconds(seconds).unwrap();
+        let mut state = State::new();
+        assert_eq!(state.last_event_created_on(), None);
+        assert!(state.update_last_event_created_on(time(1499190282)));
+        assert_eq!(state.last_event_created_on(), Some(time(1499190282)));
+        // older or equal events don't move the timestamp back
+        assert!(!state.update_last_event_created_on(time(1499190200)));
+        assert!(!state.update_last_event_created_on(time(1499190282)));
+        assert_eq!(state.last_event_created_on(), Some(time(1499190282)));
+
+        // the timestamp is stored as seconds since the epoch
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(json.contains(r#""last_event_created_on":1499190282"#));
+    }
+
+    #[test]
+    fn subscribe_room() {
+        let room_id = spark::RoomId::new("some-room".to_string());
+        let mut state = State::new();
+        assert!(state.subscribe_room(&room_id, "gerritbot-rs"));
+        assert!(!state.subscribe_room(&room_id, "gerritbot-rs"));
+        assert!(state.subscribe_room(&room_id, "other"));
+        assert_eq!(state.subscribed_rooms("gerritbot-rs").count(), 1);
+        assert_eq!(state.subscribed_rooms("unknown").count(), 0);
+
+        let json = serde_json::to_string(&state).unwrap();
+        let state: State = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            state
+                .find_room(&room_id)
+                .unwrap()
+                .projects()
+                .collect::<Vec<_>>(),
+            ["gerritbot-rs", "other"]
+        );
+    }
+
+    #[test]
+    fn unsubscribe_room() {
+        let room_id = spark::RoomId::new("some-room".to_string());
+        let mut state = State::new();
+        assert!(!state.unsubscribe_room(&room_id, "gerritbot-rs"));
+        state.subscribe_room(&room_id, "gerritbot-rs");
+        assert!(state.unsubscribe_room(&room_id, "gerritbot-rs"));
+        // rooms without subscriptions are forgotten
+        assert!(state.find_room(&room_id).is_none());
+        assert!(!serde_json::to_string(&state).unwrap().contains("rooms"));
+    }
+
+    #[test]
+    fn manage_teams() {
+        let mut state = State::new();
+        let alice = spark::Email::new("alice@example.com".to_string());
+        let bob = spark::Email::new("bob@example.com".to_string());
+        let topic = TeamSubscription::Topic("wayland".to_string());
+        assert_eq!(state.subscribe_team("graphics", topic.clone()), None);
+        assert_eq!(
+            state.remove_team_members("graphics", std::slice::from_ref(&alice)),
+            None
+        );
+
+        assert_eq!(
+            state.add_team_members("graphics", std::slice::from_ref(&alice)),
+            1
+        );
+        assert_eq!(state.add_team_members("Graphics", &[alice.clone(), bob]), 1);
+        assert_eq!(state.subscribe_team("graphics", topic.clone()), Some(true));
+        assert_eq!(state.subscribe_team("graphics", topic.clone()), Some(false));
+        let team = state.find_team("GRAPHICS").unwrap();
+        assert_eq!(team.name(), "graphics");
+        assert_eq!(team.members().count(), 2);
+        assert_eq!(team.subscriptions().collect::<Vec<_>>(), [&topic]);
+
+        assert_eq!(state.remove_team_members("graphics", &[alice]), Some(1));
+        assert_eq!(state.unsubscribe_team("graphics", &topic), Some(true));
+        assert!(state.delete_team("graphics"));
+        assert!(!state.delete_team("graphics"));
+        assert!(!serde_json::to_string(&state).unwrap().contains("teams"));
+    }
+
     #[test]
     fn enable_non_configured_filter_for_existing_user() {
         let mut state = State::new();
@@ -0,0 +1,35 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use gerritbot_gerrit as gerrit;
+
+/// Counters of the activity of a user with the bot.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Activity {
+    /// Number of notifications about changes sent to the user.
+    #[serde(default)]
+    pub notifications: u64,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        default,
+        with = "gerrit::timestamp::option"
+    )]
+    pub last_notification: Option<DateTime<Utc>>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        default,
+        with = "gerrit::timestamp::option"
+    )]
+    pub last_command: Option<DateTime<Utc>>,
+}
+
+impl Activity {
+    pub(super) fn is_default(&self) -> bool {
+        self.notifications == 0 && self.last_notification.is_none() && self.last_command.is_none()
+    }
+
+    /// When the user was last notified or sent a command.
+    pub fn last_active(&self) -> Option<DateTime<Utc>> {
+        self.last_notification.max(self.last_command)
+    }
+}
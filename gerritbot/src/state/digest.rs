@@ -0,0 +1,126 @@
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+
+use gerritbot_gerrit as gerrit;
+
+/// Number of notifications kept for a single digest.
+const MAX_DIGEST_ENTRIES: usize = 100;
+
+/// In digest mode, the notifications of a user are collected and delivered
+/// once a day as a single message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Digest {
+    /// Time of day in minutes after midnight, in the time zone of the user.
+    time: u32,
+    /// When the last digest was sent, or digest mode was enabled.
+    #[serde(with = "gerrit::timestamp")]
+    last_sent: DateTime<Utc>,
+    /// Collected notifications, at most `MAX_DIGEST_ENTRIES`.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    entries: Vec<DigestEntry>,
+    /// Number of all collected notifications.
+    #[serde(default)]
+    count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigestEntry {
+    pub change_number: u32,
+    pub message: String,
+}
+
+impl Digest {
+    pub(super) fn new(time: u32, now: DateTime<Utc>) -> Self {
+        Self {
+            time,
+            last_sent: now,
+            entries: Vec::new(),
+            count: 0,
+        }
+    }
+
+    /// Time of day in minutes after midnight.
+    pub(super) fn time(&self) -> u32 {
+        self.time
+    }
+
+    /// Time of day formatted as `HH:MM`.
+    pub fn time_of_day(&self) -> String {
+        format!("{:02}:{:02}", self.time / 60, self.time % 60)
+    }
+
+    pub(super) fn set_time(&mut self, time: u32) {
+        self.time = time;
+    }
+
+    pub fn entries(&self) -> &[DigestEntry] {
+        &self.entries
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub(super) fn add(&mut self, change_number: u32, message: &str) {
+        self.count += 1;
+        if self.entries.len() < MAX_DIGEST_ENTRIES {
+            self.entries.push(DigestEntry {
+                change_number,
+                message: message.to_string(),
+            });
+        }
+    }
+
+    /// When the next digest is due.
+    pub fn next(&self, timezone: &FixedOffset) -> DateTime<Utc> {
+        let last_sent = self.last_sent.with_timezone(timezone);
+        let (hour, minute) = (self.time / 60, self.time % 60);
+        let mut next = last_sent.date().and_hms(hour, minute, 0);
+        if next <= last_sent {
+            next = last_sent.date().succ().and_hms(hour, minute, 0);
+        }
+        next.with_timezone(&Utc)
+    }
+
+    /// Take the collected notifications, and start collecting for the next
+    /// digest.
+    pub(super) fn take(&mut self, now: DateTime<Utc>) -> Digest {
+        let digest = self.clone();
+        self.last_sent = now;
+        self.entries.clear();
+        self.count = 0;
+        digest
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone as _;
+
+    use super::*;
+
+    #[test]
+    fn next() {
+        let now = Utc.ymd(2020, 2, 28).and_hms(12, 0, 0);
+        let utc = FixedOffset::east(0);
+        let digest = Digest::new(18 * 60, now);
+        assert_eq!(digest.time_of_day(), "18:00");
+        assert_eq!(digest.next(&utc), Utc.ymd(2020, 2, 28).and_hms(18, 0, 0));
+        // 18:00 in UTC+8 is already over
+        let utc_plus_8 = FixedOffset::east(8 * 3600);
+        assert_eq!(
+            digest.next(&utc_plus_8),
+            Utc.ymd(2020, 2, 29).and_hms(10, 0, 0)
+        );
+
+        let mut digest = Digest::new(8 * 60 + 30, now);
+        digest.add(1, "first");
+        digest.add(2, "second");
+        let taken = digest.take(Utc.ymd(2020, 2, 29).and_hms(8, 30, 0));
+        assert_eq!(taken.count(), 2);
+        assert_eq!(taken.entries()[1].message, "second");
+        assert_eq!(digest.count(), 0);
+        assert!(digest.entries().is_empty());
+        assert_eq!(digest.next(&utc), Utc.ymd(2020, 3, 1).and_hms(8, 30, 0));
+    }
+}
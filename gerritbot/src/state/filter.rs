@@ -5,12 +5,39 @@ use serde::{Deserialize, Serialize};
 pub struct Filter {
     pub regex: Regex,
     pub enabled: bool,
+    pub mode: FilterMode,
+}
+
+impl Filter {
+    /// Whether the message is suppressed by the filter.
+    pub fn is_filtered(&self, msg: &str) -> bool {
+        self.enabled && self.regex.is_match(msg) == (self.mode == FilterMode::Drop)
+    }
+}
+
+/// What happens to the messages matching a filter.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterMode {
+    /// Matching messages are suppressed.
+    #[default]
+    Drop,
+    /// Only matching messages are delivered.
+    Keep,
+}
+
+impl FilterMode {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct FilterForSerialize<'a> {
     regex: &'a str,
     enabled: bool,
+    #[serde(skip_serializing_if = "FilterMode::is_default", default)]
+    mode: FilterMode,
 }
 
 /// Serialize the filter by storing the regex as a string.
@@ -23,6 +50,7 @@ where
         .map(|f| FilterForSerialize {
             regex: f.regex.as_str(),
             enabled: f.enabled,
+            mode: f.mode,
         })
         .serialize(serializer)
 }
@@ -40,6 +68,7 @@ where
                 .map(|regex| Filter {
                     regex,
                     enabled: f.enabled,
+                    mode: f.mode,
                 })
                 .map_err(|e| {
                     <D::Error as serde::de::Error>::custom(format!("invalid regex: {}", e))
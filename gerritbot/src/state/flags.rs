@@ -20,8 +20,22 @@ pub enum UserFlag {
     NotifyReviewResponses,
     /// User wants notification messages for merged changes.
     NotifyChangeMerged,
-    /// User wants notification messages for abandoned changes.
+    /// User wants notification messages for abandoned or deleted changes.
     NotifyChangeAbandoned,
+    /// User wants notification messages about changes they are CC'd on.
+    NotifyCc,
+    /// User wants a notification message when their change becomes
+    /// submittable.
+    NotifySubmittable,
+    /// User wants notification messages for reviews of patchsets they
+    /// uploaded to changes of other owners.
+    NotifyUploadedByMe,
+    /// User wants a notification message when their change needs a rebase.
+    NotifyMergeConflicts,
+    /// User wants to be reminded of their changes without activity.
+    NotifyStaleReviews,
+    /// User wants to be reminded of changes they didn't vote on yet.
+    NotifyReviewReminders,
 }
 
 impl Display for UserFlag {
@@ -85,6 +99,33 @@ mod test_flag {
         UserFlag::NotifyReviewerAdded,
     );
 
+    test_from_to_string!(notify_cc, "notify_cc", UserFlag::NotifyCc);
+    test_from_to_string!(
+        notify_submittable,
+        "notify_submittable",
+        UserFlag::NotifySubmittable,
+    );
+    test_from_to_string!(
+        notify_uploaded_by_me,
+        "notify_uploaded_by_me",
+        UserFlag::NotifyUploadedByMe,
+    );
+    test_from_to_string!(
+        notify_merge_conflicts,
+        "notify_merge_conflicts",
+        UserFlag::NotifyMergeConflicts,
+    );
+    test_from_to_string!(
+        notify_stale_reviews,
+        "notify_stale_reviews",
+        UserFlag::NotifyStaleReviews,
+    );
+    test_from_to_string!(
+        notify_review_reminders,
+        "notify_review_reminders",
+        UserFlag::NotifyReviewReminders,
+    );
+
     test_parse_fail!(unknown_flag, "unknown_flag");
     test_parse_fail!(integer, "123");
     test_parse_fail!(quotation_mark, "\"");
@@ -95,6 +136,8 @@ const DEFAULT_FLAGS: &[UserFlag] = &[
     UserFlag::NotifyReviewApprovals,
     UserFlag::NotifyReviewInlineComments,
     UserFlag::NotifyReviewerAdded,
+    UserFlag::NotifyStaleReviews,
+    UserFlag::NotifyReviewReminders,
 ];
 
 /// All flags that deal with review comments.
@@ -104,6 +147,13 @@ pub const REVIEW_COMMENT_FLAGS: &[UserFlag] = &[
     UserFlag::NotifyReviewInlineComments,
 ];
 
+/// Notifications posted in group rooms subscribed to a project.
+pub const ROOM_FLAGS: &[UserFlag] = &[
+    UserFlag::NotifyReviewApprovals,
+    UserFlag::NotifyChangeMerged,
+    UserFlag::NotifyChangeAbandoned,
+];
+
 /// All flags that deal with notifications.
 pub const NOTIFICATION_FLAGS: &[UserFlag] = &[
     UserFlag::NotifyReviewApprovals,
@@ -113,23 +163,24 @@ pub const NOTIFICATION_FLAGS: &[UserFlag] = &[
     UserFlag::NotifyReviewResponses,
     UserFlag::NotifyChangeMerged,
     UserFlag::NotifyChangeAbandoned,
+    UserFlag::NotifyCc,
+    UserFlag::NotifySubmittable,
+    UserFlag::NotifyUploadedByMe,
+    UserFlag::NotifyMergeConflicts,
+    UserFlag::NotifyStaleReviews,
+    UserFlag::NotifyReviewReminders,
 ];
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(untagged)]
-pub(super) enum UserFlags {
+pub enum UserFlags {
+    #[default]
     Default,
     // Note: this could be optimized into bitflags to make it faster and avoid
     // allocation.
     Custom(HashSet<UserFlag>),
 }
 
-impl Default for UserFlags {
-    fn default() -> Self {
-        UserFlags::Default
-    }
-}
-
 impl UserFlags {
     pub fn contains(&self, flag: UserFlag) -> bool {
         match self {
@@ -0,0 +1,75 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt as _;
+
+use super::BotError;
+
+/// Lock of the state file against other instances of the bot, so they don't
+/// overwrite each other's saves. It is an advisory lock on a file next to
+/// the state file, which the kernel releases when the instance exits, also
+/// if it crashed. The file contains the process id of the instance, for
+/// information only.
+#[derive(Debug)]
+pub struct StateLock {
+    file: File,
+}
+
+impl StateLock {
+    /// Fails if another instance holds the lock.
+    pub fn acquire(state_filename: impl AsRef<Path>) -> Result<Self, BotError> {
+        let mut filename = state_filename.as_ref().as_os_str().to_owned();
+        filename.push(".lock");
+        let filename = PathBuf::from(filename);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&filename)?;
+        if let Err(e) = file.try_lock_exclusive() {
+            return Err(BotError::Io(io::Error::new(
+                e.kind(),
+                format!("{} is held by another instance: {}", filename.display(), e),
+            )));
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        // the lock is released with the file anyway
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn lock_is_exclusive() {
+        let filename =
+            std::env::temp_dir().join(format!("gerritbot-state-lock-{}.json", std::process::id()));
+        let lock_filename = filename.with_extension("json.lock");
+        // the file of a crashed instance doesn't hold the lock
+        fs::write(&lock_filename, "1").unwrap();
+
+        let lock = StateLock::acquire(&filename).expect("not locked");
+        assert_eq!(
+            fs::read_to_string(&lock_filename).unwrap(),
+            std::process::id().to_string()
+        );
+        assert!(StateLock::acquire(&filename).is_err());
+        drop(lock);
+        let lock = StateLock::acquire(&filename).expect("not unlocked");
+        drop(lock);
+        fs::remove_file(&lock_filename).unwrap();
+    }
+}
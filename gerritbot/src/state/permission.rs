@@ -0,0 +1,50 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Permissions granted to users by the bot admins.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// User may abandon and restore changes from chat.
+    ManageChanges,
+    /// User may vote and comment on changes from chat.
+    Review,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Ok(serde_json::Value::String(s)) = serde_json::to_value(self) {
+            write!(f, "{}", s)
+        } else {
+            panic!("failed to encode permission")
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Permission;
+
+    #[test]
+    fn from_to_string() {
+        assert_eq!(
+            "manage_changes"
+                .parse::<Permission>()
+                .expect("parse failed"),
+            Permission::ManageChanges
+        );
+        assert_eq!(Permission::ManageChanges.to_string(), "manage_changes");
+        assert_eq!("review".parse::<Permission>().unwrap(), Permission::Review);
+        "abandon".parse::<Permission>().expect_err("did not fail");
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike as _, TimeZone, Timelike as _, Weekday};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Recurring time of day, and optionally weekends, during which a user
+/// doesn't want to be notified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    /// Start in minutes after midnight.
+    start: u32,
+    /// End in minutes after midnight; before `start` if the quiet hours span
+    /// midnight.
+    end: u32,
+    /// Saturdays and Sundays are quiet as a whole.
+    #[serde(default)]
+    weekends: bool,
+}
+
+impl QuietHours {
+    /// Whether the time, given in the time zone of the user, is quiet.
+    pub fn contains<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        if self.weekends && matches!(time.weekday(), Weekday::Sat | Weekday::Sun) {
+            return true;
+        }
+        let minute = time.hour() * 60 + time.minute();
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            self.start <= minute || minute < self.end
+        }
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}–{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )?;
+        if self.weekends {
+            write!(f, " and weekends")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = ();
+
+    /// Parse e.g. `19:00-08:00` or `22-7 weekends`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        lazy_static! {
+            static ref QUIET_HOURS_REGEX: Regex = Regex::new(
+                r"(?i)^(\d{1,2})(?::(\d{2}))?\s*[-–]\s*(\d{1,2})(?::(\d{2}))?(\s+(?:and\s+)?weekends)?$"
+            )
+            .unwrap();
+        };
+
+        let cap = QUIET_HOURS_REGEX.captures(s.trim()).ok_or(())?;
+        let minutes = |hour: usize, minute: usize| -> Result<u32, ()> {
+            let hour: u32 = cap[hour].parse().map_err(|_| ())?;
+            let minute: u32 = cap
+                .get(minute)
+                .map_or(Ok(0), |m| m.as_str().parse())
+                .map_err(|_| ())?;
+            if hour > 24 || minute > 59 || hour * 60 + minute > MINUTES_PER_DAY {
+                return Err(());
+            }
+            Ok((hour * 60 + minute) % MINUTES_PER_DAY)
+        };
+        Ok(Self {
+            start: minutes(1, 2)?,
+            end: minutes(3, 4)?,
+            weekends: cap.get(5).is_some(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{FixedOffset, Utc};
+
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let quiet_hours: QuietHours = "19:00-08:00".parse().unwrap();
+        assert_eq!(quiet_hours.to_string(), "19:00–08:00");
+        let quiet_hours: QuietHours = "22-7:30 weekends".parse().unwrap();
+        assert_eq!(quiet_hours.to_string(), "22:00–07:30 and weekends");
+        assert_eq!("12:00-24:00".parse::<QuietHours>().unwrap().end, 0);
+        assert!("25:00-08:00".parse::<QuietHours>().is_err());
+        assert!("19:60-08:00".parse::<QuietHours>().is_err());
+        assert!("evenings".parse::<QuietHours>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        let quiet_hours: QuietHours = "19:00-08:00 weekends".parse().unwrap();
+        // Friday
+        let at = |hour, minute| Utc.ymd(2020, 2, 28).and_hms(hour, minute, 0);
+        assert!(quiet_hours.contains(&at(19, 0)));
+        assert!(quiet_hours.contains(&at(7, 59)));
+        assert!(!quiet_hours.contains(&at(8, 0)));
+        assert!(!quiet_hours.contains(&at(18, 59)));
+        // Saturday
+        assert!(quiet_hours.contains(&Utc.ymd(2020, 2, 29).and_hms(12, 0, 0)));
+
+        // 17:30 UTC is 19:30 in UTC+2
+        let utc_plus_2 = FixedOffset::east(2 * 3600);
+        assert!(!quiet_hours.contains(&at(17, 30)));
+        assert!(quiet_hours.contains(&at(17, 30).with_timezone(&utc_plus_2)));
+
+        let quiet_hours: QuietHours = "12:00-13:00".parse().unwrap();
+        assert!(quiet_hours.contains(&at(12, 30)));
+        assert!(!quiet_hours.contains(&at(13, 0)));
+        assert!(!quiet_hours.contains(&Utc.ymd(2020, 2, 29).and_hms(14, 0, 0)));
+    }
+}
@@ -0,0 +1,50 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use gerritbot_gerrit as gerrit;
+use gerritbot_spark as spark;
+
+/// A reviewer who didn't vote on a change yet, and is reminded of it from
+/// time to time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReviewReminder {
+    /// The change, without its patchsets, comments and other details.
+    pub change: gerrit::Change,
+    pub reviewer: spark::Email,
+    /// When the reviewer was added.
+    #[serde(with = "gerrit::timestamp")]
+    pub added: DateTime<Utc>,
+    /// Number of reminders sent so far.
+    #[serde(default)]
+    pub sent: u32,
+    /// When the next reminder is due.
+    #[serde(with = "gerrit::timestamp")]
+    pub next: DateTime<Utc>,
+}
+
+impl ReviewReminder {
+    pub(super) fn new(
+        change: &gerrit::Change,
+        reviewer: &spark::EmailRef,
+        added: DateTime<Utc>,
+        next: DateTime<Utc>,
+    ) -> Self {
+        let change = gerrit::Change {
+            commit_message: String::new(),
+            current_patch_set: None,
+            patch_sets: None,
+            comments: None,
+            submit_records: None,
+            submit_requirements: None,
+            all_reviewers: None,
+            ..change.clone()
+        };
+        Self {
+            change,
+            reviewer: reviewer.to_owned(),
+            added,
+            sent: 0,
+            next,
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Role of a user. Admins may run all commands, including the dangerous ones.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    /// Made an admin by another admin; the admins from the configuration
+    /// don't need the role.
+    Admin,
+}
+
+impl Role {
+    pub fn is_user(&self) -> bool {
+        *self == Role::User
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Ok(serde_json::Value::String(s)) = serde_json::to_value(self) {
+            write!(f, "{}", s)
+        } else {
+            panic!("failed to encode role")
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+    }
+}
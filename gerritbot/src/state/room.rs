@@ -0,0 +1,48 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use gerritbot_spark as spark;
+
+/// A group room that gets notifications about the projects it is subscribed
+/// to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Room {
+    id: spark::RoomId,
+    projects: BTreeSet<String>,
+}
+
+impl Room {
+    pub(super) fn new(id: spark::RoomId) -> Self {
+        Self {
+            id,
+            projects: BTreeSet::new(),
+        }
+    }
+
+    pub fn id(&self) -> &spark::RoomIdRef {
+        &self.id
+    }
+
+    pub fn projects(&self) -> impl Iterator<Item = &str> {
+        self.projects.iter().map(String::as_str)
+    }
+
+    pub fn is_subscribed(&self, project: &str) -> bool {
+        self.projects.contains(project)
+    }
+
+    /// Returns `false` if the room was already subscribed to the project.
+    pub(super) fn subscribe(&mut self, project: &str) -> bool {
+        self.projects.insert(project.to_string())
+    }
+
+    /// Returns `false` if the room wasn't subscribed to the project.
+    pub(super) fn unsubscribe(&mut self, project: &str) -> bool {
+        self.projects.remove(project)
+    }
+
+    pub(super) fn has_subscriptions(&self) -> bool {
+        !self.projects.is_empty()
+    }
+}
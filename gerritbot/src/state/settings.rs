@@ -0,0 +1,43 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::filter::{deserialize_filter, serialize_filter, Filter};
+use super::flags::UserFlags;
+use super::quiet_hours::QuietHours;
+use super::vote_policy::VotePolicy;
+
+/// Personal settings of a user, exported and imported as JSON, e.g. to move
+/// to another bot instance. The role, permissions and linked emails are not
+/// part of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "UserFlags::is_default", default)]
+    pub(super) flags: UserFlags,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_filter",
+        deserialize_with = "deserialize_filter",
+        default
+    )]
+    pub(super) filter: Option<Filter>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub(super) branches: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    pub(super) topics: BTreeSet<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(super) vote_threshold: Option<i32>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub(super) label_votes: BTreeMap<String, VotePolicy>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(super) utc_offset: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(super) timezone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(super) quiet_hours: Option<QuietHours>,
+    /// Time of day of the digest in minutes after midnight.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(super) digest: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(super) language: Option<String>,
+}
@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use gerritbot_gerrit as gerrit;
+
+/// Number of missed notifications listed in the summary after a snooze.
+const MAX_SUMMARY_LINES: usize = 20;
+
+/// Notifications are held back until the end of the snooze. Afterwards the
+/// user gets a summary of the missed ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snooze {
+    #[serde(with = "gerrit::timestamp")]
+    until: DateTime<Utc>,
+    /// First lines of the missed notifications, at most `MAX_SUMMARY_LINES`.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    missed: Vec<String>,
+    #[serde(default)]
+    missed_count: usize,
+}
+
+impl Snooze {
+    pub(super) fn new(until: DateTime<Utc>) -> Self {
+        Self {
+            until,
+            missed: Vec::new(),
+            missed_count: 0,
+        }
+    }
+
+    pub fn until(&self) -> DateTime<Utc> {
+        self.until
+    }
+
+    pub(super) fn set_until(&mut self, until: DateTime<Utc>) {
+        self.until = until;
+    }
+
+    pub fn is_over(&self, now: DateTime<Utc>) -> bool {
+        self.until <= now
+    }
+
+    pub(super) fn add_missed(&mut self, message: &str) {
+        self.missed_count += 1;
+        if self.missed.len() < MAX_SUMMARY_LINES {
+            let first_line = message.lines().next().unwrap_or_default();
+            self.missed.push(first_line.to_string());
+        }
+    }
+
+    /// Summary of the missed notifications, if any.
+    pub fn summary(&self) -> Option<String> {
+        if self.missed_count == 0 {
+            return None;
+        }
+        let mut summary = format!(
+            "While you were snoozing, you missed {} notification(s):\n",
+            self.missed_count
+        );
+        for line in &self.missed {
+            summary.push_str("\n* ");
+            summary.push_str(line);
+        }
+        if self.missed_count > self.missed.len() {
+            summary.push_str(&format!(
+                "\n\n… and {} more.",
+                self.missed_count - self.missed.len()
+            ));
+        }
+        Some(summary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone as _;
+
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let until = Utc.timestamp(1_500_000_000, 0);
+        let mut snooze = Snooze::new(until);
+        assert!(snooze.summary().is_none());
+        assert!(!snooze.is_over(until - chrono::Duration::seconds(1)));
+        assert!(snooze.is_over(until));
+
+        for i in 0..MAX_SUMMARY_LINES + 2 {
+            snooze.add_missed(&format!("notification {}\n\ndetails", i));
+        }
+        let summary = snooze.summary().unwrap();
+        assert!(summary.starts_with("While you were snoozing, you missed 22 notification(s):"));
+        assert!(summary.contains("\n* notification 19"));
+        assert!(!summary.contains("notification 20"));
+        assert!(!summary.contains("details"));
+        assert!(summary.ends_with("… and 2 more."));
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use gerritbot_gerrit as gerrit;
+use gerritbot_spark as spark;
+
+/// A team of users managed by admins. The team is subscribed to projects,
+/// branches and topics as a whole; its members are looked up when notifying,
+/// so new members get the notifications of the existing subscriptions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Team {
+    name: String,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    members: BTreeSet<spark::Email>,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    subscriptions: BTreeSet<TeamSubscription>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TeamSubscription {
+    /// Changes of a project, or only of one of its branches.
+    Project {
+        project: String,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        branch: Option<String>,
+    },
+    /// Changes with a topic.
+    Topic(String),
+}
+
+impl Team {
+    pub(super) fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            members: BTreeSet::new(),
+            subscriptions: BTreeSet::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &spark::EmailRef> {
+        self.members.iter().map(|email| &**email)
+    }
+
+    pub fn subscriptions(&self) -> impl Iterator<Item = &TeamSubscription> {
+        self.subscriptions.iter()
+    }
+
+    /// Whether any subscription of the team matches the change.
+    pub fn is_subscribed(&self, change: &gerrit::Change) -> bool {
+        self.subscriptions
+            .iter()
+            .any(|subscription| subscription.matches(change))
+    }
+
+    /// Returns `false` if the email was already a member.
+    pub(super) fn add_member(&mut self, email: &spark::EmailRef) -> bool {
+        self.members.insert(email.to_owned())
+    }
+
+    /// Returns `false` if the email wasn't a member.
+    pub(super) fn remove_member(&mut self, email: &spark::EmailRef) -> bool {
+        self.members.remove(email)
+    }
+
+    /// Returns `false` if the team was already subscribed.
+    pub(super) fn subscribe(&mut self, subscription: TeamSubscription) -> bool {
+        self.subscriptions.insert(subscription)
+    }
+
+    /// Returns `false` if the team wasn't subscribed.
+    pub(super) fn unsubscribe(&mut self, subscription: &TeamSubscription) -> bool {
+        self.subscriptions.remove(subscription)
+    }
+}
+
+impl TeamSubscription {
+    pub fn matches(&self, change: &gerrit::Change) -> bool {
+        match self {
+            TeamSubscription::Project { project, branch } => {
+                *project == change.project
+                    && branch
+                        .as_ref()
+                        .is_none_or(|branch| *branch == change.branch)
+            }
+            TeamSubscription::Topic(topic) => change.topic.as_ref() == Some(topic),
+        }
+    }
+}
+
+impl fmt::Display for TeamSubscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TeamSubscription::Project {
+                project,
+                branch: None,
+            } => write!(f, "project `{}`", project),
+            TeamSubscription::Project {
+                project,
+                branch: Some(branch),
+            } => write!(f, "project `{}` branch `{}`", project, branch),
+            TeamSubscription::Topic(topic) => write!(f, "topic `{}`", topic),
+        }
+    }
+}
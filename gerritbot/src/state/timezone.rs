@@ -0,0 +1,177 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, Offset as _, TimeZone as _, Utc};
+use chrono_tz::Tz;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// Time zone of a user, either a fixed offset to UTC or a time zone of the
+/// IANA database, e.g. `Europe/Berlin`, which follows its daylight saving
+/// time rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timezone {
+    Offset(FixedOffset),
+    Named(Tz),
+}
+
+impl Timezone {
+    /// Look up a time zone by its IANA name, ignoring the case.
+    pub fn named(name: &str) -> Option<Self> {
+        chrono_tz::TZ_VARIANTS
+            .iter()
+            .find(|timezone| timezone.name().eq_ignore_ascii_case(name))
+            .map(|timezone| Timezone::Named(*timezone))
+    }
+
+    /// Name of the time zone, if it is a named one.
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            Timezone::Offset(_) => None,
+            Timezone::Named(timezone) => Some(timezone.name()),
+        }
+    }
+
+    /// Offset to UTC at the given point in time.
+    pub fn offset_at(&self, time: DateTime<Utc>) -> FixedOffset {
+        match self {
+            Timezone::Offset(offset) => *offset,
+            Timezone::Named(timezone) => timezone.offset_from_utc_datetime(&time.naive_utc()).fix(),
+        }
+    }
+
+    /// Point in time of a local date and time.
+    pub fn local_to_utc(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        let to_utc = |offset: FixedOffset| {
+            Utc.from_utc_datetime(&(local - Duration::seconds(offset.local_minus_utc().into())))
+        };
+        // the offset may change between the guess and the actual time
+        let guess = to_utc(self.offset_at(Utc.from_utc_datetime(&local)));
+        to_utc(self.offset_at(guess))
+    }
+
+    /// Format a point in time as local time, e.g. `2020-02-28 19:00
+    /// Europe/Berlin`.
+    pub fn format_time(&self, time: DateTime<Utc>) -> String {
+        format!(
+            "{} {}",
+            time.with_timezone(&self.offset_at(time))
+                .format("%Y-%m-%d %H:%M"),
+            self
+        )
+    }
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Timezone::Offset(FixedOffset::east(0))
+    }
+}
+
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Timezone::Offset(offset) if offset.local_minus_utc() == 0 => write!(f, "UTC"),
+            Timezone::Offset(offset) => write!(f, "UTC{}", offset),
+            Timezone::Named(timezone) => write!(f, "{}", timezone.name()),
+        }
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = ();
+
+    /// Parse a named time zone, e.g. `Europe/Berlin`, or an offset to UTC,
+    /// e.g. `UTC+2`, `+05:30` or `UTC`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        lazy_static! {
+            static ref UTC_OFFSET_REGEX: Regex =
+                Regex::new(r"(?i)^(?:utc|gmt)?\s*(?:([+-])\s*(\d{1,2})(?::?(\d{2}))?)?$").unwrap();
+        };
+
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(());
+        }
+        let cap = match UTC_OFFSET_REGEX.captures(s) {
+            Some(cap) => cap,
+            None => return Timezone::named(s).ok_or(()),
+        };
+        let seconds = match cap.get(1) {
+            Some(sign) => {
+                let hours: i32 = cap[2].parse().map_err(|_| ())?;
+                let minutes: i32 = cap
+                    .get(3)
+                    .map_or(Ok(0), |m| m.as_str().parse())
+                    .map_err(|_| ())?;
+                if hours > 14 || minutes > 59 {
+                    return Err(());
+                }
+                let seconds = hours * 3600 + minutes * 60;
+                if sign.as_str() == "-" {
+                    -seconds
+                } else {
+                    seconds
+                }
+            }
+            None => 0,
+        };
+        FixedOffset::east_opt(seconds)
+            .map(Timezone::Offset)
+            .ok_or(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let berlin: Timezone = "europe/berlin".parse().unwrap();
+        assert_eq!(berlin.to_string(), "Europe/Berlin");
+        assert_eq!(berlin.name(), Some("Europe/Berlin"));
+        let offset: Timezone = "UTC+5:30".parse().unwrap();
+        assert_eq!(offset.to_string(), "UTC+05:30");
+        assert_eq!(offset.name(), None);
+        assert_eq!("utc".parse::<Timezone>().unwrap().to_string(), "UTC");
+        assert!("Mars/Olympus_Mons".parse::<Timezone>().is_err());
+        assert!("UTC+15".parse::<Timezone>().is_err());
+    }
+
+    #[test]
+    fn daylight_saving_time() {
+        let berlin = Timezone::named("Europe/Berlin").unwrap();
+        let hours = |time: DateTime<Utc>| berlin.offset_at(time).local_minus_utc() / 3600;
+        // switches on 2020-03-29 and 2020-10-25 at 1:00 UTC
+        assert_eq!(hours(Utc.ymd(2020, 3, 29).and_hms(0, 59, 0)), 1);
+        assert_eq!(hours(Utc.ymd(2020, 3, 29).and_hms(1, 0, 0)), 2);
+        assert_eq!(hours(Utc.ymd(2020, 10, 25).and_hms(0, 59, 0)), 2);
+        assert_eq!(hours(Utc.ymd(2020, 10, 25).and_hms(1, 0, 0)), 1);
+
+        let new_york = Timezone::named("America/New_York").unwrap();
+        let hours = |time: DateTime<Utc>| new_york.offset_at(time).local_minus_utc() / 3600;
+        // switches on 2020-03-08 at 7:00 UTC and on 2020-11-01 at 6:00 UTC
+        assert_eq!(hours(Utc.ymd(2020, 3, 8).and_hms(6, 59, 0)), -5);
+        assert_eq!(hours(Utc.ymd(2020, 3, 8).and_hms(7, 0, 0)), -4);
+        assert_eq!(hours(Utc.ymd(2020, 11, 1).and_hms(5, 59, 0)), -4);
+        assert_eq!(hours(Utc.ymd(2020, 11, 1).and_hms(6, 0, 0)), -5);
+
+        let sydney = Timezone::named("Australia/Sydney").unwrap();
+        let hours = |time: DateTime<Utc>| sydney.offset_at(time).local_minus_utc() / 3600;
+        // daylight saving time from October to April
+        assert_eq!(hours(Utc.ymd(2020, 1, 15).and_hms(0, 0, 0)), 11);
+        assert_eq!(hours(Utc.ymd(2020, 7, 15).and_hms(0, 0, 0)), 10);
+
+        assert_eq!(
+            berlin.local_to_utc(NaiveDate::from_ymd(2020, 7, 1).and_hms(8, 0, 0)),
+            Utc.ymd(2020, 7, 1).and_hms(6, 0, 0)
+        );
+        assert_eq!(
+            berlin.format_time(Utc.ymd(2020, 2, 28).and_hms(18, 0, 0)),
+            "2020-02-28 19:00 Europe/Berlin"
+        );
+    }
+}
@@ -0,0 +1,17 @@
+use super::filter::Filter;
+use super::flags::UserFlags;
+
+/// The previous value of the setting changed by the last command of a user,
+/// to undo the change.
+#[derive(Debug, Clone)]
+pub enum Undo {
+    Enabled(bool),
+    Flags(UserFlags),
+    Filter(Option<Filter>),
+    /// Whether the user was subscribed to the topic.
+    Topic {
+        topic: String,
+        subscribed: bool,
+    },
+    Branches(Vec<String>),
+}
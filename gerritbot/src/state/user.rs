@@ -1,22 +1,39 @@
 use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet};
 
+use chrono::{DateTime, FixedOffset, Utc};
 use serde::{Deserialize, Serialize};
 
+use gerritbot_gerrit as gerrit;
 use gerritbot_spark as spark;
 
-use super::filter::{deserialize_filter, serialize_filter, Filter};
+use super::activity::Activity;
+use super::digest::Digest;
+use super::filter::{deserialize_filter, serialize_filter, Filter, FilterMode};
 use super::flags::{UserFlag, UserFlags};
+use super::permission::Permission;
+use super::quiet_hours::QuietHours;
+use super::role::Role;
+use super::settings::Settings;
+use super::snooze::Snooze;
+use super::timezone::Timezone;
+use super::undo::Undo;
+use super::vote_policy::VotePolicy;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
-    // Legacy attribute.  Keep so we don't drop it on deserialize, serialize.
-    // Should be removed later.
+    /// Webex id of the user, verified when the user enabled notifications.
     #[serde(skip_serializing_if = "Option::is_none")]
-    spark_person_id: Option<String>,
+    spark_person_id: Option<spark::PersonId>,
     /// email of the user; assumed to be the same in Spark and Gerrit
     email: spark::Email,
     #[serde(skip_serializing_if = "UserFlags::is_default", default)]
     flags: UserFlags,
+    #[serde(skip_serializing_if = "Role::is_user", default)]
+    role: Role,
+    /// Permissions granted by the bot admins.
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    permissions: BTreeSet<Permission>,
     enabled: bool,
     #[serde(
         skip_serializing_if = "Option::is_none",
@@ -25,6 +42,52 @@ pub struct User {
         default
     )]
     filter: Option<Filter>,
+    /// Patterns of the branches the user gets notifications about; all
+    /// branches if empty.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    branches: Vec<String>,
+    /// Gerrit topics the user gets notifications about, no matter whether
+    /// they take part in the changes.
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    topics: BTreeSet<String>,
+    /// Only votes below this value are notified about; all votes if none.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    vote_threshold: Option<i32>,
+    /// Which votes on a label are notified about, taking precedence over the
+    /// vote threshold.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    label_votes: BTreeMap<String, VotePolicy>,
+    /// Further emails of the user in Gerrit, if the Gerrit account doesn't
+    /// use the email of the Webex account.
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    gerrit_emails: BTreeSet<spark::Email>,
+    /// Gerrit email waiting for the approval of an admin to be linked.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pending_gerrit_email: Option<spark::Email>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    snooze: Option<Snooze>,
+    /// Offset of the time zone of the user to UTC in seconds.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    utc_offset: Option<i32>,
+    /// Named time zone of the user, e.g. `Europe/Berlin`; takes precedence
+    /// over the offset.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    timezone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    quiet_hours: Option<QuietHours>,
+    /// Notifications held back during the quiet hours.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    held_notifications: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    digest: Option<Digest>,
+    /// Language of the replies to commands; English if none.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    language: Option<String>,
+    #[serde(skip_serializing_if = "Activity::is_default", default)]
+    activity: Activity,
+    /// Previous value of the setting changed last.
+    #[serde(skip_serializing, skip_deserializing)]
+    undo: Option<Undo>,
 }
 
 impl User {
@@ -33,8 +96,25 @@ impl User {
             spark_person_id: None,
             email,
             filter: None,
+            branches: Vec::new(),
+            topics: BTreeSet::new(),
+            vote_threshold: None,
+            label_votes: BTreeMap::new(),
+            gerrit_emails: BTreeSet::new(),
+            pending_gerrit_email: None,
+            snooze: None,
+            utc_offset: None,
+            timezone: None,
+            quiet_hours: None,
+            held_notifications: Vec::new(),
+            digest: None,
+            language: None,
+            activity: Activity::default(),
+            undo: None,
             enabled: true,
             flags: UserFlags::Default,
+            role: Role::User,
+            permissions: BTreeSet::new(),
         }
     }
 
@@ -42,6 +122,52 @@ impl User {
         &self.email
     }
 
+    pub fn gerrit_emails(&self) -> impl Iterator<Item = &spark::EmailRef> {
+        self.gerrit_emails.iter().map(|email| &**email)
+    }
+
+    /// Whether the email is the one of the user or a linked Gerrit email.
+    pub fn has_email(&self, email: &spark::EmailRef) -> bool {
+        self.email() == email || self.gerrit_emails.iter().any(|linked| **linked == *email)
+    }
+
+    /// Email of the user in Gerrit: the first linked one, or the one of the
+    /// Webex account.
+    pub fn gerrit_email(&self) -> &spark::EmailRef {
+        self.gerrit_emails().next().unwrap_or_else(|| self.email())
+    }
+
+    /// Returns `false` if the email was already linked.
+    pub(super) fn link_gerrit_email(&mut self, email: spark::Email) -> bool {
+        if self.pending_gerrit_email.as_ref() == Some(&email) {
+            self.pending_gerrit_email = None;
+        }
+        self.gerrit_emails.insert(email)
+    }
+
+    /// Returns `false` if the email wasn't linked.
+    pub(super) fn unlink_gerrit_email(&mut self, email: &spark::EmailRef) -> bool {
+        let linked = self.gerrit_emails.len();
+        self.gerrit_emails.retain(|linked| **linked != *email);
+        self.gerrit_emails.len() != linked
+    }
+
+    pub fn pending_gerrit_email(&self) -> Option<&spark::EmailRef> {
+        self.pending_gerrit_email.as_deref()
+    }
+
+    pub(super) fn set_pending_gerrit_email(&mut self, email: Option<spark::Email>) {
+        self.pending_gerrit_email = email;
+    }
+
+    pub fn person_id(&self) -> Option<&spark::PersonIdRef> {
+        self.spark_person_id.as_deref()
+    }
+
+    pub fn set_person_id(&mut self, person_id: spark::PersonId) {
+        self.spark_person_id = Some(person_id);
+    }
+
     pub fn has_any_flag<I, F>(&self, flags: I) -> bool
     where
         I: IntoIterator<Item = F>,
@@ -54,18 +180,112 @@ impl User {
     }
 
     pub fn has_flag(&self, flag: UserFlag) -> bool {
-        self.has_any_flag(&[flag])
+        self.has_any_flag([flag])
+    }
+
+    /// Whether the user didn't change their flags. Only then the default
+    /// flags of projects apply to them.
+    pub fn has_default_flags(&self) -> bool {
+        self.enabled && self.flags.is_default()
     }
 
     pub fn reset_flags(&mut self) {
         self.flags.reset();
     }
 
+    /// Restore the default flags and drop the filter, subscriptions, snooze
+    /// and the other settings. Role and permissions are kept.
+    pub fn reset_settings(&mut self) {
+        self.reset_flags();
+        self.filter = None;
+        self.branches.clear();
+        self.topics.clear();
+        self.vote_threshold = None;
+        self.label_votes.clear();
+        self.snooze = None;
+        self.utc_offset = None;
+        self.timezone = None;
+        self.quiet_hours = None;
+        self.held_notifications.clear();
+        self.digest = None;
+        self.language = None;
+        self.undo = None;
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            flags: self.flags.clone(),
+            filter: self.filter.clone(),
+            branches: self.branches.clone(),
+            topics: self.topics.clone(),
+            vote_threshold: self.vote_threshold,
+            label_votes: self.label_votes.clone(),
+            utc_offset: self.utc_offset,
+            timezone: self.timezone.clone(),
+            quiet_hours: self.quiet_hours,
+            digest: self.digest.as_ref().map(Digest::time),
+            language: self.language.clone(),
+        }
+    }
+
+    /// Replace the settings of the user, except for the digest, which is
+    /// left to the caller.
+    pub(super) fn set_settings(&mut self, settings: Settings) {
+        let Settings {
+            flags,
+            filter,
+            branches,
+            topics,
+            vote_threshold,
+            label_votes,
+            utc_offset,
+            timezone,
+            quiet_hours,
+            digest: _,
+            language,
+        } = settings;
+        self.flags = flags;
+        self.filter = filter;
+        self.branches = branches;
+        self.topics = topics;
+        self.vote_threshold = vote_threshold;
+        self.label_votes = label_votes;
+        self.utc_offset = utc_offset;
+        self.timezone = timezone;
+        self.quiet_hours = quiet_hours;
+        self.language = language;
+        self.undo = None;
+    }
+
     pub fn set_flag(&mut self, flag: UserFlag, value: bool) {
+        self.undo = Some(Undo::Flags(self.flags.clone()));
         self.flags.set(flag, value);
     }
 
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Returns `false` if the user already had the role.
+    pub(super) fn set_role(&mut self, role: Role) -> bool {
+        std::mem::replace(&mut self.role, role) != role
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// Returns `false` if the permission was already granted or revoked.
+    pub(super) fn set_permission(&mut self, permission: Permission, granted: bool) -> bool {
+        if granted {
+            self.permissions.insert(permission)
+        } else {
+            self.permissions.remove(&permission)
+        }
+    }
+
     pub fn set_enabled(&mut self, enabled: bool) {
+        self.undo = Some(Undo::Enabled(self.enabled));
         self.enabled = enabled;
     }
 
@@ -75,11 +295,358 @@ impl User {
 
     pub fn set_filter_enabled(&mut self, enabled: bool) {
         if let Some(f) = self.filter.as_mut() {
+            self.undo = Some(Undo::Filter(Some(f.clone())));
             f.enabled = enabled;
         }
     }
 
+    /// Returns `false` if the user doesn't have a filter.
+    pub fn set_filter_mode(&mut self, mode: FilterMode) -> bool {
+        match self.filter.as_mut() {
+            Some(f) => {
+                self.undo = Some(Undo::Filter(Some(f.clone())));
+                f.mode = mode;
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn set_filter(&mut self, filter: Filter) {
-        self.filter = Some(filter);
+        self.undo = Some(Undo::Filter(self.filter.replace(filter)));
+    }
+
+    pub fn snooze(&self) -> Option<&Snooze> {
+        self.snooze.as_ref()
+    }
+
+    pub(super) fn snooze_mut(&mut self) -> Option<&mut Snooze> {
+        self.snooze.as_mut()
+    }
+
+    pub(super) fn set_snooze(&mut self, snooze: Snooze) {
+        self.snooze = Some(snooze);
+    }
+
+    pub(super) fn take_snooze(&mut self) -> Option<Snooze> {
+        self.snooze.take()
+    }
+
+    /// Time zone of the user, UTC if not set.
+    pub fn timezone(&self) -> Timezone {
+        self.timezone
+            .as_deref()
+            .and_then(Timezone::named)
+            .or_else(|| {
+                self.utc_offset
+                    .and_then(FixedOffset::east_opt)
+                    .map(Timezone::Offset)
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn set_timezone(&mut self, timezone: Timezone) {
+        match timezone {
+            Timezone::Offset(offset) => {
+                self.utc_offset = Some(offset.local_minus_utc());
+                self.timezone = None;
+            }
+            Timezone::Named(_) => {
+                self.utc_offset = None;
+                self.timezone = timezone.name().map(String::from);
+            }
+        }
+    }
+
+    pub fn quiet_hours(&self) -> Option<&QuietHours> {
+        self.quiet_hours.as_ref()
+    }
+
+    pub fn set_quiet_hours(&mut self, quiet_hours: Option<QuietHours>) {
+        self.quiet_hours = quiet_hours;
+    }
+
+    /// Whether the time is in the quiet hours of the user.
+    pub fn is_quiet(&self, now: DateTime<Utc>) -> bool {
+        self.quiet_hours
+            .map(|quiet_hours| {
+                quiet_hours.contains(&now.with_timezone(&self.timezone().offset_at(now)))
+            })
+            .unwrap_or(false)
+    }
+
+    pub fn held_notifications(&self) -> &[String] {
+        &self.held_notifications
+    }
+
+    /// Hold back a notification. The oldest ones are dropped if there are
+    /// already `max_held` of them.
+    pub(super) fn hold_notification(&mut self, message: &str, max_held: usize) {
+        if self.held_notifications.len() >= max_held {
+            self.held_notifications.remove(0);
+        }
+        self.held_notifications.push(message.to_string());
+    }
+
+    pub(super) fn take_held_notifications(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.held_notifications)
+    }
+
+    pub fn digest(&self) -> Option<&Digest> {
+        self.digest.as_ref()
+    }
+
+    pub(super) fn digest_mut(&mut self) -> Option<&mut Digest> {
+        self.digest.as_mut()
+    }
+
+    /// Returns the previous digest.
+    pub(super) fn set_digest(&mut self, digest: Option<Digest>) -> Option<Digest> {
+        std::mem::replace(&mut self.digest, digest)
+    }
+
+    pub fn activity(&self) -> &Activity {
+        &self.activity
+    }
+
+    pub(super) fn activity_mut(&mut self) -> &mut Activity {
+        &mut self.activity
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    pub fn set_language(&mut self, language: Option<String>) {
+        self.language = language;
+    }
+
+    pub fn branches(&self) -> &[String] {
+        &self.branches
+    }
+
+    pub fn set_branches(&mut self, branches: Vec<String>) {
+        let previous = std::mem::replace(&mut self.branches, branches);
+        self.undo = Some(Undo::Branches(previous));
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &str> {
+        self.topics.iter().map(String::as_str)
+    }
+
+    pub fn is_subscribed_to_topic(&self, topic: &str) -> bool {
+        self.topics.contains(topic)
+    }
+
+    /// Returns `false` if the user was already subscribed to the topic.
+    pub(super) fn subscribe_topic(&mut self, topic: &str) -> bool {
+        let subscribed = self.topics.insert(topic.to_string());
+        if subscribed {
+            self.undo = Some(Undo::Topic {
+                topic: topic.to_string(),
+                subscribed: false,
+            });
+        }
+        subscribed
+    }
+
+    /// Returns `false` if the user wasn't subscribed to the topic.
+    pub(super) fn unsubscribe_topic(&mut self, topic: &str) -> bool {
+        let unsubscribed = self.topics.remove(topic);
+        if unsubscribed {
+            self.undo = Some(Undo::Topic {
+                topic: topic.to_string(),
+                subscribed: true,
+            });
+        }
+        unsubscribed
+    }
+
+    /// Restore the previous value of the setting changed last, and return
+    /// it. Undoing again redoes the change.
+    pub(super) fn undo(&mut self) -> Option<Undo> {
+        let undo = self.undo.take()?;
+        let redo = match &undo {
+            Undo::Enabled(enabled) => Undo::Enabled(std::mem::replace(&mut self.enabled, *enabled)),
+            Undo::Flags(flags) => Undo::Flags(std::mem::replace(&mut self.flags, flags.clone())),
+            Undo::Filter(filter) => {
+                Undo::Filter(std::mem::replace(&mut self.filter, filter.clone()))
+            }
+            Undo::Topic { topic, subscribed } => {
+                if *subscribed {
+                    self.topics.insert(topic.clone());
+                } else {
+                    self.topics.remove(topic);
+                }
+                Undo::Topic {
+                    topic: topic.clone(),
+                    subscribed: !subscribed,
+                }
+            }
+            Undo::Branches(branches) => {
+                Undo::Branches(std::mem::replace(&mut self.branches, branches.clone()))
+            }
+        };
+        self.undo = Some(redo);
+        Some(undo)
+    }
+
+    pub fn vote_threshold(&self) -> Option<i32> {
+        self.vote_threshold
+    }
+
+    /// Without a threshold, the rules of the labels are dropped, too.
+    pub fn set_vote_threshold(&mut self, vote_threshold: Option<i32>) {
+        self.vote_threshold = vote_threshold;
+        if vote_threshold.is_none() {
+            self.label_votes.clear();
+        }
+    }
+
+    pub fn label_votes(&self) -> &BTreeMap<String, VotePolicy> {
+        &self.label_votes
+    }
+
+    pub fn set_label_vote_policy(&mut self, label: String, policy: VotePolicy) {
+        self.label_votes
+            .retain(|existing, _| !existing.eq_ignore_ascii_case(&label));
+        self.label_votes.insert(label, policy);
+    }
+
+    /// Which votes on the label the user gets notifications about.
+    pub fn vote_policy(&self, label: &str) -> VotePolicy {
+        self.label_votes
+            .iter()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(label))
+            .map(|(_, policy)| *policy)
+            .unwrap_or_else(|| VotePolicy::from_threshold(self.vote_threshold))
+    }
+
+    /// Whether the user gets notifications about the votes of a review.
+    /// Reviews without new votes are always of interest.
+    pub fn is_interested_in_approvals(&self, approvals: &[gerrit::Approval]) -> bool {
+        let mut votes = approvals
+            .iter()
+            .map(|approval| {
+                let value = |value: Option<&str>| -> i32 {
+                    value.and_then(|value| value.parse().ok()).unwrap_or(0)
+                };
+                (
+                    &approval.approval_type,
+                    value(Some(&approval.value)),
+                    value(approval.old_value.as_deref()),
+                )
+            })
+            .filter(|(_, value, old_value)| value != old_value)
+            .peekable();
+        votes.peek().is_none()
+            || votes.any(|(label, value, _)| self.vote_policy(label).allows(value))
+    }
+
+    /// Whether the user gets notifications about changes on the branch.
+    pub fn is_interested_in_branch(&self, branch: &str) -> bool {
+        self.branches.is_empty()
+            || self
+                .branches
+                .iter()
+                .any(|pattern| matches_branch_pattern(pattern, branch))
+    }
+}
+
+/// Match the branch against a pattern, in which `*` matches any characters.
+fn matches_branch_pattern(pattern: &str, branch: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let mut rest = match branch.strip_prefix(parts.next().unwrap_or_default()) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // no wildcard
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn branch_patterns() {
+        assert!(matches_branch_pattern("master", "master"));
+        assert!(!matches_branch_pattern("master", "master-old"));
+        assert!(matches_branch_pattern("release/*", "release/1.0"));
+        assert!(!matches_branch_pattern("release/*", "feature/release/1.0"));
+        assert!(matches_branch_pattern("*", "master"));
+        assert!(matches_branch_pattern("*/stable-*", "team/stable-2.1"));
+        assert!(!matches_branch_pattern("a*a", "a"));
+    }
+
+    #[test]
+    fn interested_in_branch() {
+        let mut user = User::new(spark::Email::new("some@example.com".to_string()));
+        assert!(user.is_interested_in_branch("feature"));
+        user.set_branches(vec!["master".to_string(), "release/*".to_string()]);
+        assert!(user.is_interested_in_branch("master"));
+        assert!(user.is_interested_in_branch("release/2.0"));
+        assert!(!user.is_interested_in_branch("feature"));
+    }
+
+    #[test]
+    fn interested_in_approvals() {
+        let approval = |value: &str, old_value: Option<&str>| gerrit::Approval {
+            approval_type: "Code-Review".to_string(),
+            description: None,
+            value: value.to_string(),
+            old_value: old_value.map(str::to_string),
+            by: None,
+            granted_on: None,
+        };
+        let mut user = User::new(spark::Email::new("some@example.com".to_string()));
+        assert!(user.is_interested_in_approvals(&[approval("1", None)]));
+        user.set_vote_threshold(Some(0));
+        assert!(!user.is_interested_in_approvals(&[approval("1", None)]));
+        assert!(user.is_interested_in_approvals(&[approval("1", None), approval("-1", None)]));
+        // unchanged votes don't count
+        assert!(
+            !user.is_interested_in_approvals(&[approval("1", None), approval("-1", Some("-1"))])
+        );
+        assert!(user.is_interested_in_approvals(&[approval("-1", Some("-1"))]));
+        assert!(user.is_interested_in_approvals(&[]));
+    }
+
+    #[test]
+    fn interested_in_approvals_per_label() {
+        let approval = |label: &str, value: &str| gerrit::Approval {
+            approval_type: label.to_string(),
+            description: None,
+            value: value.to_string(),
+            old_value: None,
+            by: None,
+            granted_on: None,
+        };
+        let mut user = User::new(spark::Email::new("some@example.com".to_string()));
+        user.set_label_vote_policy("verified".to_string(), VotePolicy::Below(0));
+        assert!(user.is_interested_in_approvals(&[approval("Code-Review", "1")]));
+        assert!(!user.is_interested_in_approvals(&[approval("Verified", "1")]));
+        assert!(user.is_interested_in_approvals(&[approval("Verified", "-1")]));
+
+        // the rules of labels take precedence over the threshold
+        user.set_vote_threshold(Some(0));
+        user.set_label_vote_policy("Code-Review".to_string(), VotePolicy::All);
+        assert!(user.is_interested_in_approvals(&[approval("Code-Review", "1")]));
+        assert!(!user.is_interested_in_approvals(&[approval("Workflow", "1")]));
+        assert_eq!(user.label_votes().len(), 2);
+
+        user.set_vote_threshold(None);
+        assert!(user.label_votes().is_empty());
     }
 }
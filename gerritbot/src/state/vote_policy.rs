@@ -0,0 +1,42 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Which votes on a label a user gets notifications about.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VotePolicy {
+    All,
+    /// Only votes below the value.
+    Below(i32),
+}
+
+impl VotePolicy {
+    pub fn from_threshold(threshold: Option<i32>) -> Self {
+        threshold.map_or(VotePolicy::All, VotePolicy::Below)
+    }
+
+    /// The votes have to be below the threshold, if any.
+    pub fn threshold(&self) -> Option<i32> {
+        match self {
+            VotePolicy::All => None,
+            VotePolicy::Below(threshold) => Some(*threshold),
+        }
+    }
+
+    pub fn allows(&self, value: i32) -> bool {
+        match self {
+            VotePolicy::All => true,
+            VotePolicy::Below(threshold) => value < *threshold,
+        }
+    }
+}
+
+impl fmt::Display for VotePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VotePolicy::All => write!(f, "all"),
+            VotePolicy::Below(threshold) => write!(f, "below {}", threshold),
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use super::State;
+
+/// Time during which further saves are coalesced into one.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Saves the state in a thread of its own, so a slow disk doesn't hold up
+/// the processing of events. Of the states sent within `SAVE_DELAY` after
+/// the first one, only the latest is written.
+///
+/// The file isn't overwritten if it was modified by someone else since it
+/// was last written, until the state is saved with `overwrite`.
+///
+/// Backups are rotated with the first save, and then at most once per
+/// backup interval, so frequent saves don't replace all of them.
+#[derive(Debug)]
+pub struct StateWriter {
+    sender: mpsc::Sender<Job>,
+    thread: thread::JoinHandle<()>,
+    /// Set when a save is refused, until it is taken.
+    modified: Arc<AtomicBool>,
+}
+
+#[derive(Debug)]
+enum Job {
+    Save(Save),
+    /// Exports are written right away, and never to the state file.
+    Export {
+        state: State,
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug)]
+struct Save {
+    state: State,
+    overwrite: bool,
+}
+
+impl StateWriter {
+    /// Spawn the writer thread, which keeps `backups` previous saves.
+    pub fn spawn(filename: impl Into<PathBuf>, backups: usize, backup_interval: Duration) -> Self {
+        let filename = filename.into();
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let modified = Arc::new(AtomicBool::new(false));
+        let modified_by_thread = modified.clone();
+        // the file as it was loaded
+        let mut written = fingerprint(&filename);
+        let thread = thread::spawn(move || {
+            let mut refused = false;
+            let mut last_backup: Option<Instant> = None;
+            while let Ok(job) = receiver.recv() {
+                let mut save = match job {
+                    Job::Save(save) => save,
+                    Job::Export { state, path } => {
+                        export(&state, &path, &filename);
+                        continue;
+                    }
+                };
+                let deadline = Instant::now() + SAVE_DELAY;
+                // stops early if the writer is finished
+                while let Ok(job) =
+                    receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                {
+                    match job {
+                        Job::Save(newer_save) => {
+                            save = Save {
+                                overwrite: save.overwrite || newer_save.overwrite,
+                                ..newer_save
+                            }
+                        }
+                        Job::Export { state, path } => export(&state, &path, &filename),
+                    }
+                }
+                let current = fingerprint(&filename);
+                if !save.overwrite && current.is_some() && current != written {
+                    error!(
+                        "Not saving state: {} was modified by someone else",
+                        filename.display()
+                    );
+                    if !refused {
+                        refused = true;
+                        modified_by_thread.store(true, Ordering::SeqCst);
+                    }
+                    continue;
+                }
+                let backup = backups > 0
+                    && last_backup
+                        .is_none_or(|last_backup| last_backup.elapsed() >= backup_interval);
+                let result = if backup {
+                    save.state.save_with_backups(&filename, backups)
+                } else {
+                    save.state.save(&filename)
+                };
+                match result {
+                    Ok(()) => {
+                        written = fingerprint(&filename);
+                        refused = false;
+                        if backup {
+                            last_backup = Some(Instant::now());
+                        }
+                    }
+                    Err(e) => error!("Could not save state: {:?}", e),
+                }
+            }
+        });
+        Self {
+            sender,
+            thread,
+            modified,
+        }
+    }
+
+    pub fn save(&self, state: State) {
+        self.send(Job::Save(Save {
+            state,
+            overwrite: false,
+        }));
+    }
+
+    /// Save the state even if the file was modified by someone else.
+    pub fn overwrite(&self, state: State) {
+        self.send(Job::Save(Save {
+            state,
+            overwrite: true,
+        }));
+    }
+
+    /// Write the state to another file, e.g. to move it to another host.
+    pub fn export(&self, state: State, path: PathBuf) {
+        self.send(Job::Export { state, path });
+    }
+
+    fn send(&self, job: Job) {
+        if self.sender.send(job).is_err() {
+            error!("Could not save state: writer stopped");
+        }
+    }
+
+    /// Whether a save was refused since the last call, since the file was
+    /// modified by someone else.
+    pub fn take_modified(&self) -> bool {
+        self.modified.swap(false, Ordering::SeqCst)
+    }
+
+    /// Wait until the pending state is written.
+    pub fn finish(self) {
+        drop(self.sender);
+        if self.thread.join().is_err() {
+            error!("state writer panicked");
+        }
+    }
+}
+
+/// Write the exported state, unless the file is the state file or one of its
+/// backups, which only saves may write.
+fn export(state: &State, path: &Path, state_filename: &Path) {
+    if is_state_file(path, state_filename) {
+        error!(
+            "Not exporting state: {} belongs to the state file",
+            path.display()
+        );
+        return;
+    }
+    if let Err(e) = state.save(path) {
+        error!("Could not export state to {}: {:?}", path.display(), e);
+    }
+}
+
+/// Whether the file, which may not exist yet, is the state file, its lock or
+/// one of its backups.
+fn is_state_file(path: &Path, state_filename: &Path) -> bool {
+    let canonical_dir = |path: &Path| {
+        fs::canonicalize(match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        })
+        .ok()
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let state_name = state_filename
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    canonical_dir(path) == canonical_dir(state_filename) && name.starts_with(&*state_name)
+}
+
+/// Hash of the content of the file, `None` if it can't be read.
+fn fingerprint(filename: &Path) -> Option<u64> {
+    let content = fs::read(filename).ok()?;
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use gerritbot_spark as spark;
+
+    use super::*;
+    use crate::state::backup_filename;
+
+    #[test]
+    fn latest_state_is_written() {
+        let filename =
+            std::env::temp_dir().join(format!("gerritbot-state-{}.json", std::process::id()));
+        let writer = StateWriter::spawn(&filename, 0, Duration::from_secs(3600));
+        let mut state = State::new();
+        for email in &["a@example.com", "b@example.com", "c@example.com"] {
+            state.add_user(spark::EmailRef::new(email));
+            writer.save(state.clone());
+        }
+        writer.finish();
+
+        let saved = State::load(&filename).expect("state not saved");
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(saved.num_users(), 3);
+    }
+
+    #[test]
+    fn modified_file_is_not_overwritten() {
+        let filename = std::env::temp_dir().join(format!(
+            "gerritbot-state-modified-{}.json",
+            std::process::id()
+        ));
+        State::new().save(&filename).unwrap();
+        let writer = StateWriter::spawn(&filename, 0, Duration::from_secs(3600));
+        std::fs::write(&filename, r#"{"users": []}"#).unwrap();
+        let mut state = State::new();
+        state.add_user(spark::EmailRef::new("a@example.com"));
+        writer.save(state.clone());
+        // wait for the refused save
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !writer.take_modified() {
+            assert!(Instant::now() < deadline, "save not refused");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(State::load(&filename).unwrap().num_users(), 0);
+
+        writer.overwrite(state);
+        writer.finish();
+        let saved = State::load(&filename).expect("state not saved");
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(saved.num_users(), 1);
+    }
+
+    #[test]
+    fn backups_are_rotated_once_per_interval() {
+        let filename = std::env::temp_dir().join(format!(
+            "gerritbot-state-rotated-{}.json",
+            std::process::id()
+        ));
+        State::new().save(&filename).unwrap();
+        let mut state = State::new();
+        for email in &["a@example.com", "b@example.com"] {
+            let writer = StateWriter::spawn(&filename, 2, Duration::from_secs(3600));
+            state.add_user(spark::EmailRef::new(email));
+            writer.save(state.clone());
+            // wait for the save, so the next one isn't coalesced with it
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while State::load(&filename).map_or(true, |saved| saved.num_users() < state.num_users())
+            {
+                assert!(Instant::now() < deadline, "state not saved");
+                thread::sleep(Duration::from_millis(10));
+            }
+            state.add_user(spark::EmailRef::new(&format!("x{}", email)));
+            writer.save(state.clone());
+            writer.finish();
+        }
+
+        let num_users = |n| {
+            State::load(backup_filename(&filename, n))
+                .unwrap()
+                .num_users()
+        };
+        // one backup per writer
+        assert_eq!(State::load(&filename).unwrap().num_users(), 4);
+        assert_eq!(num_users(1), 2);
+        assert_eq!(num_users(2), 0);
+        for n in 1..=2 {
+            std::fs::remove_file(backup_filename(&filename, n)).unwrap();
+        }
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn state_file_is_not_exported_to() {
+        let dir = std::env::temp_dir().join(format!("gerritbot-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("state.json");
+        let writer = StateWriter::spawn(&filename, 0, Duration::from_secs(3600));
+        let mut state = State::new();
+        state.add_user(spark::EmailRef::new("a@example.com"));
+        writer.export(state.clone(), dir.join("state.json"));
+        writer.export(state.clone(), dir.join("state.json.1"));
+        writer.export(state, dir.join("export.json"));
+        writer.finish();
+
+        let exported = State::load(dir.join("export.json")).expect("state not exported");
+        let state_written = filename.exists();
+        let backup_written = dir.join("state.json.1").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(exported.num_users(), 1);
+        assert!(!state_written);
+        assert!(!backup_written);
+    }
+}
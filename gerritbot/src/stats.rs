@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Duration, Timelike as _, Utc};
+
+/// Lightweight counters about the running bot, shown to admins by
+/// `admin stats`. They are not persisted and start over with every restart.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    started: DateTime<Utc>,
+    gerrit_events: u64,
+    last_gerrit_event: Option<DateTime<Utc>>,
+    gerrit_errors: u64,
+    last_gerrit_error: Option<DateTime<Utc>>,
+    /// Number of sent messages per hour, oldest first, for the last day.
+    sent_messages: VecDeque<(DateTime<Utc>, u64)>,
+    failed_messages: u64,
+}
+
+impl Stats {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            started: now,
+            gerrit_events: 0,
+            last_gerrit_event: None,
+            gerrit_errors: 0,
+            last_gerrit_error: None,
+            sent_messages: VecDeque::new(),
+            failed_messages: 0,
+        }
+    }
+
+    pub fn started(&self) -> DateTime<Utc> {
+        self.started
+    }
+
+    pub fn gerrit_event(&mut self, now: DateTime<Utc>) {
+        self.gerrit_events += 1;
+        self.last_gerrit_event = Some(now);
+    }
+
+    pub fn gerrit_events(&self) -> u64 {
+        self.gerrit_events
+    }
+
+    pub fn last_gerrit_event(&self) -> Option<DateTime<Utc>> {
+        self.last_gerrit_event
+    }
+
+    pub fn gerrit_error(&mut self, now: DateTime<Utc>) {
+        self.gerrit_errors += 1;
+        self.last_gerrit_error = Some(now);
+    }
+
+    pub fn gerrit_errors(&self) -> u64 {
+        self.gerrit_errors
+    }
+
+    pub fn last_gerrit_error(&self) -> Option<DateTime<Utc>> {
+        self.last_gerrit_error
+    }
+
+    /// Whether the Gerrit event stream delivered events since its last error.
+    pub fn gerrit_connected(&self) -> bool {
+        match (self.last_gerrit_event, self.last_gerrit_error) {
+            (_, None) => true,
+            (Some(event), Some(error)) => event > error,
+            (None, Some(_)) => false,
+        }
+    }
+
+    pub fn message_sent(&mut self, now: DateTime<Utc>) {
+        let hour = now
+            .with_minute(0)
+            .and_then(|t| t.with_second(0))
+            .and_then(|t| t.with_nanosecond(0))
+            .unwrap_or(now);
+        match self.sent_messages.back_mut() {
+            Some((last_hour, count)) if *last_hour == hour => *count += 1,
+            _ => self.sent_messages.push_back((hour, 1)),
+        }
+        while self
+            .sent_messages
+            .front()
+            .is_some_and(|(hour, _)| *hour <= now - Duration::days(1))
+        {
+            self.sent_messages.pop_front();
+        }
+    }
+
+    pub fn message_failed(&mut self) {
+        self.failed_messages += 1;
+    }
+
+    pub fn failed_messages(&self) -> u64 {
+        self.failed_messages
+    }
+
+    /// Number of messages sent during the last day, counted in whole hours.
+    pub fn messages_sent_last_day(&self, now: DateTime<Utc>) -> u64 {
+        self.sent_messages
+            .iter()
+            .filter(|(hour, _)| *hour > now - Duration::days(1))
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone as _;
+
+    use super::*;
+
+    #[test]
+    fn messages_sent_last_day() {
+        let now = Utc.ymd(2020, 2, 28).and_hms(12, 30, 0);
+        let mut stats = Stats::new(now);
+        stats.message_sent(now - Duration::hours(30));
+        stats.message_sent(now - Duration::hours(2));
+        stats.message_sent(now - Duration::minutes(20));
+        stats.message_sent(now);
+        assert_eq!(stats.messages_sent_last_day(now), 3);
+        // the messages of the current hour are kept in one bucket
+        assert_eq!(stats.sent_messages.len(), 2);
+        assert_eq!(stats.messages_sent_last_day(now + Duration::hours(23)), 2);
+    }
+
+    #[test]
+    fn gerrit_connected() {
+        let now = Utc.ymd(2020, 2, 28).and_hms(12, 0, 0);
+        let mut stats = Stats::new(now);
+        assert!(stats.gerrit_connected());
+        stats.gerrit_error(now);
+        assert!(!stats.gerrit_connected());
+        stats.gerrit_event(now + Duration::seconds(1));
+        assert!(stats.gerrit_connected());
+        assert_eq!(stats.gerrit_events(), 1);
+        assert_eq!(stats.gerrit_errors(), 1);
+    }
+}
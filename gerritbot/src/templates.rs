@@ -0,0 +1,100 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as _;
+
+use chrono::Utc;
+use serde::Serialize;
+use tera::{Context, Tera, Value};
+
+use gerritbot_spark as spark;
+
+use crate::state::{Timezone, UserFlag};
+
+/// Tera templates of the messages, for those who prefer templates over the
+/// format script. A template is named after the format function it replaces,
+/// e.g. `format_comment_added`, and gets the same input, along with `flags`,
+/// `timezone`, `locale` and `gerrit_base_url`. Functions without a template
+/// are taken from the format script.
+pub struct Templates {
+    tera: Tera,
+}
+
+#[derive(Serialize)]
+struct TimezoneDetails {
+    name: String,
+    utc_offset: i32,
+}
+
+impl Templates {
+    pub fn new(templates: &BTreeMap<String, String>) -> Result<Self, String> {
+        let mut tera = Tera::default();
+        tera.register_filter(
+            "escape_markdown",
+            |value: &Value, _: &HashMap<String, Value>| match value {
+                Value::String(text) => Ok(Value::String(spark::escape_markdown(text))),
+                value => Ok(value.clone()),
+            },
+        );
+        tera.add_raw_templates(templates.iter())
+            .map_err(|e| format!("invalid format template: {}", error_chain(&e)))?;
+        Ok(Self { tera })
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.tera
+            .get_template_names()
+            .any(|template| template == name)
+    }
+
+    /// Render the template. Like `nil` from a format function, an empty
+    /// result means there is no message.
+    pub fn render<I: Serialize>(
+        &self,
+        name: &str,
+        input: I,
+        flags: Option<&[UserFlag]>,
+        timezone: Option<Timezone>,
+        locale: Option<&str>,
+        gerrit_base_url: Option<&str>,
+    ) -> Result<Option<String>, String> {
+        let mut context = Context::from_serialize(input)
+            .map_err(|e| format!("failed to serialize input: {}", error_chain(&e)))?;
+        if let Some(flags) = flags {
+            let flags: BTreeMap<String, bool> =
+                flags.iter().map(|flag| (flag.to_string(), true)).collect();
+            context.insert("flags", &flags);
+        }
+        if let Some(timezone) = timezone {
+            context.insert(
+                "timezone",
+                &TimezoneDetails {
+                    name: timezone.to_string(),
+                    utc_offset: timezone.offset_at(Utc::now()).local_minus_utc(),
+                },
+            );
+        }
+        context.insert("locale", locale.unwrap_or("en"));
+        context.insert("gerrit_base_url", &gerrit_base_url);
+        let message = self
+            .tera
+            .render(name, &context)
+            .map_err(|e| format!("template {} failed: {}", name, error_chain(&e)))?;
+        let message = message.trim();
+        Ok(if message.is_empty() {
+            None
+        } else {
+            Some(message.to_string())
+        })
+    }
+}
+
+/// The error with its causes, which tell what actually went wrong.
+fn error_chain(error: &tera::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message += ": ";
+        message += &cause.to_string();
+        source = cause.source();
+    }
+    message
+}
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use lru_time_cache::LruCache;
+
+use gerritbot_gerrit as gerrit;
+use gerritbot_spark::{Email, EmailRef, MessageId, MessageIdRef};
+
+use super::{IsHuman as _, SparkEmail as _};
+
+/// Number of changes whose participants are remembered.
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Time after which participants of a change without any activity are
+/// forgotten.
+const DEFAULT_EXPIRATION: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Tracks the participants of changes, i.e. users that were added as
+/// reviewers or commented on a change, by observing gerrit events. Also
+/// remembers the first notification sent to each user about a change, so
+/// later notifications can be threaded under it.
+#[derive(Clone)]
+pub struct ChangeTracker {
+    participants: LruCache<u32, BTreeSet<Email>>,
+    threads: LruCache<u32, BTreeMap<Email, MessageId>>,
+    /// Changes known to be submittable.
+    submittable: LruCache<u32, ()>,
+}
+
+impl Default for ChangeTracker {
+    fn default() -> Self {
+        Self::with_expiry_duration_and_capacity(DEFAULT_EXPIRATION, DEFAULT_CAPACITY)
+    }
+}
+
+impl ChangeTracker {
+    pub fn with_expiry_duration_and_capacity(expiration: Duration, capacity: usize) -> Self {
+        Self {
+            participants: LruCache::with_expiry_duration_and_capacity(expiration, capacity),
+            threads: LruCache::with_expiry_duration_and_capacity(expiration, capacity),
+            submittable: LruCache::with_expiry_duration_and_capacity(expiration, capacity),
+        }
+    }
+
+    /// Remember the user as participant of the change. Bots are ignored.
+    pub fn add_participant(&mut self, change: &gerrit::Change, user: &gerrit::User) {
+        if !user.is_human() {
+            return;
+        }
+
+        if let Some(email) = user.spark_email() {
+            self.participants
+                .entry(change.number)
+                .or_insert_with(BTreeSet::new)
+                .insert(email.to_owned());
+        }
+    }
+
+    /// Participants of the change seen so far.
+    pub fn participants<'a>(
+        &'a self,
+        change: &gerrit::Change,
+    ) -> impl Iterator<Item = &'a EmailRef> + 'a {
+        self.participants
+            .peek(&change.number)
+            .into_iter()
+            .flatten()
+            .map(|email| &**email)
+    }
+
+    /// First notification sent to the user about the change.
+    pub fn thread(&self, change_number: u32, email: &EmailRef) -> Option<&MessageIdRef> {
+        self.threads
+            .peek(&change_number)
+            .and_then(|threads| threads.get(email))
+            .map(|message_id| &**message_id)
+    }
+
+    /// Remember a notification sent to the user about the change, unless
+    /// there already is an earlier one.
+    pub fn add_thread(&mut self, change_number: u32, email: Email, message_id: MessageId) {
+        self.threads
+            .entry(change_number)
+            .or_insert_with(BTreeMap::new)
+            .entry(email)
+            .or_insert(message_id);
+    }
+
+    /// Remember whether the change is submittable. Returns whether it just
+    /// became submittable.
+    pub fn set_submittable(&mut self, change: &gerrit::Change, submittable: bool) -> bool {
+        if submittable {
+            self.submittable.insert(change.number, ()).is_none()
+        } else {
+            self.submittable.remove(&change.number);
+            false
+        }
+    }
+
+    /// Forget the participants and notification threads of a change that was
+    /// merged, abandoned or deleted.
+    pub fn forget(&mut self, change: &gerrit::Change) {
+        self.participants.remove(&change.number);
+        self.threads.remove(&change.number);
+        self.submittable.remove(&change.number);
+    }
+}